@@ -0,0 +1,28 @@
+//! Generate the gRPC service stubs of `src/grpc.rs`.
+//!
+//! The service is described in Rust instead of compiling
+//! `proto/vismatch.proto`, so building does not need `protoc`. Keep both
+//! in sync.
+
+fn method(name: &str, route_name: &str, input_type: &str, output_type: &str) -> tonic_build::manual::MethodBuilder {
+    tonic_build::manual::Method::builder()
+        .name(name)
+        .route_name(route_name)
+        .input_type(format!("crate::grpc::{}", input_type))
+        .output_type(format!("crate::grpc::{}", output_type))
+        .codec_path("tonic_prost::ProstCodec")
+}
+
+fn main() {
+    let vismatch_service = tonic_build::manual::Service::builder()
+        .name("VisMatch")
+        .package("vismatch")
+        .method(method("upload", "Upload", "UploadChunk", "UploadReply").client_streaming().build())
+        .method(method("compare", "Compare", "CompareRequest", "CompareReply").build())
+        .method(method("remove", "Remove", "RemoveRequest", "RemoveReply").build())
+        .build();
+
+    tonic_build::manual::Builder::new()
+        .build_client(false)
+        .compile(&[vismatch_service]);
+}
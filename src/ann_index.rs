@@ -0,0 +1,518 @@
+//! Approximate nearest neighbor search for large projects.
+//!
+//! Ranking a query scans every image of a project, which gets slow with
+//! hundreds of thousands of images. Projects listed in the `[ann_index]`
+//! config get an HNSW graph (hierarchical navigable small world) over
+//! their bit-packed hashes instead, a query then visits a few thousand
+//! images at most, at the price of sometimes missing a close one.
+//!
+//! The graph is built in the background on the first query, uploads and
+//! removals update it in place. Until it is built, after larger changes,
+//! and for projects below `min_images`, queries use the exact scan.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use serde::Deserialize;
+
+use crate::blocking::run_blocking;
+use crate::image_hash::{Hash, ImageDistEntry, ImageHashEntry, dist_to_similarity};
+
+/// Project list entry matching every project.
+const ANY_PROJECT: &str = "*";
+
+/// Highest graph layer, only reached by absurdly large projects.
+const MAX_LEVEL: usize = 16;
+
+/// Share of removed images after which the graph is rebuilt, removed
+/// images still take part in routing but are never returned.
+const MAX_DELETED_RATIO: f64 = 0.25;
+
+/// ANN settings as written in the config file.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnnConfig {
+    /// Projects ranked through the index, `*` for all.
+    pub projects: Vec<String>,
+    /// Smaller projects always use the exact scan.
+    pub min_images: usize,
+    /// Links per image and layer, twice as many on the bottom layer.
+    pub m: usize,
+    /// Candidates considered while linking a new image.
+    pub ef_construction: usize,
+    /// Candidates considered per query, at least `top_k`, higher finds
+    /// more of the true neighbors but is slower.
+    pub ef_search: usize,
+}
+
+impl Default for AnnConfig {
+    fn default() -> Self {
+        AnnConfig {
+            projects: Vec::new(),
+            min_images: 10_000,
+            m: 16,
+            ef_construction: 100,
+            ef_search: 64,
+        }
+    }
+}
+
+impl AnnConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.m < 2 {
+            return Err("`ann_index.m` must be at least 2".to_owned());
+        }
+        if self.ef_construction == 0 || self.ef_search == 0 {
+            return Err("`ann_index.ef_construction` and `ann_index.ef_search` must not be zero".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Whether `project_name` is listed.
+    pub fn is_enabled_for(&self, project_name: &str) -> bool {
+        self.projects.iter().any(|p| p == ANY_PROJECT || p == project_name)
+    }
+}
+
+/// Hash bits packed into 64-bit words, so distances are a few popcounts.
+fn pack_bits(hash: &Hash) -> Vec<u64> {
+    hash.bits.chunks(64)
+        .map(|word_bits| word_bits.iter()
+            .enumerate()
+            .fold(0u64, |acc, (i, b)| acc | ((*b as u64) << i)))
+        .collect()
+}
+
+fn hamming(lhs: &[u64], rhs: &[u64]) -> u32 {
+    lhs.iter().zip(rhs).map(|(a, b)| (a ^ b).count_ones()).sum()
+}
+
+/// Deterministic layer of a node, geometrically distributed with
+/// factor `1 / ln(m)` as in the HNSW paper.
+fn random_level(node: u32, m: usize) -> usize {
+    // splitmix64, good enough to spread nodes over layers.
+    let mut z = (node as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+
+    let uniform = ((z >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+    let level = -uniform.ln() / (m as f64).ln();
+    (level as usize).min(MAX_LEVEL)
+}
+
+/// HNSW graph over the hashes of one project.
+#[derive(Debug)]
+pub struct HnswIndex {
+    m: usize,
+    ef_construction: usize,
+    ef_search: usize,
+    bit_length: usize,
+    /// Packed hash words, `words_per_hash` per node.
+    words: Vec<u64>,
+    words_per_hash: usize,
+    image_names: Vec<PathBuf>,
+    /// Neighbors of every node, per layer from the bottom.
+    links: Vec<Vec<Vec<u32>>>,
+    deleted: Vec<bool>,
+    deleted_count: usize,
+    /// Live node of every image.
+    nodes: HashMap<PathBuf, u32>,
+    entry_point: Option<u32>,
+    top_level: usize,
+}
+
+impl HnswIndex {
+    /// Empty index for hashes of `bit_length` bits.
+    pub fn new(config: &AnnConfig, bit_length: usize) -> Self {
+        HnswIndex {
+            m: config.m,
+            ef_construction: config.ef_construction,
+            ef_search: config.ef_search,
+            bit_length,
+            words: Vec::new(),
+            words_per_hash: bit_length.div_ceil(64),
+            image_names: Vec::new(),
+            links: Vec::new(),
+            deleted: Vec::new(),
+            deleted_count: 0,
+            nodes: HashMap::new(),
+            entry_point: None,
+            top_level: 0,
+        }
+    }
+
+    /// Index every entry of a project hash list.
+    pub fn build(config: &AnnConfig, hash_list: &[ImageHashEntry]) -> Self {
+        let bit_length = hash_list.first().map_or(0, |h_ent| h_ent.hash.bits.len());
+        let mut index = HnswIndex::new(config, bit_length);
+        for h_entry in hash_list {
+            index.insert(h_entry);
+        }
+        index
+    }
+
+    /// Images that can be returned.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Whether so many images were removed that a rebuild pays off.
+    fn is_degraded(&self) -> bool {
+        self.deleted_count as f64 > self.image_names.len() as f64 * MAX_DELETED_RATIO
+    }
+
+    fn words_of(&self, node: u32) -> &[u64] {
+        let start = node as usize * self.words_per_hash;
+        &self.words[start..start + self.words_per_hash]
+    }
+
+    fn max_links(&self, level: usize) -> usize {
+        match level {
+            0 => self.m * 2,
+            _ => self.m,
+        }
+    }
+
+    /// Closest nodes to `query` on `level` found from `entry_points`,
+    /// sorted by distance, at most `ef`.
+    fn search_layer(&self, query: &[u64], entry_points: &[u32], ef: usize, level: usize) -> Vec<(u32, u32)> {
+        let mut visited: HashSet<u32> = entry_points.iter().copied().collect();
+        let mut candidates: BinaryHeap<Reverse<(u32, u32)>> = BinaryHeap::new();
+        let mut closest: BinaryHeap<(u32, u32)> = BinaryHeap::new();
+
+        for &node in entry_points {
+            let dist = hamming(query, self.words_of(node));
+            candidates.push(Reverse((dist, node)));
+            closest.push((dist, node));
+        }
+
+        while let Some(Reverse((dist, node))) = candidates.pop() {
+            let farthest = closest.peek().map_or(u32::MAX, |(d, _)| *d);
+            if dist > farthest && closest.len() >= ef {
+                break;
+            }
+
+            for &neighbor in &self.links[node as usize][level] {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+
+                let neighbor_dist = hamming(query, self.words_of(neighbor));
+                let farthest = closest.peek().map_or(u32::MAX, |(d, _)| *d);
+                if closest.len() < ef || neighbor_dist < farthest {
+                    candidates.push(Reverse((neighbor_dist, neighbor)));
+                    closest.push((neighbor_dist, neighbor));
+                    if closest.len() > ef {
+                        closest.pop();
+                    }
+                }
+            }
+        }
+
+        closest.into_sorted_vec()
+    }
+
+    /// Walk down the upper layers to the node closest to `query` on `level`.
+    fn descend(&self, query: &[u64], entry_point: u32, level: usize) -> u32 {
+        (level + 1..=self.top_level).rev()
+            .fold(entry_point, |node, l| self.search_layer(query, &[node], 1, l)
+                .first()
+                .map_or(node, |(_, closest)| *closest))
+    }
+
+    /// Add an image, replacing an older hash of the same image.
+    pub fn insert(&mut self, h_entry: &ImageHashEntry) {
+        self.remove(&h_entry.image_name);
+
+        let query = pack_bits(&h_entry.hash);
+        let node = self.image_names.len() as u32;
+        let level = random_level(node, self.m);
+
+        self.words.extend_from_slice(&query);
+        self.words.resize((node as usize + 1) * self.words_per_hash, 0);
+        self.image_names.push(h_entry.image_name.clone());
+        self.links.push(vec![Vec::new(); level + 1]);
+        self.deleted.push(false);
+        self.nodes.insert(h_entry.image_name.clone(), node);
+
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(node);
+            self.top_level = level;
+            return;
+        };
+
+        let query = self.words_of(node).to_vec();
+        let mut entry_points = vec![self.descend(&query, entry_point, level)];
+
+        for l in (0..=level.min(self.top_level)).rev() {
+            let found = self.search_layer(&query, &entry_points, self.ef_construction, l);
+            let neighbors: Vec<u32> = found.iter()
+                .map(|(_, n)| *n)
+                .take(self.max_links(l))
+                .collect();
+
+            for &neighbor in &neighbors {
+                self.links[neighbor as usize][l].push(node);
+                self.shrink_links(neighbor, l);
+            }
+            self.links[node as usize][l] = neighbors;
+            entry_points = found.into_iter().map(|(_, n)| n).collect();
+        }
+
+        if level > self.top_level {
+            self.entry_point = Some(node);
+            self.top_level = level;
+        }
+    }
+
+    /// Keep only the closest links of `node` on `level`.
+    fn shrink_links(&mut self, node: u32, level: usize) {
+        let max_links = self.max_links(level);
+        if self.links[node as usize][level].len() <= max_links {
+            return;
+        }
+
+        let words = self.words_of(node);
+        let mut links: Vec<(u32, u32)> = self.links[node as usize][level].iter()
+            .map(|&n| (hamming(words, self.words_of(n)), n))
+            .collect();
+        links.sort_unstable();
+
+        self.links[node as usize][level] = links.into_iter()
+            .take(max_links)
+            .map(|(_, n)| n)
+            .collect();
+    }
+
+    /// Hide an image from results, it stays in the graph for routing.
+    pub fn remove(&mut self, image_name: &Path) {
+        if let Some(node) = self.nodes.remove(image_name) {
+            self.deleted[node as usize] = true;
+            self.deleted_count += 1;
+        }
+    }
+
+    /// The `k` closest images to `hash`, or `None` when its length
+    /// doesn't match the indexed hashes.
+    pub fn search(&self, hash: &Hash, k: usize) -> Option<Vec<ImageDistEntry>> {
+        if hash.bits.len() != self.bit_length {
+            return None;
+        }
+
+        let Some(entry_point) = self.entry_point else {
+            return Some(Vec::new());
+        };
+
+        let query = pack_bits(hash);
+        let entry_point = self.descend(&query, entry_point, 0);
+        let ef = self.ef_search.max(k);
+
+        let result = self.search_layer(&query, &[entry_point], ef, 0)
+            .into_iter()
+            .filter(|(_, node)| !self.deleted[*node as usize])
+            .take(k)
+            .map(|(dist, node)| ImageDistEntry {
+                image_name: self.image_names[node as usize].clone(),
+                distance: dist as f64,
+                similarity: dist_to_similarity(dist as f64, self.bit_length),
+            })
+            .collect();
+
+        Some(result)
+    }
+}
+
+/// Index of a project shared between queries and updates.
+pub type SharedIndex = Arc<RwLock<HnswIndex>>;
+
+#[derive(Debug, Default)]
+struct ProjectIndex {
+    /// Bumped on every change, a build started before is thrown away.
+    generation: u64,
+    index: Option<SharedIndex>,
+    is_building: bool,
+}
+
+/// ANN indexes of all projects.
+///
+/// Changes to a project hash list must be reported with `insert`,
+/// `remove` or `invalidate` while holding its write lock, so an index
+/// never lags behind the list it answers for.
+#[derive(Debug, Default)]
+pub struct AnnIndexes {
+    config: AnnConfig,
+    projects: Mutex<HashMap<String, ProjectIndex>>,
+}
+
+impl AnnIndexes {
+    pub fn new(config: AnnConfig) -> Self {
+        AnnIndexes { config, projects: Mutex::new(HashMap::new()) }
+    }
+
+    /// Index to rank `hash_list` of `project_name` with, `None` when the
+    /// exact scan should be used.
+    ///
+    /// A missing index of a listed project is built in the background
+    /// from a copy of `hash_list`. Hash types not compared by hamming
+    /// distance are never indexed.
+    pub fn lookup(self: &Arc<Self>, project_name: &str, hash_list: &[ImageHashEntry]) -> Option<SharedIndex> {
+        if !self.config.is_enabled_for(project_name) || hash_list.len() < self.config.min_images {
+            return None;
+        }
+
+        if hash_list.first().is_some_and(|h_ent| !h_ent.hash_type.is_hamming()) {
+            return None;
+        }
+
+        let mut projects = self.projects.lock().unwrap_or_else(|e| e.into_inner());
+        let project = projects.entry(project_name.to_owned()).or_default();
+
+        if let Some(index) = &project.index {
+            return Some(Arc::clone(index));
+        }
+
+        if !project.is_building {
+            project.is_building = true;
+            self.spawn_build(project_name.to_owned(), project.generation, hash_list.to_vec());
+        }
+        None
+    }
+
+    fn spawn_build(self: &Arc<Self>, project_name: String, generation: u64, hash_list: Vec<ImageHashEntry>) {
+        let indexes = Arc::clone(self);
+
+        tokio::spawn(async move {
+            let build_start = Instant::now();
+            let config = indexes.config.clone();
+            let built = run_blocking(move || HnswIndex::build(&config, &hash_list)).await;
+
+            let mut projects = indexes.projects.lock().unwrap_or_else(|e| e.into_inner());
+            let project = projects.entry(project_name.clone()).or_default();
+            project.is_building = false;
+
+            match built {
+                Ok(index) if project.generation == generation => {
+                    tracing::info!(project = %project_name, images = index.len(), elapsed = ?build_start.elapsed(), "ANN index built");
+                    project.index = Some(Arc::new(RwLock::new(index)));
+                },
+                Ok(_) => tracing::info!(project = %project_name, "project changed while building its ANN index, build dropped"),
+                Err(e) => tracing::warn!(project = %project_name, error = %e, "cannot build ANN index"),
+            }
+        });
+    }
+
+    /// Apply a change to the index of `project_name`, dropping it when
+    /// `update` returns false.
+    fn update<F>(&self, project_name: &str, update: F)
+        where F: FnOnce(&mut HnswIndex) -> bool {
+
+        let mut projects = self.projects.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(project) = projects.get_mut(project_name) else {
+            return;
+        };
+        project.generation += 1;
+
+        let Some(index) = &project.index else {
+            return;
+        };
+
+        let is_kept = {
+            let mut index = index.write().unwrap_or_else(|e| e.into_inner());
+            update(&mut index) && !index.is_degraded()
+        };
+        if !is_kept {
+            project.index = None;
+        }
+    }
+
+    /// Add or replace an image in the index of `project_name`.
+    pub fn insert(&self, project_name: &str, h_entry: &ImageHashEntry) {
+        self.update(project_name, |index| {
+            let is_comparable = h_entry.hash.bits.len() == index.bit_length;
+            if is_comparable {
+                index.insert(h_entry);
+            }
+            is_comparable
+        });
+    }
+
+    /// Drop an image from the index of `project_name`.
+    pub fn remove(&self, project_name: &str, image_name: &Path) {
+        self.update(project_name, |index| {
+            index.remove(image_name);
+            true
+        });
+    }
+
+    /// Forget the index of `project_name`, for changes that replace the
+    /// whole hash list.
+    pub fn invalidate(&self, project_name: &str) {
+        self.update(project_name, |_| false);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_hash::{HashType, calc_similarity_list_from_hash};
+
+    fn mk_entry(i: u64) -> ImageHashEntry {
+        // spread hashes over the space with a cheap mixer.
+        let word = i.wrapping_mul(0x9e37_79b9_7f4a_7c15).rotate_left(17) ^ i;
+        let bits = (0..64).map(|b| (word >> b) & 1 == 1).collect();
+        ImageHashEntry::new(PathBuf::from(format!("{}.png", i)), HashType::PHASH, Hash { bits })
+    }
+
+    #[test]
+    fn test_hnsw_index() {
+        let hash_list: Vec<ImageHashEntry> = (0..1000).map(mk_entry).collect();
+        let mut index = HnswIndex::build(&AnnConfig::default(), &hash_list);
+        assert_eq!(index.len(), 1000);
+
+        // every indexed image finds itself first.
+        for h_entry in hash_list.iter().step_by(25) {
+            let result = index.search(&h_entry.hash, 3).unwrap();
+            assert_eq!(result.len(), 3);
+            assert_eq!(result[0].image_name, h_entry.image_name);
+            assert_eq!(result[0].distance, 0.0);
+        }
+
+        // neighbors agree with the exact scan most of the time.
+        let query = mk_entry(5000).hash;
+        let mut exact = calc_similarity_list_from_hash(&query, &hash_list);
+        exact.sort();
+        let approximate = index.search(&query, 10).unwrap();
+        assert!(approximate[0].distance <= exact[2].distance);
+
+        // removed images are never returned, replaced ones move.
+        index.remove(&hash_list[0].image_name);
+        let result = index.search(&hash_list[0].hash, 1).unwrap();
+        assert_ne!(result[0].image_name, hash_list[0].image_name);
+
+        let moved = ImageHashEntry::new(hash_list[1].image_name.clone(), HashType::PHASH, hash_list[2].hash.clone());
+        index.insert(&moved);
+        let result = index.search(&hash_list[2].hash, 2).unwrap();
+        assert!(result.iter().all(|d| d.distance == 0.0));
+        assert_eq!(index.len(), 999);
+
+        assert!(index.search(&Hash { bits: vec![true; 8] }, 1).is_none());
+    }
+
+    #[test]
+    fn test_ann_config() {
+        let config = AnnConfig { projects: vec!["big".to_owned()], ..Default::default() };
+        assert!(config.is_enabled_for("big"));
+        assert!(!config.is_enabled_for("small"));
+        assert!(config.validate().is_ok());
+        assert!(AnnConfig { m: 1, ..config }.validate().is_err());
+    }
+}
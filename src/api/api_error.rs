@@ -8,20 +8,39 @@ pub enum AppError {
     InternalError(String),
     Teapot(String),
     BadRequest(String),
+    PayloadTooLarge(String),
+    /// Missing or unknown API key.
+    Unauthorized(String),
+    /// The API key may not access the project.
+    Forbidden(String),
+    /// Another request is working on the same thing, e.g. a retried
+    /// upload whose first attempt is still processed.
+    Conflict(String),
+    /// Rate limit hit, with the seconds to wait before retrying.
+    TooManyRequests(String, u64),
+    /// A blocking computation panicked, the detail is only logged.
+    ComputePanic(String),
 }
 
-#[derive(serde::Serialize, Debug)]
+#[derive(serde::Serialize, Debug, utoipa::ToSchema)]
 pub struct AppErrorPayload {
     message: String,
+    /// Same as the `X-Request-Id` response header, to find the request in logs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl AppErrorPayload {
+    fn new(message: String) -> Self {
+        AppErrorPayload { message, request_id: crate::middleware::current_request_id() }
+    }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
         match self {
             AppError::InternalError(msg) => {
-                let body = json!( AppErrorPayload{
-                    message: msg,
-                });
+                let body = json!(AppErrorPayload::new(msg));
 
                 (   
                     http::StatusCode::INTERNAL_SERVER_ERROR, 
@@ -31,9 +50,7 @@ impl IntoResponse for AppError {
             },
 
             AppError::Teapot(msg) => {
-                let body = json!( AppErrorPayload{
-                    message: msg,
-                });
+                let body = json!(AppErrorPayload::new(msg));
 
                 (   
                     http::StatusCode::IM_A_TEAPOT, 
@@ -43,9 +60,7 @@ impl IntoResponse for AppError {
             },
 
             AppError::BadRequest(msg) => {
-                let body = json!( AppErrorPayload{
-                    message: msg,
-                });
+                let body = json!(AppErrorPayload::new(msg));
 
                 (   
                     http::StatusCode::BAD_REQUEST, 
@@ -53,6 +68,78 @@ impl IntoResponse for AppError {
                     body.to_string()
                 ).into_response()
             },
+
+            AppError::PayloadTooLarge(msg) => {
+                let body = json!(AppErrorPayload::new(msg));
+
+                (   
+                    http::StatusCode::PAYLOAD_TOO_LARGE, 
+                    [(http::header::CONTENT_TYPE, "application/json")],
+                    body.to_string()
+                ).into_response()
+            },
+
+            AppError::Unauthorized(msg) => {
+                let body = json!(AppErrorPayload::new(msg));
+
+                (   
+                    http::StatusCode::UNAUTHORIZED, 
+                    [
+                        (http::header::CONTENT_TYPE, "application/json"),
+                        (http::header::WWW_AUTHENTICATE, "Bearer"),
+                    ],
+                    body.to_string()
+                ).into_response()
+            },
+
+            AppError::Forbidden(msg) => {
+                let body = json!(AppErrorPayload::new(msg));
+
+                (   
+                    http::StatusCode::FORBIDDEN, 
+                    [(http::header::CONTENT_TYPE, "application/json")],
+                    body.to_string()
+                ).into_response()
+            },
+
+            AppError::Conflict(msg) => {
+                let body = json!(AppErrorPayload::new(msg));
+
+                (   
+                    http::StatusCode::CONFLICT, 
+                    [(http::header::CONTENT_TYPE, "application/json")],
+                    body.to_string()
+                ).into_response()
+            },
+
+            AppError::TooManyRequests(msg, retry_after_secs) => {
+                let body = json!(AppErrorPayload::new(msg));
+
+                (   
+                    http::StatusCode::TOO_MANY_REQUESTS, 
+                    [
+                        (http::header::CONTENT_TYPE, "application/json".to_owned()),
+                        (http::header::RETRY_AFTER, retry_after_secs.to_string()),
+                    ],
+                    body.to_string()
+                ).into_response()
+            },
+
+            AppError::ComputePanic(_detail) => {
+                let body = json!(AppErrorPayload::new("internal computation failed".to_owned()));
+
+                (   
+                    http::StatusCode::INTERNAL_SERVER_ERROR, 
+                    [(http::header::CONTENT_TYPE, "application/json")],
+                    body.to_string()
+                ).into_response()
+            },
         }
     }
-}
\ No newline at end of file
+}
+
+impl From<crate::blocking::ComputePanic> for AppError {
+    fn from(err: crate::blocking::ComputePanic) -> Self {
+        AppError::ComputePanic(err.0)
+    }
+}
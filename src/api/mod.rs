@@ -2,21 +2,75 @@ mod api_error;
 pub use api_error::*;
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+use crate::image_hash::{HashParams, HashType};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
 pub struct SimilarImageEntry {
 	pub image_name: String,	  // the name of image
 	pub distance: f32,		  // distance score, lower is closer
+	#[serde(default)]
+	pub similarity_score: f32, // normalized similarity in [0, 1], higher is closer
 	pub data: Option<String>, // image data as base64 string.
 }
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+
+/// Quote a CSV field if it contains separators, quotes or line breaks.
+fn csv_field(field: &str) -> String {
+	match field.contains([',', '"', '\n', '\r']) {
+		false => field.to_owned(),
+		true => format!("\"{}\"", field.replace('"', "\"\"")),
+	}
+}
+
+/// Render comparison results as CSV, ranked from the closest match.
+/// 
+/// Columns are `rank,image_name,distance,similarity_score`, image data
+/// is never included.
+pub fn to_csv(results: &[SimilarImageEntry]) -> String {
+	let mut csv = String::from("rank,image_name,distance,similarity_score\n");
+
+	for (rank, entry) in results.iter().enumerate() {
+		csv.push_str(&format!("{},{},{},{}\n",
+			rank + 1,
+			csv_field(&entry.image_name),
+			entry.distance,
+			entry.similarity_score));
+	}
+
+	csv
+}
+/// Serializable form of an image hash entry, with hash bits as hex.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct ImageHashEntryJson {
+	pub image_name: String,
+	pub hash_type: HashType,
+	pub hash_hex: String, // hash bits, most significant first
+	#[serde(default)]
+	pub params: Option<HashParams>, // parameters the hash was calculated with, must match the project
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
 pub struct CompareImageReq {
 	pub project_name: String,
+	#[serde(default)]
 	pub data: String,
     pub with_image: bool,
+	#[serde(default)]
+	pub precomputed_entry: Option<ImageHashEntryJson>, // skip hashing, use this hash as query
+	#[serde(default)]
+	pub hash_size: Option<String>, // "small", "medium" or "large", must match the project
+	#[serde(default)]
+	pub top_k: Option<usize>, // closest images to return, server default if unset
+	#[serde(default)]
+	pub max_distance: Option<u32>, // only return images within this many differing hash bits
+	#[serde(default)]
+	pub rotation_invariant: bool, // also match the query turned by 90, 180 and 270 degrees
+	#[serde(default)]
+	pub flip_invariant: bool, // also match the query mirrored horizontally and vertically
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
 pub struct CompareImageResp {
 	pub success: bool,
 	pub message: String,
@@ -24,29 +78,499 @@ pub struct CompareImageResp {
 	pub compare_result: Vec<SimilarImageEntry>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+/// Several query images compared against one project.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct BatchCompareReq {
+	pub project_name: String,
+	pub images: Vec<String>, // query images as base64 strings
+	#[serde(default)]
+	pub with_image: bool,
+	#[serde(default)]
+	pub hash_size: Option<String>, // "small", "medium" or "large", must match the project
+	#[serde(default)]
+	pub top_k: Option<usize>, // closest images per query, server default if unset
+	#[serde(default)]
+	pub max_distance: Option<u32>, // only return images within this many differing hash bits
+}
+
+/// Result of one query of a batch, a failed query does not fail the batch.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
+pub struct BatchCompareResult {
+	pub success: bool,
+	pub message: String,
+	pub compare_result: Vec<SimilarImageEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
+pub struct BatchCompareResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String,
+	pub results: Vec<BatchCompareResult>, // in the order of request images
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
 pub struct UploadImageReq {
 	pub project_name: String,
     pub image_name: String,
 	pub data: String,
+	#[serde(default)]
+	pub hash_size: Option<String>, // "small", "medium" or "large", must match the project
+	#[serde(default)]
+	pub tags: Vec<String>,         // labels for image filters, replace those of an earlier upload
+}
+
+/// Metadata part of a multipart upload, the image itself is sent as raw
+/// bytes in another part.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct UploadImageMeta {
+	pub project_name: String,
+	pub image_name: String,
+	#[serde(default)]
+	pub hash_size: Option<String>, // "small", "medium" or "large", must match the project
+	#[serde(default)]
+	pub tags: Vec<String>,         // labels for image filters, replace those of an earlier upload
+}
+
+/// Query of raw body uploads, the names come from the path.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::IntoParams)]
+pub struct RawUploadQuery {
+	#[serde(default)]
+	pub hash_size: Option<String>, // "small", "medium" or "large", must match the project
+	#[serde(default)]
+	pub tags: Option<String>,      // comma separated labels for image filters
+}
+
+impl RawUploadQuery {
+	/// `tags` split on commas, blank tags are dropped.
+	pub fn tag_list(&self) -> Vec<String> {
+		self.tags.iter()
+			.flat_map(|tags| tags.split(','))
+			.map(|tag| tag.trim().to_owned())
+			.filter(|tag| !tag.is_empty())
+			.collect()
+	}
+}
+
+/// A valid 1x1 PNG, the `data` of default requests.
+const PLACEHOLDER_PNG_B64: &str =
+	"iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=";
+
+impl Default for CompareImageReq {
+	/// Placeholder request for tests, `data` is a valid 1x1 PNG.
+	fn default() -> Self {
+		CompareImageReq {
+			project_name: "test_project".to_owned(),
+			data: PLACEHOLDER_PNG_B64.to_owned(),
+			with_image: false,
+			precomputed_entry: None,
+			hash_size: None,
+			top_k: None,
+			max_distance: None,
+			rotation_invariant: false,
+			flip_invariant: false,
+		}
+	}
+}
+
+impl Default for UploadImageReq {
+	/// Placeholder request for tests, `data` is a valid 1x1 PNG.
+	fn default() -> Self {
+		UploadImageReq {
+			project_name: "test_project".to_owned(),
+			image_name: "test.png".to_owned(),
+			data: PLACEHOLDER_PNG_B64.to_owned(),
+			hash_size: None,
+			tags: Vec::new(),
+		}
+	}
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
 pub struct UploadImageResp {
 	pub success: bool,
 	pub message: String,
 	pub token: String,
+	pub image_size_bytes: u64, // size of the stored image file
+	pub hash_size_bits: usize, // bits of the computed hash
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct CreateProjectReq {
+	pub project_name: String, // name of the new, empty project
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct CreateProjectResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct DeleteProjectResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String,
+	pub removed_images: usize,      // image files removed from disk
+	pub removed_cache_files: usize, // hash cache files removed from disk
+	pub removed_other_files: usize, // any other file in the project folder
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct RenameProjectReq {
+	pub new_name: String, // the name the project is renamed to
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct RenameProjectResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String, // the new name of the project
+	pub image_count: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct CopyProjectReq {
+	pub destination_name: String, // name of the new project
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct CopyProjectResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String, // the name of the new project
+	pub image_count: usize,   // images indexed in the new project
+}
+
+/// Criteria to select images of a project, all given criteria must match.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default, utoipa::ToSchema)]
+pub struct ImageFilter {
+	#[serde(default)]
+	pub tags: Vec<String>,                  // tags set on upload, images must carry all of them
+	#[serde(default, alias = "max_name_pattern")]
+	pub name_pattern: Option<String>,       // `*` and `?` wildcards, e.g. "cat_*.png"
+	#[serde(default)]
+	pub max_distance_to_ref: Option<f32>,   // normalized distance in [0, 1], needs `ref_image_name`
+	#[serde(default)]
+	pub min_similarity_to_ref: Option<f32>, // similarity in [0, 1], needs `ref_image_name`
+	#[serde(default)]
+	pub ref_image_name: Option<String>,     // reference image in the same project
+	#[serde(default)]
+	pub added_before: Option<u64>,          // unix time in seconds, of the last upload of the image
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
+pub struct CloneSubsetReq {
+	pub destination: String, // name of the new project
+	#[serde(default)]
+	pub filter: ImageFilter,
+}
+
+impl ImageFilter {
+	/// True if no criterion is set, i.e. the filter selects every image.
+	pub fn is_empty(&self) -> bool {
+		self.tags.is_empty()
+			&& self.name_pattern.is_none()
+			&& self.max_distance_to_ref.is_none()
+			&& self.min_similarity_to_ref.is_none()
+			&& self.ref_image_name.is_none()
+			&& self.added_before.is_none()
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
+pub struct DeleteImagesReq {
+	pub filter: ImageFilter, // must set at least one criterion
+	#[serde(default)]
+	pub dry_run: bool,       // only list the matching images
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct DeleteImagesResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String,
+	pub dry_run: bool,
+	pub deleted: Vec<String>, // image names, would-be deleted ones on dry run
+}
+
+/// A record of one comparison request, never holds image data.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
+pub struct CompareHistoryEntry {
+	pub timestamp: u64,                   // unix time in seconds
+	pub project_name: String,
+	pub query_hash_hex: String,           // hash of the query image
+	pub top_result_name: Option<String>,  // closest image, if any
+	pub top_result_distance: Option<f32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::IntoParams)]
+pub struct CompareHistoryQuery {
+	pub limit: Option<usize>,    // max entries returned
+	pub project: Option<String>, // only entries of this project
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
+pub struct CompareHistoryResp {
+	pub success: bool,
+	pub message: String,
+	pub history: Vec<CompareHistoryEntry>, // newest first
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default, utoipa::ToSchema)]
+pub struct PrecomputeHashReq {
+	#[serde(default)]
+	pub hash_type: Option<HashType>, // must match the project, default the project's type
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct PrecomputeHashResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String,
+	pub image_name: String,
+	pub hash_type: HashType,
+	pub hash_hex: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default, utoipa::ToSchema)]
+pub struct BenchmarkReq {
+	#[serde(default)]
+	pub iterations: Option<usize>, // comparison rounds, default 10
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
+pub struct BenchmarkResp {
+	pub success: bool,
+	pub message: String,
+	pub iterations: usize,
+	pub total_ms: f64,       // wall time of all rounds
+	pub qps: f64,            // comparisons per second
+	pub project_size: usize, // images compared per round
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct ProjectInfo {
+	pub project_name: String,
+	pub image_count: usize, // indexed images in project
+	/// False until a lazily loaded project gets its first request,
+	/// `image_count` is 0 until then.
+	pub is_loaded: bool,
+}
+
+/// Sort order of the project listing.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ProjectSortBy {
+	NameAsc,
+	NameDesc,
+	CountAsc,
+	#[default]
+	CountDesc,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default, utoipa::IntoParams)]
+pub struct ListProjectsQuery {
+	pub sort_by: Option<ProjectSortBy>, // default `count_desc`
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct ListProjectsResp {
+	pub success: bool,
+	pub message: String,
+	pub projects: Vec<ProjectInfo>,
+}
+
+/// Sort projects in place, ties are always broken by project name so
+/// the listing is deterministic.
+pub fn sort_projects(projects: &mut [ProjectInfo], sort_by: ProjectSortBy) {
+	match sort_by {
+		ProjectSortBy::NameAsc => projects.sort_by(|a, b| a.project_name.cmp(&b.project_name)),
+		ProjectSortBy::NameDesc => projects.sort_by(|a, b| b.project_name.cmp(&a.project_name)),
+		ProjectSortBy::CountAsc => projects.sort_by(|a, b| 
+			a.image_count.cmp(&b.image_count).then_with(|| a.project_name.cmp(&b.project_name))),
+		ProjectSortBy::CountDesc => projects.sort_by(|a, b| 
+			b.image_count.cmp(&a.image_count).then_with(|| a.project_name.cmp(&b.project_name))),
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::IntoParams)]
+pub struct DumpProjectQuery {
+	pub project: String,
+	pub limit: Option<usize>,  // page size, default 100
+	pub offset: Option<usize>, // entries to skip, default 0
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct DumpProjectResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String,
+	pub total: usize, // entries in project, regardless of paging
+	pub entries: Vec<ImageHashEntryJson>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::IntoParams)]
+pub struct ListImagesQuery {
+	pub after: Option<String>, // cursor, the last image name of previous page
+	pub limit: Option<usize>,  // page size, default 100
+	#[serde(default)]
+	pub thumbnails: bool,      // include a small PNG preview of each image
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct ImageInfo {
+	pub image_name: String,
+	pub hash_type: HashType,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub thumbnail: Option<String>, // base64 PNG, only when thumbnails are requested
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct ListImagesResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String,
+	pub images: Vec<ImageInfo>,      // sorted by image name
+	pub next_cursor: Option<String>, // pass as `after` for next page, none on last page
+}
+
+/// Pick one page of image names sorting after the `after` cursor.
+/// 
+/// Returns the page, sorted by name, and the cursor of the next page if
+/// more names are left. Unlike offsets, the cursor stays valid while
+/// images are added or removed.
+pub fn paginate_after(mut image_names: Vec<String>, after: Option<&str>, limit: usize) 
+	-> (Vec<String>, Option<String>) {
+
+	image_names.sort_unstable();
+
+	let start = match after {
+		Some(cursor) => image_names.partition_point(|name| name.as_str() <= cursor),
+		None => 0,
+	};
+	let end = start.saturating_add(limit).min(image_names.len());
+
+	let next_cursor = match end < image_names.len() && end > start {
+		true => Some(image_names[end - 1].clone()),
+		false => None,
+	};
+
+	image_names.truncate(end);
+	image_names.drain(..start);
+
+	(image_names, next_cursor)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
+pub struct WarmCacheResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String,
+	pub warmed_files: usize, // image files read
+	pub elapsed_ms: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default, utoipa::ToSchema)]
+pub struct ReindexProjectReq {
+	#[serde(default)]
+	pub force_recompute: bool, // hash every image again, ignoring caches
+	/// Run as a job and answer with its id right away, poll `/jobs/{job_id}`
+	/// for progress. For projects too large to reindex within a request.
+	#[serde(default)]
+	pub background: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
+pub struct ReindexProjectResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String,
+	pub image_count: usize,          // indexed images after the reindex
+	pub previous_image_count: usize, // indexed images before
+	pub elapsed_ms: f64,
+}
+
+/// What a background job does.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+	Reindex,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+	Running,
+	Completed, // the result is in use, e.g. the new index swapped in
+	Failed,    // see `message`
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
+pub struct JobResp {
+	pub success: bool, // false once the job failed
+	pub message: String,
+	pub job_id: String,
+	pub kind: JobKind,
+	pub project_name: String,
+	pub status: JobStatus,
+	pub images_done: usize,  // images hashed or reused so far
+	pub images_total: usize, // 0 until the images are listed
+	pub error_count: usize,  // images that could not be hashed
+	pub errors: Vec<String>, // the first errors only
+	pub elapsed_ms: f64,
+}
+
+/// Aggregate statistics of every stored hash, across all projects.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, utoipa::ToSchema)]
+pub struct HashMetricsResp {
+	pub success: bool,
+	pub message: String,
+	pub total_hashes_stored: usize,
+	pub hashes_by_type: HashMap<String, usize>, // keyed by hash type name, e.g. "phash"
+	pub average_hash_bits: f64,                 // 0 when no hash is stored
+	pub projects_with_no_hashes: usize,
+	pub total_cache_files_on_disk: usize,
+}
+
+/// Readiness probe result, see `/readyz`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct ReadinessResp {
+	pub ready: bool,
+	pub project_root_accessible: bool,
+	pub hashes_loaded: bool, // initial hash loading has finished
+	pub message: String,
+}
+
+/// Events broadcast to `/events` subscribers when projects change.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProjectEvent {
+	ServiceStarted,
+	ProjectUpdated {
+		project_name: String,
+		new_image_count: usize, // images indexed in project after the update
+	},
+	ProjectDeleted {
+		project_name: String,
+	},
+	ProjectRenamed {
+		project_name: String,     // the old name
+		new_project_name: String,
+	},
+	ServiceStopping, // last event, streams end after it
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
 pub struct RemoveImageReq {
-	token: String, // image removal token.
+	pub token: String, // image removal token.
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
 pub struct RemoveImageResp {
-	success: bool,
-	message: String,
+	pub success: bool,
+	pub message: String,
 }
 
 #[cfg(test)]
@@ -71,12 +595,14 @@ mod tests {
         let ent1: SimilarImageEntry = SimilarImageEntry {
             image_name: "img01".to_owned(),
             distance: 3.0,
+            similarity_score: 0.75,
             data: None,
         };
 
         let ent2: SimilarImageEntry = SimilarImageEntry {
             image_name: "img02".to_owned(),
             distance: 8.7,
+            similarity_score: 0.25,
             data: Some(smallest_png_1.clone()),
         };
 
@@ -94,9 +620,11 @@ mod tests {
 
         println!("--- Testing CompareImageReq ---");
         let comp_req: CompareImageReq = CompareImageReq {
-            project_name: "some_project".to_owned(),
             data: smallest_gif_2.clone(),
             with_image: true,
+            rotation_invariant: true,
+            flip_invariant: true,
+            ..Default::default()
         };
 
         let comp_req_json: String = serde_json::to_string_pretty(&comp_req).unwrap();
@@ -104,15 +632,34 @@ mod tests {
         println!("{}\n", comp_req_json);
         assert_eq!(comp_req, comp_req_deserialized);
 
+        let comp_req2: CompareImageReq = CompareImageReq {
+            data: "".to_owned(),
+            precomputed_entry: Some(ImageHashEntryJson {
+                image_name: "query.png".to_owned(),
+                hash_type: HashType::PHASH,
+                hash_hex: "deadbeef".to_owned(),
+                params: Some(HashParams::default().for_type(HashType::PHASH)),
+            }),
+            hash_size: Some("medium".to_owned()),
+            top_k: Some(5),
+            max_distance: Some(12),
+            ..Default::default()
+        };
+
+        let comp_req2_json: String = serde_json::to_string_pretty(&comp_req2).unwrap();
+        let comp_req2_deserialized: CompareImageReq = serde_json::from_str(&comp_req2_json).unwrap();
+        println!("{}\n", comp_req2_json);
+        assert_eq!(comp_req2, comp_req2_deserialized);
+
 
         // ---------------------------------------------------------
         // 2. Test UploadImageReq & UploadImageResp
         // ---------------------------------------------------------
         println!("--- Testing UploadImageReq ---");
         let upload_req: UploadImageReq = UploadImageReq {
-            project_name: "some_project".to_owned(),
-            image_name: "test.png".to_owned(),
             data: smallest_png_1.clone(),
+            hash_size: Some("small".to_owned()),
+            ..Default::default()
         };
 
         let upload_req_json: String = serde_json::to_string_pretty(&upload_req).unwrap();
@@ -126,6 +673,8 @@ mod tests {
             success: true,
             message: "image uploaded and indexed successfully".to_owned(),
             token: "abc-123-unique-token-xyz".to_owned(),
+            image_size_bytes: 68,
+            hash_size_bits: 992,
         };
 
         let upload_resp_json: String = serde_json::to_string_pretty(&upload_resp).unwrap();
@@ -137,6 +686,8 @@ mod tests {
             success: false,
             message: "duplication".to_owned(),
             token: "".to_owned(),
+            image_size_bytes: 0,
+            hash_size_bits: 0,
         };
 
         let upload_resp2_json: String = serde_json::to_string_pretty(&upload_resp2).unwrap();
@@ -170,6 +721,105 @@ mod tests {
         println!("{}\n", remove_resp_json);
         assert_eq!(remove_resp, remove_resp_deserialized);
     }
-}
 
+    #[test]
+    fn test_to_csv() {
+        let entries = vec![
+            SimilarImageEntry {
+                image_name: "img01.png".to_owned(),
+                distance: 3.0,
+                similarity_score: 0.75,
+                data: Some("should-not-appear".to_owned()),
+            },
+            SimilarImageEntry {
+                image_name: "cat, \"fluffy\".png".to_owned(),
+                distance: 8.5,
+                similarity_score: 0.25,
+                data: None,
+            },
+        ];
+
+        let csv = to_csv(&entries);
+        assert_eq!(csv,
+            "rank,image_name,distance,similarity_score\n\
+             1,img01.png,3,0.75\n\
+             2,\"cat, \"\"fluffy\"\".png\",8.5,0.25\n");
+    }
 
+    #[test]
+    fn test_image_filter() {
+        let req: DeleteImagesReq = serde_json::from_str(
+            r#"{ "filter": { "tags": ["draft"], "max_name_pattern": "temp_*" } }"#).unwrap();
+
+        assert_eq!(req.filter.name_pattern.as_deref(), Some("temp_*"));
+        assert_eq!(req.filter.tags, ["draft"]);
+        assert!(!req.dry_run);
+        assert!(!req.filter.is_empty());
+        assert!(ImageFilter::default().is_empty());
+        assert!(!ImageFilter { added_before: Some(0), ..Default::default() }.is_empty());
+
+        let query = RawUploadQuery { hash_size: None, tags: Some(" draft,,outdoor ".to_owned()) };
+        assert_eq!(query.tag_list(), ["draft", "outdoor"]);
+    }
+
+    #[test]
+    fn test_paginate_after() {
+        let names: Vec<String> = ["c.png", "a.png", "e.png", "b.png", "d.png"]
+            .iter().map(|n| n.to_string()).collect();
+
+        let (page, cursor) = paginate_after(names.clone(), None, 2);
+        assert_eq!(page, ["a.png", "b.png"]);
+        assert_eq!(cursor.as_deref(), Some("b.png"));
+
+        let (page, cursor) = paginate_after(names.clone(), cursor.as_deref(), 2);
+        assert_eq!(page, ["c.png", "d.png"]);
+
+        let (page, cursor) = paginate_after(names.clone(), cursor.as_deref(), 2);
+        assert_eq!(page, ["e.png"]);
+        assert_eq!(cursor, None);
+
+        // a cursor that is not an image name still works, e.g. a removed image.
+        let (page, cursor) = paginate_after(names.clone(), Some("bb.png"), 10);
+        assert_eq!(page, ["c.png", "d.png", "e.png"]);
+        assert_eq!(cursor, None);
+
+        let (page, cursor) = paginate_after(names, Some("e.png"), 10);
+        assert!(page.is_empty() && cursor.is_none());
+    }
+
+    #[test]
+    fn test_image_info_serde() {
+        let info = ImageInfo {
+            image_name: "a.png".to_owned(),
+            hash_type: HashType::PHASH,
+            thumbnail: None,
+        };
+        let json = serde_json::to_string(&info).unwrap();
+        assert_eq!(json, r#"{"image_name":"a.png","hash_type":"phash"}"#);
+        assert_eq!(serde_json::from_str::<ImageInfo>(&json).unwrap(), info);
+    }
+
+    #[test]
+    fn test_sort_projects() {
+        let mk = |name: &str, count: usize| ProjectInfo { 
+            project_name: name.to_owned(), 
+            image_count: count,
+            is_loaded: true,
+        };
+        let mut projects = vec![mk("b", 3), mk("c", 10), mk("a", 3)];
+
+        let names = |p: &[ProjectInfo]| p.iter().map(|x| x.project_name.clone()).collect::<Vec<_>>();
+
+        sort_projects(&mut projects, ProjectSortBy::default());
+        assert_eq!(names(&projects), vec!["c", "a", "b"]);
+
+        sort_projects(&mut projects, ProjectSortBy::CountAsc);
+        assert_eq!(names(&projects), vec!["a", "b", "c"]);
+
+        sort_projects(&mut projects, ProjectSortBy::NameDesc);
+        assert_eq!(names(&projects), vec!["c", "b", "a"]);
+
+        let query: ListProjectsQuery = serde_json::from_str(r#"{"sort_by":"name_asc"}"#).unwrap();
+        assert_eq!(query.sort_by, Some(ProjectSortBy::NameAsc));
+    }
+}
@@ -0,0 +1,61 @@
+//! Panic-safe blocking tasks.
+//! 
+//! Hashing runs third-party code on the blocking pool, a panic there
+//! (e.g. on a degenerate image) must fail the request, never the server.
+
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+/// A blocking task panicked or was cancelled.
+/// 
+/// The panic message is logged, but kept out of `Display` so it never
+/// reaches a response.
+#[derive(Debug)]
+pub struct ComputePanic(pub String);
+
+impl std::fmt::Display for ComputePanic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("internal computation failed")
+    }
+}
+
+impl std::error::Error for ComputePanic {}
+
+/// Run `f` on the blocking pool, catching panics inside it.
+pub async fn run_blocking<F, T>(f: F) -> Result<T, ComputePanic>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static {
+
+    let task = tokio::task::spawn_blocking(move || {
+        catch_unwind(AssertUnwindSafe(f))
+            .map_err(|payload| {
+                // panic payloads are either `&str` or `String`.
+                payload.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_owned())
+            })
+    });
+
+    let panic_message = match task.await {
+        Ok(Ok(value)) => return Ok(value),
+        Ok(Err(panic_message)) => panic_message,
+        Err(join_error) => join_error.to_string(),
+    };
+
+    tracing::error!(panic = %panic_message, "blocking task panicked");
+    Err(ComputePanic(panic_message))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_blocking() {
+        assert_eq!(run_blocking(|| 1 + 1).await.unwrap(), 2);
+
+        let err = run_blocking(|| -> u32 { panic!("bad pixel buffer") }).await.unwrap_err();
+        assert_eq!(err.0, "bad pixel buffer");
+        assert_eq!(err.to_string(), "internal computation failed");
+    }
+}
@@ -0,0 +1,813 @@
+//! Server configuration file.
+//!
+//! Settings are read from a TOML file, every key is optional and falls
+//! back to the defaults below. Example:
+//!
+//! ```toml
+//! listen_addr = "0.0.0.0:3000"
+//! project_root = "./image_root"
+//! hash_type = "phash"
+//! compare_top_k = 3
+//! verbosity = "info"
+//! log_format = "pretty" # or "json"
+//! cache_rewrite = true
+//! max_body_bytes = 2097152
+//! hash_threads = 0 # one per CPU
+//! lazy_load = false # load projects on first use instead of at startup
+//! watch_project_root = false # index images copied into project folders
+//! # serve HTTPS, both or neither must be set
+//! tls_cert = "/etc/vismatch/cert.pem"
+//! tls_key = "/etc/vismatch/key.pem"
+//! grpc_enabled = true
+//! grpc_port = 50051 # on the `listen_addr` IP, over TLS with `tls_cert`
+//! # names new projects may take, any name not denied when unset
+//! project_name_allowlist = ["catalog", "uploads"]
+//! project_name_denylist = ["admin"]
+//! prewarm = false # one comparison per project before serving
+//! heartbeat_secs = 30 # `heartbeat.txt` under project root, 0 turns it off
+//! idempotency_ttl_secs = 86400 # how long retried uploads are replayed
+//!
+//! [slow_request] # requests slower than this are logged
+//! threshold_ms = 500
+//! route_thresholds_ms = { "/upload" = 2000 }
+//!
+//! [rate_limit] # per client, 0 turns a limit off
+//! upload_per_minute = 60
+//! upload_burst = 10
+//! diff_per_minute = 600
+//! diff_burst = 50
+//!
+//! [cors] # browser frontends allowed to call the API
+//! allowed_origins = ["https://app.example.com"]
+//!
+//! [ann_index] # approximate search for projects of many images
+//! projects = ["catalog"]
+//! min_images = 10000
+//!
+//! [ensemble] # projects also indexed under other hash types, ranked on
+//! # the weighted distance of every type, `*` for all projects
+//! catalog = { phash = 1.0, colorhash = 0.5 }
+//!
+//! [hash_store] # hashes in one database instead of sidecar files
+//! sqlite_path = "./image_root/hashes.sqlite"
+//! # or shared by replicas, with deletion tokens:
+//! # postgres_url = "postgres://vismatch:secret@db/vismatch"
+//! # or a cache in front of sidecar files:
+//! # redis_url = "redis://cache:6379/0"
+//! # or one manifest file per project folder:
+//! # manifest = true
+//!
+//! [hash_params] # changing these rehashes every image
+//! hash_size = "medium"
+//! image_size = 64 # dhash and phash only, the hash side when unset
+//! resize_filter = "lanczos3" # each hasher's own when unset
+//!
+//! [image_store] # images in object storage instead of project folders
+//! s3_bucket = "vismatch-images"
+//! s3_prefix = "prod/"
+//! # s3_endpoint = "http://minio:9000" # S3 compatible services
+//!
+//! # without keys, requests need no credentials
+//! [[api_keys]]
+//! key = "change-me"
+//! read = ["catalog"]
+//! write = ["uploads"]
+//! # admin = true # may use `/admin/*` routes
+//! ```
+//!
+//! `VISMATCH_*` environment variables override the file (see
+//! `Config::apply_env`), command line flags (`Cli`) override both.
+
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::ann_index::AnnConfig;
+use crate::ensemble::EnsembleConfig;
+use crate::hash_store::HashStoreConfig;
+use crate::idempotency::DEFAULT_IDEMPOTENCY_TTL_SECS;
+use crate::image_store::ImageStoreConfig;
+use crate::image_hash::{HashParams, HashType};
+use crate::middleware::{parse_route_thresholds, ApiKeyConfig, CorsConfig, RateLimitConfig, SlowRequestConfig, DEFAULT_MAX_BODY_BYTES};
+use crate::project_mgmt::ProjectNamePolicy;
+
+/// Config file read when no path is given, it may be absent.
+pub const DEFAULT_CONFIG_FILE: &str = "vismatch.toml";
+
+/// Default number of similar images returned by `/diff`.
+pub const DEFAULT_COMPARE_TOP_K: usize = 3;
+
+/// Default port of the gRPC listener.
+pub const DEFAULT_GRPC_PORT: u16 = 50051;
+
+/// Default interval of the heartbeat file write.
+pub const DEFAULT_HEARTBEAT_SECS: u64 = 30;
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address of the HTTP listener.
+    pub listen_addr: SocketAddr,
+    /// Folder holding one subfolder per project.
+    pub project_root: PathBuf,
+    /// Hash type of projects and requests that don't name one.
+    pub hash_type: HashType,
+    /// Similar images returned by `/diff` without `top_k`.
+    pub compare_top_k: usize,
+    /// Lowest log level printed: `error`, `warn`, `info`, `debug` or `trace`.
+    pub verbosity: String,
+    /// Log line format.
+    pub log_format: LogFormat,
+    /// Write hash cache files next to images, turn off when the project
+    /// root is read-only.
+    pub cache_rewrite: bool,
+    /// Largest accepted request body, in bytes.
+    pub max_body_bytes: u64,
+    /// Threads hashing project images in bulk, e.g. at startup, 0 for
+    /// one per CPU.
+    pub hash_threads: usize,
+    /// Load projects on their first request instead of at startup.
+    pub lazy_load: bool,
+    /// Index images added, replaced or removed in project folders by
+    /// other programs, without a restart.
+    pub watch_project_root: bool,
+    /// PEM certificate chain, HTTPS is served when set.
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of `tls_cert`.
+    pub tls_key: Option<PathBuf>,
+    /// Serve the gRPC API next to HTTP, with the same certificate.
+    pub grpc_enabled: bool,
+    /// Port of the gRPC listener, bound to the `listen_addr` IP.
+    pub grpc_port: u16,
+    /// Only names new projects may take, every name when unset.
+    pub project_name_allowlist: Option<Vec<String>>,
+    /// Names new projects may not take.
+    pub project_name_denylist: Vec<String>,
+    /// Run one comparison per project before serving, trading startup
+    /// time for first-request latency.
+    pub prewarm: bool,
+    /// Interval of the `heartbeat.txt` write under project root, for
+    /// external watchdogs, 0 turns it off.
+    pub heartbeat_secs: u64,
+    /// How long the response to an `Idempotency-Key` is replayed.
+    pub idempotency_ttl_secs: u64,
+    /// Thresholds of slow request warnings.
+    pub slow_request: SlowRequestConfig,
+    /// API keys and the projects they may access, see `ApiKeys`.
+    pub api_keys: Vec<ApiKeyConfig>,
+    /// Upload and comparison limits per client.
+    pub rate_limit: RateLimitConfig,
+    /// Cross-origin access for browser frontends.
+    pub cors: CorsConfig,
+    /// Projects ranked through an approximate nearest neighbor index.
+    pub ann_index: AnnConfig,
+    /// Projects ranked on several hash types at once.
+    pub ensemble: EnsembleConfig,
+    /// Where hashes are kept instead of sidecar cache files.
+    pub hash_store: HashStoreConfig,
+    /// Where images are kept instead of project folders.
+    pub image_store: ImageStoreConfig,
+    /// Parameters every hash is calculated with, requests may still
+    /// pick another hash size.
+    pub hash_params: HashParams,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            project_root: PathBuf::from("./image_root"),
+            hash_type: HashType::PHASH,
+            compare_top_k: DEFAULT_COMPARE_TOP_K,
+            verbosity: "info".to_owned(),
+            log_format: LogFormat::Pretty,
+            cache_rewrite: true,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            hash_threads: 0,
+            lazy_load: false,
+            watch_project_root: false,
+            tls_cert: None,
+            tls_key: None,
+            grpc_enabled: true,
+            grpc_port: DEFAULT_GRPC_PORT,
+            project_name_allowlist: None,
+            project_name_denylist: Vec::new(),
+            prewarm: false,
+            heartbeat_secs: DEFAULT_HEARTBEAT_SECS,
+            idempotency_ttl_secs: DEFAULT_IDEMPOTENCY_TTL_SECS,
+            slow_request: SlowRequestConfig::default(),
+            api_keys: Vec::new(),
+            rate_limit: RateLimitConfig::default(),
+            cors: CorsConfig::default(),
+            ann_index: AnnConfig::default(),
+            ensemble: EnsembleConfig::default(),
+            hash_store: HashStoreConfig::default(),
+            image_store: ImageStoreConfig::default(),
+            hash_params: HashParams::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the config file at `path`.
+    ///
+    /// Without a path, `DEFAULT_CONFIG_FILE` is read if it exists,
+    /// otherwise defaults are used. A given path must exist.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let (path, is_required) = match path {
+            Some(path) => (path, true),
+            None => (Path::new(DEFAULT_CONFIG_FILE), false),
+        };
+
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !is_required =>
+                return Ok(Config::default()),
+            Err(e) => return Err(format!("cannot read config file <{}>: {}", path.display(), e).into()),
+        };
+
+        let config = Config::parse(&content)
+            .map_err(|e| format!("invalid config file <{}>: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Parse and validate a TOML config.
+    pub fn parse(content: &str) -> Result<Self, Box<dyn Error>> {
+        let config: Config = toml::from_str(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check values that parse but are unusable, run again once command
+    /// line flags are applied.
+    pub fn validate(&self) -> Result<(), String> {
+        self.log_level()?;
+
+        if self.compare_top_k == 0 {
+            return Err("`compare_top_k` must be at least 1".to_owned());
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err("`tls_cert` and `tls_key` must be set together".to_owned());
+        }
+        if self.grpc_enabled && (self.grpc_port == 0 || self.grpc_port == self.listen_addr.port()) {
+            return Err("`grpc_port` must be set, and differ from the HTTP port".to_owned());
+        }
+        self.cors.layer()?;
+        self.ann_index.validate()?;
+        self.ensemble.validate()?;
+        self.hash_store.validate()?;
+        self.image_store.validate()?;
+        self.hash_params.validate()?;
+        if self.watch_project_root && self.image_store.is_enabled() {
+            return Err("`watch_project_root` needs project folders, it cannot be used with `image_store`".to_owned());
+        }
+        if self.hash_store.manifest && self.image_store.is_enabled() {
+            return Err("`hash_store.manifest` needs project folders, it cannot be used with `image_store`".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Override with the `VISMATCH_*` environment variables that are set.
+    ///
+    /// - `VISMATCH_PORT`, `VISMATCH_BIND`: listener port and address.
+    /// - `VISMATCH_PROJECT_ROOT`
+    /// - `VISMATCH_DEFAULT_HASH_TYPE`
+    /// - `VISMATCH_COMPARE_TOP_K`
+    /// - `VISMATCH_VERBOSITY`, `VISMATCH_LOG_FORMAT`
+    /// - `VISMATCH_MAX_BODY_BYTES`
+    /// - `VISMATCH_HASH_THREADS`, `VISMATCH_LAZY_LOAD`
+    /// - `VISMATCH_WATCH_PROJECT_ROOT`
+    /// - `VISMATCH_TLS_CERT`, `VISMATCH_TLS_KEY`
+    /// - `VISMATCH_GRPC_ENABLED`, `VISMATCH_GRPC_PORT`
+    /// - `VISMATCH_PROJECT_NAME_ALLOWLIST`, `VISMATCH_PROJECT_NAME_DENYLIST`:
+    ///   comma separated names.
+    /// - `VISMATCH_PREWARM`, `VISMATCH_HEARTBEAT_SECS`
+    /// - `VISMATCH_IDEMPOTENCY_TTL_SECS`
+    /// - `VISMATCH_SLOW_REQUEST_MS`, `VISMATCH_SLOW_REQUEST_ROUTES_MS`:
+    ///   the latter as comma separated `route=ms` pairs, e.g.
+    ///   `/upload=2000,/admin/gc=60000`.
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        self.apply_vars(|name| std::env::var(name).ok())
+    }
+
+    /// `apply_env` with variables looked up by `var`.
+    fn apply_vars(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String>
+            where T::Err: std::fmt::Display {
+            value.trim().parse()
+                .map_err(|e| format!("invalid {} <{}>: {}", name, value, e))
+        }
+        fn parse_list(value: &str) -> Vec<String> {
+            value.split(',')
+                .map(|name| name.trim().to_owned())
+                .filter(|name| !name.is_empty())
+                .collect()
+        }
+
+        if let Some(v) = var("VISMATCH_PORT") {
+            self.listen_addr.set_port(parse("VISMATCH_PORT", &v)?);
+        }
+        if let Some(v) = var("VISMATCH_BIND") {
+            self.listen_addr.set_ip(parse("VISMATCH_BIND", &v)?);
+        }
+        if let Some(v) = var("VISMATCH_PROJECT_ROOT") {
+            self.project_root = PathBuf::from(v);
+        }
+        if let Some(v) = var("VISMATCH_DEFAULT_HASH_TYPE") {
+            self.hash_type = parse("VISMATCH_DEFAULT_HASH_TYPE", &v)?;
+        }
+        if let Some(v) = var("VISMATCH_COMPARE_TOP_K") {
+            self.compare_top_k = parse("VISMATCH_COMPARE_TOP_K", &v)?;
+        }
+        if let Some(v) = var("VISMATCH_VERBOSITY") {
+            self.verbosity = v.trim().to_owned();
+        }
+        if let Some(v) = var("VISMATCH_LOG_FORMAT") {
+            self.log_format = parse("VISMATCH_LOG_FORMAT", &v)?;
+        }
+        if let Some(v) = var("VISMATCH_MAX_BODY_BYTES") {
+            self.max_body_bytes = parse("VISMATCH_MAX_BODY_BYTES", &v)?;
+        }
+        if let Some(v) = var("VISMATCH_HASH_THREADS") {
+            self.hash_threads = parse("VISMATCH_HASH_THREADS", &v)?;
+        }
+        if let Some(v) = var("VISMATCH_LAZY_LOAD") {
+            self.lazy_load = parse("VISMATCH_LAZY_LOAD", &v)?;
+        }
+        if let Some(v) = var("VISMATCH_WATCH_PROJECT_ROOT") {
+            self.watch_project_root = parse("VISMATCH_WATCH_PROJECT_ROOT", &v)?;
+        }
+        if let Some(v) = var("VISMATCH_TLS_CERT") {
+            self.tls_cert = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("VISMATCH_TLS_KEY") {
+            self.tls_key = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("VISMATCH_GRPC_ENABLED") {
+            self.grpc_enabled = parse("VISMATCH_GRPC_ENABLED", &v)?;
+        }
+        if let Some(v) = var("VISMATCH_GRPC_PORT") {
+            self.grpc_port = parse("VISMATCH_GRPC_PORT", &v)?;
+        }
+        if let Some(v) = var("VISMATCH_PROJECT_NAME_ALLOWLIST") {
+            self.project_name_allowlist = Some(parse_list(&v));
+        }
+        if let Some(v) = var("VISMATCH_PROJECT_NAME_DENYLIST") {
+            self.project_name_denylist = parse_list(&v);
+        }
+        if let Some(v) = var("VISMATCH_PREWARM") {
+            self.prewarm = v.trim().eq_ignore_ascii_case("true") || v.trim() == "1";
+        }
+        if let Some(v) = var("VISMATCH_HEARTBEAT_SECS") {
+            self.heartbeat_secs = parse("VISMATCH_HEARTBEAT_SECS", &v)?;
+        }
+        if let Some(v) = var("VISMATCH_IDEMPOTENCY_TTL_SECS") {
+            self.idempotency_ttl_secs = parse("VISMATCH_IDEMPOTENCY_TTL_SECS", &v)?;
+        }
+        if let Some(v) = var("VISMATCH_SLOW_REQUEST_MS") {
+            self.slow_request.threshold_ms = parse("VISMATCH_SLOW_REQUEST_MS", &v)?;
+        }
+        if let Some(v) = var("VISMATCH_SLOW_REQUEST_ROUTES_MS") {
+            self.slow_request.route_thresholds_ms = parse_route_thresholds(&v)
+                .map_err(|e| format!("invalid VISMATCH_SLOW_REQUEST_ROUTES_MS: {}", e))?;
+        }
+
+        self.validate()
+    }
+
+    /// Certificate and key paths when HTTPS is enabled.
+    pub fn tls_files(&self) -> Option<(&Path, &Path)> {
+        self.tls_cert.as_deref().zip(self.tls_key.as_deref())
+    }
+
+    /// Address of the gRPC listener, `None` when gRPC is off.
+    pub fn grpc_addr(&self) -> Option<SocketAddr> {
+        self.grpc_enabled.then(|| SocketAddr::new(self.listen_addr.ip(), self.grpc_port))
+    }
+
+    /// Rules on the names of new projects.
+    pub fn project_name_policy(&self) -> ProjectNamePolicy {
+        ProjectNamePolicy {
+            allowlist: self.project_name_allowlist.clone(),
+            denylist: self.project_name_denylist.clone(),
+        }
+    }
+
+    /// `verbosity` as a tracing level.
+    pub fn log_level(&self) -> Result<tracing::Level, String> {
+        self.verbosity.parse()
+            .map_err(|_| format!("unknown verbosity <{}>, valid values are: error, warn, info, debug, trace", self.verbosity))
+    }
+
+    /// Check the files the config names, which `validate` leaves to
+    /// startup: the TLS certificate and key must be readable, and
+    /// `project_root` a folder unless it is yet to be created.
+    pub fn check_files(&self) -> Result<(), String> {
+        if let Some((tls_cert, tls_key)) = self.tls_files() {
+            for path in [tls_cert, tls_key] {
+                std::fs::read(path)
+                    .map_err(|e| format!("cannot read <{}>: {}", path.display(), e))?;
+            }
+        }
+        if self.project_root.exists() && !self.project_root.is_dir() {
+            return Err(format!("`project_root` <{}> is not a folder", self.project_root.display()));
+        }
+        Ok(())
+    }
+
+    /// What the server would run with, one setting per line, without
+    /// secrets such as keys or connection strings.
+    pub fn summary(&self) -> String {
+        let scheme = match self.tls_files() {
+            Some(_) => "https",
+            None => "http",
+        };
+        let grpc = match self.grpc_addr() {
+            Some(addr) => addr.to_string(),
+            None => "off".to_owned(),
+        };
+        let hash_store = match &self.hash_store {
+            store if store.sqlite_path.is_some() => "sqlite",
+            store if store.postgres_url.is_some() => "postgres",
+            store if store.redis_url.is_some() => "redis",
+            store if store.manifest => "manifest",
+            _ => "cache files",
+        };
+        let image_store = match &self.image_store.s3_bucket {
+            Some(bucket) => format!("s3 bucket {}", bucket),
+            None => "project folders".to_owned(),
+        };
+
+        [
+            format!("listen: {}://{}", scheme, self.listen_addr),
+            format!("grpc: {}", grpc),
+            format!("project_root: {}", self.project_root.display()),
+            format!("hash_type: {}, hash_size: {:?}", self.hash_type, self.hash_params.hash_size),
+            format!("hash_store: {}", hash_store),
+            format!("image_store: {}", image_store),
+            format!("api_keys: {}", self.api_keys.len()),
+            format!("lazy_load: {}, watch_project_root: {}", self.lazy_load, self.watch_project_root),
+        ].join("\n")
+    }
+}
+
+/// Format of log lines.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, one event per line.
+    Pretty,
+    /// One JSON object per line, span fields included, for log collectors.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("valid values are: pretty, json".to_owned()),
+        }
+    }
+}
+
+/// Command line flags of the server binary.
+#[derive(Debug, Parser, Default)]
+#[command(version, about = "Image similarity search service")]
+pub struct Cli {
+    /// Config file [default: vismatch.toml, if present]
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// HTTP listener port
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// HTTP listener address
+    #[arg(long, value_name = "IP")]
+    pub bind: Option<IpAddr>,
+    /// Folder holding one subfolder per project
+    #[arg(long, value_name = "DIR")]
+    pub project_root: Option<PathBuf>,
+    /// Default hash type, e.g. `phash`
+    #[arg(long)]
+    pub hash_type: Option<HashType>,
+    /// Never write hash cache files, hashes are kept in memory only
+    #[arg(long)]
+    pub no_cache_rewrite: bool,
+    /// PEM certificate chain, serves HTTPS together with `--tls-key`
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of `--tls-cert`
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// gRPC listener port
+    #[arg(long, conflicts_with = "no_grpc")]
+    pub grpc_port: Option<u16>,
+    /// Serve HTTP only, without the gRPC API
+    #[arg(long)]
+    pub no_grpc: bool,
+    /// Check the configuration, print a summary and exit, 1 when it is
+    /// unusable
+    #[arg(long)]
+    pub config_check: bool,
+}
+
+impl Cli {
+    /// Config the server runs with: the config file (`--config`,
+    /// `VISMATCH_CONFIG` or `DEFAULT_CONFIG_FILE`), overridden by the
+    /// environment, then by these flags.
+    pub fn load_config(&self) -> Result<Config, String> {
+        self.load_config_with(|name| std::env::var(name).ok())
+    }
+
+    /// `load_config` with environment variables looked up by `var`.
+    fn load_config_with(&self, var: impl Fn(&str) -> Option<String>) -> Result<Config, String> {
+        let path = self.config.clone()
+            .or_else(|| var("VISMATCH_CONFIG").map(PathBuf::from));
+        let mut config = Config::load(path.as_deref())
+            .map_err(|e| e.to_string())?;
+        config.apply_vars(&var)?;
+
+        // flags win over the config file and environment, checked again as
+        // they may clash with either, e.g. the gRPC port with the HTTP one.
+        self.apply(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// `--config-check`: the summary of a usable config, or why it is not.
+    pub fn check_config(&self) -> Result<String, String> {
+        self.check_config_with(|name| std::env::var(name).ok())
+    }
+
+    fn check_config_with(&self, var: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+        let config = self.load_config_with(var)?;
+        config.check_files()?;
+        Ok(config.summary())
+    }
+
+    /// Override `config` with the flags that are set.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(port) = self.port {
+            config.listen_addr.set_port(port);
+        }
+        if let Some(bind) = self.bind {
+            config.listen_addr.set_ip(bind);
+        }
+        if let Some(project_root) = &self.project_root {
+            config.project_root = project_root.clone();
+        }
+        if let Some(hash_type) = self.hash_type {
+            config.hash_type = hash_type;
+        }
+        if self.no_cache_rewrite {
+            config.cache_rewrite = false;
+        }
+        if let Some((tls_cert, tls_key)) = self.tls_cert.as_ref().zip(self.tls_key.as_ref()) {
+            config.tls_cert = Some(tls_cert.clone());
+            config.tls_key = Some(tls_key.clone());
+        }
+        if let Some(grpc_port) = self.grpc_port {
+            config.grpc_port = grpc_port;
+        }
+        if self.no_grpc {
+            config.grpc_enabled = false;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::image_hash::{HashSize, ResizeFilter};
+
+    #[test]
+    fn test_config_parse() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+
+        let config = Config::parse(r#"
+            listen_addr = "127.0.0.1:8080"
+            hash_type = "dhash"
+            verbosity = "debug"
+            log_format = "json"
+        "#).unwrap();
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.listen_addr.port(), 8080);
+        assert_eq!(config.hash_type, HashType::DHASH);
+        assert_eq!(config.log_level().unwrap(), tracing::Level::DEBUG);
+        assert_eq!(config.project_root, Config::default().project_root);
+
+        assert!(Config::parse("verbosity = \"loud\"").is_err());
+        assert!(Config::parse("compare_top_k = 0").is_err());
+        assert!(Config::parse("hash_type = \"nohash\"").is_err());
+        assert!(Config::parse("listen_port = 3000").is_err());
+        assert!(Config::parse("tls_cert = \"cert.pem\"").is_err());
+        assert!(Config::parse("grpc_port = 3000").is_err());
+        assert!(Config::parse("grpc_enabled = false\ngrpc_port = 0").is_ok());
+
+        let config = Config::parse("project_name_allowlist = [\"cats\"]\nproject_name_denylist = [\"admin\"]").unwrap();
+        assert!(config.project_name_policy().check("cats").is_ok());
+        assert!(config.project_name_policy().check("dogs").is_err());
+        assert!(config.project_name_policy().check("admin").is_err());
+        assert!(Config::default().project_name_policy().check("dogs").is_ok());
+
+        let config = Config::parse(r#"
+            [[api_keys]]
+            key = "k1"
+            read = ["cats"]
+        "#).unwrap();
+        assert_eq!(config.api_keys[0].read, ["cats"]);
+        assert!(config.api_keys[0].write.is_empty());
+        assert!(!config.api_keys[0].admin);
+
+        let config = Config::parse("[rate_limit]\nupload_per_minute = 30").unwrap();
+        assert_eq!(config.rate_limit.upload_per_minute, 30);
+        assert_eq!(config.rate_limit.diff_per_minute, 0);
+
+        let config = Config::parse("[cors]\nallowed_origins = [\"*\"]").unwrap();
+        assert_eq!(config.cors.allowed_origins, ["*"]);
+        assert_eq!(config.cors.allowed_methods, CorsConfig::default().allowed_methods);
+        assert!(Config::parse("[cors]\nallowed_origins = [\"*\"]\nallowed_methods = [\"GE T\"]").is_err());
+
+        let config = Config::parse("[ann_index]\nprojects = [\"catalog\"]\nef_search = 128").unwrap();
+        assert!(config.ann_index.is_enabled_for("catalog"));
+        assert_eq!(config.ann_index.ef_search, 128);
+        assert!(Config::parse("[ann_index]\nm = 0").is_err());
+
+        let config = Config::parse("[ensemble]\ncatalog = { phash = 1.0, colorhash = 0.5 }").unwrap();
+        assert_eq!(config.ensemble.weights_for("catalog").map(|w| w.len()), Some(2));
+        assert!(Config::parse("[ensemble]\ncatalog = { phash = 0.0 }").is_err());
+
+        let config = Config::parse("[hash_store]\nsqlite_path = \"/data/hashes.sqlite\"").unwrap();
+        assert_eq!(config.hash_store.sqlite_path, Some(PathBuf::from("/data/hashes.sqlite")));
+        assert!(Config::parse("[hash_store]\npath = \"/data/hashes.sqlite\"").is_err());
+        assert!(Config::parse("[hash_store]\nsqlite_path = \"a\"\npostgres_url = \"postgres://db\"").is_err());
+        assert!(Config::parse("[hash_store]\nsqlite_path = \"a\"\nredis_url = \"redis://cache\"").is_err());
+        assert_eq!(Config::default().hash_store.redis_key_prefix, "vismatch");
+
+        let config = Config::parse("[image_store]\ns3_bucket = \"images\"\ns3_prefix = \"prod/\"").unwrap();
+        assert_eq!(config.image_store.s3_bucket.as_deref(), Some("images"));
+        assert!(Config::parse("[image_store]\ns3_endpoint = \"http://minio:9000\"").is_err());
+        assert!(Config::parse("watch_project_root = true\n[image_store]\ns3_bucket = \"images\"").is_err());
+        assert!(Config::parse("[hash_store]\nmanifest = true\nsqlite_path = \"a\"").is_err());
+        assert!(Config::parse("[hash_store]\nmanifest = true\n[image_store]\ns3_bucket = \"images\"").is_err());
+
+        let config = Config::parse("[hash_params]\nhash_size = \"large\"\nimage_size = 128\nresize_filter = \"triangle\"").unwrap();
+        assert_eq!(config.hash_params.hash_size, HashSize::Large);
+        assert_eq!(config.hash_params.image_size, Some(128));
+        assert_eq!(config.hash_params.resize_filter, Some(ResizeFilter::Triangle));
+        assert!(Config::parse("[hash_params]\nimage_size = 1").is_err());
+        assert!(Config::parse("[hash_params]\nresize_filter = \"bicubic\"").is_err());
+
+        let config = Config::parse("prewarm = true\nheartbeat_secs = 0\n[slow_request]\nroute_thresholds_ms = { \"/upload\" = 2000 }").unwrap();
+        assert!(config.prewarm);
+        assert_eq!(config.heartbeat_secs, 0);
+        assert_eq!(config.idempotency_ttl_secs, DEFAULT_IDEMPOTENCY_TTL_SECS);
+        assert_eq!(config.slow_request.threshold_for("/upload"), Duration::from_millis(2000));
+        assert_eq!(config.slow_request.threshold_for("/diff"), SlowRequestConfig::default().threshold_for("/diff"));
+        assert!(Config::parse("[slow_request]\nthreshold = 500").is_err());
+
+        assert!(Config::load(Some(Path::new("/nonexistent/vismatch.toml"))).is_err());
+    }
+
+    #[test]
+    fn test_cli_overrides() {
+        let mut config = Config::parse("listen_addr = \"127.0.0.1:8080\"").unwrap();
+        Cli::default().apply(&mut config);
+        assert_eq!(config, Config::parse("listen_addr = \"127.0.0.1:8080\"").unwrap());
+
+        let cli = Cli::try_parse_from(["vismatch-svc", "--port", "9000", "--hash-type", "AHASH", "--no-cache-rewrite"]).unwrap();
+        cli.apply(&mut config);
+        assert_eq!(config.listen_addr.to_string(), "127.0.0.1:9000");
+        assert_eq!(config.hash_type, HashType::AHASH);
+        assert!(!config.cache_rewrite);
+
+        let cli = Cli::try_parse_from(["vismatch-svc", "--bind", "::1"]).unwrap();
+        cli.apply(&mut config);
+        assert_eq!(config.listen_addr.to_string(), "[::1]:9000");
+
+        assert!(Cli::try_parse_from(["vismatch-svc", "--hash-type", "nohash"]).is_err());
+
+        assert_eq!(config.tls_files(), None);
+        let cli = Cli::try_parse_from(["vismatch-svc", "--tls-cert", "c.pem", "--tls-key", "k.pem"]).unwrap();
+        cli.apply(&mut config);
+        assert_eq!(config.tls_files(), Some((Path::new("c.pem"), Path::new("k.pem"))));
+        assert!(Cli::try_parse_from(["vismatch-svc", "--tls-cert", "c.pem"]).is_err());
+
+        assert_eq!(config.grpc_addr().unwrap().to_string(), "[::1]:50051");
+        let cli = Cli::try_parse_from(["vismatch-svc", "--grpc-port", "6000"]).unwrap();
+        cli.apply(&mut config);
+        assert_eq!(config.grpc_addr().unwrap().to_string(), "[::1]:6000");
+        let cli = Cli::try_parse_from(["vismatch-svc", "--no-grpc"]).unwrap();
+        cli.apply(&mut config);
+        assert_eq!(config.grpc_addr(), None);
+        assert!(Cli::try_parse_from(["vismatch-svc", "--no-grpc", "--grpc-port", "6000"]).is_err());
+
+        // flags clashing with the config only fail validation once applied.
+        for flags in [["--grpc-port", "3000"], ["--port", "50051"]] {
+            let mut config = Config::default();
+            Cli::try_parse_from(["vismatch-svc"].into_iter().chain(flags)).unwrap().apply(&mut config);
+            assert!(config.validate().is_err(), "{:?}", flags);
+        }
+    }
+
+    #[test]
+    fn test_config_check() {
+        let dir = std::env::temp_dir().join(format!("vismatch-config-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (config_path, cert_path, key_path) = (dir.join("vismatch.toml"), dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&config_path, format!("tls_cert = {:?}\ntls_key = {:?}\n[[api_keys]]\nkey = \"secret\"",
+            cert_path.display().to_string(), key_path.display().to_string())).unwrap();
+        std::fs::write(&cert_path, "cert").unwrap();
+        std::fs::write(&key_path, "key").unwrap();
+
+        let no_vars = |_: &str| None;
+        let cli = Cli::try_parse_from(["vismatch-svc", "--config-check", "--port", "8443"]).unwrap();
+        assert!(cli.config_check);
+
+        // the file comes from `VISMATCH_CONFIG`, flags still win.
+        let config_var = |name: &str| (name == "VISMATCH_CONFIG").then(|| config_path.display().to_string());
+        let summary = cli.check_config_with(config_var).unwrap();
+        assert!(summary.contains("https://0.0.0.0:8443"), "{}", summary);
+        assert!(summary.contains("api_keys: 1"));
+        assert!(!summary.contains("secret"));
+
+        // an unreadable key, a missing config file and a clash are refused.
+        std::fs::remove_file(&key_path).unwrap();
+        assert!(cli.check_config_with(config_var).unwrap_err().contains("key.pem"));
+        let cli = Cli::try_parse_from(["vismatch-svc", "--config-check", "--config", "/nonexistent/vismatch.toml"]).unwrap();
+        assert!(cli.check_config_with(no_vars).is_err());
+        let cli = Cli::try_parse_from(["vismatch-svc", "--config-check", "--grpc-port", "3000"]).unwrap();
+        assert!(cli.check_config_with(no_vars).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let vars = |pairs: &'static [(&'static str, &'static str)]| move |name: &str| pairs.iter()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.to_string());
+
+        let mut config = Config::default();
+        config.apply_vars(vars(&[])).unwrap();
+        assert_eq!(config, Config::default());
+
+        config.apply_vars(vars(&[
+            ("VISMATCH_PORT", "8080"),
+            ("VISMATCH_PROJECT_ROOT", "/data/images"),
+            ("VISMATCH_DEFAULT_HASH_TYPE", "dhash"),
+            ("VISMATCH_MAX_BODY_BYTES", " 1024 "),
+            ("VISMATCH_HASH_THREADS", "4"),
+            ("VISMATCH_LAZY_LOAD", "true"),
+            ("VISMATCH_WATCH_PROJECT_ROOT", "true"),
+            ("VISMATCH_GRPC_PORT", "6000"),
+            ("VISMATCH_PROJECT_NAME_ALLOWLIST", "cats, dogs,"),
+            ("VISMATCH_PROJECT_NAME_DENYLIST", "admin"),
+            ("VISMATCH_PREWARM", "1"),
+            ("VISMATCH_HEARTBEAT_SECS", "0"),
+            ("VISMATCH_IDEMPOTENCY_TTL_SECS", "60"),
+            ("VISMATCH_SLOW_REQUEST_MS", "250"),
+            ("VISMATCH_SLOW_REQUEST_ROUTES_MS", "/upload=2000"),
+        ])).unwrap();
+        assert_eq!(config.listen_addr.to_string(), "0.0.0.0:8080");
+        assert_eq!(config.project_root, PathBuf::from("/data/images"));
+        assert_eq!(config.hash_type, HashType::DHASH);
+        assert_eq!(config.max_body_bytes, 1024);
+        assert_eq!(config.hash_threads, 4);
+        assert!(config.lazy_load);
+        assert!(config.watch_project_root);
+        assert_eq!(config.grpc_addr(), Some(SocketAddr::from(([0, 0, 0, 0], 6000))));
+        assert_eq!(config.project_name_allowlist, Some(vec!["cats".to_owned(), "dogs".to_owned()]));
+        assert_eq!(config.project_name_denylist, ["admin"]);
+        assert!(config.prewarm);
+        assert_eq!(config.heartbeat_secs, 0);
+        assert_eq!(config.idempotency_ttl_secs, 60);
+        assert_eq!(config.slow_request.threshold_for("/diff"), Duration::from_millis(250));
+        assert_eq!(config.slow_request.threshold_for("/upload"), Duration::from_millis(2000));
+
+        // the flags still win.
+        let cli = Cli::try_parse_from(["vismatch-svc", "--port", "9000"]).unwrap();
+        cli.apply(&mut config);
+        assert_eq!(config.listen_addr.port(), 9000);
+
+        config.apply_vars(vars(&[("VISMATCH_LOG_FORMAT", "JSON")])).unwrap();
+        assert_eq!(config.log_format, LogFormat::Json);
+
+        let err = config.apply_vars(vars(&[("VISMATCH_PORT", "http")])).unwrap_err();
+        assert!(err.contains("VISMATCH_PORT"));
+        assert!(config.apply_vars(vars(&[("VISMATCH_BIND", "localhost")])).is_err());
+        assert!(config.apply_vars(vars(&[("VISMATCH_COMPARE_TOP_K", "0")])).is_err());
+        assert!(config.apply_vars(vars(&[("VISMATCH_LOG_FORMAT", "xml")])).is_err());
+        assert!(config.apply_vars(vars(&[("VISMATCH_VERBOSITY", "loud")])).is_err());
+        assert!(Config::default().apply_vars(vars(&[("VISMATCH_GRPC_PORT", "0")])).is_err());
+        assert!(config.apply_vars(vars(&[("VISMATCH_HEARTBEAT_SECS", "-1")])).is_err());
+        assert!(config.apply_vars(vars(&[("VISMATCH_SLOW_REQUEST_ROUTES_MS", "/upload")])).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! Deletion tokens handed out by uploads.
+//!
+//! Each uploaded image gets a random token, presenting the token to
+//! `/remove` deletes that image. Tokens are kept in a JSON file under
+//! project root, so they survive restarts, or in the Postgres hash
+//! store, so replicas accept each other's tokens.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hash_store::StoreError;
+
+/// File name of the token store under project root.
+pub const DELETION_TOKENS_FILE: &str = "deletion_tokens.json";
+
+/// The image a token may delete.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TokenTarget {
+    pub project_name: String,
+    pub image_name: String,
+}
+
+/// Storage of deletion tokens, see `DeletionTokens` for the meaning of
+/// each call.
+///
+/// Calls block, run them on the blocking pool from async code.
+pub trait TokenStore: Send + Sync {
+    fn issue(&self, project_name: &str, image_name: &str) -> Result<String, StoreError>;
+    fn get(&self, token: &str) -> Result<Option<TokenTarget>, StoreError>;
+    fn revoke_image(&self, project_name: &str, image_name: &str) -> Result<(), StoreError>;
+    fn revoke_project(&self, project_name: &str) -> Result<(), StoreError>;
+    fn rename_project(&self, project_name: &str, new_name: &str) -> Result<(), StoreError>;
+}
+
+/// Shared handle of the token store.
+pub type SharedTokenStore = Arc<dyn TokenStore>;
+
+/// Token to image mapping, backed by a JSON file.
+pub struct DeletionTokens {
+    path: PathBuf,
+    tokens: HashMap<String, TokenTarget>,
+}
+
+impl DeletionTokens {
+    /// Load the store at `path`, a missing file is an empty store.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let tokens = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| format!("invalid token store <{}>: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(format!("cannot read token store <{}>: {}", path.display(), e).into()),
+        };
+
+        Ok(DeletionTokens { path: path.to_owned(), tokens })
+    }
+
+    /// Create a token for an image and persist it.
+    ///
+    /// Tokens of earlier uploads of the same image stay valid.
+    pub fn issue(&mut self, project_name: &str, image_name: &str) -> Result<String, Box<dyn Error>> {
+        let token = uuid::Uuid::new_v4().to_string();
+
+        self.tokens.insert(token.clone(), TokenTarget {
+            project_name: project_name.to_owned(),
+            image_name: image_name.to_owned(),
+        });
+
+        if let Err(e) = self.save() {
+            self.tokens.remove(&token);
+            return Err(e);
+        }
+        Ok(token)
+    }
+
+    pub fn get(&self, token: &str) -> Option<&TokenTarget> {
+        self.tokens.get(token)
+    }
+
+    /// Every token and its image.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &TokenTarget)> {
+        self.tokens.iter()
+    }
+
+    /// Drop every token of an image, once the image is gone.
+    pub fn revoke_image(&mut self, project_name: &str, image_name: &str) -> Result<(), Box<dyn Error>> {
+        self.tokens.retain(|_, target|
+            !(target.project_name == project_name && target.image_name == image_name));
+        self.save()
+    }
+
+    /// Drop every token of a project, once the project is gone.
+    pub fn revoke_project(&mut self, project_name: &str) -> Result<(), Box<dyn Error>> {
+        self.tokens.retain(|_, target| target.project_name != project_name);
+        self.save()
+    }
+
+    /// Point every token of a project to its new name.
+    pub fn rename_project(&mut self, project_name: &str, new_name: &str) -> Result<(), Box<dyn Error>> {
+        self.tokens.values_mut()
+            .filter(|target| target.project_name == project_name)
+            .for_each(|target| target.project_name = new_name.to_owned());
+        self.save()
+    }
+
+    /// Write to a temporary file then rename, so a crash never leaves
+    /// a truncated store behind.
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        let data = serde_json::to_vec_pretty(&self.tokens)?;
+
+        std::fs::write(&tmp_path, data)
+            .map_err(|e| format!("cannot write token store <{}>: {}", tmp_path.display(), e))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .map_err(|e| format!("cannot write token store <{}>: {}", self.path.display(), e))?;
+        Ok(())
+    }
+}
+
+/// The JSON file store, one request at a time.
+impl TokenStore for Mutex<DeletionTokens> {
+    fn issue(&self, project_name: &str, image_name: &str) -> Result<String, StoreError> {
+        lock(self).issue(project_name, image_name).map_err(|e| e.to_string().into())
+    }
+
+    fn get(&self, token: &str) -> Result<Option<TokenTarget>, StoreError> {
+        Ok(lock(self).get(token).cloned())
+    }
+
+    fn revoke_image(&self, project_name: &str, image_name: &str) -> Result<(), StoreError> {
+        lock(self).revoke_image(project_name, image_name).map_err(|e| e.to_string().into())
+    }
+
+    fn revoke_project(&self, project_name: &str) -> Result<(), StoreError> {
+        lock(self).revoke_project(project_name).map_err(|e| e.to_string().into())
+    }
+
+    fn rename_project(&self, project_name: &str, new_name: &str) -> Result<(), StoreError> {
+        lock(self).rename_project(project_name, new_name).map_err(|e| e.to_string().into())
+    }
+}
+
+fn lock(tokens: &Mutex<DeletionTokens>) -> std::sync::MutexGuard<'_, DeletionTokens> {
+    tokens.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deletion_tokens() {
+        let dir = std::env::temp_dir().join(format!("vismatch-tokens-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(DELETION_TOKENS_FILE);
+
+        let mut tokens = DeletionTokens::load(&path).unwrap();
+        let t1 = tokens.issue("p", "a.png").unwrap();
+        let t2 = tokens.issue("p", "a.png").unwrap();
+        let t3 = tokens.issue("p", "b.png").unwrap();
+        assert_ne!(t1, t2);
+
+        // reloading sees the same tokens.
+        let mut tokens = DeletionTokens::load(&path).unwrap();
+        assert_eq!(tokens.get(&t1).unwrap().image_name, "a.png");
+        assert!(tokens.get("not-a-token").is_none());
+
+        tokens.revoke_image("p", "a.png").unwrap();
+        let mut tokens = DeletionTokens::load(&path).unwrap();
+        assert!(tokens.get(&t1).is_none());
+        assert!(tokens.get(&t2).is_none());
+        assert_eq!(tokens.get(&t3).unwrap().project_name, "p");
+
+        let t4 = tokens.issue("q", "a.png").unwrap();
+        tokens.rename_project("q", "r").unwrap();
+        assert_eq!(tokens.get(&t4).unwrap().project_name, "r");
+
+        tokens.revoke_project("p").unwrap();
+        assert!(tokens.get(&t3).is_none());
+        assert!(tokens.get(&t4).is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,443 @@
+//! Multi-hash ensemble matching.
+//!
+//! Every hash type misses some edits: a luminance hash cannot see a
+//! recoloring, a whole-image hash barely recognizes a crop. Projects
+//! listed in the `[ensemble]` config are also indexed under other hash
+//! types, and image queries are ranked on a weighted mean of the
+//! normalized distances under every listed type.
+//!
+//! The project hash list keeps the project hash type. The other types
+//! are loaded in the background on the first query, uploads and
+//! removals update them in place, or are replayed onto them once
+//! loaded. Until they are loaded, and for precomputed query hashes,
+//! queries are ranked on the project hash type alone.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use image::DynamicImage;
+use serde::Deserialize;
+
+use crate::blocking::run_blocking;
+use crate::image_hash::{
+    Hash, HashParams, HashType, ImageDistEntry, ImageHashEntry,
+    calc_hash, dist_to_similarity, hash_dist};
+
+/// Project entry matching every project not listed by name.
+const ANY_PROJECT: &str = "*";
+
+/// Weight of every hash type of an ensemble.
+pub type EnsembleWeights = HashMap<HashType, f64>;
+
+/// Ensemble settings as written in the config file, a table of hash
+/// type weights per project, e.g. `catalog = { phash = 1.0, colorhash = 0.5 }`.
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+#[serde(transparent)]
+pub struct EnsembleConfig {
+    /// Weights per project name, `*` for every other project.
+    pub projects: HashMap<String, EnsembleWeights>,
+}
+
+impl EnsembleConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (project_name, weights) in &self.projects {
+            if weights.values().any(|w| !w.is_finite() || *w < 0.0) {
+                return Err(format!("`ensemble.{}` weights must be positive numbers", project_name));
+            }
+            if !weights.values().any(|w| *w > 0.0) {
+                return Err(format!("`ensemble.{}` needs a weight above zero", project_name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Weights of `project_name`, `None` when it is not listed.
+    pub fn weights_for(&self, project_name: &str) -> Option<&EnsembleWeights> {
+        self.projects.get(project_name)
+            .or_else(|| self.projects.get(ANY_PROJECT))
+    }
+}
+
+/// Hashes of a project under one more hash type.
+#[derive(Debug)]
+struct Member {
+    hash_type: HashType,
+    weight: f64,
+    /// Parameters query images are hashed with.
+    params: HashParams,
+    hashes: HashMap<PathBuf, Hash>,
+}
+
+/// Hashes of a project under every listed hash type besides the
+/// project hash type.
+#[derive(Debug)]
+pub struct EnsembleLists {
+    /// Hash type of the project hash list.
+    primary: HashType,
+    /// Weight of `primary`, 0 when it is not listed.
+    primary_weight: f64,
+    members: Vec<Member>,
+}
+
+impl EnsembleLists {
+    /// Lists of a project of `primary` hashes, from its hash list under
+    /// every other hash type of `weights`. Queries are hashed with the
+    /// parameters of each list, `params` for an empty one.
+    pub fn new(weights: &EnsembleWeights, primary: HashType, params: HashParams, member_lists: Vec<(HashType, Vec<ImageHashEntry>)>) -> Self {
+        let members = member_lists.into_iter()
+            .map(|(hash_type, hash_list)| Member {
+                hash_type,
+                weight: weights.get(&hash_type).copied().unwrap_or(0.0),
+                params: hash_list.first().map_or_else(|| params.for_type(hash_type), |h_ent| h_ent.params),
+                hashes: hash_list.into_iter()
+                    .map(|h_ent| (h_ent.image_name, h_ent.hash))
+                    .collect(),
+            })
+            .collect();
+
+        EnsembleLists {
+            primary,
+            primary_weight: weights.get(&primary).copied().unwrap_or(0.0),
+            members,
+        }
+    }
+
+    /// Add or replace the hash of an image, ignored for hash types not
+    /// in the ensemble.
+    pub fn insert(&mut self, h_entry: &ImageHashEntry) {
+        if let Some(member) = self.members.iter_mut().find(|m| m.hash_type == h_entry.hash_type) {
+            member.hashes.insert(h_entry.image_name.clone(), h_entry.hash.clone());
+        }
+    }
+
+    pub fn remove(&mut self, image_name: &Path) {
+        for member in &mut self.members {
+            member.hashes.remove(image_name);
+        }
+    }
+
+    /// Turn `dist_list`, distances of `image` to project hashes of
+    /// `bit_length` bits, into distances under the whole ensemble.
+    ///
+    /// Each distance is normalized by its hash length before weighting,
+    /// the mean is scaled back to `bit_length` bits, so it still reads
+    /// as a distance of the project hash type. Hash types without a
+    /// hash of an image are left out of its mean.
+    pub fn combine(&self, image: &DynamicImage, dist_list: Vec<ImageDistEntry>, bit_length: usize) -> Vec<ImageDistEntry> {
+        let query_hashes: Vec<Hash> = self.members.iter()
+            .map(|m| calc_hash(image, m.hash_type, m.params))
+            .collect();
+        let normalize = |distance: f64, bit_length: usize| match bit_length {
+            0 => 0.0,
+            n => distance / n as f64,
+        };
+
+        dist_list.into_iter()
+            .map(|d_entry| {
+                let (weighted_sum, weight_sum) = self.members.iter()
+                    .zip(&query_hashes)
+                    .filter_map(|(m, query_hash)| m.hashes.get(&d_entry.image_name)
+                        .filter(|h| h.bits.len() == query_hash.bits.len())
+                        .map(|h| (m.weight, normalize(hash_dist(m.hash_type, query_hash, h), h.bits.len()))))
+                    .fold(
+                        (self.primary_weight * normalize(d_entry.distance, bit_length), self.primary_weight),
+                        |(weighted_sum, weight_sum), (weight, dist)| (weighted_sum + weight * dist, weight_sum + weight));
+
+                let distance = match weight_sum > 0.0 {
+                    true => weighted_sum / weight_sum * bit_length as f64,
+                    false => d_entry.distance,
+                };
+                ImageDistEntry {
+                    image_name: d_entry.image_name,
+                    distance,
+                    similarity: dist_to_similarity(distance, bit_length),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Lists of a project shared between queries and updates.
+pub type SharedEnsemble = Arc<RwLock<EnsembleLists>>;
+
+/// Loads the hash list of a project under a hash type, from caches or
+/// stores where possible.
+pub type MemberLoader = Box<dyn Fn(&str, HashType) -> Result<Vec<ImageHashEntry>, String> + Send + Sync>;
+
+/// A change to the lists of a project.
+#[derive(Debug)]
+enum Change {
+    Insert(ImageHashEntry),
+    Remove(PathBuf),
+}
+
+impl Change {
+    fn apply(self, lists: &mut EnsembleLists) {
+        match self {
+            Change::Insert(h_entry) => lists.insert(&h_entry),
+            Change::Remove(image_name) => lists.remove(&image_name),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ProjectEnsemble {
+    /// Bumped when the whole hash list is replaced, a build started
+    /// before is thrown away.
+    generation: u64,
+    lists: Option<SharedEnsemble>,
+    is_building: bool,
+    /// Changes made during the build, the loaded lists may predate them.
+    pending: Vec<Change>,
+}
+
+/// Ensemble lists of all projects.
+///
+/// Like `AnnIndexes`, changes to a project hash list must be reported
+/// with `insert`, `remove` or `invalidate` while holding its write lock.
+pub struct EnsembleIndexes {
+    config: EnsembleConfig,
+    /// Parameters members are hashed with.
+    params: HashParams,
+    load: MemberLoader,
+    projects: Mutex<HashMap<String, ProjectEnsemble>>,
+}
+
+impl EnsembleIndexes {
+    pub fn new(config: EnsembleConfig, params: HashParams, load: MemberLoader) -> Self {
+        EnsembleIndexes { config, params, load, projects: Mutex::new(HashMap::new()) }
+    }
+
+    /// Parameters members are hashed with.
+    pub fn params(&self) -> HashParams {
+        self.params
+    }
+
+    /// Hash types a project of `primary` hashes is also indexed under,
+    /// empty when it is not listed.
+    pub fn member_types(&self, project_name: &str, primary: HashType) -> Vec<HashType> {
+        let Some(weights) = self.config.weights_for(project_name) else {
+            return Vec::new();
+        };
+
+        HashType::ALL.iter()
+            .filter(|t| **t != primary && weights.contains_key(*t))
+            .copied()
+            .collect()
+    }
+
+    /// Lists to rank queries of `project_name` with, `None` when only
+    /// its `primary` hashes should be used.
+    ///
+    /// Missing lists of a listed project are loaded in the background.
+    pub fn lookup(self: &Arc<Self>, project_name: &str, primary: HashType) -> Option<SharedEnsemble> {
+        let weights = self.config.weights_for(project_name)?;
+
+        let mut projects = self.projects.lock().unwrap_or_else(|e| e.into_inner());
+        let project = projects.entry(project_name.to_owned()).or_default();
+
+        // the project hash type is part of the lists, rebuild if it changed.
+        if let Some(lists) = &project.lists {
+            if lists.read().unwrap_or_else(|e| e.into_inner()).primary == primary {
+                return Some(Arc::clone(lists));
+            }
+            project.lists = None;
+        }
+
+        if !project.is_building {
+            project.is_building = true;
+            self.spawn_build(project_name.to_owned(), project.generation, weights.clone(), primary);
+        }
+        None
+    }
+
+    fn spawn_build(self: &Arc<Self>, project_name: String, generation: u64, weights: EnsembleWeights, primary: HashType) {
+        let indexes = Arc::clone(self);
+        let member_types = self.member_types(&project_name, primary);
+        let params = self.params;
+
+        tokio::spawn(async move {
+            let build_start = Instant::now();
+            let _indexes = Arc::clone(&indexes);
+            let _project_name = project_name.clone();
+            let built = run_blocking(move || member_types.into_iter()
+                    .map(|hash_type| (_indexes.load)(&_project_name, hash_type).map(|hash_list| (hash_type, hash_list)))
+                    .collect::<Result<Vec<_>, String>>()
+                    .map(|member_lists| EnsembleLists::new(&weights, primary, params, member_lists)))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|built| built);
+
+            let mut projects = indexes.projects.lock().unwrap_or_else(|e| e.into_inner());
+            let project = projects.entry(project_name.clone()).or_default();
+            project.is_building = false;
+            let pending = std::mem::take(&mut project.pending);
+
+            match built {
+                Ok(mut lists) if project.generation == generation => {
+                    // replaying a change the loader already saw is harmless,
+                    // an insert replaces and a remove finds nothing.
+                    let replayed = pending.len();
+                    pending.into_iter().for_each(|change| change.apply(&mut lists));
+                    tracing::info!(project = %project_name, hash_types = lists.members.len() + 1, replayed, elapsed = ?build_start.elapsed(), "ensemble loaded");
+                    project.lists = Some(Arc::new(RwLock::new(lists)));
+                },
+                Ok(_) => tracing::info!(project = %project_name, "project replaced while loading its ensemble, load dropped"),
+                Err(e) => tracing::warn!(project = %project_name, error = %e, "cannot load ensemble"),
+            }
+        });
+    }
+
+    /// Apply a change to the lists of `project_name`, or queue it for
+    /// the lists being built.
+    fn update(&self, project_name: &str, change: Change) {
+        let mut projects = self.projects.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(project) = projects.get_mut(project_name) else {
+            return;
+        };
+
+        match &project.lists {
+            Some(lists) => change.apply(&mut lists.write().unwrap_or_else(|e| e.into_inner())),
+            None if project.is_building => project.pending.push(change),
+            None => {},
+        }
+    }
+
+    /// Add or replace the hash of an image under one of the ensemble
+    /// hash types of `project_name`.
+    pub fn insert(&self, project_name: &str, h_entry: &ImageHashEntry) {
+        self.update(project_name, Change::Insert(h_entry.clone()));
+    }
+
+    /// Drop an image from the lists of `project_name`.
+    pub fn remove(&self, project_name: &str, image_name: &Path) {
+        self.update(project_name, Change::Remove(image_name.to_owned()));
+    }
+
+    /// Forget the lists of `project_name`, for changes that replace the
+    /// whole hash list.
+    pub fn invalidate(&self, project_name: &str) {
+        let mut projects = self.projects.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(project) = projects.get_mut(project_name) {
+            project.generation += 1;
+            project.lists = None;
+            project.pending.clear();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+    use crate::image_hash::calc_similarity_list_from_hash;
+
+    #[test]
+    fn test_ensemble_config() {
+        let config: EnsembleConfig = toml::from_str("catalog = { phash = 1.0, colorhash = 0.5 }\n\"*\" = { dhash = 1.0 }").unwrap();
+        assert_eq!(config.weights_for("catalog").unwrap()[&HashType::COLORHASH], 0.5);
+        assert!(config.weights_for("other").unwrap().contains_key(&HashType::DHASH));
+        assert!(config.validate().is_ok());
+        assert!(EnsembleConfig::default().weights_for("catalog").is_none());
+
+        let indexes = EnsembleIndexes::new(config, HashParams::default(), Box::new(|_, _| Ok(Vec::new())));
+        assert_eq!(indexes.member_types("catalog", HashType::PHASH), [HashType::COLORHASH]);
+        assert_eq!(indexes.member_types("other", HashType::PHASH), [HashType::DHASH]);
+
+        assert!(toml::from_str::<EnsembleConfig>("catalog = { nohash = 1.0 }").is_err());
+        let negative: EnsembleConfig = toml::from_str("catalog = { phash = -1.0 }").unwrap();
+        assert!(negative.validate().is_err());
+        let zero: EnsembleConfig = toml::from_str("catalog = { phash = 0.0 }").unwrap();
+        assert!(zero.validate().is_err());
+    }
+
+    #[test]
+    fn test_ensemble_recolor() {
+        let mk_image = |swap: bool| DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(64, 64, |x, y| {
+            let (r, g) = ((x * 4) as u8, (y * 4) as u8);
+            image::Rgb(if swap { [g, r, 96] } else { [r, g, 96] })
+        }));
+        let (original, recolored) = (mk_image(false), mk_image(true));
+        let hash_lists = |hash_type: HashType| vec![
+            ImageHashEntry::new(PathBuf::from("original.png"), hash_type, calc_hash(&original, hash_type, HashParams::default())),
+            ImageHashEntry::new(PathBuf::from("recolored.png"), hash_type, calc_hash(&recolored, hash_type, HashParams::default())),
+        ];
+
+        let weights = EnsembleWeights::from([(HashType::AHASH, 1.0), (HashType::COLORHASH, 1.0)]);
+        let mut lists = EnsembleLists::new(&weights, HashType::AHASH, HashParams::default(), vec![(HashType::COLORHASH, hash_lists(HashType::COLORHASH))]);
+
+        let primary = hash_lists(HashType::AHASH);
+        let query_hash = calc_hash(&original, HashType::AHASH, HashParams::default());
+        let bit_length = query_hash.bits.len();
+        let combined = lists.combine(&original, calc_similarity_list_from_hash(&query_hash, &primary), bit_length);
+
+        // the original matches under both types, so only it stays at 0.
+        assert_eq!(combined[0].distance, 0.0);
+        assert_eq!(combined[0].similarity, 1.0);
+        assert!(combined[1].distance > 0.0);
+
+        // an image missing from a member is ranked on the others only.
+        lists.remove(Path::new("recolored.png"));
+        let recolored_hash = calc_hash(&recolored, HashType::AHASH, HashParams::default());
+        let combined = lists.combine(&recolored, calc_similarity_list_from_hash(&recolored_hash, &primary), bit_length);
+        assert_eq!(combined[1].distance, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_changes_during_build() {
+        let config: EnsembleConfig = toml::from_str("catalog = { phash = 1.0, colorhash = 0.5 }").unwrap();
+        let h_entry = |name: &str| ImageHashEntry::new(PathBuf::from(name), HashType::COLORHASH, Hash { bits: vec![true; 8] });
+
+        // the loader reads the project before the changes below, and
+        // returns only once they are made.
+        let gate = Arc::new(Mutex::new(()));
+        let held = gate.lock().unwrap();
+        let _gate = Arc::clone(&gate);
+        let indexes = Arc::new(EnsembleIndexes::new(config, HashParams::default(), Box::new(move |_, _| {
+            let loaded = vec![h_entry("a.png"), h_entry("b.png")];
+            drop(_gate.lock().unwrap());
+            Ok(loaded)
+        })));
+
+        let lookup = || indexes.lookup("catalog", HashType::PHASH);
+        let built = || async {
+            for _ in 0..200 {
+                if let Some(lists) = lookup() {
+                    return lists;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            panic!("ensemble never loaded");
+        };
+        let names = |lists: &SharedEnsemble| lists.read().unwrap().members[0].hashes.keys()
+            .map(|p| p.to_string_lossy().into_owned())
+            .sorted()
+            .collect::<Vec<_>>();
+
+        assert!(lookup().is_none());
+        indexes.insert("catalog", &h_entry("c.png"));
+        indexes.remove("catalog", Path::new("a.png"));
+        drop(held);
+
+        // the changes are replayed onto the loaded lists.
+        let lists = built().await;
+        assert_eq!(names(&lists), ["b.png", "c.png"]);
+
+        // and made in place afterwards.
+        indexes.remove("catalog", Path::new("b.png"));
+        assert_eq!(names(&lists), ["c.png"]);
+
+        // replacing the whole hash list drops a build in progress, along
+        // with the changes queued for it.
+        indexes.invalidate("catalog");
+        let held = gate.lock().unwrap();
+        assert!(lookup().is_none());
+        indexes.insert("catalog", &h_entry("d.png"));
+        indexes.invalidate("catalog");
+        drop(held);
+        assert_eq!(names(&built().await), ["a.png", "b.png"]);
+    }
+}
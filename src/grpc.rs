@@ -0,0 +1,139 @@
+//! gRPC API, for internal callers that want to skip base64 and JSON.
+//!
+//! Messages are declared here with `prost`, the service stubs are
+//! generated by `build.rs`. `proto/vismatch.proto` describes the same
+//! API for client code generation.
+
+use crate::api::AppError;
+
+include!(concat!(env!("OUT_DIR"), "/vismatch.VisMatch.rs"));
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UploadChunk {
+    #[prost(string, tag = "1")]
+    pub project_name: String,
+    #[prost(string, tag = "2")]
+    pub image_name: String,
+    #[prost(string, tag = "3")]
+    pub hash_size: String,
+    #[prost(bytes = "vec", tag = "4")]
+    pub data: Vec<u8>,
+    #[prost(string, repeated, tag = "5")]
+    pub tags: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UploadReply {
+    #[prost(bool, tag = "1")]
+    pub success: bool,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(string, tag = "3")]
+    pub token: String,
+    #[prost(uint64, tag = "4")]
+    pub image_size_bytes: u64,
+    #[prost(uint32, tag = "5")]
+    pub hash_size_bits: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CompareRequest {
+    #[prost(string, tag = "1")]
+    pub project_name: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub data: Vec<u8>,
+    #[prost(string, tag = "3")]
+    pub hash_size: String,
+    #[prost(uint32, optional, tag = "4")]
+    pub top_k: Option<u32>,
+    #[prost(uint32, optional, tag = "5")]
+    pub max_distance: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SimilarImage {
+    #[prost(string, tag = "1")]
+    pub image_name: String,
+    #[prost(float, tag = "2")]
+    pub distance: f32,
+    #[prost(float, tag = "3")]
+    pub similarity_score: f32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CompareReply {
+    #[prost(bool, tag = "1")]
+    pub success: bool,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(string, tag = "3")]
+    pub project_name: String,
+    #[prost(message, repeated, tag = "4")]
+    pub compare_result: Vec<SimilarImage>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RemoveRequest {
+    #[prost(string, tag = "1")]
+    pub token: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RemoveReply {
+    #[prost(bool, tag = "1")]
+    pub success: bool,
+    #[prost(string, tag = "2")]
+    pub message: String,
+}
+
+/// Empty strings stand for unset optional text fields in proto3.
+pub fn non_empty(s: String) -> Option<String> {
+    match s.is_empty() {
+        true => None,
+        false => Some(s),
+    }
+}
+
+impl From<AppError> for tonic::Status {
+    /// Same mapping as the HTTP status codes of `AppError`.
+    fn from(err: AppError) -> Self {
+        match err {
+            AppError::BadRequest(msg) => tonic::Status::invalid_argument(msg),
+            AppError::PayloadTooLarge(msg) => tonic::Status::resource_exhausted(msg),
+            AppError::InternalError(msg) => tonic::Status::internal(msg),
+            AppError::Teapot(msg) => tonic::Status::unimplemented(msg),
+            AppError::Unauthorized(msg) => tonic::Status::unauthenticated(msg),
+            AppError::Forbidden(msg) => tonic::Status::permission_denied(msg),
+            AppError::Conflict(msg) => tonic::Status::aborted(msg),
+            AppError::TooManyRequests(msg, _) => tonic::Status::resource_exhausted(msg),
+            AppError::ComputePanic(_detail) => tonic::Status::internal("internal computation failed"),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let req = CompareRequest {
+            project_name: "p".to_owned(),
+            data: vec![1, 2, 3],
+            hash_size: String::new(),
+            top_k: Some(5),
+            max_distance: None,
+        };
+        let decoded = CompareRequest::decode(req.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, req);
+
+        assert_eq!(non_empty(decoded.hash_size), None);
+        assert_eq!(non_empty("small".to_owned()).as_deref(), Some("small"));
+
+        let status: tonic::Status = AppError::ComputePanic("secret".to_owned()).into();
+        assert_eq!(status.code(), tonic::Code::Internal);
+        assert!(!status.message().contains("secret"));
+    }
+}
@@ -0,0 +1,340 @@
+//! Hash store in one manifest file per project folder.
+//!
+//! The manifest replaces the sidecar cache files of a project: loading
+//! the project reads one file instead of one per image. It is a log of
+//! records, uploads and removals append to it, and it is rewritten with
+//! only the live hashes once replaced and removed ones pile up.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hash_store::{HashStore, StoreError, StoredHash};
+use crate::image_hash::{Hash, HashType};
+
+/// Manifest file in each project folder.
+pub const MANIFEST_FILE: &str = ".vismatch-hashes";
+
+/// Written while the manifest is rewritten, then renamed over it.
+const MANIFEST_TMP_FILE: &str = ".vismatch-hashes.tmp";
+
+const MANIFEST_MAGIC: &[u8; 3] = b"VMM";
+
+/// Bump when the record layout changes, older manifests are then dropped
+/// and hashes rebuilt from sidecar caches or images.
+const MANIFEST_VERSION: u8 = 2;
+
+/// Records allowed beyond twice the live hashes before a rewrite, so
+/// small projects are not rewritten on every removal.
+const REWRITE_SLACK: usize = 1024;
+
+/// One change of a manifest, bincode encoded.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    /// A hash stored, replacing the one of the same image and hash type.
+    Put {
+        image_name: String,
+        hash_type: HashType,
+        bit_length: u32,
+        /// Bits packed by `Hash::to_bytes`.
+        hash: Vec<u8>,
+        modified_ns: i64,
+        fingerprint: Option<u64>,
+    },
+    /// Every hash of an image dropped.
+    Remove { image_name: String },
+}
+
+impl Record {
+    fn put(h: &StoredHash) -> Result<Self, StoreError> {
+        Ok(Record::Put {
+            image_name: h.image_name.clone(),
+            hash_type: h.hash_type,
+            bit_length: u32::try_from(h.hash.bits.len()).map_err(|_| "hash too long to be stored")?,
+            hash: h.hash.to_bytes(),
+            modified_ns: h.modified_ns,
+            fingerprint: h.fingerprint,
+        })
+    }
+}
+
+/// Live hashes of a manifest, keyed by image name and hash type.
+type ManifestHashes = HashMap<(String, String), StoredHash>;
+
+/// Records of a manifest file, and how many of them hold live hashes.
+/// Appends count every put as live, a rewrite sets the exact numbers.
+#[derive(Debug, Default, Clone, Copy)]
+struct RecordCount {
+    records: usize,
+    live: usize,
+}
+
+impl RecordCount {
+    fn needs_rewrite(&self) -> bool {
+        self.records > 2 * self.live + REWRITE_SLACK
+    }
+}
+
+/// Hashes in a manifest file next to the images of each project.
+pub struct ManifestHashStore {
+    project_root: PathBuf,
+    // file access takes turns, the counts are per project.
+    counts: Mutex<HashMap<String, RecordCount>>,
+}
+
+/// Encode records after each other.
+fn encode_records<'a>(records: impl IntoIterator<Item = &'a Record>) -> Result<Vec<u8>, StoreError> {
+    let mut content = Vec::new();
+    for record in records {
+        bincode::serde::encode_into_std_write(record, &mut content, bincode::config::standard())?;
+    }
+    Ok(content)
+}
+
+/// Replay the records of a manifest file. A missing file is empty, an
+/// unreadable header or a record cut short, e.g. by a crash while
+/// appending, ends the replay. Returns whether the file was whole.
+fn read_manifest(path: &Path) -> Result<(ManifestHashes, RecordCount, bool), StoreError> {
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((ManifestHashes::new(), RecordCount::default(), true)),
+        Err(e) => return Err(format!("cannot read manifest <{}>: {}", path.display(), e).into()),
+    };
+
+    let mut hashes = ManifestHashes::new();
+    let mut count = RecordCount::default();
+
+    let Some(mut rest) = content.strip_prefix(MANIFEST_MAGIC)
+        .and_then(|c| c.strip_prefix(&[MANIFEST_VERSION])) else {
+        return Ok((hashes, count, false));
+    };
+
+    while !rest.is_empty() {
+        let Ok((record, read)) = bincode::serde::decode_from_slice::<Record, _>(rest, bincode::config::standard()) else {
+            return Ok((hashes, count, false));
+        };
+        rest = &rest[read..];
+        count.records += 1;
+
+        match record {
+            Record::Put { image_name, hash_type, bit_length, hash, modified_ns, fingerprint } => {
+                let bit_length = bit_length as usize;
+                if hash.len() * 8 < bit_length {
+                    return Ok((hashes, count, false));
+                }
+
+                let key = (image_name.clone(), hash_type.to_string());
+                let hash = Hash::from_bytes(&hash, bit_length);
+                hashes.insert(key, StoredHash { image_name, hash_type, hash, modified_ns, fingerprint });
+            },
+            Record::Remove { image_name } => hashes.retain(|(name, _), _| *name != image_name),
+        }
+    }
+
+    count.live = hashes.len();
+    Ok((hashes, count, true))
+}
+
+impl ManifestHashStore {
+    /// Keep manifests in the project folders under `project_root`.
+    pub fn new(project_root: &Path) -> Self {
+        ManifestHashStore {
+            project_root: project_root.to_owned(),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn counts(&self) -> MutexGuard<'_, HashMap<String, RecordCount>> {
+        self.counts.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn manifest_path(&self, project_name: &str) -> PathBuf {
+        self.project_root.join(project_name).join(MANIFEST_FILE)
+    }
+
+    /// Live hashes of a project, the manifest is rewritten when damaged
+    /// or mostly made of stale records.
+    fn read(&self, counts: &mut HashMap<String, RecordCount>, project_name: &str) -> Result<ManifestHashes, StoreError> {
+        let path = self.manifest_path(project_name);
+        let (hashes, count, is_whole) = read_manifest(&path)?;
+
+        match !is_whole || count.needs_rewrite() {
+            true => {
+                if !is_whole {
+                    tracing::warn!(project = %project_name, kept = hashes.len(), "damaged hash manifest, rewriting it");
+                }
+                self.rewrite(counts, project_name, &hashes)?;
+            },
+            false => { counts.insert(project_name.to_owned(), count); },
+        }
+        Ok(hashes)
+    }
+
+    /// Replace the manifest by one holding `hashes` only.
+    fn rewrite(&self, counts: &mut HashMap<String, RecordCount>, project_name: &str, hashes: &ManifestHashes) -> Result<(), StoreError> {
+        let records = hashes.values().map(Record::put).collect::<Result<Vec<_>, _>>()?;
+
+        let mut content = vec![];
+        content.extend_from_slice(MANIFEST_MAGIC);
+        content.push(MANIFEST_VERSION);
+        content.extend(encode_records(&records)?);
+
+        // a crash while writing leaves the old manifest in place.
+        let tmp_path = self.project_root.join(project_name).join(MANIFEST_TMP_FILE);
+        std::fs::write(&tmp_path, content)
+            .and_then(|_| std::fs::rename(&tmp_path, self.manifest_path(project_name)))
+            .map_err(|e| format!("cannot rewrite manifest of project <{}>: {}", project_name, e))?;
+
+        counts.insert(project_name.to_owned(), RecordCount { records: records.len(), live: records.len() });
+        Ok(())
+    }
+
+    /// Append records to the manifest, created when missing.
+    fn append(&self, project_name: &str, records: &[Record], live_change: isize) -> Result<(), StoreError> {
+        let mut counts = self.counts();
+        let path = self.manifest_path(project_name);
+
+        let mut file: File = OpenOptions::new().create(true).append(true).open(&path)
+            .map_err(|e| format!("cannot open manifest <{}>: {}", path.display(), e))?;
+
+        let mut content = vec![];
+        if file.metadata()?.len() == 0 {
+            content.extend_from_slice(MANIFEST_MAGIC);
+            content.push(MANIFEST_VERSION);
+        }
+        content.extend(encode_records(records)?);
+        file.write_all(&content)?;
+
+        let count = counts.entry(project_name.to_owned()).or_default();
+        count.records += records.len();
+        count.live = count.live.saturating_add_signed(live_change);
+
+        if count.needs_rewrite() {
+            self.read(&mut counts, project_name)?;
+        }
+        Ok(())
+    }
+}
+
+impl HashStore for ManifestHashStore {
+    fn load_project(&self, project_name: &str, hash_type: HashType) -> Result<Vec<StoredHash>, StoreError> {
+        let hashes = self.read(&mut self.counts(), project_name)?;
+
+        Ok(hashes.into_values()
+            .filter(|h| h.hash_type == hash_type)
+            .collect())
+    }
+
+    fn get(&self, project_name: &str, image_name: &str, hash_type: HashType) -> Result<Option<StoredHash>, StoreError> {
+        let (mut hashes, _, _) = read_manifest(&self.manifest_path(project_name))?;
+        Ok(hashes.remove(&(image_name.to_owned(), hash_type.to_string())))
+    }
+
+    fn put(&self, project_name: &str, hashes: &[StoredHash]) -> Result<(), StoreError> {
+        if hashes.is_empty() {
+            return Ok(());
+        }
+
+        let records = hashes.iter().map(Record::put).collect::<Result<Vec<_>, _>>()?;
+        self.append(project_name, &records, records.len() as isize)
+    }
+
+    fn remove_images(&self, project_name: &str, image_names: &[String]) -> Result<(), StoreError> {
+        // an image without a manifest has nothing to remove.
+        if image_names.is_empty() || !self.manifest_path(project_name).exists() {
+            return Ok(());
+        }
+
+        let records: Vec<Record> = image_names.iter()
+            .map(|image_name| Record::Remove { image_name: image_name.clone() })
+            .collect();
+        self.append(project_name, &records, -(records.len() as isize))
+    }
+
+    fn remove_project(&self, project_name: &str) -> Result<(), StoreError> {
+        self.counts().remove(project_name);
+
+        match std::fs::remove_file(self.manifest_path(project_name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn rename_project(&self, project_name: &str, new_name: &str) -> Result<(), StoreError> {
+        let mut counts = self.counts();
+
+        // the manifest moves along with a renamed folder, only a folder
+        // renamed afterwards leaves it behind.
+        let path = self.manifest_path(project_name);
+        if path.exists() {
+            std::fs::rename(&path, self.manifest_path(new_name))?;
+        }
+
+        counts.remove(new_name);
+        if let Some(count) = counts.remove(project_name) {
+            counts.insert(new_name.to_owned(), count);
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_hash_store() {
+        let root = std::env::temp_dir().join(format!("vismatch-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("cats")).unwrap();
+        let store = ManifestHashStore::new(&root);
+
+        let mk = |name: &str, hash_type: HashType, bits: Vec<bool>| StoredHash {
+            image_name: name.to_owned(),
+            hash_type,
+            hash: Hash { bits },
+            modified_ns: 42,
+            fingerprint: Some(7),
+        };
+        let bits = vec![true, false, true, true, false, false, true, false, true];
+        store.put("cats", &[
+            mk("a.png", HashType::PHASH, bits.clone()),
+            mk("b.png", HashType::PHASH, vec![false; 64]),
+            mk("a.png", HashType::DHASH, vec![true; 64]),
+        ]).unwrap();
+        store.put("cats", &[mk("b.png", HashType::PHASH, vec![true; 64])]).unwrap();
+        store.remove_images("cats", &["a.png".to_owned()]).unwrap();
+
+        assert!(store.get("cats", "a.png", HashType::DHASH).unwrap().is_none());
+        let b = store.get("cats", "b.png", HashType::PHASH).unwrap().unwrap();
+        assert_eq!(b.hash.bits, vec![true; 64]);
+        assert_eq!(b.fingerprint, Some(7));
+
+        // a record cut short is dropped, the manifest rewritten.
+        store.put("cats", &[mk("a.png", HashType::PHASH, bits.clone())]).unwrap();
+        let path = root.join("cats").join(MANIFEST_FILE);
+        let whole = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &whole[..whole.len() - 2]).unwrap();
+
+        let store = ManifestHashStore::new(&root);
+        assert!(store.load_project("birds", HashType::PHASH).unwrap().is_empty());
+        assert!(!root.join("birds").exists());
+        let hashes = store.load_project("cats", HashType::PHASH).unwrap();
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(read_manifest(&path).unwrap().1.records, 1);
+        assert!(read_manifest(&path).unwrap().2);
+
+        // the folder is renamed first, the manifest is in it.
+        std::fs::rename(root.join("cats"), root.join("dogs")).unwrap();
+        store.rename_project("cats", "dogs").unwrap();
+        assert_eq!(store.load_project("dogs", HashType::PHASH).unwrap().len(), 1);
+        store.remove_project("dogs").unwrap();
+        assert!(!root.join("dogs").join(MANIFEST_FILE).exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
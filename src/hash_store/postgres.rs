@@ -0,0 +1,299 @@
+//! Hash and deletion token store in a PostgreSQL database, shared by
+//! every replica of the service.
+//!
+//! Replicas serving the same image root reuse each other's hashes, and
+//! accept deletion tokens issued by any of them.
+
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+
+use deadpool_postgres::{Manager, Pool, Runtime};
+use tokio::runtime::Handle;
+use tokio_postgres::NoTls;
+
+use crate::deletion_tokens::{DeletionTokens, TokenStore, TokenTarget};
+use crate::hash_store::{HashStore, StoreError, StoredHash};
+use crate::image_hash::{Hash, HashType};
+
+/// How long to wait for a free connection, or for a new one.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Advisory lock held while creating tables, so replicas starting
+/// together don't race on `CREATE TABLE IF NOT EXISTS`.
+const SCHEMA_LOCK_ID: i64 = 0x7669_736d_6174_6368; // "vismatch"
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS image_hashes (
+        project     TEXT    NOT NULL,
+        image_name  TEXT    NOT NULL,
+        hash_type   TEXT    NOT NULL,
+        bit_length  INTEGER NOT NULL,
+        hash        BYTEA   NOT NULL,
+        modified_ns BIGINT  NOT NULL,
+        fingerprint BIGINT,
+        PRIMARY KEY (project, image_name, hash_type)
+    );
+    -- tables created before hasher fingerprints, their hashes are taken as current.
+    ALTER TABLE image_hashes ADD COLUMN IF NOT EXISTS fingerprint BIGINT;
+    CREATE TABLE IF NOT EXISTS deletion_tokens (
+        token       TEXT    PRIMARY KEY,
+        project     TEXT    NOT NULL,
+        image_name  TEXT    NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS deletion_tokens_image ON deletion_tokens (project, image_name);";
+
+/// Hashes and deletion tokens in Postgres, over a connection pool.
+///
+/// The async client runs on the tokio runtime the store was created in,
+/// calls block the calling thread until it is done.
+pub struct PostgresStore {
+    pool: Pool,
+    runtime: Handle,
+}
+
+impl PostgresStore {
+    /// Connect to `url` with at most `pool_size` connections, creating
+    /// the tables if missing. Must be called within a tokio runtime, but
+    /// outside of async code.
+    pub fn connect(url: &str, pool_size: u32) -> Result<Self, StoreError> {
+        let manager = Manager::new(url.parse()?, NoTls);
+        let pool = Pool::builder(manager)
+            .max_size(pool_size as usize)
+            .wait_timeout(Some(CONNECTION_TIMEOUT))
+            .create_timeout(Some(CONNECTION_TIMEOUT))
+            .runtime(Runtime::Tokio1)
+            .build()?;
+
+        let store = PostgresStore { pool, runtime: Handle::try_current()? };
+
+        store.block_on(async {
+            let mut conn = store.pool.get().await
+                .map_err(|e| format!("cannot connect to hash store: {}", e))?;
+            let tx = conn.transaction().await?;
+            tx.execute("SELECT pg_advisory_xact_lock($1)", &[&SCHEMA_LOCK_ID]).await?;
+            tx.batch_execute(SCHEMA).await?;
+            tx.commit().await?;
+            Ok(())
+        })?;
+
+        Ok(store)
+    }
+
+    fn block_on<T>(&self, query: impl Future<Output = Result<T, StoreError>>) -> Result<T, StoreError> {
+        self.runtime.block_on(query)
+    }
+
+    /// Move the tokens of a JSON token file into the database, the file
+    /// is renamed so revoked tokens don't come back on the next start.
+    ///
+    /// Returns the number of imported tokens.
+    pub fn import_token_file(&self, path: &Path) -> Result<usize, StoreError> {
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        let tokens = DeletionTokens::load(path).map_err(|e| e.to_string())?;
+        let (token_ids, targets): (Vec<&String>, Vec<&TokenTarget>) = tokens.iter().unzip();
+        let projects: Vec<&str> = targets.iter().map(|t| t.project_name.as_str()).collect();
+        let image_names: Vec<&str> = targets.iter().map(|t| t.image_name.as_str()).collect();
+
+        let imported = self.block_on(async {
+            Ok(self.pool.get().await?.execute(
+                "INSERT INTO deletion_tokens (token, project, image_name)
+                 SELECT * FROM UNNEST($1::text[], $2::text[], $3::text[])
+                 ON CONFLICT (token) DO NOTHING",
+                &[&token_ids, &projects, &image_names]).await?)
+        })?;
+
+        std::fs::rename(path, path.with_extension("json.imported"))
+            .map_err(|e| format!("cannot rename imported token file <{}>: {}", path.display(), e))?;
+        Ok(imported as usize)
+    }
+}
+
+/// Decode a row of `image_name, hash_type, bit_length, hash, modified_ns,
+/// fingerprint`.
+fn stored_hash(row: &tokio_postgres::Row) -> Result<StoredHash, StoreError> {
+    let hash_type: HashType = row.try_get::<_, &str>(1)?.parse()?;
+    let bit_length: i32 = row.try_get(2)?;
+    let hash: &[u8] = row.try_get(3)?;
+
+    Ok(StoredHash {
+        image_name: row.try_get(0)?,
+        hash_type,
+        hash: Hash::from_bytes(hash, bit_length as usize),
+        modified_ns: row.try_get(4)?,
+        // Postgres integers are signed, the bits are kept as is.
+        fingerprint: row.try_get::<_, Option<i64>>(5)?.map(|fp| fp as u64),
+    })
+}
+
+impl HashStore for PostgresStore {
+    fn load_project(&self, project_name: &str, hash_type: HashType) -> Result<Vec<StoredHash>, StoreError> {
+        let rows = self.block_on(async {
+            Ok(self.pool.get().await?.query(
+                "SELECT image_name, hash_type, bit_length, hash, modified_ns, fingerprint FROM image_hashes
+                 WHERE project = $1 AND hash_type = $2",
+                &[&project_name, &hash_type.to_string()]).await?)
+        })?;
+
+        rows.iter().map(stored_hash).collect()
+    }
+
+    fn get(&self, project_name: &str, image_name: &str, hash_type: HashType) -> Result<Option<StoredHash>, StoreError> {
+        let row = self.block_on(async {
+            Ok(self.pool.get().await?.query_opt(
+                "SELECT image_name, hash_type, bit_length, hash, modified_ns, fingerprint FROM image_hashes
+                 WHERE project = $1 AND image_name = $2 AND hash_type = $3",
+                &[&project_name, &image_name, &hash_type.to_string()]).await?)
+        })?;
+
+        row.as_ref().map(stored_hash).transpose()
+    }
+
+    fn put(&self, project_name: &str, hashes: &[StoredHash]) -> Result<(), StoreError> {
+        if hashes.is_empty() {
+            return Ok(());
+        }
+
+        // one statement for the whole batch, migrations store thousands.
+        let image_names: Vec<&str> = hashes.iter().map(|h| h.image_name.as_str()).collect();
+        let hash_types: Vec<String> = hashes.iter().map(|h| h.hash_type.to_string()).collect();
+        let bit_lengths: Vec<i32> = hashes.iter()
+            .map(|h| i32::try_from(h.hash.bits.len()).map_err(|_| "hash too long to be stored"))
+            .collect::<Result<_, _>>()?;
+        let packed: Vec<Vec<u8>> = hashes.iter().map(|h| h.hash.to_bytes()).collect();
+        let modified: Vec<i64> = hashes.iter().map(|h| h.modified_ns).collect();
+        let fingerprints: Vec<Option<i64>> = hashes.iter().map(|h| h.fingerprint.map(|fp| fp as i64)).collect();
+
+        self.block_on(async {
+            self.pool.get().await?.execute(
+                "INSERT INTO image_hashes (project, image_name, hash_type, bit_length, hash, modified_ns, fingerprint)
+                 SELECT $1, * FROM UNNEST($2::text[], $3::text[], $4::int[], $5::bytea[], $6::bigint[], $7::bigint[])
+                 ON CONFLICT (project, image_name, hash_type) DO UPDATE
+                 SET bit_length = EXCLUDED.bit_length, hash = EXCLUDED.hash, modified_ns = EXCLUDED.modified_ns,
+                     fingerprint = EXCLUDED.fingerprint",
+                &[&project_name, &image_names, &hash_types, &bit_lengths, &packed, &modified, &fingerprints]).await?;
+            Ok(())
+        })
+    }
+
+    fn remove_images(&self, project_name: &str, image_names: &[String]) -> Result<(), StoreError> {
+        self.block_on(async {
+            self.pool.get().await?.execute(
+                "DELETE FROM image_hashes WHERE project = $1 AND image_name = ANY($2)",
+                &[&project_name, &image_names]).await?;
+            Ok(())
+        })
+    }
+
+    fn remove_project(&self, project_name: &str) -> Result<(), StoreError> {
+        self.block_on(async {
+            self.pool.get().await?.execute("DELETE FROM image_hashes WHERE project = $1", &[&project_name]).await?;
+            Ok(())
+        })
+    }
+
+    fn rename_project(&self, project_name: &str, new_name: &str) -> Result<(), StoreError> {
+        self.block_on(async {
+            let mut conn = self.pool.get().await?;
+            let tx = conn.transaction().await?;
+            // leftovers of an earlier project with the new name would collide.
+            tx.execute("DELETE FROM image_hashes WHERE project = $1", &[&new_name]).await?;
+            tx.execute("UPDATE image_hashes SET project = $2 WHERE project = $1", &[&project_name, &new_name]).await?;
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+}
+
+impl TokenStore for PostgresStore {
+    fn issue(&self, project_name: &str, image_name: &str) -> Result<String, StoreError> {
+        let token = uuid::Uuid::new_v4().to_string();
+
+        self.block_on(async {
+            self.pool.get().await?.execute(
+                "INSERT INTO deletion_tokens (token, project, image_name) VALUES ($1, $2, $3)",
+                &[&token, &project_name, &image_name]).await?;
+            Ok(())
+        })?;
+        Ok(token)
+    }
+
+    fn get(&self, token: &str) -> Result<Option<TokenTarget>, StoreError> {
+        let row = self.block_on(async {
+            Ok(self.pool.get().await?.query_opt(
+                "SELECT project, image_name FROM deletion_tokens WHERE token = $1",
+                &[&token]).await?)
+        })?;
+
+        Ok(row.map(|row| TokenTarget { project_name: row.get(0), image_name: row.get(1) }))
+    }
+
+    fn revoke_image(&self, project_name: &str, image_name: &str) -> Result<(), StoreError> {
+        self.block_on(async {
+            self.pool.get().await?.execute(
+                "DELETE FROM deletion_tokens WHERE project = $1 AND image_name = $2",
+                &[&project_name, &image_name]).await?;
+            Ok(())
+        })
+    }
+
+    fn revoke_project(&self, project_name: &str) -> Result<(), StoreError> {
+        self.block_on(async {
+            self.pool.get().await?.execute("DELETE FROM deletion_tokens WHERE project = $1", &[&project_name]).await?;
+            Ok(())
+        })
+    }
+
+    fn rename_project(&self, project_name: &str, new_name: &str) -> Result<(), StoreError> {
+        self.block_on(async {
+            self.pool.get().await?.execute(
+                "UPDATE deletion_tokens SET project = $2 WHERE project = $1",
+                &[&project_name, &new_name]).await?;
+            Ok(())
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs against the database in `VISMATCH_TEST_POSTGRES_URL`, skipped
+    /// when it is not set.
+    #[test]
+    fn test_postgres_store() {
+        let Ok(url) = std::env::var("VISMATCH_TEST_POSTGRES_URL") else {
+            return;
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime_guard = runtime.enter();
+        let store = PostgresStore::connect(&url, 2).unwrap();
+        let project = format!("test-{}", uuid::Uuid::new_v4());
+
+        let bits = vec![true, false, true, true, false, false, true, false, true];
+        store.put(&project, &[StoredHash {
+            image_name: "a.png".to_owned(),
+            hash_type: HashType::PHASH,
+            hash: Hash { bits: bits.clone() },
+            modified_ns: 42,
+            fingerprint: Some(u64::MAX),
+        }]).unwrap();
+
+        let a = HashStore::get(&store, &project, "a.png", HashType::PHASH).unwrap().unwrap();
+        assert_eq!(a.hash.bits, bits);
+        assert_eq!(a.fingerprint, Some(u64::MAX));
+        assert_eq!(store.load_project(&project, HashType::DHASH).unwrap().len(), 0);
+
+        let token = store.issue(&project, "a.png").unwrap();
+        assert_eq!(TokenStore::get(&store, &token).unwrap().unwrap().image_name, "a.png");
+        store.revoke_image(&project, "a.png").unwrap();
+        assert!(TokenStore::get(&store, &token).unwrap().is_none());
+
+        HashStore::remove_project(&store, &project).unwrap();
+        assert!(store.load_project(&project, HashType::PHASH).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,218 @@
+//! Hot cache of project hashes in Redis.
+//!
+//! Each project and hash type is one Redis hash, image names map to the
+//! packed hash, so a project loads with a single `HGETALL`. Unlike the
+//! database stores, Redis may evict or lose keys, sidecar cache files
+//! stay the durable copy and are still written.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use redis::{Commands, Connection, RedisResult};
+
+use crate::hash_store::{HashStore, StoreError, StoredHash};
+use crate::image_hash::{Hash, HashType};
+
+/// How long to wait for Redis to connect or answer.
+const REDIS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Hashes in Redis, over one connection that is reopened after it broke.
+pub struct RedisHashCache {
+    client: redis::Client,
+    conn: Mutex<Option<Connection>>,
+    key_prefix: String,
+}
+
+/// Value of an image field: modification time (i64, little endian), bit
+/// length (u32, little endian), bits packed by `Hash::to_bytes`, then the
+/// hasher fingerprint (u64, little endian) if known.
+fn encode(h: &StoredHash) -> Result<Vec<u8>, StoreError> {
+    let bit_length = u32::try_from(h.hash.bits.len()).map_err(|_| "hash too long to be cached")?;
+
+    let mut value = Vec::with_capacity(20 + h.hash.bits.len() / 8 + 1);
+    value.extend_from_slice(&h.modified_ns.to_le_bytes());
+    value.extend_from_slice(&bit_length.to_le_bytes());
+    value.extend_from_slice(&h.hash.to_bytes());
+    if let Some(fingerprint) = h.fingerprint {
+        value.extend_from_slice(&fingerprint.to_le_bytes());
+    }
+    Ok(value)
+}
+
+/// Decode a field written by `encode`, `None` when it is malformed.
+/// 
+/// Fields cached before fingerprints end with the packed bits.
+fn decode(image_name: String, hash_type: HashType, value: &[u8]) -> Option<StoredHash> {
+    let (modified_ns, value) = value.split_first_chunk::<8>()?;
+    let (bit_length, value) = value.split_first_chunk::<4>()?;
+    let bit_length = u32::from_le_bytes(*bit_length) as usize;
+
+    let packed_len = bit_length.div_ceil(8);
+    if value.len() < packed_len {
+        return None;
+    }
+    let (packed, fingerprint) = value.split_at(packed_len);
+
+    let fingerprint = match fingerprint {
+        [] => None,
+        fingerprint => Some(u64::from_le_bytes(fingerprint.try_into().ok()?)),
+    };
+
+    Some(StoredHash {
+        image_name,
+        hash_type,
+        hash: Hash::from_bytes(packed, bit_length),
+        modified_ns: i64::from_le_bytes(*modified_ns),
+        fingerprint,
+    })
+}
+
+impl RedisHashCache {
+    /// Connect to `url`, keys are named `<key_prefix>:<project>:<hash type>`.
+    pub fn connect(url: &str, key_prefix: &str) -> Result<Self, StoreError> {
+        let client = redis::Client::open(url)?;
+        let cache = RedisHashCache {
+            client,
+            conn: Mutex::new(None),
+            key_prefix: key_prefix.to_owned(),
+        };
+
+        // fail at startup rather than on the first request.
+        cache.with_conn(|conn| redis::cmd("PING").query::<String>(conn))
+            .map_err(|e| format!("cannot connect to hash cache: {}", e))?;
+        Ok(cache)
+    }
+
+    fn key(&self, project_name: &str, hash_type: HashType) -> String {
+        format!("{}:{}:{}", self.key_prefix, project_name, hash_type)
+    }
+
+    fn open_conn(&self) -> RedisResult<Connection> {
+        let conn = self.client.get_connection_with_timeout(REDIS_TIMEOUT)?;
+        conn.set_read_timeout(Some(REDIS_TIMEOUT))?;
+        conn.set_write_timeout(Some(REDIS_TIMEOUT))?;
+        Ok(conn)
+    }
+
+    /// Run `call` on the connection, a broken connection is dropped so
+    /// the next call opens a new one.
+    fn with_conn<T>(&self, call: impl FnOnce(&mut Connection) -> RedisResult<T>) -> Result<T, StoreError> {
+        let mut conn: MutexGuard<'_, Option<Connection>> = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+
+        let active = match &mut *conn {
+            Some(active) => active,
+            None => conn.insert(self.open_conn()?),
+        };
+
+        let result = call(active);
+        if let Err(e) = &result && (e.is_io_error() || e.is_connection_dropped() || e.is_timeout()) {
+            *conn = None;
+        }
+        Ok(result?)
+    }
+}
+
+impl HashStore for RedisHashCache {
+    fn load_project(&self, project_name: &str, hash_type: HashType) -> Result<Vec<StoredHash>, StoreError> {
+        let fields: HashMap<String, Vec<u8>> = self.with_conn(|conn| conn.hgetall(self.key(project_name, hash_type)))?;
+
+        // a malformed field is a miss, the image is hashed and stored again.
+        Ok(fields.into_iter()
+            .filter_map(|(image_name, value)| decode(image_name, hash_type, &value))
+            .collect())
+    }
+
+    fn get(&self, project_name: &str, image_name: &str, hash_type: HashType) -> Result<Option<StoredHash>, StoreError> {
+        let value: Option<Vec<u8>> = self.with_conn(|conn| conn.hget(self.key(project_name, hash_type), image_name))?;
+        Ok(value.and_then(|value| decode(image_name.to_owned(), hash_type, &value)))
+    }
+
+    fn put(&self, project_name: &str, hashes: &[StoredHash]) -> Result<(), StoreError> {
+        let mut by_key: HashMap<String, Vec<(&str, Vec<u8>)>> = HashMap::new();
+        for h in hashes {
+            by_key.entry(self.key(project_name, h.hash_type))
+                .or_default()
+                .push((h.image_name.as_str(), encode(h)?));
+        }
+
+        if by_key.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        for (key, fields) in &by_key {
+            pipe.hset_multiple(key, fields).ignore();
+        }
+        self.with_conn(|conn| pipe.query::<()>(conn))
+    }
+
+    fn remove_images(&self, project_name: &str, image_names: &[String]) -> Result<(), StoreError> {
+        if image_names.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        for hash_type in HashType::ALL {
+            pipe.hdel(self.key(project_name, *hash_type), image_names).ignore();
+        }
+        self.with_conn(|conn| pipe.query::<()>(conn))
+    }
+
+    fn remove_project(&self, project_name: &str) -> Result<(), StoreError> {
+        let keys: Vec<String> = HashType::ALL.iter().map(|t| self.key(project_name, *t)).collect();
+        self.with_conn(|conn| conn.del::<_, ()>(keys))
+    }
+
+    fn rename_project(&self, project_name: &str, new_name: &str) -> Result<(), StoreError> {
+        self.with_conn(|conn| {
+            for hash_type in HashType::ALL {
+                let (key, new_key) = (self.key(project_name, *hash_type), self.key(new_name, *hash_type));
+
+                // `RENAME` fails on a missing key, and leftovers of an
+                // earlier project with the new name must go either way.
+                conn.del::<_, ()>(&new_key)?;
+                if conn.exists(&key)? {
+                    conn.rename::<_, _, ()>(&key, &new_key)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn replaces_sidecar_caches(&self) -> bool {
+        false
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let h = StoredHash {
+            image_name: "a.png".to_owned(),
+            hash_type: HashType::DHASH,
+            hash: Hash { bits: vec![true, false, true, true, false, false, true, false, true] },
+            modified_ns: -42,
+            fingerprint: Some(7),
+        };
+
+        let value = encode(&h).unwrap();
+        let decoded = decode("a.png".to_owned(), HashType::DHASH, &value).unwrap();
+        assert_eq!(decoded.hash.bits, h.hash.bits);
+        assert_eq!(decoded.modified_ns, -42);
+        assert_eq!(decoded.fingerprint, Some(7));
+
+        // a field cached before fingerprints.
+        let legacy = decode("a.png".to_owned(), HashType::DHASH, &value[..14]).unwrap();
+        assert_eq!(legacy.hash.bits, h.hash.bits);
+        assert_eq!(legacy.fingerprint, None);
+
+        assert!(decode("a.png".to_owned(), HashType::DHASH, &value[..13]).is_none());
+        assert!(decode("a.png".to_owned(), HashType::DHASH, &value[..18]).is_none());
+        assert!(decode("a.png".to_owned(), HashType::DHASH, b"short").is_none());
+    }
+}
@@ -0,0 +1,251 @@
+//! Hash store in a SQLite database file.
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+
+use crate::hash_store::{HashStore, StoreError, StoredHash};
+use crate::image_hash::{Hash, HashType};
+
+/// How long a write waits for another process holding the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS image_hashes (
+        project     TEXT    NOT NULL,
+        image_name  TEXT    NOT NULL,
+        hash_type   TEXT    NOT NULL,
+        bit_length  INTEGER NOT NULL,
+        hash        BLOB    NOT NULL,
+        modified_ns INTEGER NOT NULL,
+        fingerprint INTEGER,
+        PRIMARY KEY (project, image_name, hash_type)
+    );";
+
+/// Tables created before hasher fingerprints lack their column, their
+/// hashes are taken as current.
+const ADD_FINGERPRINT: &str = "ALTER TABLE image_hashes ADD COLUMN fingerprint INTEGER";
+
+/// Hashes in one SQLite file, bits packed by `Hash::to_bytes`.
+pub struct SqliteHashStore {
+    // a connection is not `Sync`, calls take turns.
+    conn: Mutex<Connection>,
+}
+
+impl SqliteHashStore {
+    /// Open the database at `path`, creating it and its table if missing.
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let mut conn = Connection::open(path)
+            .map_err(|e| format!("cannot open hash store <{}>: {}", path.display(), e))?;
+
+        // readers don't wait for a writer, and commits don't sync every time.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+
+        // another process may open the same file, check and alter at once.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute_batch(SCHEMA)?;
+        let has_fingerprint = tx.prepare("SELECT 1 FROM pragma_table_info('image_hashes') WHERE name = 'fingerprint'")?
+            .exists([])?;
+        if !has_fingerprint {
+            tx.execute_batch(ADD_FINGERPRINT)?;
+        }
+        tx.commit()?;
+
+        Ok(SqliteHashStore { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Decode a row of `image_name, hash_type, bit_length, hash, modified_ns,
+/// fingerprint`.
+fn stored_hash(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredHash> {
+    let hash_type: String = row.get(1)?;
+    let hash_type = hash_type.parse::<HashType>()
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, e.into()))?;
+    let bit_length: usize = row.get(2)?;
+    let hash: Vec<u8> = row.get(3)?;
+
+    Ok(StoredHash {
+        image_name: row.get(0)?,
+        hash_type,
+        hash: Hash::from_bytes(&hash, bit_length),
+        modified_ns: row.get(4)?,
+        // SQLite integers are signed, the bits are kept as is.
+        fingerprint: row.get::<_, Option<i64>>(5)?.map(|fp| fp as u64),
+    })
+}
+
+impl HashStore for SqliteHashStore {
+    fn load_project(&self, project_name: &str, hash_type: HashType) -> Result<Vec<StoredHash>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(
+            "SELECT image_name, hash_type, bit_length, hash, modified_ns, fingerprint FROM image_hashes
+             WHERE project = ?1 AND hash_type = ?2")?;
+
+        let hashes = stmt.query_map(params![project_name, hash_type.to_string()], stored_hash)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(hashes)
+    }
+
+    fn get(&self, project_name: &str, image_name: &str, hash_type: HashType) -> Result<Option<StoredHash>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(
+            "SELECT image_name, hash_type, bit_length, hash, modified_ns, fingerprint FROM image_hashes
+             WHERE project = ?1 AND image_name = ?2 AND hash_type = ?3")?;
+
+        let hash = stmt.query_row(params![project_name, image_name, hash_type.to_string()], stored_hash)
+            .optional()?;
+        Ok(hash)
+    }
+
+    fn put(&self, project_name: &str, hashes: &[StoredHash]) -> Result<(), StoreError> {
+        if hashes.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT OR REPLACE INTO image_hashes
+                 (project, image_name, hash_type, bit_length, hash, modified_ns, fingerprint)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")?;
+
+            for h in hashes {
+                stmt.execute(params![
+                    project_name,
+                    h.image_name,
+                    h.hash_type.to_string(),
+                    h.hash.bits.len(),
+                    h.hash.to_bytes(),
+                    h.modified_ns,
+                    h.fingerprint.map(|fp| fp as i64),
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn remove_images(&self, project_name: &str, image_names: &[String]) -> Result<(), StoreError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "DELETE FROM image_hashes WHERE project = ?1 AND image_name = ?2")?;
+
+            for image_name in image_names {
+                stmt.execute(params![project_name, image_name])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn remove_project(&self, project_name: &str) -> Result<(), StoreError> {
+        self.conn().execute("DELETE FROM image_hashes WHERE project = ?1", params![project_name])?;
+        Ok(())
+    }
+
+    fn rename_project(&self, project_name: &str, new_name: &str) -> Result<(), StoreError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        // leftovers of an earlier project with the new name would collide.
+        tx.execute("DELETE FROM image_hashes WHERE project = ?1", params![new_name])?;
+        tx.execute("UPDATE image_hashes SET project = ?2 WHERE project = ?1", params![project_name, new_name])?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqlite_hash_store() {
+        let path = std::env::temp_dir().join(format!("vismatch-sqlite-{}.sqlite", std::process::id()));
+        let store = SqliteHashStore::open(&path).unwrap();
+
+        let mk = |name: &str, hash_type: HashType, bits: Vec<bool>| StoredHash {
+            image_name: name.to_owned(),
+            hash_type,
+            hash: Hash { bits },
+            modified_ns: 42,
+            fingerprint: Some(u64::MAX),
+        };
+        // an odd bit length survives packing.
+        let bits = vec![true, false, true, true, false, false, true, false, true];
+        store.put("cats", &[
+            mk("a.png", HashType::PHASH, bits.clone()),
+            mk("b.png", HashType::PHASH, vec![false; 64]),
+            mk("a.png", HashType::DHASH, vec![true; 64]),
+        ]).unwrap();
+
+        let a = store.get("cats", "a.png", HashType::PHASH).unwrap().unwrap();
+        assert_eq!(a.hash.bits, bits);
+        assert_eq!(a.modified_ns, 42);
+        assert_eq!(a.fingerprint, Some(u64::MAX));
+        assert!(store.get("dogs", "a.png", HashType::PHASH).unwrap().is_none());
+        assert_eq!(store.load_project("cats", HashType::PHASH).unwrap().len(), 2);
+
+        // a put replaces the hash of the same image and type.
+        store.put("cats", &[mk("b.png", HashType::PHASH, vec![true; 64])]).unwrap();
+        assert_eq!(store.get("cats", "b.png", HashType::PHASH).unwrap().unwrap().hash.bits, vec![true; 64]);
+
+        store.remove_images("cats", &["a.png".to_owned()]).unwrap();
+        assert!(store.get("cats", "a.png", HashType::DHASH).unwrap().is_none());
+
+        store.rename_project("cats", "dogs").unwrap();
+        assert!(store.load_project("cats", HashType::PHASH).unwrap().is_empty());
+        assert_eq!(store.load_project("dogs", HashType::PHASH).unwrap().len(), 1);
+
+        store.remove_project("dogs").unwrap();
+        assert!(store.load_project("dogs", HashType::PHASH).unwrap().is_empty());
+
+        // reopening keeps the data and the schema.
+        store.put("cats", &[mk("a.png", HashType::PHASH, bits)]).unwrap();
+        drop(store);
+        let store = SqliteHashStore::open(&path).unwrap();
+        assert_eq!(store.load_project("cats", HashType::PHASH).unwrap().len(), 1);
+
+        drop(store);
+        for ext in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), ext));
+        }
+    }
+
+    #[test]
+    fn test_sqlite_fingerprint_migration() {
+        let path = std::env::temp_dir().join(format!("vismatch-sqlite-migration-{}.sqlite", std::process::id()));
+
+        // a database of a version before hasher fingerprints.
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(&SCHEMA.replace("fingerprint INTEGER,", "")).unwrap();
+        conn.execute(
+            "INSERT INTO image_hashes (project, image_name, hash_type, bit_length, hash, modified_ns)
+             VALUES ('cats', 'a.png', 'phash', 8, x'ff', 42)", []).unwrap();
+        drop(conn);
+
+        let store = SqliteHashStore::open(&path).unwrap();
+        let a = store.get("cats", "a.png", HashType::PHASH).unwrap().unwrap();
+        assert_eq!(a.fingerprint, None);
+        assert_eq!(a.hash.bits, vec![true; 8]);
+        drop(store);
+
+        // opening again does not add the column twice.
+        SqliteHashStore::open(&path).unwrap();
+
+        for ext in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), ext));
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Replay protection for retried requests.
+//! 
+//! Clients may send an `Idempotency-Key` header, the first response
+//! for a key is remembered and returned again for retries with the
+//! same key, instead of processing the request twice. A retry arriving
+//! while the first request is still processed is told so.
+//!
+//! Keys are scoped to the client and project, and remember a fingerprint
+//! of the request, a key reused for another request is refused instead
+//! of replaying a response meant for someone else.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use tokio::sync::Mutex;
+
+/// Header carrying the client chosen key, usually a UUID.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Default time a stored response stays valid, see
+/// `Config::idempotency_ttl_secs`.
+pub const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Number of remembered keys, least recently used keys are dropped
+/// first.
+const DEFAULT_IDEMPOTENCY_CAPACITY: NonZeroUsize = NonZeroUsize::new(10_000).unwrap();
+
+/// Idempotency key of a request, along with the client and project it
+/// is only valid for.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ScopedKey {
+    /// API key or address of the client, as in `RateLimiter::client_of`.
+    pub client: String,
+    pub project_name: String,
+    pub key: String,
+}
+
+/// Bounded store of responses keyed by scoped idempotency key.
+pub struct IdempotencyCache<V> {
+    entries: LruCache<ScopedKey, Entry<V>>,
+    ttl: Duration,
+}
+
+struct Entry<V> {
+    stored_at: Instant,
+    /// `request_fingerprint` of the request that used the key first.
+    fingerprint: u64,
+    slot: Slot<V>,
+}
+
+enum Slot<V> {
+    /// Reserved by a request still being processed.
+    InFlight,
+    Done(V),
+}
+
+/// Outcome of `IdempotencyCache::claim`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Claim<V> {
+    /// The key is reserved for the caller, which stores its response with
+    /// `insert` or frees the key with `release`.
+    Reserved,
+    /// Stored response of an earlier request with the key.
+    Replay(V),
+    /// An earlier request with the key is still being processed.
+    InFlight,
+    /// The key was used for a different request.
+    Mismatch,
+}
+
+/// Fingerprint of a request, over the parts that must be equal for a
+/// retry, e.g. the image name and body of an upload.
+pub fn request_fingerprint(parts: &[&[u8]]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    parts.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl<V: Clone> IdempotencyCache<V> {
+    pub fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        IdempotencyCache { entries: LruCache::new(capacity), ttl }
+    }
+
+    /// Cache of the default capacity, responses are replayed for `ttl`.
+    pub fn with_ttl(ttl: Duration) -> Self {
+        IdempotencyCache::new(DEFAULT_IDEMPOTENCY_CAPACITY, ttl)
+    }
+
+    /// Reserve `key` for the request with `fingerprint` unless a request
+    /// already used it, in one call so two requests with the same key
+    /// never both get `Reserved`. Expired entries are replaced.
+    pub fn claim(&mut self, key: &ScopedKey, fingerprint: u64) -> Claim<V> {
+        match self.entries.get(key) {
+            Some(entry) if entry.stored_at.elapsed() < self.ttl => match &entry.slot {
+                _ if entry.fingerprint != fingerprint => Claim::Mismatch,
+                Slot::Done(value) => Claim::Replay(value.clone()),
+                Slot::InFlight => Claim::InFlight,
+            },
+            _ => {
+                self.entries.put(key.clone(), Entry { stored_at: Instant::now(), fingerprint, slot: Slot::InFlight });
+                Claim::Reserved
+            },
+        }
+    }
+
+    /// Store the response of `key`, replayed to later requests with the
+    /// same `fingerprint`.
+    pub fn insert(&mut self, key: ScopedKey, fingerprint: u64, value: V) {
+        self.entries.put(key, Entry { stored_at: Instant::now(), fingerprint, slot: Slot::Done(value) });
+    }
+
+    /// Free a key reserved by a request that failed, a stored response
+    /// is kept.
+    pub fn release(&mut self, key: &ScopedKey) {
+        if let Some(Entry { slot: Slot::InFlight, .. }) = self.entries.peek(key) {
+            self.entries.pop(key);
+        }
+    }
+}
+
+/// A key reserved by `IdempotencyCache::claim`, released when dropped
+/// so a failed or cancelled request lets retries through.
+pub struct Reservation<V: Clone + Send + 'static> {
+    cache: Arc<Mutex<IdempotencyCache<V>>>,
+    key: Option<ScopedKey>,
+}
+
+impl<V: Clone + Send + 'static> Reservation<V> {
+    pub fn new(cache: Arc<Mutex<IdempotencyCache<V>>>, key: ScopedKey) -> Self {
+        let key = Some(key);
+        Reservation { cache, key }
+    }
+}
+
+impl<V: Clone + Send + 'static> Drop for Reservation<V> {
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+
+        // the lock is async, release from a task unless it is free now.
+        if let Ok(mut cache) = self.cache.try_lock() {
+            cache.release(&key);
+            return;
+        }
+        let cache = Arc::clone(&self.cache);
+        tokio::spawn(async move { cache.lock().await.release(&key) });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scoped(client: &str, project_name: &str, key: &str) -> ScopedKey {
+        ScopedKey { client: client.to_owned(), project_name: project_name.to_owned(), key: key.to_owned() }
+    }
+
+    #[test]
+    fn test_idempotency_cache() {
+        let mut cache: IdempotencyCache<u32> =
+            IdempotencyCache::new(NonZeroUsize::new(2).unwrap(), Duration::from_secs(60));
+        let (a, b, c) = (scoped("k", "p", "a"), scoped("k", "p", "b"), scoped("k", "p", "c"));
+
+        // a key is reserved once, until its response is stored.
+        assert_eq!(cache.claim(&a, 7), Claim::Reserved);
+        assert_eq!(cache.claim(&a, 7), Claim::InFlight);
+        cache.insert(a.clone(), 7, 1);
+        cache.release(&a);
+        assert_eq!(cache.claim(&a, 7), Claim::Replay(1));
+
+        // "a" is the least recently used key once "b" is claimed.
+        assert_eq!(cache.claim(&b, 7), Claim::Reserved);
+        cache.release(&b);
+        assert_eq!(cache.claim(&b, 7), Claim::Reserved);
+        cache.insert(b.clone(), 7, 2);
+        cache.insert(c.clone(), 7, 3);
+        assert_eq!(cache.claim(&a, 7), Claim::Reserved);
+        assert_eq!(cache.claim(&c, 7), Claim::Replay(3));
+
+        let mut expired: IdempotencyCache<u32> =
+            IdempotencyCache::new(NonZeroUsize::new(2).unwrap(), Duration::ZERO);
+        expired.insert(a.clone(), 7, 1);
+        assert_eq!(expired.claim(&a, 7), Claim::Reserved);
+    }
+
+    #[test]
+    fn test_scoped_keys() {
+        let mut cache: IdempotencyCache<u32> =
+            IdempotencyCache::new(NonZeroUsize::new(8).unwrap(), Duration::from_secs(60));
+        let fingerprint = request_fingerprint(&[b"p", b"a.png", b"body"]);
+        cache.insert(scoped("k", "p", "a"), fingerprint, 1);
+
+        // another client or project has its own keys.
+        assert_eq!(cache.claim(&scoped("other", "p", "a"), fingerprint), Claim::Reserved);
+        assert_eq!(cache.claim(&scoped("k", "q", "a"), fingerprint), Claim::Reserved);
+
+        // the same key with another request is refused, while done or not.
+        let other_body = request_fingerprint(&[b"p", b"a.png", b"other body"]);
+        assert_eq!(cache.claim(&scoped("k", "p", "a"), other_body), Claim::Mismatch);
+        assert_eq!(cache.claim(&scoped("other", "p", "a"), other_body), Claim::Mismatch);
+        assert_eq!(cache.claim(&scoped("k", "p", "a"), fingerprint), Claim::Replay(1));
+
+        // parts are delimited, moving bytes between them changes the fingerprint.
+        assert_ne!(request_fingerprint(&[b"ab", b"c"]), request_fingerprint(&[b"a", b"bc"]));
+    }
+
+    #[tokio::test]
+    async fn test_reservation_drop() {
+        let cache = Arc::new(Mutex::new(
+            IdempotencyCache::<u32>::new(NonZeroUsize::new(2).unwrap(), Duration::from_secs(60))));
+        let a = scoped("k", "p", "a");
+
+        assert_eq!(cache.lock().await.claim(&a, 7), Claim::Reserved);
+        drop(Reservation::new(Arc::clone(&cache), a.clone()));
+        assert_eq!(cache.lock().await.claim(&a, 7), Claim::Reserved);
+
+        // a stored response outlives the reservation.
+        let reservation = Reservation::new(Arc::clone(&cache), a.clone());
+        cache.lock().await.insert(a.clone(), 7, 1);
+        drop(reservation);
+        assert_eq!(cache.lock().await.claim(&a, 7), Claim::Replay(1));
+    }
+}
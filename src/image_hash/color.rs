@@ -0,0 +1,141 @@
+//! Color moment hash.
+//!
+//! Luminance hashes cannot tell a recolored image from the original.
+//! This hash describes the color distribution instead: the mean,
+//! standard deviation and skewness of brightness and of both chroma
+//! axes (saturation projected on the hue angle).
+//!
+//! Each moment is quantized and written as a thermometer code, the
+//! first `level` bits of its segment set. The hamming distance of two
+//! hashes is then the L1 distance of their quantized moments, the
+//! metric of `ColorMoments`, so popcount pre-filtering and hamming
+//! based indexes keep working for this hash.
+
+use image::DynamicImage;
+use image::imageops::FilterType;
+
+use crate::image_hash::traits::Hasher;
+use crate::metric::Metrizable;
+
+/// Side of the square the image is resized to before measuring.
+pub const SAMPLE_SIDE: u32 = 64;
+
+/// Channels measured: brightness, then both chroma axes.
+const CHANNELS: usize = 3;
+
+/// Moments per channel: mean, standard deviation, skewness.
+const MOMENTS: usize = 3;
+
+/// Number of moments of a color hash.
+pub const MOMENT_COUNT: usize = CHANNELS * MOMENTS;
+
+/// Color moments of an image, each normalized into `[0, 1]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorMoments {
+    /// Per channel: mean, standard deviation, skewness.
+    pub moments: [f64; MOMENT_COUNT],
+}
+
+impl ColorMoments {
+    /// Measure the moments of an image, resized with `resize_filter`.
+    pub fn of_image(image: &DynamicImage, resize_filter: FilterType) -> Self {
+        let pixels = image.to_rgb8();
+        let pixels = image::imageops::resize(&pixels, SAMPLE_SIDE, SAMPLE_SIDE, resize_filter);
+
+        // every channel in `[0, 1]`, chroma axes are centered on 0.5.
+        let channels: Vec<[f64; CHANNELS]> = pixels.pixels()
+            .map(|p| {
+                let [r, g, b] = p.0.map(|c| c as f64 / 255.0);
+                let value = r.max(g).max(b);
+                let chroma = value - r.min(g).min(b);
+                let saturation = if value > 0.0 { chroma / value } else { 0.0 };
+                let (sin, cos) = hue_radians(r, g, b, value, chroma).sin_cos();
+                [value, 0.5 + saturation * cos / 2.0, 0.5 + saturation * sin / 2.0]
+            })
+            .collect();
+
+        let n = channels.len().max(1) as f64;
+        let mut moments = [0.0; MOMENT_COUNT];
+        for c in 0..CHANNELS {
+            let mean = channels.iter().map(|p| p[c]).sum::<f64>() / n;
+            let variance = channels.iter().map(|p| (p[c] - mean).powi(2)).sum::<f64>() / n;
+            let third = channels.iter().map(|p| (p[c] - mean).powi(3)).sum::<f64>() / n;
+
+            // values in `[0, 1]` keep the deviation within 0.5, and the
+            // cube root of the third moment about as far from 0.
+            moments[c * MOMENTS] = mean;
+            moments[c * MOMENTS + 1] = (variance.sqrt() * 2.0).clamp(0.0, 1.0);
+            moments[c * MOMENTS + 2] = (0.5 + third.cbrt()).clamp(0.0, 1.0);
+        }
+
+        ColorMoments { moments }
+    }
+
+    /// Quantize every moment into `0..=levels`.
+    pub fn quantize(&self, levels: usize) -> [usize; MOMENT_COUNT] {
+        self.moments.map(|m| (m * levels as f64).round() as usize)
+    }
+
+    /// Thermometer code of the moments, `levels` bits per moment.
+    pub fn to_hash(&self, levels: usize) -> imagehash::Hash {
+        let bits = self.quantize(levels).iter()
+            .flat_map(|level| (0..levels).map(move |i| i < *level))
+            .collect();
+
+        imagehash::Hash { bits }
+    }
+
+    /// Read back moments from a `to_hash` code, at its quantization.
+    pub fn from_hash(hash: &imagehash::Hash) -> Self {
+        let levels = hash.bits.len() / MOMENT_COUNT;
+        let mut moments = [0.0; MOMENT_COUNT];
+
+        if levels > 0 {
+            for (m, segment) in moments.iter_mut().zip(hash.bits.chunks(levels)) {
+                *m = segment.iter().filter(|b| **b).count() as f64 / levels as f64;
+            }
+        }
+
+        ColorMoments { moments }
+    }
+}
+
+/// Hue angle of an RGB color, 0 for grays.
+fn hue_radians(r: f64, g: f64, b: f64, value: f64, chroma: f64) -> f64 {
+    if chroma == 0.0 {
+        return 0.0;
+    }
+
+    let sextant = if value == r {
+        ((g - b) / chroma).rem_euclid(6.0)
+    } else if value == g {
+        (b - r) / chroma + 2.0
+    } else {
+        (r - g) / chroma + 4.0
+    };
+    sextant * std::f64::consts::FRAC_PI_3
+}
+
+impl Metrizable for ColorMoments {
+    /// L1 distance of the moments. For moments read back by `from_hash`,
+    /// `levels` times this is the hamming distance of both hashes.
+    fn dist(&self, other: &Self) -> f64 {
+        self.moments.iter()
+            .zip(other.moments.iter())
+            .map(|(a, b)| (a - b).abs())
+            .sum()
+    }
+}
+
+/// Color moment hasher.
+pub struct ColorMomentHasher {
+    /// Quantization levels per moment, the hash has `MOMENT_COUNT * levels` bits.
+    pub levels: usize,
+    pub resize_filter: FilterType,
+}
+
+impl Hasher for ColorMomentHasher {
+    fn hash(&self, image: &DynamicImage) -> imagehash::Hash {
+        ColorMoments::of_image(image, self.resize_filter).to_hash(self.levels.max(1))
+    }
+}
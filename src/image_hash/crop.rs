@@ -0,0 +1,94 @@
+//! Crop-resistant hash.
+//!
+//! A cropped image hashes nothing like the original with a whole-image
+//! hash. This hash is made of segment hashes instead: the whole image,
+//! and a 3 x 3 grid of overlapping windows of 2/3 of its side. A crop of
+//! a stored image looks like one of its windows, and the other way around.
+//!
+//! Two hashes are compared on their best matching subset of segments,
+//! a single pair of segments: the distance is the smallest hamming
+//! distance between any segment of one and any segment of the other.
+
+use image::DynamicImage;
+
+use crate::image_hash::ResizeFilter;
+use crate::image_hash::traits::Hasher;
+use crate::metric::Metrizable;
+
+/// Window offsets along each axis, in sixths of the image side.
+const WINDOW_OFFSETS: [u32; 3] = [0, 1, 2];
+
+/// Window side, in sixths of the image side.
+const WINDOW_SIDE: u32 = 4;
+
+/// Segments per hash, the whole image and every window.
+pub const SEGMENT_COUNT: usize = 1 + WINDOW_OFFSETS.len() * WINDOW_OFFSETS.len();
+
+/// Crop-resistant hasher, a difference hash per segment.
+pub struct CropResistantHasher {
+    /// Side the segments are resized to, each segment hash has
+    /// `(segment_side - 1) * segment_side` bits.
+    pub segment_side: u32,
+    pub resize_filter: ResizeFilter,
+}
+
+impl CropResistantHasher {
+    /// Bits of each segment hash.
+    pub fn segment_bits(segment_side: u32) -> usize {
+        (segment_side.saturating_sub(1) * segment_side) as usize
+    }
+
+    /// The whole image, then every window, row by row.
+    fn segments(image: &DynamicImage) -> Vec<DynamicImage> {
+        let (w, h) = (image.width(), image.height());
+        let windows = WINDOW_OFFSETS.iter()
+            .flat_map(|y| WINDOW_OFFSETS.iter().map(move |x| (*x, *y)))
+            .map(|(x, y)| image.crop_imm(w * x / 6, h * y / 6, (w * WINDOW_SIDE / 6).max(1), (h * WINDOW_SIDE / 6).max(1)));
+
+        std::iter::once(image.clone())
+            .chain(windows)
+            .collect()
+    }
+}
+
+impl Hasher for CropResistantHasher {
+    fn hash(&self, image: &DynamicImage) -> imagehash::Hash {
+        let side = self.segment_side.max(2) as usize;
+        let hasher = imagehash::DifferenceHash::new()
+            .with_image_size(side, side)
+            .with_hash_size(side, side)
+            .with_resizer(self.resize_filter.resizer());
+
+        let bits = CropResistantHasher::segments(image).iter()
+            .flat_map(|segment| hasher.hash(segment).bits)
+            .collect();
+
+        imagehash::Hash { bits }
+    }
+}
+
+/// Best-subset distance of two crop-resistant hashes, see the module
+/// documentation.
+///
+/// Scaled by the segment count, so it normalizes by the full bit length
+/// like a hamming distance does.
+pub fn best_subset_dist(lhs: &[bool], rhs: &[bool]) -> f64 {
+    let segment_bits = lhs.len().min(rhs.len()) / SEGMENT_COUNT;
+    if segment_bits == 0 {
+        return 0.0;
+    }
+
+    let segments = |bits: &[bool]| -> Vec<imagehash::Hash> {
+        bits.chunks_exact(segment_bits)
+            .take(SEGMENT_COUNT)
+            .map(|segment| imagehash::Hash { bits: segment.to_vec() })
+            .collect()
+    };
+    let (lhs, rhs) = (segments(lhs), segments(rhs));
+
+    let best = lhs.iter()
+        .flat_map(|l| rhs.iter().map(move |r| l.dist(r)))
+        .fold(f64::INFINITY, f64::min);
+
+    best * SEGMENT_COUNT as f64
+}
@@ -1,23 +1,174 @@
 pub mod traits;
+pub mod radial;
+pub mod wavelet;
+pub mod color;
+pub mod crop;
 
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::error::Error;
 
 
 use serde;
+use itertools::Itertools;
 use image::{self, DynamicImage};
+use image::imageops::FilterType;
 use std::fs::File;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicBool};
 use crate::image_hash::traits::Hasher;
+use crate::image_hash::radial::RadialVarianceHasher;
+use crate::image_hash::wavelet::WaveletHasher;
+use crate::image_hash::color::{ColorMomentHasher, MOMENT_COUNT};
+use crate::image_hash::crop::{CropResistantHasher, SEGMENT_COUNT};
 use crate::metric::*;
 
 
-/// Enumerates all supported hash algorithm.
-#[derive(Debug, Clone, Copy)]
-pub enum HashType {
-    DHASH,
-    PHASH,
-    AHASH,
+/// Define a fieldless enum together with `ALL`, the list of its
+/// variants, so a new variant cannot be left out of it.
+macro_rules! listed_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $( $(#[$variant_meta:meta])* $variant:ident, )+
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $( $(#[$variant_meta])* $variant, )+
+        }
+
+        impl $name {
+            /// Every variant, in declaration order.
+            ///
+            /// Use this instead of hand-written lists when every variant
+            /// has to be handled (e.g. cleaning up all cache extensions).
+            pub const ALL: &'static [$name] = &[$($name::$variant),+];
+        }
+    };
+}
+
+listed_enum! {
+    /// Enumerates all supported hash algorithm.
+    #[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    #[serde(rename_all = "lowercase")]
+    pub enum HashType {
+        DHASH,
+        PHASH,
+        AHASH,
+        BLOCKHASH,
+        RVHASH,
+        WHASH,
+        COLORHASH,
+        CROPHASH,
+        MHASH,
+    }
+}
+
+impl HashType {
+    /// Whether hashes of this type are compared by the hamming distance
+    /// of their bits, which popcount pre-filtering and ANN indexes rely on.
+    pub fn is_hamming(self) -> bool {
+        self != HashType::CROPHASH
+    }
+}
+
+impl std::str::FromStr for HashType {
+    type Err = String;
+
+    /// Parse a hash type name, case-insensitive (e.g. `phash`, `PHASH`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HashType::ALL.iter()
+            .find(|t| cache_ext(**t).eq_ignore_ascii_case(s.trim()))
+            .copied()
+            .ok_or_else(|| format!("unknown hash type <{}>, valid values are: {}", 
+                s, 
+                HashType::ALL.iter().map(|t| cache_ext(*t)).join(", ")))
+    }
+}
+
+impl std::fmt::Display for HashType {
+    /// Lowercase name, same as the serialized form (e.g. `phash`).
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&cache_ext(*self))
+    }
+}
+
+listed_enum! {
+    /// User-facing hash resolution, independent of the hash algorithm.
+    /// 
+    /// Each size maps to the same width and height for both the resized
+    /// image and the hash, a larger size means a longer and finer hash.
+    #[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+    #[serde(rename_all = "lowercase")]
+    pub enum HashSize {
+        /// 16x16.
+        Small,
+        /// 32x32, the size used before sizes were configurable.
+        #[default]
+        Medium,
+        /// 64x64.
+        Large,
+    }
+}
+
+impl HashSize {
+    /// The `(width, height)` used for both image and hash dimensions.
+    pub fn dimensions(self) -> (u32, u32) {
+        match self {
+            HashSize::Small => (16, 16),
+            HashSize::Medium => (32, 32),
+            HashSize::Large => (64, 64),
+        }
+    }
+
+    /// Number of bits of a `hash_type` hash calculated with this size.
+    /// 
+    /// `DHASH` compares neighbouring pixels of a row and `PHASH` drops
+    /// the DC term of each row, so both have one column less than the image.
+    pub fn bit_length(self, hash_type: HashType) -> usize {
+        let (w, h) = self.dimensions();
+        match hash_type {
+            HashType::DHASH | HashType::PHASH => ((w - 1) * h) as usize,
+            HashType::AHASH | HashType::MHASH | HashType::BLOCKHASH | HashType::WHASH => (w * h) as usize,
+            // one bit per spectrum frequency, see `RadialVarianceHasher`.
+            HashType::RVHASH => (4 * w) as usize,
+            // one thermometer code of `w` bits per moment, see `ColorMoments`.
+            HashType::COLORHASH => MOMENT_COUNT * w as usize,
+            // one `DHASH` of half the size per segment, see `CropResistantHasher`.
+            HashType::CROPHASH => SEGMENT_COUNT * CropResistantHasher::segment_bits(w / 2),
+        }
+    }
+
+    /// Find the size a `hash_type` hash of `bit_length` bits was calculated with.
+    pub fn from_bit_length(hash_type: HashType, bit_length: usize) -> Option<HashSize> {
+        HashSize::ALL.iter()
+            .find(|s| s.bit_length(hash_type) == bit_length)
+            .copied()
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            HashSize::Small => "small",
+            HashSize::Medium => "medium",
+            HashSize::Large => "large",
+        }
+    }
+}
+
+impl std::str::FromStr for HashSize {
+    type Err = String;
+
+    /// Parse a hash size name, case-insensitive (e.g. `small`, `Large`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HashSize::ALL.iter()
+            .find(|size| size.name().eq_ignore_ascii_case(s.trim()))
+            .copied()
+            .ok_or_else(|| format!("unknown hash size <{}>, valid values are: {}", 
+                s, 
+                HashSize::ALL.iter().map(|size| size.name()).join(", ")))
+    }
 }
 
 fn cache_ext(hash_type: HashType) -> String {
@@ -25,44 +176,355 @@ fn cache_ext(hash_type: HashType) -> String {
         HashType::DHASH => "dhash".to_owned(),
         HashType::PHASH => "phash".to_owned(),
         HashType::AHASH => "ahash".to_owned(),
+        HashType::BLOCKHASH => "blockhash".to_owned(),
+        HashType::RVHASH => "rvhash".to_owned(),
+        HashType::WHASH => "whash".to_owned(),
+        HashType::COLORHASH => "colorhash".to_owned(),
+        HashType::CROPHASH => "crophash".to_owned(),
+        HashType::MHASH => "mhash".to_owned(),
+    }
+}
+
+/// Resize filter applied before hashing, see `image::imageops::FilterType`.
+#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    pub fn filter_type(self) -> FilterType {
+        match self {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+
+    /// Resizer of the `imagehash` hashers, which only take plain functions.
+    fn resizer(self) -> fn(&DynamicImage, usize, usize) -> DynamicImage {
+        match self {
+            ResizeFilter::Nearest => |img, w, h| img.resize_exact(w as u32, h as u32, FilterType::Nearest),
+            ResizeFilter::Triangle => |img, w, h| img.resize_exact(w as u32, h as u32, FilterType::Triangle),
+            ResizeFilter::CatmullRom => |img, w, h| img.resize_exact(w as u32, h as u32, FilterType::CatmullRom),
+            ResizeFilter::Gaussian => |img, w, h| img.resize_exact(w as u32, h as u32, FilterType::Gaussian),
+            ResizeFilter::Lanczos3 => |img, w, h| img.resize_exact(w as u32, h as u32, FilterType::Lanczos3),
+        }
     }
 }
 
-/// Make new hasher with default parameters.
+/// Parameters a hash is calculated with, hashes are only comparable
+/// when calculated with the same parameters.
 /// 
-/// TODO: make parameter adjustable
-pub fn mk_hasher(hash_type: HashType) -> Box<dyn Hasher> {
+/// Unset fields fall back to each hasher's own choice, `for_type` fills
+/// them in so equal parameters compare equal.
+#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HashParams {
+    /// Hash resolution.
+    pub hash_size: HashSize,
+    /// Side of the square image `DHASH` and `PHASH` hash, the hash side
+    /// when unset. A larger image keeps more detail for the `PHASH` DCT.
+    pub image_size: Option<u32>,
+    /// Resize filter of every hasher, the hasher's own when unset.
+    pub resize_filter: Option<ResizeFilter>,
+}
+
+impl HashParams {
+    /// The same parameters at another hash size.
+    pub fn with_size(self, hash_size: HashSize) -> Self {
+        HashParams { hash_size, ..self }
+    }
+
+    /// The parameters a `hash_type` hasher uses, unset fields filled in
+    /// and fields it ignores cleared.
+    pub fn for_type(self, hash_type: HashType) -> Self {
+        let image_size = match hash_type {
+            HashType::DHASH | HashType::PHASH => Some(self.image_size.unwrap_or(self.hash_size.dimensions().0)),
+            _ => None,
+        };
+        let resize_filter = self.resize_filter.unwrap_or(match hash_type {
+            HashType::BLOCKHASH | HashType::RVHASH | HashType::COLORHASH => ResizeFilter::Triangle,
+            _ => ResizeFilter::Lanczos3,
+        });
+
+        HashParams { hash_size: self.hash_size, image_size, resize_filter: Some(resize_filter) }
+    }
+
+    /// Check values that parse but are unusable.
+    /// 
+    /// Hashes are exchanged as hex, so every hash size must give `DHASH`
+    /// and `PHASH` hashes of whole hex digits with this `image_size`.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.image_size {
+            Some(side) if !(2..=MAX_IMAGE_SIZE).contains(&side) => 
+                Err(format!("`image_size` must be within 2 and {}", MAX_IMAGE_SIZE)),
+            Some(side) if HashSize::ALL.iter()
+                .any(|hash_size| !self.with_size(*hash_size).bit_length(HashType::PHASH).is_multiple_of(4)) =>
+                Err(format!("`image_size` {} makes hashes that are not whole hex digits", side)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Side of the image `hash_type` hashes, only `DHASH` and `PHASH`
+    /// hash images of another size than the hash.
+    fn image_side(self, hash_type: HashType) -> u32 {
+        self.for_type(hash_type).image_size.unwrap_or(self.hash_size.dimensions().0)
+    }
+
+    fn filter(self, hash_type: HashType) -> ResizeFilter {
+        self.for_type(hash_type).resize_filter.unwrap_or(ResizeFilter::Lanczos3)
+    }
+
+    /// Number of bits of a `hash_type` hash calculated with these parameters.
+    /// 
+    /// `DHASH` and `PHASH` take at most one bit less per row than the
+    /// image side, and at most one row per image row, see `HashSize::bit_length`.
+    pub fn bit_length(self, hash_type: HashType) -> usize {
+        let (w, h) = self.hash_size.dimensions();
+        match hash_type {
+            HashType::DHASH | HashType::PHASH => {
+                let side = self.image_side(hash_type);
+                (side.saturating_sub(1).min(w) * side.min(h)) as usize
+            },
+            _ => self.hash_size.bit_length(hash_type),
+        }
+    }
+
+    /// These parameters at the hash size a `hash_type` hash of
+    /// `bit_length` bits was calculated with, e.g. for a hash read from a
+    /// cache. A length no hash size makes keeps the hash size.
+    pub fn matching(self, hash_type: HashType, bit_length: usize) -> HashParams {
+        HashSize::ALL.iter()
+            .map(|hash_size| self.with_size(*hash_size).for_type(hash_type))
+            .find(|p| p.bit_length(hash_type) == bit_length)
+            .unwrap_or_else(|| self.for_type(hash_type))
+    }
+}
+
+/// Largest `HashParams::image_size`, hashing larger images is slow and
+/// does not help.
+const MAX_IMAGE_SIZE: u32 = 1024;
+
+impl From<HashSize> for HashParams {
+    /// Parameters of `hash_size`, with each hasher's own image size and filter.
+    fn from(hash_size: HashSize) -> Self {
+        HashParams { hash_size, ..HashParams::default() }
+    }
+}
+
+/// Bump when a hasher changes in a way `hasher_params` does not show,
+/// e.g. a fixed bug, so caches written before are recalculated.
+const HASHER_REVISION: u32 = 1;
+
+/// Haar levels of the `WHASH` hasher, the image is 4 times the hash side.
+const WAVELET_LEVELS: u32 = 2;
+
+/// Make new hasher with `params`.
+pub fn mk_hasher(hash_type: HashType, params: impl Into<HashParams>) -> Box<dyn Hasher> {
+    let params: HashParams = params.into();
+    let (w, h) = params.hash_size.dimensions();
+    let side = params.image_side(hash_type) as usize;
+    let filter = params.filter(hash_type);
+
     match hash_type {
         HashType::DHASH => {
             Box::new(imagehash::DifferenceHash::new()
-                .with_image_size(32, 32)
-                .with_hash_size(32, 32)
-                .with_resizer(|img, w, h| {
-                    // for resizer function, we choose a more smooth one.
-                    img.resize_exact(w as u32, h as u32, image::imageops::FilterType::Lanczos3)
-                }))
+                .with_image_size(side, side)
+                .with_hash_size(w as usize, h as usize)
+                .with_resizer(filter.resizer()))
         },
         HashType::PHASH => {
             Box::new(imagehash::PerceptualHash::new()
-                .with_image_size(32, 32)
-                .with_hash_size(32, 32)
-                .with_resizer(|img, w, h| {
-                    // for resizer function, we choose a more smooth one.
-                    img.resize_exact(w as u32, h as u32, image::imageops::FilterType::Lanczos3)
-                }))
+                .with_image_size(side, side)
+                .with_hash_size(w as usize, h as usize)
+                .with_resizer(filter.resizer()))
         },
         HashType::AHASH => {
             Box::new(imagehash::AverageHash::new()
-                .with_image_size(32, 32)
-                .with_hash_size(32, 32)
-                .with_resizer(|img, w, h| {
-                    // for resizer function, we choose a more smooth one.
-                    img.resize_exact(w as u32, h as u32, image::imageops::FilterType::Lanczos3)
-                }))
+                .with_image_size(w as usize, h as usize)
+                .with_hash_size(w as usize, h as usize)
+                .with_resizer(filter.resizer()))
+        },
+        HashType::BLOCKHASH => {
+            Box::new(BlockHasher { grid_bits: w, resize_filter: filter.filter_type() })
+        },
+        HashType::RVHASH => {
+            Box::new(radial_hasher(params))
+        },
+        HashType::WHASH => {
+            Box::new(WaveletHasher { hash_side: w, levels: WAVELET_LEVELS, resize_filter: filter.filter_type() })
+        },
+        HashType::COLORHASH => {
+            Box::new(ColorMomentHasher { levels: w as usize, resize_filter: filter.filter_type() })
+        },
+        HashType::CROPHASH => {
+            Box::new(CropResistantHasher { segment_side: w / 2, resize_filter: filter })
+        },
+        HashType::MHASH => {
+            Box::new(MedianHasher { hash_size: (w, h), resize_filter: filter.filter_type() })
         },
     }
 }
 
+fn radial_hasher(params: HashParams) -> RadialVarianceHasher {
+    let hash_bits = params.hash_size.bit_length(HashType::RVHASH);
+    RadialVarianceHasher {
+        num_angles: (2 * hash_bits + 2).max(180),
+        num_radii: 64,
+        hash_bits,
+        resize_filter: params.filter(HashType::RVHASH).filter_type(),
+    }
+}
+
+/// Describe the parameters `mk_hasher` makes a hasher with, a hash is
+/// only comparable to hashes of the same description.
+pub fn hasher_params(hash_type: HashType, params: impl Into<HashParams>) -> String {
+    let params: HashParams = params.into();
+    let (w, h) = params.hash_size.dimensions();
+    let side = params.image_side(hash_type);
+    let filter = params.filter(hash_type).filter_type();
+
+    let params = match hash_type {
+        HashType::DHASH | HashType::PHASH => {
+            format!("image={}x{} hash={}x{} filter={:?}", side, side, w, h, filter)
+        },
+        HashType::AHASH | HashType::MHASH => {
+            format!("image={}x{} hash={}x{} filter={:?}", w, h, w, h, filter)
+        },
+        HashType::BLOCKHASH => {
+            format!("grid={} block={} filter={:?}", w, BlockHasher::BLOCK_PIXELS, filter)
+        },
+        HashType::RVHASH => {
+            let hasher = radial_hasher(params);
+            format!("angles={} radii={} bits={} filter={:?}", hasher.num_angles, hasher.num_radii, hasher.hash_bits, filter)
+        },
+        HashType::WHASH => {
+            let side = w << WAVELET_LEVELS;
+            format!("image={}x{} hash={}x{} levels={} filter={:?}", side, side, w, h, WAVELET_LEVELS, filter)
+        },
+        HashType::COLORHASH => {
+            format!("sample={}x{} moments={} levels={} filter={:?}", 
+                color::SAMPLE_SIDE, color::SAMPLE_SIDE, MOMENT_COUNT, w, filter)
+        },
+        HashType::CROPHASH => {
+            format!("segments={} segment={}x{} filter={:?}", SEGMENT_COUNT, w / 2, h / 2, filter)
+        },
+    };
+    format!("{} rev={} {}", hash_type, HASHER_REVISION, params)
+}
+
+/// Fingerprint of the hasher parameters behind a `bit_length` bits
+/// `hash_type` hash calculated with `params`, stored in hash caches to
+/// detect parameter changes, e.g. another configured resize filter.
+/// 
+/// FNV-1a of `hasher_params`, which unlike `std::hash` stays the same
+/// across builds.
+pub fn hasher_fingerprint(hash_type: HashType, params: HashParams, bit_length: usize) -> u64 {
+    let params = params.for_type(hash_type);
+
+    // no hasher makes hashes of other lengths, they can only be kept.
+    match params.bit_length(hash_type) == bit_length {
+        true => fnv1a(&hasher_params(hash_type, params)),
+        false => fnv1a(&format!("{} bits={}", hash_type, bit_length)),
+    }
+}
+
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |fp: u64, b| (fp ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Blockhash, robust to JPEG compression artifacts.
+/// 
+/// The image is divided into a `grid_bits` x `grid_bits` grid of blocks,
+/// and each block's pixel sum is compared against the median of its
+/// horizontal band (a quarter of the grid), one bit per block.
+pub struct BlockHasher {
+    /// Blocks per side of the grid, the hash has `grid_bits ^ 2` bits.
+    pub grid_bits: u32,
+    pub resize_filter: FilterType,
+}
+
+impl BlockHasher {
+    /// Pixels per side of a block after resizing.
+    const BLOCK_PIXELS: u32 = 8;
+}
+
+impl Hasher for BlockHasher {
+    fn hash(&self, image: &DynamicImage) -> imagehash::Hash {
+        let grid = self.grid_bits.max(1);
+        let side = grid * BlockHasher::BLOCK_PIXELS;
+
+        // resize so every block has the same number of pixels.
+        let pixels = image.grayscale()
+            .resize_exact(side, side, self.resize_filter)
+            .to_luma8();
+
+        let mut blocks = vec![0u64; (grid * grid) as usize];
+        for (x, y, p) in pixels.enumerate_pixels() {
+            let block = (y / BlockHasher::BLOCK_PIXELS) * grid + x / BlockHasher::BLOCK_PIXELS;
+            blocks[block as usize] += p.0[0] as u64;
+        }
+
+        // a block equal to the median goes to the brighter side of a bright band.
+        let half_block_value = (BlockHasher::BLOCK_PIXELS.pow(2) * 255 / 2) as u64;
+        let band_size = blocks.len().div_ceil(4);
+
+        let bits = blocks.chunks(band_size)
+            .flat_map(|band| {
+                let mut sorted = band.to_vec();
+                sorted.sort_unstable();
+                let median = match sorted.len() % 2 {
+                    0 => (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2,
+                    _ => sorted[sorted.len() / 2],
+                };
+
+                band.iter()
+                    .map(move |v| *v > median || (*v == median && median > half_block_value))
+                    .collect::<Vec<bool>>()
+            })
+            .collect();
+
+        imagehash::Hash { bits }
+    }
+}
+
+/// Median hash, `AHASH` with the median instead of the mean as threshold.
+/// 
+/// A few very bright or dark pixels shift the mean but not the median, so
+/// the bits stay balanced, about half of them set.
+pub struct MedianHasher {
+    /// Width and height of the resized image, one bit per pixel.
+    pub hash_size: (u32, u32),
+    pub resize_filter: FilterType,
+}
+
+impl Hasher for MedianHasher {
+    fn hash(&self, image: &DynamicImage) -> imagehash::Hash {
+        let (w, h) = self.hash_size;
+        let pixels = image.grayscale()
+            .resize_exact(w.max(1), h.max(1), self.resize_filter)
+            .to_luma8();
+
+        let mut sorted: Vec<u8> = pixels.pixels().map(|p| p.0[0]).collect();
+        sorted.sort_unstable();
+        let median = match sorted.len() % 2 {
+            0 => (sorted[sorted.len() / 2 - 1] as f64 + sorted[sorted.len() / 2] as f64) / 2.0,
+            _ => sorted[sorted.len() / 2] as f64,
+        };
+
+        imagehash::Hash {
+            bits: pixels.pixels().map(|p| p.0[0] as f64 > median).collect(),
+        }
+    }
+}
+
 /// We make a proxy struct for `imagehash::Hash` because it is 
 /// so bad, it cannot serialize, cannot measure distance, and
 /// even cannot clone. 
@@ -74,6 +536,72 @@ pub struct Hash {
     pub bits: Vec<bool>,
 }
 
+impl Hash {
+    /// Parse hash bits from the representation of `to_hex`.
+    /// 
+    /// Every hex digit yields 4 bits, so the bit length is always
+    /// a multiple of 4.
+    pub fn from_hex(hex: &str) -> Result<Hash, String> {
+        let bits = hex.chars()
+            .map(|c| c.to_digit(16)
+                .ok_or_else(|| format!("invalid hex digit '{}' in hash", c)))
+            .map_ok(|v| (0..4).rev().map(move |i| (v >> i) & 1 == 1))
+            .flatten_ok()
+            .collect::<Result<Vec<bool>, String>>()?;
+
+        Ok(Hash { bits })
+    }
+
+    /// Similarity score in `[0, 1]` to another hash, higher is more alike.
+    /// 
+    /// `dist` stays the primitive for calculation, this maps it to
+    /// `1 - dist / bit_length` for display.
+    pub fn similarity(&self, other: &Hash) -> f64 {
+        dist_to_similarity(self.dist(other), self.bits.len())
+    }
+
+    /// Number of set bits (hamming weight) of the hash.
+    pub fn popcount(&self) -> usize {
+        self.bits.iter().filter(|b| **b).count()
+    }
+
+    /// Pack bits into bytes, 8 bits per byte, LSB first.
+    /// 
+    /// The last byte is zero-padded if the bit length is not a multiple of 8.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.bits.chunks(8)
+            .map(|byte_bits| {
+                byte_bits.iter()
+                    .enumerate()
+                    .fold(0u8, |acc, (i, b)| acc | ((*b as u8) << i))
+            })
+            .collect()
+    }
+
+    /// Unpack `bit_length` bits from bytes packed by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8], bit_length: usize) -> Hash {
+        let bits = (0..bit_length)
+            .map(|i| bytes.get(i / 8).is_some_and(|b| (b >> (i % 8)) & 1 == 1))
+            .collect();
+
+        Hash { bits }
+    }
+
+    /// Hex representation of hash bits, most significant bit first.
+    /// 
+    /// Trailing bits that don't fill a nibble are padded with zero.
+    pub fn to_hex(&self) -> String {
+        self.bits.chunks(4)
+            .map(|nibble| {
+                let v = nibble.iter()
+                    .enumerate()
+                    .fold(0u32, |acc, (i, b)| acc | ((*b as u32) << (3 - i)));
+                std::char::from_digit(v, 16).unwrap_or('0')
+            })
+            .collect()
+    }
+}
+
 impl From<imagehash::Hash> for Hash {
     fn from(value: imagehash::Hash) -> Self {
         Hash {
@@ -99,56 +627,190 @@ impl crate::metric::Metrizable for Hash {
     }
 }
 
-fn calc_hash(image: &DynamicImage, hash_type: HashType) -> Hash {
-    let hasher = mk_hasher(hash_type);
+/// Calculate the hash of an in-memory image.
+pub fn calc_hash(image: &DynamicImage, hash_type: HashType, params: impl Into<HashParams>) -> Hash {
+    let hasher = mk_hasher(hash_type, params);
     hasher.hash(image).into()
 }
 
-pub fn calc_image_hash(image_path: &Path, hash_type: HashType) 
+#[must_use = "the calculated hash entry should be used or stored"]
+#[tracing::instrument(level = "debug", skip_all, fields(image_path = %image_path.display(), hash_type = ?hash_type))]
+pub fn calc_image_hash(image_path: &Path, hash_type: HashType, params: HashParams) 
         -> Result<ImageHashEntry, Box<dyn Error>> {
 
     let img = image::open(image_path)?;
 
-    let h = calc_hash(&img, hash_type);
+    let params = params.for_type(hash_type);
+    let h = calc_hash(&img, hash_type, params);
+
+    Ok(ImageHashEntry::with_params(image_path.to_owned(), hash_type, h, params))
+}
+
+/// Calculate the hash entry of an in-memory image located at `image_path`,
+/// and try to write its hash cache.
+/// 
+/// Same as `calc_image_hash` followed by a cache write, without reading
+/// the image back from disk.
+#[must_use = "the calculated hash entry should be used or stored"]
+#[tracing::instrument(level = "debug", skip_all, fields(image_path = %image_path.display(), hash_type = ?hash_type, params = ?params))]
+pub fn calc_hash_from_image(image: &DynamicImage, image_path: &Path, hash_type: HashType, params: HashParams) 
+        -> Result<ImageHashEntry, Box<dyn Error>> {
+
+    let params = params.for_type(hash_type);
+    let h_entry = ImageHashEntry::with_params(image_path.to_owned(), hash_type, calc_hash(image, hash_type, params), params);
+
+    // IGNORE the cache error like `fetch_cache_or_calc_hash` does, the
+    // hash is still valid and gets recalculated on next load.
+    write_hash_cache(image_path, &h_entry).ok();
+
+    Ok(h_entry)
+}
 
-    Ok(ImageHashEntry { 
-        image_name: image_path.to_owned(), 
-        hash_type, 
-        hash: h })
+/// Check if a path looks like a hash cache file of any hash type.
+pub fn is_cache_file(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.to_string_lossy())
+        .is_some_and(|ext| HashType::ALL.iter().any(|t| cache_ext(*t) == ext))
 }
 
-/// Write hash value to cache file in the same folder
+/// Path of the `hash_type` cache file of an image, next to the image.
+pub fn cache_path(image_path: &Path, hash_type: HashType) -> PathBuf {
+    image_path.with_added_extension(cache_ext(hash_type))
+}
+
+/// Whether the `hash_type` cache file of an image is older than the
+/// image, e.g. after the image was replaced on disk. A missing cache or
+/// image is not stale.
+pub fn is_cache_stale(image_path: &Path, hash_type: HashType) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    match (modified(image_path), modified(&cache_path(image_path, hash_type))) {
+        (Some(image_modified), Some(cache_modified)) => cache_modified < image_modified,
+        _ => false,
+    }
+}
+
+/// Leading bytes of a packed hash cache file.
+/// 
+/// Legacy caches are a bincode-encoded `Vec<bool>`, which never starts with
+/// these bytes (the second byte of a legacy file is a bool, 0 or 1).
+const CACHE_MAGIC: &[u8; 3] = b"VMH";
+
+/// Version of the packed cache layout, stored right after the magic bytes.
+/// Version 1 caches have no hasher fingerprint.
+const CACHE_VERSION: u8 = 2;
+
+/// Whether hash cache files may be written, see `set_cache_writes`.
+static CACHE_WRITES_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turn hash cache writes on or off for the whole process, e.g. for an
+/// image root mounted read-only. Hashes are then kept in memory only and
+/// `write_hash_cache` fails.
+pub fn set_cache_writes(enabled: bool) {
+    CACHE_WRITES_ENABLED.store(enabled, atomic::Ordering::Relaxed);
+}
+
+/// Write the hash of `h_entry` to cache file in the same folder
 /// of image file located.
-pub fn write_hash_cache(image_path: &Path, image_hash: &Hash, hash_type: HashType) -> Result<usize, Box<dyn Error>> {
+/// 
+/// The cache layout is: magic bytes, version, hasher fingerprint (u64,
+/// little endian, see `hasher_fingerprint`) of the entry's parameters,
+/// bit length (u32, little endian), then bits packed by `Hash::to_bytes`.
+#[must_use = "a failed cache write should be handled or explicitly ignored"]
+#[tracing::instrument(level = "debug", skip_all, fields(image_path = %image_path.display(), hash_type = ?h_entry.hash_type))]
+pub fn write_hash_cache(image_path: &Path, h_entry: &ImageHashEntry) -> Result<usize, Box<dyn Error>> {
+
+    if !CACHE_WRITES_ENABLED.load(atomic::Ordering::Relaxed) {
+        return Err("hash cache writes are disabled".into());
+    }
+
+    let image_hash = &h_entry.hash;
+    let hash_file_name = cache_path(image_path, h_entry.hash_type);
 
-    let image_path = image_path.to_owned();
+    let bit_length = u32::try_from(image_hash.bits.len())
+        .map_err(|_| "hash too long to be cached")?;
 
-    let hash_file_name = image_path.with_added_extension(cache_ext(hash_type));
+    let fingerprint = hasher_fingerprint(h_entry.hash_type, h_entry.params, image_hash.bits.len());
 
-    // Serialize: using proxy trick.
-    let hash_pxy = 
-        Hash { bits: image_hash.bits.clone() }; // clone to a already-derived (de)serialize struct.
+    let mut cache_data: Vec<u8> = Vec::with_capacity(CACHE_MAGIC.len() + 13 + image_hash.bits.len() / 8 + 1);
+    cache_data.extend_from_slice(CACHE_MAGIC);
+    cache_data.push(CACHE_VERSION);
+    cache_data.extend_from_slice(&fingerprint.to_le_bytes());
+    cache_data.extend_from_slice(&bit_length.to_le_bytes());
+    cache_data.extend_from_slice(&image_hash.to_bytes());
 
     let mut f_handle = File::create(hash_file_name)?;
+    f_handle.write_all(&cache_data)?;
+
+    Ok(cache_data.len())
+}
+
+/// Decode a cache file content, returns the hash, the parameters at
+/// its size and whether the file is in an older format and should be
+/// rewritten.
+/// 
+/// A cache must be written with `params`, at any hash size. Caches older
+/// than the fingerprint are taken as such.
+fn decode_hash_cache(cache_data: &[u8], hash_type: HashType, params: HashParams) -> Result<(Hash, HashParams, bool), String> {
+
+    match cache_data.strip_prefix(CACHE_MAGIC) {
+        Some(packed) => {
+            let (version, packed) = packed.split_first()
+                .ok_or("truncated cache header")?;
+
+            let (fingerprint, packed) = match *version {
+                1 => (None, packed),
+                CACHE_VERSION => {
+                    let (fingerprint, packed) = packed.split_first_chunk::<8>()
+                        .ok_or("truncated cache header")?;
+                    (Some(u64::from_le_bytes(*fingerprint)), packed)
+                },
+                version => return Err(format!("unsupported cache version {}", version)),
+            };
+
+            let (bit_length, packed) = packed.split_first_chunk::<4>()
+                .ok_or("truncated cache header")?;
+            let bit_length = u32::from_le_bytes(*bit_length) as usize;
+            let params = params.matching(hash_type, bit_length);
+
+            if fingerprint.is_some_and(|fp| fp != hasher_fingerprint(hash_type, params, bit_length)) {
+                return Err("cache written with other hasher parameters".to_owned());
+            }
+
+            if packed.len() * 8 < bit_length {
+                return Err("truncated cache content".to_owned());
+            }
 
-    bincode::serde::encode_into_std_write(
-                            &hash_pxy,
-                            &mut f_handle,
-                            bincode::config::standard())
-                                    .map_err(|e| format!("error while serialize ({})", e).into())
+            Ok((Hash::from_bytes(packed, bit_length), params, fingerprint.is_none()))
+        },
+        None => {
+            // legacy format: bincode of the `Hash` proxy struct.
+            let (hash_pxy, _): (Hash, usize) = 
+                bincode::serde::decode_from_slice(
+                    cache_data,
+                    bincode::config::standard())
+                        .map_err(|e: bincode::error::DecodeError| e.to_string())?;
+
+            let params = params.matching(hash_type, hash_pxy.bits.len());
+            Ok((hash_pxy, params, true))
+        },
+    }
 }
 
 /// Attempt to load hash value from cache in the same folder of 
 /// given image.
 /// 
-/// It also implemented the `Ord` trait so it's possible to sort a list
-/// of measured, images and fetch the most similar images.
-pub fn fetch_hash_cache(image_path: &Path, hash_type: HashType) -> Result<ImageHashEntry, Box<dyn Error>> {
+/// Caches written in an older format are upgraded on read, a cache
+/// written with other hasher parameters than `params` is an error, so
+/// callers like `fetch_cache_or_calc_hash` recalculate the hash.
+#[must_use = "a missing or corrupted cache should be handled"]
+#[tracing::instrument(level = "debug", skip_all, fields(image_path = %image_path.display(), hash_type = ?hash_type))]
+pub fn fetch_hash_cache(image_path: &Path, hash_type: HashType, params: HashParams) -> Result<ImageHashEntry, Box<dyn Error>> {
     
-    let hash_file_name = image_path.with_added_extension(cache_ext(hash_type));
+    let hash_file_name = cache_path(image_path, hash_type);
 
-    // try to open the cache corresponding to the given hash type
-    let mut f_handle = match File::open(&hash_file_name) {
+    // try to read the cache corresponding to the given hash type
+    let cache_data = match std::fs::read(&hash_file_name) {
         Ok(f) => f,
         Err(e) => {
             // Provide a more descriptive error if the file doesn't exist
@@ -158,36 +820,37 @@ pub fn fetch_hash_cache(image_path: &Path, hash_type: HashType) -> Result<ImageH
     };
 
     // try to decode
-    let hash_pxy: Hash = 
-        bincode::serde::decode_from_std_read(
-        &mut f_handle,
-        bincode::config::standard(),
-        ).map_err(|e: bincode::error::DecodeError| format!("cannot deserialize cache file '{}' with type {:?}: {}",
+    let (img_hash, params, is_outdated) = 
+        decode_hash_cache(&cache_data, hash_type, params)
+            .map_err(|e| format!("cannot deserialize cache file '{}' with type {:?}: {}",
                             hash_file_name.display(), hash_type, e))?;
 
-    let img_hash = Hash {
-        bits: hash_pxy.bits.clone(),
-    };
+    let h_entry = ImageHashEntry::with_params(image_path.to_owned(), hash_type, img_hash, params);
 
-    Ok(ImageHashEntry { 
-        image_name: image_path.to_owned(), 
-        hash_type, 
-        hash: img_hash.into() 
-    })
+    if is_outdated {
+        // Rewrite in the current format, IGNORE the error: the old
+        // cache is still readable and the upgrade is retried next time.
+        write_hash_cache(image_path, &h_entry).ok();
+    }
+
+    Ok(h_entry)
 }
 
-pub fn fetch_cache_or_calc_hash(image_path: &Path, hash_type: HashType, force_rewrite_cache: bool) -> Result<ImageHashEntry, Box<dyn Error>> {
+/// Hash of an image from its cache written with `params`, or calculated
+/// with them and cached.
+pub fn fetch_cache_or_calc_hash(image_path: &Path, hash_type: HashType, params: HashParams, force_rewrite_cache: bool) -> Result<ImageHashEntry, Box<dyn Error>> {
     
-    match fetch_hash_cache(image_path, hash_type) {
+    match fetch_hash_cache(image_path, hash_type, params) {
         Ok(h) => { // we found exist hash cache
             let h = match force_rewrite_cache {
                 true => { // force recalculate
-                    match calc_image_hash(image_path, hash_type) {
+                    match calc_image_hash(image_path, hash_type, params) {
                         Ok(h_new) => {
                         // now try to write cache, and IGNORE the error.
-                        // [NOTE] shoule we catch the error of cache writing?
+                        // A failed write only means the hash gets recalculated
+                        // on next load, the fresh hash is still returned.
                         // Hey, cache really looks like catch!
-                        write_hash_cache(image_path, &h.hash, hash_type).ok();
+                        write_hash_cache(image_path, &h_new).ok();
                         h_new
                     },
                 Err(_err) => h, // calculation error, just return cache
@@ -198,13 +861,14 @@ pub fn fetch_cache_or_calc_hash(image_path: &Path, hash_type: HashType, force_re
             Ok(h)
         },
         Err(_) => {
-            match calc_image_hash(image_path, hash_type) {
+            match calc_image_hash(image_path, hash_type, params) {
                 Ok(h) => {
 
                     // now try to write cache, and IGNORE the error.
-                    // [NOTE] shoule we catch the error of cache writing?
+                    // A failed write only means the hash gets recalculated
+                    // on next load, the calculated hash is still valid.
                     // Hey, cache really looks like catch!
-                    write_hash_cache(image_path, &h.hash, hash_type).ok();
+                    write_hash_cache(image_path, &h).ok();
                     Ok(h)
                 },
                 Err(err) => Err(err),
@@ -214,19 +878,96 @@ pub fn fetch_cache_or_calc_hash(image_path: &Path, hash_type: HashType, force_re
 }
 
 /// The definition of (image name, hash value) pair format.
-#[derive(Debug, Clone)]
+/// 
+/// Two entries are considered equal when they point to the same
+/// image path, so a list of entries can be deduplicated with a `HashSet`.
+/// Ordering follows the image path as well, which gives a deterministic
+/// order regardless of how the filesystem lists the project folder.
+/// 
+/// Only the file name of `image_name` is serialized, so server paths never
+/// leak into stored or returned data, use `in_project` to restore the full
+/// path after deserializing.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct ImageHashEntry {
+    #[serde(serialize_with = "serialize_file_name")]
     pub image_name: PathBuf,
     pub hash_type: HashType,
     pub hash: Hash,
+    /// Parameters `hash` was calculated with, see `HashParams::for_type`.
+    pub params: HashParams,
+    /// Number of set bits in `hash`, stored to pre-filter candidates.
+    pub popcount: usize,
+}
+
+fn serialize_file_name<S: serde::Serializer>(image_name: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    let file_name = image_name.file_name().unwrap_or_default();
+    serde::Serialize::serialize(Path::new(file_name), serializer)
+}
+
+impl ImageHashEntry {
+    /// Make a new entry, the popcount is calculated from `hash`.
+    /// 
+    /// The hash is taken as calculated with the default parameters of its
+    /// size, use `with_params` for hashes of other parameters.
+    pub fn new(image_name: PathBuf, hash_type: HashType, hash: Hash) -> Self {
+        let params = HashParams::default().matching(hash_type, hash.bits.len());
+        ImageHashEntry::with_params(image_name, hash_type, hash, params)
+    }
+
+    /// Make a new entry of a hash calculated with `params`.
+    pub fn with_params(image_name: PathBuf, hash_type: HashType, hash: Hash, params: HashParams) -> Self {
+        let popcount = hash.popcount();
+        let params = params.for_type(hash_type);
+        ImageHashEntry { image_name, hash_type, hash, params, popcount }
+    }
+
+    /// Place the entry's image in `project_path`, keeping only its file name.
+    pub fn in_project(mut self, project_path: &Path) -> Self {
+        if let Some(file_name) = self.image_name.file_name() {
+            self.image_name = project_path.join(file_name);
+        }
+        self
+    }
+}
+
+impl PartialEq for ImageHashEntry {
+    fn eq(&self, other: &Self) -> bool {
+        // Identity of an entry is the image path, not the hash value.
+        self.image_name == other.image_name
+    }
+}
+
+impl Eq for ImageHashEntry {}
+
+impl PartialOrd for ImageHashEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ImageHashEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Keyed on image path, consistent with `PartialEq`.
+        self.image_name.cmp(&other.image_name)
+    }
+}
+
+impl std::hash::Hash for ImageHashEntry {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        // Must agree with `PartialEq`, so only the image path is hashed.
+        self.image_name.hash(state);
+    }
 }
 
 /// The definition of an entry of image, pair with the distance 
 /// of another given image.
-#[derive(Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct ImageDistEntry {
     pub image_name: PathBuf,
     pub distance: f64,
+    /// Distance normalized by hash length and flipped into `[0, 1]`,
+    /// higher is closer.
+    pub similarity: f64,
 }
 
 impl PartialEq for ImageDistEntry {
@@ -238,8 +979,8 @@ impl PartialEq for ImageDistEntry {
 
 impl PartialOrd for ImageDistEntry {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        // You can use standard partial_cmp here, though total_cmp is also fine.
-        self.distance.partial_cmp(&other.distance)
+        // Delegate to `Ord`, which uses `total_cmp` for a stable ordering.
+        Some(self.cmp(other))
     }
 }
 
@@ -252,41 +993,821 @@ impl Ord for ImageDistEntry {
     }
 }
 
+/// Map a hamming distance to a similarity score in `[0, 1]`.
+pub(crate) fn dist_to_similarity(distance: f64, bit_length: usize) -> f64 {
+    match bit_length {
+        0 => 0.0,
+        n => (1.0 - distance / n as f64).clamp(0.0, 1.0),
+    }
+}
+
+/// Distance of two `hash_type` hashes, the hamming distance unless
+/// the hash type defines its own metric.
+pub fn hash_dist(hash_type: HashType, lhs: &Hash, rhs: &Hash) -> f64 {
+    match hash_type {
+        HashType::CROPHASH => crop::best_subset_dist(&lhs.bits, &rhs.bits),
+        _ => lhs.dist(rhs),
+    }
+}
+
 pub fn calc_distance(image: &DynamicImage, h_entry: &ImageHashEntry) -> ImageDistEntry {
-    let hasher = mk_hasher(h_entry.hash_type);
-    let h: Hash = hasher.hash(&image).into();
-    let h_dist = h.dist(&h_entry.hash);
+    let hasher = mk_hasher(h_entry.hash_type, h_entry.params);
+    let h: Hash = hasher.hash(image).into();
+    let h_dist = hash_dist(h_entry.hash_type, &h, &h_entry.hash);
 
     ImageDistEntry {
         image_name: h_entry.image_name.clone(),
         distance: h_dist,    
+        similarity: dist_to_similarity(h_dist, h_entry.hash.bits.len()),
     }
 }
 
 fn calc_distance_from_hash(hash: &Hash, h_entry: &ImageHashEntry) -> ImageDistEntry {
+    let h_dist = hash_dist(h_entry.hash_type, hash, &h_entry.hash);
+
     ImageDistEntry {
         image_name: h_entry.image_name.clone(),
-        distance: hash.dist(&h_entry.hash),    
+        distance: h_dist,    
+        similarity: dist_to_similarity(h_dist, h_entry.hash.bits.len()),
     }
 }
 
-pub fn calc_similarity_list(image: &image::DynamicImage, hash_list: &Vec<ImageHashEntry>) -> Vec<ImageDistEntry> {
-    
-    if hash_list.len() == 0 {
-        return vec![];
+/// Same as `calc_distance_from_hash`, but returns `None` if the distance
+/// exceeds `max_distance` bits.
+/// 
+/// The hamming distance is at least the difference of both popcounts, so
+/// entries can be rejected from the stored popcount without comparing bits.
+/// Other metrics have no such bound, their entries are always compared.
+fn calc_distance_from_hash_within(hash: &Hash, hash_popcount: usize, h_entry: &ImageHashEntry, max_distance: f64) 
+    -> Option<ImageDistEntry> {
+
+    if h_entry.hash_type.is_hamming() && hash_popcount.abs_diff(h_entry.popcount) as f64 > max_distance {
+        return None;
+    }
+
+    Some(calc_distance_from_hash(hash, h_entry))
+        .filter(|d| d.distance <= max_distance)
+}
+
+/// Variant of `calc_similarity_list_from_hash` that only keeps entries
+/// within `max_distance` bits of the query hash.
+pub fn calc_similarity_list_within(hash: &Hash, hash_list: &[ImageHashEntry], max_distance: f64) -> Vec<ImageDistEntry> {
+    let hash_popcount = hash.popcount();
+
+    hash_list.iter().filter_map(|h_ent: &ImageHashEntry| {
+        calc_distance_from_hash_within(hash, hash_popcount, h_ent, max_distance)
+    }).collect()
+}
+
+/// Order of project hash lists: by popcount, then by image name.
+fn popcount_order(a: &ImageHashEntry, b: &ImageHashEntry) -> Ordering {
+    a.popcount.cmp(&b.popcount).then_with(|| a.cmp(b))
+}
+
+/// Sort a hash list by popcount (then image name), the order expected
+/// by `calc_similarity_list_within_sorted`.
+pub fn sort_hash_list(hash_list: &mut [ImageHashEntry]) {
+    hash_list.sort_by(popcount_order);
+}
+
+/// Insert an entry into a list sorted by `sort_hash_list`, keeping it 
+/// sorted. An existing entry of the same image is replaced.
+pub fn insert_hash_entry(hash_list: &mut Vec<ImageHashEntry>, entry: ImageHashEntry) {
+    // the old entry may have another popcount, so look it up by name.
+    hash_list.retain(|h_ent| h_ent != &entry);
+
+    let idx = hash_list.partition_point(|h_ent| popcount_order(h_ent, &entry) == Ordering::Less);
+    hash_list.insert(idx, entry);
+}
+
+/// Same as `calc_similarity_list_within`, for a list sorted by 
+/// `sort_hash_list`.
+/// 
+/// Only entries whose popcount lies within `max_distance` of the query's
+/// popcount can match, that range is located by binary search and the
+/// rest of the list is never touched. Lists of hash types without the
+/// popcount bound are scanned in full.
+pub fn calc_similarity_list_within_sorted(hash: &Hash, hash_list: &[ImageHashEntry], max_distance: f64) -> Vec<ImageDistEntry> {
+    if hash_list.first().is_some_and(|h_ent| !h_ent.hash_type.is_hamming()) {
+        return calc_similarity_list_within(hash, hash_list, max_distance);
+    }
+
+    let hash_popcount = hash.popcount();
+    let max_bits = max_distance.max(0.0).floor() as usize;
+
+    let lower = hash_popcount.saturating_sub(max_bits);
+    let upper = hash_popcount.saturating_add(max_bits);
+
+    let range_start = hash_list.partition_point(|h_ent| h_ent.popcount < lower);
+    let range_end = hash_list.partition_point(|h_ent| h_ent.popcount <= upper);
+
+    hash_list[range_start..range_end.max(range_start)].iter().filter_map(|h_ent: &ImageHashEntry| {
+        calc_distance_from_hash_within(hash, hash_popcount, h_ent, max_distance)
+    }).collect()
+}
+
+pub fn calc_similarity_list(image: &image::DynamicImage, hash_list: &[ImageHashEntry]) -> Vec<ImageDistEntry> {
+    calc_similarity_iter(image, hash_list).collect()
+}
+
+/// Variant of `calc_similarity_list` for an already calculated query hash.
+pub fn calc_similarity_list_from_hash(hash: &Hash, hash_list: &[ImageHashEntry]) -> Vec<ImageDistEntry> {
+    hash_list.iter().map(|h_ent: &ImageHashEntry| {
+        calc_distance_from_hash(hash, h_ent)
+    }).collect()
+}
+
+/// Merge the distance lists of several variants of one query, keeping
+/// the smallest distance of every image. The result is unsorted.
+pub fn merge_min_distance(lhs: Vec<ImageDistEntry>, rhs: Vec<ImageDistEntry>) -> Vec<ImageDistEntry> {
+    let mut closest: HashMap<PathBuf, ImageDistEntry> = HashMap::with_capacity(lhs.len());
+
+    for d_entry in lhs.into_iter().chain(rhs) {
+        match closest.get(&d_entry.image_name) {
+            Some(kept) if kept.distance <= d_entry.distance => (),
+            _ => {
+                closest.insert(d_entry.image_name.clone(), d_entry);
+            },
+        }
+    }
+    closest.into_values().collect()
+}
+
+/// The other quarter turns of an image, rotated 90, 180 and 270 degrees
+/// clockwise, for queries that may be stored in another orientation.
+pub fn quarter_turns(image: &DynamicImage) -> Vec<DynamicImage> {
+    vec![image.rotate90(), image.rotate180(), image.rotate270()]
+}
+
+/// The horizontal and vertical mirrors of an image, for queries that
+/// may be stored mirrored.
+pub fn mirrors(image: &DynamicImage) -> Vec<DynamicImage> {
+    vec![image.fliph(), image.flipv()]
+}
+
+/// Other orientations of an image a query should also match: its
+/// quarter turns, its mirrors, or with both every turn of its mirror.
+pub fn query_orientations(image: &DynamicImage, is_turned: bool, is_mirrored: bool) -> Vec<DynamicImage> {
+    match (is_turned, is_mirrored) {
+        (false, false) => Vec::new(),
+        (true, false) => quarter_turns(image),
+        (false, true) => mirrors(image),
+        (true, true) => {
+            // the vertical mirror is the horizontal one turned by 180 degrees.
+            let mirrored = image.fliph();
+            let mut orientations = quarter_turns(image);
+            orientations.extend(quarter_turns(&mirrored));
+            orientations.push(mirrored);
+            orientations
+        },
     }
+}
+
+/// Lazy variant of `calc_similarity_list`, yields one distance entry
+/// at a time so callers can stop early (e.g. on an exact match) without
+/// materializing the full result.
+pub fn calc_similarity_iter<'a>(image: &'a DynamicImage, hash_list: &'a [ImageHashEntry]) 
+    -> impl Iterator<Item = ImageDistEntry> + 'a {
     
     // Important NOTE: we choose the first element from `hash_list`,
     // and use it as the hasher for all element.
     //
     // It speeds up by ignore redundant hash calculation, but less
     // generality, change if needed.
-    let hasher = mk_hasher(hash_list[0].hash_type);
-    let h: Hash = hasher.hash(&image).into();
-    
+    let h: Hash = match hash_list.first() {
+        None => Hash { bits: vec![] }, // nothing to compare, never used
+        Some(first) => {
+            mk_hasher(first.hash_type, first.params).hash(image).into()
+        },
+    };
 
+    hash_list.iter().map(move |h_ent: &ImageHashEntry| {
+        calc_distance_from_hash(&h, h_ent)
+    })
+}
 
-    hash_list.iter().map(|h_ent: &ImageHashEntry| {
-        calc_distance_from_hash(&h, &h_ent)
-    }).collect()
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn mk_entry(name: &str, bits: Vec<bool>) -> ImageHashEntry {
+        ImageHashEntry::new(PathBuf::from(name), HashType::PHASH, Hash { bits })
+    }
+
+    #[test]
+    fn test_hash_entry_dedup() {
+        let entries = vec![
+            mk_entry("proj/a.png", vec![true, false]),
+            mk_entry("proj/b.png", vec![true, true]),
+            mk_entry("proj/a.png", vec![false, false]), // same image, stale hash
+        ];
+
+        assert_eq!(entries[0], entries[2]);
+        assert_ne!(entries[0], entries[1]);
+
+        let unique: HashSet<ImageHashEntry> = entries.into_iter().collect();
+        assert_eq!(unique.len(), 2);
+    }
+
+    #[test]
+    fn test_hash_entry_sort() {
+        let mut entries = [
+            mk_entry("proj/c.png", vec![true]),
+            mk_entry("proj/a.png", vec![false]),
+            mk_entry("proj/b.png", vec![true]),
+        ];
+        entries.sort();
+
+        let names: Vec<_> = entries.iter()
+            .map(|e| e.image_name.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["proj/a.png", "proj/b.png", "proj/c.png"]);
+    }
+
+    fn mk_gradient(w: u32, h: u32, flip: bool) -> DynamicImage {
+        let buf = image::ImageBuffer::from_fn(w, h, |x, y| {
+            let v = ((x + y) * 255 / (w + h)) as u8;
+            image::Luma([if flip { 255 - v } else { v }])
+        });
+        DynamicImage::ImageLuma8(buf)
+    }
+
+    /// A colored image with fine texture, so compression has something to
+    /// damage, and without symmetry, so turns and flips change it.
+    fn mk_textured(w: u32, h: u32) -> DynamicImage {
+        let buf = image::ImageBuffer::from_fn(w, h, |x, y| {
+            let r = ((x * x + 3 * y * y) / 47 % 256) as u8;
+            image::Rgb([r, (x * 255 / w) as u8, ((x + 2 * y) % h * 255 / h) as u8])
+        });
+        DynamicImage::ImageRgb8(buf)
+    }
+
+    /// `img` after a round trip through JPEG at `quality`.
+    fn to_jpeg(img: &DynamicImage, quality: u8) -> DynamicImage {
+        let mut data: Vec<u8> = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut data, quality)
+            .encode_image(img)
+            .unwrap();
+        image::load_from_memory_with_format(&data, image::ImageFormat::Jpeg).unwrap()
+    }
+
+    #[test]
+    fn test_similarity_iter() {
+        let img_a = mk_gradient(64, 64, false);
+        let img_b = mk_gradient(64, 64, true);
+
+        let hash_list: Vec<ImageHashEntry> = [("a.png", &img_a), ("b.png", &img_b)]
+            .iter()
+            .map(|(name, img)| ImageHashEntry::new(
+                PathBuf::from(name),
+                HashType::PHASH,
+                calc_hash(img, HashType::PHASH, HashSize::default())))
+            .collect();
+
+        let eager = calc_similarity_list(&img_a, &hash_list);
+        let lazy: Vec<_> = calc_similarity_iter(&img_a, &hash_list).collect();
+        assert_eq!(eager.len(), lazy.len());
+        assert_eq!(eager[0].image_name, lazy[0].image_name);
+
+        // early exit on the exact match
+        let exact = calc_similarity_iter(&img_a, &hash_list)
+            .find(|d| d.distance == 0.0)
+            .unwrap();
+        assert_eq!(exact.image_name, PathBuf::from("a.png"));
+
+        assert_eq!(calc_similarity_iter(&img_a, &[]).count(), 0);
+    }
+
+    #[test]
+    fn test_hash_type_all() {
+        let exts: HashSet<String> = HashType::ALL.iter()
+            .map(|t| cache_ext(*t))
+            .collect();
+
+        // every type is listed once, and owns a distinct cache extension.
+        assert_eq!(exts.len(), HashType::ALL.len());
+        assert!(HashType::ALL.contains(&HashType::PHASH));
+    }
+
+    #[test]
+    fn test_hash_to_hex() {
+        let h = Hash { bits: vec![
+            true, false, true, false,   // a
+            false, false, false, true,  // 1
+            true, true,                 // c (padded)
+        ]};
+        assert_eq!(h.to_hex(), "a1c");
+        assert_eq!(Hash { bits: vec![] }.to_hex(), "");
+
+        let parsed = Hash::from_hex("A1c").unwrap();
+        assert_eq!(parsed.bits[..10], h.bits[..]);
+        assert_eq!(parsed.bits.len(), 12);
+        assert!(Hash::from_hex("a1g").is_err());
+    }
+
+    #[test]
+    fn test_hash_bytes_round_trip() {
+        let h = Hash { bits: vec![true, false, false, true, true, false, true, true, true, false, true] };
+        let packed = h.to_bytes();
+
+        assert_eq!(packed, vec![0b1101_1001, 0b0000_0101]);
+        assert_eq!(Hash::from_bytes(&packed, h.bits.len()).bits, h.bits);
+        assert!(Hash::from_bytes(&[], 0).bits.is_empty());
+    }
+
+    #[test]
+    fn test_legacy_cache_upgrade() {
+        let dir = std::env::temp_dir().join(format!("vismatch-cache-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image_path = dir.join("img.png");
+        let cache_path = cache_path(&image_path, HashType::PHASH);
+
+        let h = Hash { bits: (0..1024).map(|i| i % 3 == 0).collect() };
+        let params = HashParams::default();
+
+        // write a cache in the legacy bincode format
+        let legacy = bincode::serde::encode_to_vec(&h, bincode::config::standard()).unwrap();
+        std::fs::write(&cache_path, &legacy).unwrap();
+
+        let fetched = fetch_hash_cache(&image_path, HashType::PHASH, params).unwrap();
+        assert_eq!(fetched.hash.bits, h.bits);
+
+        // the cache is now packed, and still reads back the same
+        let upgraded = std::fs::read(&cache_path).unwrap();
+        assert!(upgraded.starts_with(CACHE_MAGIC));
+        assert!(upgraded.len() * 4 < legacy.len());
+        assert_eq!(fetch_hash_cache(&image_path, HashType::PHASH, params).unwrap().hash.bits, h.bits);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_fingerprint() {
+        let dir = std::env::temp_dir().join(format!("vismatch-fingerprint-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image_path = dir.join("img.png");
+        let cache_path = cache_path(&image_path, HashType::PHASH);
+        mk_gradient(64, 64, false).save(&image_path).unwrap();
+        let params = HashParams::default();
+
+        let fresh = calc_image_hash(&image_path, HashType::PHASH, params).unwrap().hash;
+        let bit_length = fresh.bits.len() as u32;
+
+        // a version 1 cache has no fingerprint, it is upgraded.
+        let mut v1 = CACHE_MAGIC.to_vec();
+        v1.push(1);
+        v1.extend_from_slice(&bit_length.to_le_bytes());
+        v1.extend_from_slice(&fresh.to_bytes());
+        std::fs::write(&cache_path, &v1).unwrap();
+        assert_eq!(fetch_hash_cache(&image_path, HashType::PHASH, params).unwrap().hash.bits, fresh.bits);
+        let upgraded = std::fs::read(&cache_path).unwrap();
+        assert_eq!(upgraded[CACHE_MAGIC.len()], CACHE_VERSION);
+        assert_eq!(upgraded.len(), v1.len() + 8);
+
+        // a cache written with other parameters is recalculated.
+        let stale = Hash { bits: vec![false; fresh.bits.len()] };
+        let mut other = CACHE_MAGIC.to_vec();
+        other.push(CACHE_VERSION);
+        other.extend_from_slice(&(hasher_fingerprint(HashType::PHASH, params, fresh.bits.len()) ^ 1).to_le_bytes());
+        other.extend_from_slice(&bit_length.to_le_bytes());
+        other.extend_from_slice(&stale.to_bytes());
+        std::fs::write(&cache_path, &other).unwrap();
+
+        assert!(fetch_hash_cache(&image_path, HashType::PHASH, params).is_err());
+        assert_eq!(fetch_cache_or_calc_hash(&image_path, HashType::PHASH, params, false).unwrap().hash.bits, fresh.bits);
+        assert_eq!(fetch_hash_cache(&image_path, HashType::PHASH, params).unwrap().hash.bits, fresh.bits);
+
+        // every hash type and size has its own parameters.
+        let fingerprints: std::collections::HashSet<u64> = HashType::ALL.iter()
+            .cartesian_product(HashSize::ALL)
+            .map(|(t, s)| hasher_fingerprint(*t, params, s.bit_length(*t)))
+            .collect();
+        assert_eq!(fingerprints.len(), HashType::ALL.len() * HashSize::ALL.len());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_params() {
+        let dir = std::env::temp_dir().join(format!("vismatch-params-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image_path = dir.join("img.png");
+        mk_gradient(64, 64, false).save(&image_path).unwrap();
+
+        // same hash length as the defaults, only the fingerprint tells them apart.
+        let params = HashParams { resize_filter: Some(ResizeFilter::Gaussian), ..HashParams::default() };
+        let h_entry = fetch_cache_or_calc_hash(&image_path, HashType::PHASH, params, false).unwrap();
+        assert_eq!(h_entry.params, params.for_type(HashType::PHASH));
+        assert_eq!(h_entry.hash.bits.len(), HashParams::default().bit_length(HashType::PHASH));
+
+        // the cache reads back with the parameters it was written with.
+        let cached = fetch_hash_cache(&image_path, HashType::PHASH, params).unwrap();
+        assert_eq!(cached.params, h_entry.params);
+        assert_eq!(cached.hash.bits, h_entry.hash.bits);
+
+        // and is stale for any other ones.
+        assert!(fetch_hash_cache(&image_path, HashType::PHASH, HashParams::default()).is_err());
+        let recalculated = fetch_cache_or_calc_hash(&image_path, HashType::PHASH, HashParams::default(), false).unwrap();
+        assert_eq!(recalculated.params, HashParams::default().for_type(HashType::PHASH));
+        assert!(fetch_hash_cache(&image_path, HashType::PHASH, params).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_blockhash_jpeg() {
+        let img = mk_textured(200, 150);
+
+        let h_high = calc_hash(&to_jpeg(&img, 95), HashType::BLOCKHASH, HashSize::Medium);
+        let h_low = calc_hash(&to_jpeg(&img, 30), HashType::BLOCKHASH, HashSize::Medium);
+        let h_flip = calc_hash(&to_jpeg(&img, 95).fliph(), HashType::BLOCKHASH, HashSize::Medium);
+
+        assert_eq!(h_high.bits.len(), 1024);
+        assert!(h_high.similarity(&h_low) > 0.9, "{}", h_high.similarity(&h_low));
+        assert!(h_high.similarity(&h_flip) < h_high.similarity(&h_low));
+    }
+
+    #[test]
+    fn test_whash_jpeg() {
+        let img = mk_textured(200, 150);
+
+        for hash_size in HashSize::ALL {
+            let w = calc_hash(&img, HashType::WHASH, *hash_size);
+            assert_eq!(w.bits.len(), hash_size.bit_length(HashType::WHASH));
+        }
+
+        let h_high = calc_hash(&to_jpeg(&img, 95), HashType::WHASH, HashSize::Medium);
+        let h_low = calc_hash(&to_jpeg(&img, 20), HashType::WHASH, HashSize::Medium);
+        let h_flip = calc_hash(&to_jpeg(&img, 95).fliph(), HashType::WHASH, HashSize::Medium);
+
+        assert!(h_high.similarity(&h_low) > 0.9, "{}", h_high.similarity(&h_low));
+        assert!(h_high.similarity(&h_flip) < h_high.similarity(&h_low));
+        assert_eq!("whash".parse::<HashType>().unwrap(), HashType::WHASH);
+    }
+
+    #[test]
+    fn test_colorhash_recolor() {
+        let buf = image::ImageBuffer::from_fn(64, 64, |x, y| {
+            image::Rgb([(200 + x / 2) as u8, (x * 2 + y) as u8, (y / 2) as u8])
+        });
+        let img = DynamicImage::ImageRgb8(buf);
+        let mut recolored = img.to_rgb8();
+        recolored.pixels_mut().for_each(|p| p.0 = [p.0[2], p.0[0], p.0[1]]);
+        let recolored = DynamicImage::ImageRgb8(recolored);
+
+        // a luminance hash barely notices the channel swap.
+        let l_sim = calc_hash(&img, HashType::AHASH, HashSize::Medium)
+            .similarity(&calc_hash(&recolored, HashType::AHASH, HashSize::Medium));
+        let c_sim = calc_hash(&img, HashType::COLORHASH, HashSize::Medium)
+            .similarity(&calc_hash(&recolored, HashType::COLORHASH, HashSize::Medium));
+        assert!(c_sim < l_sim, "{} >= {}", c_sim, l_sim);
+
+        let resized = img.resize_exact(100, 80, image::imageops::FilterType::Triangle);
+        let h = calc_hash(&img, HashType::COLORHASH, HashSize::Medium);
+        let h_resized = calc_hash(&resized, HashType::COLORHASH, HashSize::Medium);
+        assert_eq!(h.bits.len(), HashSize::Medium.bit_length(HashType::COLORHASH));
+        assert!(h.similarity(&h_resized) > 0.95, "{}", h.similarity(&h_resized));
+
+        // hamming distance of the codes is the moments' L1 distance.
+        let (h, h_recolored) = (mk_hasher(HashType::COLORHASH, HashSize::Small).hash(&img), 
+            mk_hasher(HashType::COLORHASH, HashSize::Small).hash(&recolored));
+        let moments_dist = color::ColorMoments::from_hash(&h).dist(&color::ColorMoments::from_hash(&h_recolored));
+        assert!((moments_dist * 16.0 - h.dist(&h_recolored)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_crophash_crop() {
+        let img = mk_textured(240, 180);
+        let cropped = img.crop_imm(0, 0, 160, 120);
+        let other = img.fliph().flipv();
+
+        for hash_size in HashSize::ALL {
+            let h = calc_hash(&img, HashType::CROPHASH, *hash_size);
+            assert_eq!(h.bits.len(), hash_size.bit_length(HashType::CROPHASH));
+        }
+
+        let similarity = |hash_type: HashType, query: &DynamicImage| {
+            let (h, q) = (calc_hash(&img, hash_type, HashSize::Medium), calc_hash(query, hash_type, HashSize::Medium));
+            dist_to_similarity(hash_dist(hash_type, &q, &h), h.bits.len())
+        };
+        let crop_sim = similarity(HashType::CROPHASH, &cropped);
+        assert!(crop_sim > 0.9, "{}", crop_sim);
+        assert!(crop_sim > similarity(HashType::DHASH, &cropped));
+        assert!(crop_sim > similarity(HashType::CROPHASH, &other));
+        assert_eq!(similarity(HashType::CROPHASH, &img), 1.0);
+
+        // no popcount bound, the sorted list is scanned in full.
+        let mut hash_list = vec![
+            ImageHashEntry::new("a.png".into(), HashType::CROPHASH, calc_hash(&img, HashType::CROPHASH, HashSize::Medium)),
+            ImageHashEntry::new("b.png".into(), HashType::CROPHASH, calc_hash(&other, HashType::CROPHASH, HashSize::Medium)),
+        ];
+        sort_hash_list(&mut hash_list);
+        let query = calc_hash(&cropped, HashType::CROPHASH, HashSize::Medium);
+        let max_distance = hash_dist(HashType::CROPHASH, &query, &hash_list.iter().find(|h| h.image_name.ends_with("a.png")).unwrap().hash);
+        let within = calc_similarity_list_within_sorted(&query, &hash_list, max_distance);
+        assert_eq!(within.len(), 1);
+        assert!(within[0].image_name.ends_with("a.png"));
+    }
+
+    #[test]
+    fn test_mhash_outliers() {
+        let img = mk_gradient(64, 64, false);
+        let h = calc_hash(&img, HashType::MHASH, HashSize::Small);
+        assert_eq!(h.bits.len(), HashSize::Small.bit_length(HashType::MHASH));
+        // pixels equal to the median stay unset.
+        assert!(h.popcount().abs_diff(h.bits.len() / 2) <= h.bits.len() / 16, "{}", h.popcount());
+
+        // a bright corner moves the mean threshold, not the median one.
+        let mut bright = img.to_luma8();
+        for (x, y, p) in bright.enumerate_pixels_mut() {
+            if x < 24 && y < 24 {
+                p.0 = [255];
+            }
+        }
+        let bright = DynamicImage::ImageLuma8(bright);
+        let m_sim = h.similarity(&calc_hash(&bright, HashType::MHASH, HashSize::Small));
+        let a_sim = calc_hash(&img, HashType::AHASH, HashSize::Small)
+            .similarity(&calc_hash(&bright, HashType::AHASH, HashSize::Small));
+        assert!(m_sim > a_sim, "{} <= {}", m_sim, a_sim);
+
+        assert_eq!("MHASH".parse::<HashType>().unwrap(), HashType::MHASH);
+        assert_eq!(serde_json::from_str::<HashType>("\"mhash\"").unwrap(), HashType::MHASH);
+        assert!(HashType::MHASH.is_hamming());
+    }
+
+    #[test]
+    fn test_rvhash_rotation() {
+        // no rotational symmetry, so pHash has to tell the rotations apart.
+        let img = mk_textured(160, 160);
+
+        for hash_size in HashSize::ALL {
+            let rv = calc_hash(&img, HashType::RVHASH, *hash_size);
+            assert_eq!(rv.bits.len(), hash_size.bit_length(HashType::RVHASH));
+        }
+
+        for rotated in [img.rotate90(), img.rotate180(), img.rotate270()] {
+            let rv_sim = calc_hash(&img, HashType::RVHASH, HashSize::Medium)
+                .similarity(&calc_hash(&rotated, HashType::RVHASH, HashSize::Medium));
+            let p_sim = calc_hash(&img, HashType::PHASH, HashSize::Medium)
+                .similarity(&calc_hash(&rotated, HashType::PHASH, HashSize::Medium));
+
+            assert!(rv_sim > 0.9, "{}", rv_sim);
+            assert!(rv_sim > p_sim, "{} <= {}", rv_sim, p_sim);
+        }
+    }
+
+    #[test]
+    fn test_oriented_query() {
+        let img = mk_textured(160, 160);
+        let hash_list = vec![
+            ImageHashEntry::new(PathBuf::from("upright.png"), HashType::PHASH, calc_hash(&img, HashType::PHASH, HashSize::Medium)),
+            ImageHashEntry::new(PathBuf::from("other.png"), HashType::PHASH, calc_hash(&mk_gradient(160, 160, false), HashType::PHASH, HashSize::Medium)),
+        ];
+
+        // a sideways query finds the upright image through one of its turns.
+        let query = img.rotate270();
+        let direct = calc_similarity_list(&query, &hash_list);
+        let closest = quarter_turns(&query).iter()
+            .map(|turned| calc_similarity_list(turned, &hash_list))
+            .fold(direct.clone(), merge_min_distance);
+
+        assert_eq!(closest.len(), 2);
+        let upright = |list: &[ImageDistEntry]| list.iter()
+            .find(|d| d.image_name == Path::new("upright.png"))
+            .unwrap()
+            .distance;
+        assert_eq!(upright(&closest), 0.0);
+        assert!(upright(&direct) > 0.0);
+
+        // mirrored queries, with or without a turn.
+        for query in [img.fliph(), img.flipv(), img.fliph().rotate90()] {
+            let closest = query_orientations(&query, true, true).iter()
+                .map(|oriented| calc_similarity_list(oriented, &hash_list))
+                .fold(calc_similarity_list(&query, &hash_list), merge_min_distance);
+            assert_eq!(upright(&closest), 0.0);
+        }
+        let closest = query_orientations(&img.flipv(), false, true).iter()
+            .map(|oriented| calc_similarity_list(oriented, &hash_list))
+            .fold(calc_similarity_list(&img.flipv(), &hash_list), merge_min_distance);
+        assert_eq!(upright(&closest), 0.0);
+
+        assert!(query_orientations(&img, false, false).is_empty());
+        assert_eq!(query_orientations(&img, true, true).len(), 7);
+    }
+
+    #[test]
+    fn test_hash_similarity() {
+        let a = Hash { bits: vec![true, true, false, false] };
+        let b = Hash { bits: vec![true, false, false, true] };
+        let not_a = Hash { bits: vec![false, false, true, true] };
+
+        assert_eq!(a.similarity(&a), 1.0);
+        assert_eq!(a.similarity(&b), 0.5);
+        assert_eq!(a.similarity(&not_a), 0.0);
+        assert_eq!(a.similarity(&b), b.similarity(&a));
+
+        // matches the score of distance entries.
+        let entry = ImageHashEntry::new("b.png".into(), HashType::PHASH, b.clone());
+        assert_eq!(calc_distance_from_hash(&a, &entry).similarity, a.similarity(&b));
+    }
+
+    #[test]
+    fn test_similarity_within() {
+        let query = Hash { bits: vec![true, true, false, false, false, false] };
+        let hash_list = vec![
+            mk_entry("same.png", vec![true, true, false, false, false, false]),
+            mk_entry("near.png", vec![true, false, false, false, false, false]),
+            mk_entry("far.png", vec![true, true, true, true, true, true]),
+            mk_entry("swap.png", vec![false, false, true, true, false, false]), // same popcount
+        ];
+
+        assert_eq!(query.popcount(), 2);
+        assert_eq!(hash_list[2].popcount, 6);
+
+        let within = calc_similarity_list_within(&query, &hash_list, 1.0);
+        let names: Vec<_> = within.iter().map(|d| d.image_name.clone()).collect();
+        assert_eq!(names, vec![PathBuf::from("same.png"), PathBuf::from("near.png")]);
+
+        // matches the full list, filtered by distance
+        let all = calc_similarity_list_from_hash(&query, &hash_list);
+        assert_eq!(all.iter().filter(|d| d.distance <= 1.0).count(), within.len());
+
+        // the sorted variant finds the same entries
+        let mut sorted_list = hash_list.clone();
+        sort_hash_list(&mut sorted_list);
+        let popcounts: Vec<_> = sorted_list.iter().map(|h| h.popcount).collect();
+        assert_eq!(popcounts, vec![1, 2, 2, 6]);
+
+        for max_distance in [0.0, 1.0, 4.0, 10.0] {
+            let mut sorted_names: Vec<_> = calc_similarity_list_within_sorted(&query, &sorted_list, max_distance)
+                .into_iter().map(|d| d.image_name).collect();
+            let mut names: Vec<_> = calc_similarity_list_within(&query, &hash_list, max_distance)
+                .into_iter().map(|d| d.image_name).collect();
+            sorted_names.sort();
+            names.sort();
+            assert_eq!(sorted_names, names);
+        }
+    }
+
+    #[test]
+    fn test_insert_hash_entry() {
+        let mut hash_list = vec![
+            mk_entry("a.png", vec![true, true, true]),
+            mk_entry("b.png", vec![false, false, false]),
+            mk_entry("c.png", vec![true, false, false]),
+        ];
+        sort_hash_list(&mut hash_list);
+
+        insert_hash_entry(&mut hash_list, mk_entry("d.png", vec![true, true, false]));
+        // re-upload of an existing image with a new hash
+        insert_hash_entry(&mut hash_list, mk_entry("a.png", vec![false, false, false]));
+
+        let names: Vec<_> = hash_list.iter()
+            .map(|e| e.image_name.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["a.png", "b.png", "c.png", "d.png"]);
+        assert_eq!(hash_list[0].popcount, 0);
+    }
+
+    #[test]
+    fn test_hash_type_from_str() {
+        assert_eq!("phash".parse::<HashType>(), Ok(HashType::PHASH));
+        assert_eq!("DHash".parse::<HashType>(), Ok(HashType::DHASH));
+        assert_eq!(" AHASH ".parse::<HashType>(), Ok(HashType::AHASH));
+
+        let err = "xhash".parse::<HashType>().unwrap_err();
+        assert!(err.contains("dhash, phash, ahash"));
+
+        // display round-trips through `FromStr`, and names cache files.
+        for &hash_type in HashType::ALL {
+            assert_eq!(hash_type.to_string().parse::<HashType>(), Ok(hash_type));
+            assert!(is_cache_file(&cache_path(Path::new("proj/a.png"), hash_type)));
+        }
+        assert!(!is_cache_file(Path::new("proj/a.png")));
+    }
+
+    #[test]
+    fn test_hash_size() {
+        assert_eq!("Small".parse::<HashSize>(), Ok(HashSize::Small));
+        assert_eq!(" large ".parse::<HashSize>(), Ok(HashSize::Large));
+        assert!("huge".parse::<HashSize>().unwrap_err().contains("small, medium, large"));
+
+        assert_eq!(HashSize::default(), HashSize::Medium);
+        assert_eq!(HashSize::from_bit_length(HashType::AHASH, 1024), Some(HashSize::Medium));
+        assert_eq!(HashSize::from_bit_length(HashType::PHASH, 31 * 32), Some(HashSize::Medium));
+        assert_eq!(HashSize::from_bit_length(HashType::PHASH, 1024), None);
+
+        // every hash type produces exactly `bit_length` bits for each size.
+        let img = mk_gradient(128, 96, false);
+        for &hash_type in HashType::ALL {
+            for &hash_size in HashSize::ALL {
+                let h = calc_hash(&img, hash_type, hash_size);
+                assert_eq!(h.bits.len(), hash_size.bit_length(hash_type), "{:?} {:?}", hash_type, hash_size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hash_params() {
+        // the defaults are the parameters used before they were configurable.
+        for &hash_type in HashType::ALL {
+            assert_eq!(hasher_params(hash_type, HashParams::default()), hasher_params(hash_type, HashSize::Medium));
+            assert_eq!(HashParams::default().bit_length(hash_type), HashSize::Medium.bit_length(hash_type));
+        }
+
+        // unset and ignored fields compare equal once filled in.
+        let explicit = HashParams { hash_size: HashSize::Medium, image_size: Some(32), resize_filter: Some(ResizeFilter::Lanczos3) };
+        assert_eq!(explicit.for_type(HashType::PHASH), HashParams::default().for_type(HashType::PHASH));
+        assert_eq!(explicit.for_type(HashType::AHASH).image_size, None);
+        assert_eq!(HashParams::default().for_type(HashType::BLOCKHASH).resize_filter, Some(ResizeFilter::Triangle));
+
+        // a larger image keeps the hash size, a smaller one shrinks it.
+        let img = mk_gradient(128, 96, false);
+        let larger = HashParams { image_size: Some(64), ..HashParams::default() };
+        let smaller = HashParams { image_size: Some(16), ..HashParams::default() };
+        for hash_type in [HashType::DHASH, HashType::PHASH] {
+            assert_eq!(calc_hash(&img, hash_type, larger).bits.len(), 32 * 32);
+            assert_eq!(calc_hash(&img, hash_type, smaller).bits.len(), smaller.bit_length(hash_type));
+            assert_ne!(hasher_params(hash_type, larger), hasher_params(hash_type, HashParams::default()));
+        }
+
+        let nearest = HashParams { resize_filter: Some(ResizeFilter::Nearest), ..HashParams::default() };
+        assert_ne!(hasher_params(HashType::AHASH, nearest), hasher_params(HashType::AHASH, HashParams::default()));
+
+        assert!(HashParams { image_size: Some(1), ..HashParams::default() }.validate().is_err());
+        assert!(HashParams { image_size: Some(4096), ..HashParams::default() }.validate().is_err());
+        assert!(larger.validate().is_ok());
+
+        // an odd image size hashes to whole hex digits, or is refused.
+        assert!(HashParams { image_size: Some(15), ..HashParams::default() }.validate().is_err());
+        let odd = HashParams { image_size: Some(17), ..HashParams::default() };
+        assert!(odd.validate().is_ok());
+        for hash_type in [HashType::DHASH, HashType::PHASH] {
+            let hash = calc_hash(&img, hash_type, odd);
+            assert_eq!(hash.bits.len(), odd.bit_length(hash_type));
+            assert_eq!(Hash::from_hex(&hash.to_hex()).unwrap().bits, hash.bits);
+        }
+    }
+
+    #[test]
+    fn test_dist_entry_serde() {
+        let dists = vec![
+            ImageDistEntry { image_name: PathBuf::from("proj/a.png"), distance: 0.0, similarity: 1.0 },
+            ImageDistEntry { image_name: PathBuf::from("proj/b.png"), distance: 591.0, similarity: 0.404 },
+        ];
+
+        let dists_json = serde_json::to_string(&dists).unwrap();
+        let dists_deserialized: Vec<ImageDistEntry> = serde_json::from_str(&dists_json).unwrap();
+
+        let dists_bin = bincode::serde::encode_to_vec(&dists, bincode::config::standard()).unwrap();
+        let (dists_bin_deserialized, _): (Vec<ImageDistEntry>, usize) = 
+            bincode::serde::decode_from_slice(&dists_bin, bincode::config::standard()).unwrap();
+
+        // `PartialEq` only looks at the distance, so compare every field.
+        for d_de in [dists_deserialized, dists_bin_deserialized] {
+            assert_eq!(d_de.len(), dists.len());
+            for (d, d_de) in dists.iter().zip(d_de.iter()) {
+                assert_eq!(d.image_name, d_de.image_name);
+                assert_eq!(d.distance, d_de.distance);
+                assert_eq!(d.similarity, d_de.similarity);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hash_entry_serde() {
+        let entries = vec![
+            ImageHashEntry::new("proj/known.png".into(), HashType::PHASH, 
+                Hash { bits: vec![true, false, true, true, false, false, true, false, true] }),
+            ImageHashEntry::new("proj/empty.png".into(), HashType::DHASH, Hash { bits: vec![] }),
+            ImageHashEntry::new("proj/ones.png".into(), HashType::AHASH, Hash { bits: vec![true; 1024] }),
+            ImageHashEntry::new("proj/zeros.png".into(), HashType::PHASH, Hash { bits: vec![false; 1024] }),
+        ];
+
+        // `PartialEq` only looks at the image name, so compare every field.
+        // only the file name is serialized, the project path is restored here.
+        let assert_same = |entry: &ImageHashEntry, entry_de: &ImageHashEntry| {
+            assert_eq!(entry, &entry_de.clone().in_project(Path::new("proj")));
+            assert_eq!(entry.hash_type, entry_de.hash_type);
+            assert_eq!(entry.hash.bits, entry_de.hash.bits);
+            assert_eq!(entry.popcount, entry_de.popcount);
+        };
+
+        for entry in &entries {
+            let entry_json = serde_json::to_string(entry).unwrap();
+            assert!(!entry_json.contains("proj/"));
+            let entry_de: ImageHashEntry = serde_json::from_str(&entry_json).unwrap();
+            assert_same(entry, &entry_de);
+
+            let entry_bin = bincode::serde::encode_to_vec(entry, bincode::config::standard()).unwrap();
+            let (entry_de, read): (ImageHashEntry, usize) = 
+                bincode::serde::decode_from_slice(&entry_bin, bincode::config::standard()).unwrap();
+            assert_same(entry, &entry_de);
+            assert_eq!(read, entry_bin.len());
+        }
+
+        assert_eq!(entries[2].popcount, 1024);
+        assert_eq!(entries[3].popcount, 0);
+    }
+}
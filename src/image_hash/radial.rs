@@ -0,0 +1,86 @@
+//! Radial variance hash.
+//!
+//! The image is sampled along lines through its center, one line per
+//! angle, and the variance of pixel values along each line forms a
+//! projection of the image over the angle. Rotating the image shifts
+//! this projection circularly, so the hash is taken from the magnitudes
+//! of its Fourier spectrum, which do not change under circular shifts.
+
+use image::DynamicImage;
+
+use crate::image_hash::traits::Hasher;
+
+/// Side of the square the image is resized to before sampling.
+const SAMPLE_SIDE: u32 = 128;
+
+/// Radial variance hasher.
+pub struct RadialVarianceHasher {
+    /// Lines through the center, evenly spread over half a turn.
+    pub num_angles: usize,
+    /// Samples along each line.
+    pub num_radii: usize,
+    /// Bits of the hash, must be less than `num_angles / 2`.
+    pub hash_bits: usize,
+    pub resize_filter: image::imageops::FilterType,
+}
+
+impl RadialVarianceHasher {
+    /// Variance of pixel values along each line through the center.
+    fn radial_projection(&self, image: &DynamicImage) -> Vec<f64> {
+        let pixels = image.grayscale()
+            .resize_exact(SAMPLE_SIDE, SAMPLE_SIDE, self.resize_filter)
+            .to_luma8();
+
+        let center = (SAMPLE_SIDE as f64 - 1.0) / 2.0;
+        let radius = center;
+        let num_radii = self.num_radii.max(2);
+
+        (0..self.num_angles)
+            .map(|a| {
+                // lines are symmetric, half a turn covers all of them.
+                let theta = std::f64::consts::PI * a as f64 / self.num_angles as f64;
+                let (sin, cos) = theta.sin_cos();
+
+                let values: Vec<f64> = (0..num_radii)
+                    .map(|r| {
+                        let t = -radius + 2.0 * radius * r as f64 / (num_radii - 1) as f64;
+                        let x = (center + t * cos).round() as u32;
+                        let y = (center + t * sin).round() as u32;
+                        pixels.get_pixel(x, y).0[0] as f64
+                    })
+                    .collect();
+
+                let mean = values.iter().sum::<f64>() / values.len() as f64;
+                values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64
+            })
+            .collect()
+    }
+}
+
+impl Hasher for RadialVarianceHasher {
+    fn hash(&self, image: &DynamicImage) -> imagehash::Hash {
+        let projection = self.radial_projection(image);
+        let n = projection.len() as f64;
+
+        // spectrum magnitudes from the first frequency on, the DC term
+        // only tells the overall contrast.
+        let magnitudes: Vec<f64> = (1..=self.hash_bits)
+            .map(|k| {
+                let (re, im) = projection.iter().enumerate()
+                    .fold((0.0, 0.0), |(re, im), (i, v)| {
+                        let phase = 2.0 * std::f64::consts::PI * (k * i) as f64 / n;
+                        (re + v * phase.cos(), im - v * phase.sin())
+                    });
+                (re * re + im * im).sqrt()
+            })
+            .collect();
+
+        let mut sorted = magnitudes.clone();
+        sorted.sort_unstable_by(f64::total_cmp);
+        let median = sorted.get(sorted.len() / 2).copied().unwrap_or(0.0);
+
+        imagehash::Hash {
+            bits: magnitudes.iter().map(|m| *m > median).collect(),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! Wavelet hash.
+//!
+//! The grayscale image is decomposed with the Haar wavelet, and only the
+//! approximation (LL) band at the hash resolution is kept, one bit per
+//! coefficient above the median. Detail bands, where compression
+//! artifacts end up, are dropped at every level.
+
+use image::DynamicImage;
+
+use crate::image_hash::traits::Hasher;
+
+/// Haar wavelet hasher.
+pub struct WaveletHasher {
+    /// Coefficients per side of the hash, the hash has `hash_side ^ 2` bits.
+    pub hash_side: u32,
+    /// Haar decomposition levels down to the hash resolution, the image
+    /// is resized to `hash_side << levels` per side.
+    pub levels: u32,
+    pub resize_filter: image::imageops::FilterType,
+}
+
+impl WaveletHasher {
+    /// One level of the 2D Haar transform, the LL band of a `side` x
+    /// `side` square of coefficients.
+    fn haar_ll(coefficients: &[f64], side: usize) -> Vec<f64> {
+        let half = side / 2;
+
+        (0..half * half)
+            .map(|i| {
+                let (x, y) = (2 * (i % half), 2 * (i / half));
+                let block = coefficients[y * side + x] + coefficients[y * side + x + 1]
+                    + coefficients[(y + 1) * side + x] + coefficients[(y + 1) * side + x + 1];
+                // orthonormal scaling, (a + b + c + d) / 2.
+                block / 2.0
+            })
+            .collect()
+    }
+}
+
+impl Hasher for WaveletHasher {
+    fn hash(&self, image: &DynamicImage) -> imagehash::Hash {
+        let hash_side = self.hash_side.max(1);
+        let side = hash_side << self.levels;
+
+        let pixels = image.grayscale()
+            .resize_exact(side, side, self.resize_filter)
+            .to_luma8();
+
+        let mut coefficients: Vec<f64> = pixels.pixels()
+            .map(|p| p.0[0] as f64 / 255.0)
+            .collect();
+        for level in 0..self.levels {
+            coefficients = WaveletHasher::haar_ll(&coefficients, (side >> level) as usize);
+        }
+
+        // removing the coarsest LL term, as the reference wHash does, only
+        // shifts every coefficient by the same amount, the median absorbs it.
+        let mut sorted = coefficients.clone();
+        sorted.sort_unstable_by(f64::total_cmp);
+        let median = match sorted.len() % 2 {
+            0 => (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2.0,
+            _ => sorted[sorted.len() / 2],
+        };
+
+        imagehash::Hash {
+            bits: coefficients.iter().map(|c| *c > median).collect(),
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! Image stores, keeping project images outside of the project root.
+//!
+//! Without a store, every project is a folder under the project root.
+//! With one, projects and their images live in object storage and the
+//! service keeps no image on local disk: images are only read to hash
+//! them, comparisons use the in-memory hash entries.
+//!
+//! Entries still name their image `<project root>/<project>/<image>`,
+//! the path is not expected to exist. Hashes go to the hash store, there
+//! are no sidecar cache files next to objects.
+
+pub mod s3;
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Arc;
+
+use image::{DynamicImage, ImageFormat};
+use itertools::Itertools;
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use crate::hash_store::{HashStore, StoreError, StoredHash};
+use crate::image_hash::{HashParams, HashType, ImageHashEntry, calc_hash, sort_hash_list};
+use crate::jobs::JobProgress;
+
+pub use s3::S3ImageStore;
+
+/// Shared handle of the configured store.
+pub type SharedImageStore = Arc<dyn ImageStore>;
+
+/// An image as listed by a store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredImage {
+    pub image_name: String,
+    /// Last modification in nanoseconds since the Unix epoch, compared
+    /// with `StoredHash::modified_ns` to tell changed images.
+    pub modified_ns: i64,
+    pub size_bytes: u64,
+}
+
+/// Storage of project images, keyed by project and image name.
+///
+/// Calls block, run them on the blocking pool from async code.
+pub trait ImageStore: Send + Sync {
+    /// Names of every project.
+    fn list_projects(&self) -> Result<Vec<String>, StoreError>;
+
+    /// Image files of a project, other files are left out.
+    fn list_images(&self, project_name: &str) -> Result<Vec<StoredImage>, StoreError>;
+
+    /// Content of an image, `None` when there is no such image.
+    fn read(&self, project_name: &str, image_name: &str) -> Result<Option<Vec<u8>>, StoreError>;
+
+    /// Write an image, replacing one of the same name.
+    fn write(&self, project_name: &str, image_name: &str, content: Vec<u8>) -> Result<StoredImage, StoreError>;
+
+    /// Create an empty project, listed until it is removed.
+    fn create_project(&self, project_name: &str) -> Result<(), StoreError>;
+
+    /// Remove an image, false when there was no such image.
+    fn remove_image(&self, project_name: &str, image_name: &str) -> Result<bool, StoreError>;
+
+    /// Remove a project with everything in it, returns the number of
+    /// images removed.
+    fn remove_project(&self, project_name: &str) -> Result<usize, StoreError>;
+}
+
+/// Image store settings as written in the config file, project folders
+/// are used unless a store is set.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImageStoreConfig {
+    /// S3 bucket holding one prefix per project.
+    pub s3_bucket: Option<String>,
+    /// Key prefix of projects in the bucket, e.g. `vismatch/`, to share
+    /// a bucket between deployments.
+    pub s3_prefix: String,
+    /// Region of the bucket, from the AWS environment when unset.
+    pub s3_region: Option<String>,
+    /// Endpoint of an S3 compatible service, e.g. `http://minio:9000`,
+    /// buckets are then addressed by path.
+    pub s3_endpoint: Option<String>,
+}
+
+impl ImageStoreConfig {
+    /// Whether images are kept in a store instead of project folders.
+    pub fn is_enabled(&self) -> bool {
+        self.s3_bucket.is_some()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.s3_bucket.as_deref().is_some_and(str::is_empty) {
+            return Err("`image_store.s3_bucket` must not be empty".to_owned());
+        }
+        if !self.is_enabled() && (self.s3_region.is_some() || self.s3_endpoint.is_some()) {
+            return Err("`image_store.s3_region` and `s3_endpoint` need `s3_bucket`".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Open the configured store. Must be called within a tokio runtime,
+    /// but outside of async code.
+    pub fn open(&self) -> Result<Option<SharedImageStore>, StoreError> {
+        let Some(bucket) = &self.s3_bucket else {
+            return Ok(None);
+        };
+
+        let store = S3ImageStore::connect(bucket, &self.s3_prefix, self.s3_region.as_deref(), self.s3_endpoint.as_deref())?;
+        Ok(Some(Arc::new(store)))
+    }
+}
+
+/// Project and image name of an entry, named `<project root>/<project>/<image>`.
+pub fn split_image_path(image_path: &Path) -> Result<(&str, &str), StoreError> {
+    let image_name = image_path.file_name()
+        .and_then(|f| f.to_str())
+        .ok_or("invalid image name")?;
+    let project_name = image_path.parent()
+        .and_then(Path::file_name)
+        .and_then(|f| f.to_str())
+        .ok_or("invalid project name")?;
+    Ok((project_name, image_name))
+}
+
+/// Read and decode the image of an entry.
+pub fn open_image(store: &dyn ImageStore, image_path: &Path) -> Result<DynamicImage, StoreError> {
+    let (project_name, image_name) = split_image_path(image_path)?;
+
+    let content = store.read(project_name, image_name)?
+        .ok_or_else(|| format!("image <{}> not found in project <{}>", image_name, project_name))?;
+    Ok(image::load_from_memory(&content)?)
+}
+
+/// Encode an image in the format its name asks for, as `image::save`
+/// would to a file of that name.
+pub fn encode_image(image: &DynamicImage, image_name: &str) -> Result<Vec<u8>, StoreError> {
+    let format = ImageFormat::from_path(image_name)?;
+
+    let mut content = Vec::new();
+    image.write_to(&mut Cursor::new(&mut content), format)?;
+    Ok(content)
+}
+
+/// Hash every image of a project with `params`, reusing the stored
+/// hashes of images unchanged since. Other images are downloaded and
+/// hashed, and stored, hashes of images gone are dropped.
+///
+/// Without a hash store, every image is downloaded on every load, and
+/// with `force_recompute` every image is downloaded anyway. Images
+/// reused or hashed are counted in `progress`.
+#[tracing::instrument(skip_all, fields(project_path = %project_path.display(), hash_type = ?hash_type))]
+pub fn load_project_hashes(
+    image_store: &dyn ImageStore,
+    hash_store: Option<&dyn HashStore>,
+    project_path: &Path,
+    hash_type: HashType,
+    params: HashParams,
+    force_recompute: bool,
+    progress: Option<&JobProgress>) -> Result<Vec<ImageHashEntry>, Box<dyn Error>> {
+
+    let project_name = project_path.file_name()
+        .and_then(|f| f.to_str())
+        .ok_or("invalid project name")?;
+
+    let images = image_store.list_images(project_name)
+        .map_err(|e| format!("cannot list project images: {}", e))?;
+
+    let mut stored: HashMap<String, StoredHash> = match hash_store {
+        Some(store) => store.load_project(project_name, hash_type)
+            .map_err(|e| format!("cannot load stored hashes: {}", e))?
+            .into_iter()
+            .map(|s| (s.image_name.clone(), s))
+            .collect(),
+        None => HashMap::new(),
+    };
+
+    let (reused, changed): (Vec<_>, Vec<_>) = images.into_iter()
+        .partition(|image| !force_recompute && stored.get(&image.image_name)
+            .is_some_and(|s| s.modified_ns == image.modified_ns && s.is_current(params)));
+
+    progress.inspect(|p| {
+        p.set_total(reused.len() + changed.len());
+        p.add_done(reused.len());
+    });
+
+    let hash_results: Vec<Result<StoredHash, StoreError>> = changed.into_par_iter()
+        .map(|image| {
+            let hashed = open_image(image_store, &project_path.join(&image.image_name))
+                .map(|decoded| calc_hash(&decoded, hash_type, params));
+            progress.inspect(|p| p.record(&image.image_name, &hashed));
+
+            Ok(StoredHash::new(image.image_name, hash_type, hashed?, params, image.modified_ns))
+        })
+        .collect();
+    let (fresh, failed): (Vec<StoredHash>, Vec<StoreError>) = hash_results.into_iter().partition_result();
+
+    for e in &failed {
+        tracing::warn!(project = %project_name, error = %e, "cannot hash image");
+    }
+
+    if let Some(store) = hash_store {
+        let listed: HashSet<&str> = reused.iter().map(|image| image.image_name.as_str())
+            .chain(fresh.iter().map(|s| s.image_name.as_str()))
+            .collect();
+        let gone: Vec<String> = stored.keys()
+            .filter(|name| !listed.contains(name.as_str()))
+            .cloned()
+            .collect();
+
+        if let Err(e) = store.put(project_name, &fresh) {
+            tracing::warn!(project = %project_name, error = %e, "cannot store project hashes");
+        }
+        if !gone.is_empty() && let Err(e) = store.remove_images(project_name, &gone) {
+            tracing::warn!(project = %project_name, error = %e, "cannot drop hashes of removed images");
+        }
+    }
+
+    tracing::debug!(project = %project_name, reused = reused.len(), hashed = fresh.len(), "loaded project from image store");
+
+    let mut hash_list: Vec<ImageHashEntry> = reused.into_iter()
+        .filter_map(|image| stored.remove(&image.image_name))
+        .chain(fresh)
+        .map(|s| s.into_entry(project_path, params))
+        .collect();
+
+    sort_hash_list(&mut hash_list);
+    Ok(hash_list)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use crate::hash_store::SqliteHashStore;
+
+    /// Project and image name to modification time and content.
+    type MemoryImages = HashMap<(String, String), (i64, Vec<u8>)>;
+
+    /// Images in memory, modified at their write count.
+    #[derive(Default)]
+    struct MemoryImageStore {
+        images: Mutex<MemoryImages>,
+        reads: Mutex<usize>,
+    }
+
+    impl ImageStore for MemoryImageStore {
+        fn list_projects(&self) -> Result<Vec<String>, StoreError> {
+            Ok(self.images.lock().unwrap().keys().map(|(p, _)| p.clone()).unique().collect())
+        }
+
+        fn list_images(&self, project_name: &str) -> Result<Vec<StoredImage>, StoreError> {
+            Ok(self.images.lock().unwrap().iter()
+                .filter(|((p, _), _)| p == project_name)
+                .map(|((_, i), (modified_ns, content))| StoredImage {
+                    image_name: i.clone(),
+                    modified_ns: *modified_ns,
+                    size_bytes: content.len() as u64,
+                })
+                .collect())
+        }
+
+        fn read(&self, project_name: &str, image_name: &str) -> Result<Option<Vec<u8>>, StoreError> {
+            *self.reads.lock().unwrap() += 1;
+            Ok(self.images.lock().unwrap().get(&(project_name.to_owned(), image_name.to_owned())).map(|(_, c)| c.clone()))
+        }
+
+        fn write(&self, project_name: &str, image_name: &str, content: Vec<u8>) -> Result<StoredImage, StoreError> {
+            let mut images = self.images.lock().unwrap();
+            let modified_ns = images.len() as i64 + 1;
+            let size_bytes = content.len() as u64;
+            images.insert((project_name.to_owned(), image_name.to_owned()), (modified_ns, content));
+            Ok(StoredImage { image_name: image_name.to_owned(), modified_ns, size_bytes })
+        }
+
+        fn create_project(&self, _project_name: &str) -> Result<(), StoreError> {
+            Ok(())
+        }
+
+        fn remove_image(&self, project_name: &str, image_name: &str) -> Result<bool, StoreError> {
+            Ok(self.images.lock().unwrap().remove(&(project_name.to_owned(), image_name.to_owned())).is_some())
+        }
+
+        fn remove_project(&self, project_name: &str) -> Result<usize, StoreError> {
+            let mut images = self.images.lock().unwrap();
+            let before = images.len();
+            images.retain(|(p, _), _| p != project_name);
+            Ok(before - images.len())
+        }
+    }
+
+    #[test]
+    fn test_load_project_hashes() {
+        let image_store = MemoryImageStore::default();
+        for name in ["a.png", "b.png"] {
+            image_store.write("cats", name, encode_image(&DynamicImage::new_rgb8(16, 16), name).unwrap()).unwrap();
+        }
+        // `.txt` is no image format.
+        assert!(encode_image(&DynamicImage::new_rgb8(16, 16), "a.txt").is_err());
+
+        let path = std::env::temp_dir().join(format!("vismatch-image-store-{}.sqlite", std::process::id()));
+        let hash_store = SqliteHashStore::open(&path).unwrap();
+        let project_path = Path::new("/image_root/cats");
+
+        let hash_list = load_project_hashes(&image_store, Some(&hash_store), project_path, HashType::PHASH, HashParams::default(), false, None).unwrap();
+        assert_eq!(hash_list.len(), 2);
+        assert_eq!(*image_store.reads.lock().unwrap(), 2);
+        assert_eq!(split_image_path(&hash_list[0].image_name).unwrap().0, "cats");
+
+        // the second load reads no image, until one is replaced.
+        load_project_hashes(&image_store, Some(&hash_store), project_path, HashType::PHASH, HashParams::default(), false, None).unwrap();
+        assert_eq!(*image_store.reads.lock().unwrap(), 2);
+
+        image_store.write("cats", "a.png", encode_image(&DynamicImage::new_rgb8(8, 8), "a.png").unwrap()).unwrap();
+        image_store.remove_image("cats", "b.png").unwrap();
+        let reloaded = load_project_hashes(&image_store, Some(&hash_store), project_path, HashType::PHASH, HashParams::default(), false, None).unwrap();
+        assert_eq!(reloaded.len(), 1);
+        assert_eq!(*image_store.reads.lock().unwrap(), 3);
+        assert_eq!(hash_store.load_project("cats", HashType::PHASH).unwrap().len(), 1);
+
+        drop(hash_store);
+        for ext in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), ext));
+        }
+    }
+}
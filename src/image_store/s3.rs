@@ -0,0 +1,259 @@
+//! Project images in an S3 bucket, or any S3 compatible object storage.
+//!
+//! A project is a key prefix, each image one object under it:
+//! `<prefix><project>/<image>`. An empty project is kept by a marker
+//! object named after the project prefix itself.
+
+use std::future::Future;
+
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::Client;
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::{ByteStream, DateTime};
+use aws_sdk_s3::types::{Delete, ObjectIdentifier};
+use tokio::runtime::Handle;
+
+use crate::hash_store::StoreError;
+use crate::image_store::{ImageStore, StoredImage};
+use crate::utils::is_image_extension;
+
+/// Most keys removed by one `DeleteObjects` request.
+const DELETE_BATCH_SIZE: usize = 1000;
+
+/// Images in one bucket, under a key prefix.
+///
+/// The async client runs on the tokio runtime the store was created in,
+/// calls block the calling thread until it is done.
+pub struct S3ImageStore {
+    client: Client,
+    bucket: String,
+    /// Empty, or ends with `/`.
+    prefix: String,
+    runtime: Handle,
+}
+
+/// Error of an SDK call with its cause, the error alone only says
+/// "service error".
+fn s3_error(e: impl std::error::Error) -> StoreError {
+    DisplayErrorContext(e).to_string().into()
+}
+
+/// Modification time in nanoseconds since the Unix epoch, to the second:
+/// listings report milliseconds but `HeadObject` only seconds.
+fn modified_ns(last_modified: Option<&DateTime>) -> i64 {
+    last_modified.map_or(0, |t| t.secs().saturating_mul(1_000_000_000))
+}
+
+impl S3ImageStore {
+    /// Connect to `bucket`, with credentials and region from the AWS
+    /// environment unless `region` is given. Must be called within a
+    /// tokio runtime, but outside of async code.
+    pub fn connect(bucket: &str, prefix: &str, region: Option<&str>, endpoint: Option<&str>) -> Result<Self, StoreError> {
+        let runtime = Handle::try_current()?;
+
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = region {
+            loader = loader.region(Region::new(region.to_owned()));
+        }
+        let sdk_config = runtime.block_on(loader.load());
+
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&sdk_config);
+        if let Some(endpoint) = endpoint {
+            // S3 compatible services seldom serve bucket subdomains.
+            s3_config = s3_config.endpoint_url(endpoint).force_path_style(true);
+        }
+
+        let prefix = prefix.trim_matches('/');
+        let store = S3ImageStore {
+            client: Client::from_conf(s3_config.build()),
+            bucket: bucket.to_owned(),
+            prefix: match prefix.is_empty() {
+                true => String::new(),
+                false => format!("{}/", prefix),
+            },
+            runtime,
+        };
+
+        // fail at startup rather than on the first request.
+        store.block_on(async {
+            store.client.head_bucket().bucket(&store.bucket).send().await.map_err(s3_error)?;
+            Ok(())
+        }).map_err(|e| format!("cannot access image bucket <{}>: {}", bucket, e))?;
+
+        Ok(store)
+    }
+
+    fn block_on<T>(&self, call: impl Future<Output = Result<T, StoreError>>) -> Result<T, StoreError> {
+        self.runtime.block_on(call)
+    }
+
+    fn project_prefix(&self, project_name: &str) -> String {
+        format!("{}{}/", self.prefix, project_name)
+    }
+
+    fn key(&self, project_name: &str, image_name: &str) -> String {
+        format!("{}{}", self.project_prefix(project_name), image_name)
+    }
+
+    /// Keys under `prefix`, only those right below it with `delimiter`,
+    /// along with the common prefixes found then.
+    async fn list(&self, prefix: &str, delimiter: Option<&str>)
+        -> Result<(Vec<aws_sdk_s3::types::Object>, Vec<String>), StoreError> {
+
+        let mut pages = self.client.list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .set_delimiter(delimiter.map(str::to_owned))
+            .into_paginator()
+            .send();
+
+        let (mut objects, mut prefixes) = (Vec::new(), Vec::new());
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(s3_error)?;
+            objects.extend(page.contents.unwrap_or_default());
+            prefixes.extend(page.common_prefixes.unwrap_or_default().into_iter().filter_map(|p| p.prefix));
+        }
+        Ok((objects, prefixes))
+    }
+}
+
+impl ImageStore for S3ImageStore {
+    fn list_projects(&self) -> Result<Vec<String>, StoreError> {
+        let (_, prefixes) = self.block_on(self.list(&self.prefix, Some("/")))?;
+
+        Ok(prefixes.iter()
+            .filter_map(|p| p.strip_prefix(&self.prefix)?.strip_suffix('/'))
+            .map(str::to_owned)
+            .collect())
+    }
+
+    fn list_images(&self, project_name: &str) -> Result<Vec<StoredImage>, StoreError> {
+        let project_prefix = self.project_prefix(project_name);
+        let (objects, _) = self.block_on(self.list(&project_prefix, Some("/")))?;
+
+        Ok(objects.into_iter()
+            .filter_map(|object| {
+                let image_name = object.key()?.strip_prefix(&project_prefix)?;
+                let (_, ext) = image_name.rsplit_once('.')?;
+
+                is_image_extension(ext).then(|| StoredImage {
+                    image_name: image_name.to_owned(),
+                    modified_ns: modified_ns(object.last_modified()),
+                    size_bytes: object.size().unwrap_or_default().max(0) as u64,
+                })
+            })
+            .collect())
+    }
+
+    fn read(&self, project_name: &str, image_name: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        self.block_on(async {
+            let object = self.client.get_object()
+                .bucket(&self.bucket)
+                .key(self.key(project_name, image_name))
+                .send()
+                .await;
+
+            let object = match object {
+                Ok(object) => object,
+                Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+                Err(e) => return Err(s3_error(e)),
+            };
+
+            Ok(Some(object.body.collect().await?.to_vec()))
+        })
+    }
+
+    fn write(&self, project_name: &str, image_name: &str, content: Vec<u8>) -> Result<StoredImage, StoreError> {
+        let key = self.key(project_name, image_name);
+        let size_bytes = content.len() as u64;
+        let content_type = image::ImageFormat::from_path(image_name).ok().map(|f| f.to_mime_type());
+
+        self.block_on(async {
+            self.client.put_object()
+                .bucket(&self.bucket)
+                .key(&key)
+                .set_content_type(content_type.map(str::to_owned))
+                .body(ByteStream::from(content))
+                .send()
+                .await
+                .map_err(s3_error)?;
+
+            // the response has no modification time, ask for it.
+            let head = self.client.head_object()
+                .bucket(&self.bucket)
+                .key(&key)
+                .send()
+                .await
+                .map_err(s3_error)?;
+
+            Ok(StoredImage {
+                image_name: image_name.to_owned(),
+                modified_ns: modified_ns(head.last_modified()),
+                size_bytes,
+            })
+        })
+    }
+
+    fn create_project(&self, project_name: &str) -> Result<(), StoreError> {
+        self.block_on(async {
+            self.client.put_object()
+                .bucket(&self.bucket)
+                .key(self.project_prefix(project_name))
+                .body(ByteStream::from_static(b""))
+                .send()
+                .await
+                .map_err(s3_error)?;
+            Ok(())
+        })
+    }
+
+    fn remove_image(&self, project_name: &str, image_name: &str) -> Result<bool, StoreError> {
+        let key = self.key(project_name, image_name);
+
+        self.block_on(async {
+            // deleting a missing key succeeds, check first.
+            let head = self.client.head_object().bucket(&self.bucket).key(&key).send().await;
+            match head {
+                Ok(_) => {},
+                Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => return Ok(false),
+                Err(e) => return Err(s3_error(e)),
+            }
+
+            self.client.delete_object().bucket(&self.bucket).key(&key).send().await.map_err(s3_error)?;
+            Ok(true)
+        })
+    }
+
+    fn remove_project(&self, project_name: &str) -> Result<usize, StoreError> {
+        let project_prefix = self.project_prefix(project_name);
+
+        self.block_on(async {
+            // everything under the prefix goes, the marker object included.
+            let (objects, _) = self.list(&project_prefix, None).await?;
+            let keys: Vec<String> = objects.into_iter().filter_map(|object| object.key).collect();
+
+            let image_count = keys.iter()
+                .filter_map(|key| key.rsplit_once('.'))
+                .filter(|(_, ext)| is_image_extension(ext))
+                .count();
+
+            for batch in keys.chunks(DELETE_BATCH_SIZE) {
+                let identifiers = batch.iter()
+                    .map(|key| ObjectIdentifier::builder().key(key).build())
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let deleted = self.client.delete_objects()
+                    .bucket(&self.bucket)
+                    .delete(Delete::builder().set_objects(Some(identifiers)).quiet(true).build()?)
+                    .send()
+                    .await
+                    .map_err(s3_error)?;
+
+                if let Some(e) = deleted.errors().first() {
+                    return Err(format!("cannot remove <{}>: {}", e.key().unwrap_or_default(), e.message().unwrap_or_default()).into());
+                }
+            }
+            Ok(image_count)
+        })
+    }
+}
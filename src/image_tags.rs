@@ -0,0 +1,150 @@
+//! Tags of uploaded images.
+//!
+//! An upload may label its image with tags, filters of bulk deletion and
+//! subset cloning select images by them. Tags are kept in a JSON file
+//! under project root, next to the deletion tokens.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// File name of the tag store under project root.
+pub const IMAGE_TAGS_FILE: &str = "image_tags.json";
+
+/// Tags of every image, by project then image name, backed by a JSON file.
+pub struct ImageTags {
+    path: PathBuf,
+    tags: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+}
+
+/// Shared handle of the tag store, calls block on file writes.
+pub type SharedImageTags = Arc<Mutex<ImageTags>>;
+
+impl ImageTags {
+    /// Load the store at `path`, a missing file is an empty store.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let tags = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| format!("invalid tag store <{}>: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(format!("cannot read tag store <{}>: {}", path.display(), e).into()),
+        };
+
+        Ok(ImageTags { path: path.to_owned(), tags })
+    }
+
+    /// Replace the tags of an image and persist them, no tags drop its
+    /// entry.
+    pub fn set(&mut self, project_name: &str, image_name: &str, tags: Vec<String>) -> Result<(), Box<dyn Error>> {
+        let had_tags = self.tags.get(project_name).is_some_and(|images| images.contains_key(image_name));
+
+        match (tags.is_empty(), had_tags) {
+            (true, false) => return Ok(()),
+            (true, true) => self.remove_entry(project_name, image_name),
+            (false, _) => {
+                self.tags.entry(project_name.to_owned()).or_default().insert(image_name.to_owned(), tags);
+            },
+        }
+        self.save()
+    }
+
+    /// Tags of every tagged image of a project.
+    pub fn project(&self, project_name: &str) -> HashMap<String, Vec<String>> {
+        self.tags.get(project_name)
+            .map(|images| images.iter().map(|(name, tags)| (name.clone(), tags.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    /// Drop the tags of images, once they are gone.
+    pub fn remove_images(&mut self, project_name: &str, image_names: &[String]) -> Result<(), Box<dyn Error>> {
+        let had_tags = image_names.iter()
+            .any(|image_name| self.tags.get(project_name).is_some_and(|images| images.contains_key(image_name)));
+        if !had_tags {
+            return Ok(());
+        }
+
+        for image_name in image_names {
+            self.remove_entry(project_name, image_name);
+        }
+        self.save()
+    }
+
+    /// Drop the tags of a project, once the project is gone.
+    pub fn remove_project(&mut self, project_name: &str) -> Result<(), Box<dyn Error>> {
+        match self.tags.remove(project_name) {
+            Some(_) => self.save(),
+            None => Ok(()),
+        }
+    }
+
+    /// Move the tags of a project to its new name.
+    pub fn rename_project(&mut self, project_name: &str, new_name: &str) -> Result<(), Box<dyn Error>> {
+        match self.tags.remove(project_name) {
+            Some(images) => {
+                self.tags.insert(new_name.to_owned(), images);
+                self.save()
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn remove_entry(&mut self, project_name: &str, image_name: &str) {
+        if let Some(images) = self.tags.get_mut(project_name) {
+            images.remove(image_name);
+            if images.is_empty() {
+                self.tags.remove(project_name);
+            }
+        }
+    }
+
+    /// Write to a temporary file then rename, so a crash never leaves
+    /// a truncated store behind.
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        let data = serde_json::to_vec_pretty(&self.tags)?;
+
+        std::fs::write(&tmp_path, data)
+            .map_err(|e| format!("cannot write tag store <{}>: {}", tmp_path.display(), e))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .map_err(|e| format!("cannot write tag store <{}>: {}", self.path.display(), e))?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_tags() {
+        let dir = std::env::temp_dir().join(format!("vismatch-tags-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(IMAGE_TAGS_FILE);
+        let tags = |names: &[&str]| names.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+
+        let mut image_tags = ImageTags::load(&path).unwrap();
+        image_tags.set("p", "a.png", tags(&["draft", "outdoor"])).unwrap();
+        image_tags.set("p", "b.png", tags(&["draft"])).unwrap();
+        image_tags.set("q", "a.png", tags(&["final"])).unwrap();
+
+        // reloading sees the same tags, a new upload replaces them.
+        let mut image_tags = ImageTags::load(&path).unwrap();
+        assert_eq!(image_tags.project("p")["a.png"], ["draft", "outdoor"]);
+        image_tags.set("p", "a.png", tags(&[])).unwrap();
+        assert!(!image_tags.project("p").contains_key("a.png"));
+
+        image_tags.remove_images("p", &tags(&["b.png"])).unwrap();
+        assert!(image_tags.project("p").is_empty());
+
+        image_tags.rename_project("q", "r").unwrap();
+        assert!(image_tags.project("q").is_empty());
+        assert_eq!(ImageTags::load(&path).unwrap().project("r")["a.png"], ["final"]);
+
+        image_tags.remove_project("r").unwrap();
+        assert!(ImageTags::load(&path).unwrap().project("r").is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
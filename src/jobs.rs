@@ -0,0 +1,195 @@
+//! Background jobs, polled for progress instead of awaited by a request.
+//!
+//! A job runs on its own task, e.g. reindexing a project too large to be
+//! done within a request timeout. Finished jobs are kept for a while so
+//! clients can read their outcome, the oldest are dropped first.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::api::{JobKind, JobResp, JobStatus};
+
+/// Default number of finished jobs kept for polling.
+pub const DEFAULT_FINISHED_JOBS: usize = 100;
+
+/// Errors kept per job, further errors are only counted.
+const MAX_JOB_ERRORS: usize = 100;
+
+/// Progress of a job, updated by its worker threads.
+#[derive(Debug, Default)]
+pub struct JobProgress {
+    total: AtomicUsize,
+    done: AtomicUsize,
+    error_count: AtomicUsize,
+    errors: Mutex<Vec<String>>,
+}
+
+impl JobProgress {
+    /// Set the number of items to process, once they are listed.
+    pub fn set_total(&self, total: usize) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    /// Count `count` items done without error, e.g. hashes reused as is.
+    pub fn add_done(&self, count: usize) {
+        self.done.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Count one item done, keeping its error if it failed.
+    pub fn record<T, E: Display>(&self, item: impl Display, result: &Result<T, E>) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+
+        if let Err(e) = result {
+            self.error_count.fetch_add(1, Ordering::Relaxed);
+            let mut errors = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+            if errors.len() < MAX_JOB_ERRORS {
+                errors.push(format!("{}: {}", item, e));
+            }
+        }
+    }
+}
+
+struct Job {
+    kind: JobKind,
+    project_name: String,
+    status: JobStatus,
+    /// Outcome once finished.
+    message: String,
+    progress: Arc<JobProgress>,
+    started: Instant,
+    elapsed: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Jobs {
+    by_id: HashMap<String, Job>,
+    /// Finished job ids, oldest first.
+    finished: VecDeque<String>,
+}
+
+/// Jobs of the service, running and recently finished.
+pub struct JobRegistry {
+    jobs: Mutex<Jobs>,
+    finished_capacity: usize,
+}
+
+impl JobRegistry {
+    /// Keep up to `finished_capacity` finished jobs.
+    pub fn new(finished_capacity: usize) -> Self {
+        JobRegistry { jobs: Mutex::new(Jobs::default()), finished_capacity }
+    }
+
+    fn jobs(&self) -> MutexGuard<'_, Jobs> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Register a running `kind` job on a project, returns its id and
+    /// progress. A job of the same kind already running on the project
+    /// is returned as `Err` with its id instead, one is enough.
+    pub fn start(&self, kind: JobKind, project_name: &str) -> Result<(String, Arc<JobProgress>), String> {
+        let mut jobs = self.jobs();
+
+        let running = jobs.by_id.iter()
+            .find(|(_, job)| job.status == JobStatus::Running && job.kind == kind && job.project_name == project_name);
+        if let Some((job_id, _)) = running {
+            return Err(job_id.clone());
+        }
+
+        let job_id = uuid::Uuid::new_v4().to_string();
+        let progress = Arc::new(JobProgress::default());
+        jobs.by_id.insert(job_id.clone(), Job {
+            kind,
+            project_name: project_name.to_owned(),
+            status: JobStatus::Running,
+            message: "running".to_owned(),
+            progress: progress.clone(),
+            started: Instant::now(),
+            elapsed: None,
+        });
+
+        Ok((job_id, progress))
+    }
+
+    /// Mark a job completed with its outcome message, or failed with its error.
+    pub fn finish(&self, job_id: &str, outcome: Result<String, String>) {
+        let mut jobs = self.jobs();
+
+        let Some(job) = jobs.by_id.get_mut(job_id) else {
+            return;
+        };
+        (job.status, job.message) = match outcome {
+            Ok(message) => (JobStatus::Completed, message),
+            Err(e) => (JobStatus::Failed, e),
+        };
+        job.elapsed = Some(job.started.elapsed());
+
+        jobs.finished.push_back(job_id.to_owned());
+        while jobs.finished.len() > self.finished_capacity {
+            if let Some(oldest) = jobs.finished.pop_front() {
+                jobs.by_id.remove(&oldest);
+            }
+        }
+    }
+
+    /// State of a job, `None` if unknown or dropped since.
+    pub fn get(&self, job_id: &str) -> Option<JobResp> {
+        let jobs = self.jobs();
+        let job = jobs.by_id.get(job_id)?;
+        let progress = &job.progress;
+
+        Some(JobResp {
+            success: job.status != JobStatus::Failed,
+            message: job.message.clone(),
+            job_id: job_id.to_owned(),
+            kind: job.kind,
+            project_name: job.project_name.clone(),
+            status: job.status,
+            images_done: progress.done.load(Ordering::Relaxed),
+            images_total: progress.total.load(Ordering::Relaxed),
+            error_count: progress.error_count.load(Ordering::Relaxed),
+            errors: progress.errors.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+            elapsed_ms: job.elapsed.unwrap_or_else(|| job.started.elapsed()).as_secs_f64() * 1000.0,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_registry() {
+        let jobs = JobRegistry::new(1);
+
+        let (job_id, progress) = jobs.start(JobKind::Reindex, "cats").unwrap();
+        assert_eq!(jobs.start(JobKind::Reindex, "cats").unwrap_err(), job_id);
+
+        progress.set_total(3);
+        progress.add_done(1);
+        progress.record("a.png", &Ok::<_, String>(()));
+        progress.record("b.png", &Err::<(), _>("cannot decode"));
+
+        let job = jobs.get(&job_id).unwrap();
+        assert_eq!(job.status, JobStatus::Running);
+        assert_eq!((job.images_done, job.images_total, job.error_count), (3, 3, 1));
+        assert_eq!(job.errors, vec!["b.png: cannot decode"]);
+
+        jobs.finish(&job_id, Ok("project reindexed".to_owned()));
+        assert_eq!(jobs.get(&job_id).unwrap().status, JobStatus::Completed);
+
+        // a finished job no longer blocks a new one, and the oldest
+        // finished job is dropped beyond capacity.
+        let (next_id, _) = jobs.start(JobKind::Reindex, "cats").unwrap();
+        jobs.finish(&next_id, Err("project folder is gone".to_owned()));
+        assert!(jobs.get(&job_id).is_none());
+
+        let failed = jobs.get(&next_id).unwrap();
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert!(!failed.success);
+        assert!(jobs.get("unknown").is_none());
+    }
+}
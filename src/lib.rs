@@ -3,16 +3,33 @@ pub mod vec_ops;
 pub mod metric;
 pub mod image_hash;
 pub mod project_mgmt;
+pub mod middleware;
+pub mod service_metrics;
+pub mod idempotency;
+pub mod blocking;
+pub mod config;
+pub mod deletion_tokens;
+pub mod image_tags;
+pub mod grpc;
+pub mod ann_index;
+pub mod ensemble;
+pub mod watcher;
+pub mod hash_store;
+pub mod image_store;
+pub mod jobs;
 mod utils;
 
 pub use utils::is_image_file;
 
 
+use std::collections::HashMap;
+
 use api::*;
 use image::DynamicImage;
 
-use crate::image_hash::ImageDistEntry;
+use crate::image_hash::{Hash, ImageDistEntry, ImageHashEntry, calc_similarity_list_from_hash};
 
+#[must_use = "a decoding error means the payload is not a usable image"]
 pub fn base64_to_image(base64_str: &str) 
     -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
     
@@ -33,7 +50,23 @@ pub fn base64_to_image(base64_str: &str)
 
     let decoded_bytes = general_purpose::STANDARD.decode(raw_base64_content)?;
 
-    let img_decoded = image::load_from_memory(&decoded_bytes)?;
+    bytes_to_image(&decoded_bytes)
+}
+
+/// Decode raw image file bytes, only accepted formats are decoded.
+#[must_use = "a decoding error means the payload is not a usable image"]
+pub fn bytes_to_image(decoded_bytes: &[u8]) 
+    -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
+
+    // check the format from magic bytes before committing to a full decode.
+    let image_format = image::guess_format(decoded_bytes)?;
+    tracing::debug!(?image_format, size = decoded_bytes.len(), "detected image format");
+
+    if !utils::is_allowed_image_format(image_format) {
+        return Err(format!("image format {:?} is not accepted", image_format).into());
+    }
+
+    let img_decoded = image::load_from_memory_with_format(decoded_bytes, image_format)?;
 
     Ok(img_decoded)
 }
@@ -49,7 +82,7 @@ pub fn image_to_base64(image: &DynamicImage)
     image.write_to(
         &mut Cursor::new(&mut image_data), 
         image::ImageOutputFormat::Png)
-            .map_err(|_e| format!("base64 encode error: cannot write to intermediate buffer"))?;
+            .map_err(|_e| "base64 encode error: cannot write to intermediate buffer".to_string())?;
 
     let b64_str = general_purpose::STANDARD.encode(image_data);
 
@@ -84,7 +117,7 @@ pub fn dist_entry_to_api_sim_entry(dist: &ImageDistEntry, with_image: bool)
             image::open(dist.image_name.clone())
                 .map_err(|e| e.into()) 
                 .and_then(|image: DynamicImage| image_to_base64(&image))
-                .ok()
+                .ok() // image data is optional, an unreadable image is sent without it
         },
     };
 
@@ -98,9 +131,100 @@ pub fn dist_entry_to_api_sim_entry(dist: &ImageDistEntry, with_image: bool)
     SimilarImageEntry { 
         image_name, 
         distance: dist.distance as f32, 
+        similarity_score: dist.similarity as f32,
         data: image_data }
 }
 
+/// Convert a `ImageHashEntry` to its serializable form, only the
+/// file name of the image is kept.
+pub fn hash_entry_to_api_json(entry: &ImageHashEntry) -> ImageHashEntryJson {
+    let image_name = match entry.image_name.file_name() {
+        None => "".to_owned(),
+        Some(f) => f.to_string_lossy().into_owned(),
+    };
+
+    ImageHashEntryJson {
+        image_name,
+        hash_type: entry.hash_type,
+        hash_hex: entry.hash.to_hex(),
+        params: Some(entry.params),
+    }
+}
+
+/// Convert a serialized hash entry back to `ImageHashEntry`.
+pub fn api_json_to_hash_entry(entry: &ImageHashEntryJson) 
+    -> Result<ImageHashEntry, Box<dyn std::error::Error>> {
+
+    let image_name = entry.image_name.clone().into();
+    let hash = Hash::from_hex(&entry.hash_hex)?;
+
+    Ok(match entry.params {
+        Some(params) => ImageHashEntry::with_params(image_name, entry.hash_type, hash, params),
+        None => ImageHashEntry::new(image_name, entry.hash_type, hash),
+    })
+}
+
+
+/// What filters may ask of project images besides their hashes, by
+/// file name.
+#[derive(Debug, Clone, Default)]
+pub struct ImageFacts {
+    /// Tags set on upload, untagged images are left out.
+    pub tags: HashMap<String, Vec<String>>,
+    /// Last modification in seconds since the Unix epoch.
+    pub modified_secs: HashMap<String, u64>,
+}
+
+/// Select the entries of a project hash list matching `filter`.
+/// 
+/// The reference image of a similarity filter is looked up by file name
+/// in the same hash list. An image whose tags or modification time the
+/// filter asks for, but `facts` lacks, does not match.
+pub fn filter_hash_entries<'a>(hash_list: &'a [ImageHashEntry], filter: &ImageFilter, facts: &ImageFacts)
+    -> Result<Vec<&'a ImageHashEntry>, String> {
+
+    let file_name = |entry: &ImageHashEntry| entry.image_name.file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let has_threshold = filter.max_distance_to_ref.is_some() || filter.min_similarity_to_ref.is_some();
+
+    // similarity of every entry to the reference image, if asked.
+    let ref_similarities: Option<Vec<f64>> = match (has_threshold, &filter.ref_image_name) {
+        (false, None) => None,
+        (true, None) => return Err("`max_distance_to_ref` and `min_similarity_to_ref` need `ref_image_name`".to_owned()),
+        (false, Some(_)) => return Err("`ref_image_name` needs `max_distance_to_ref` or `min_similarity_to_ref`".to_owned()),
+        (true, Some(ref_name)) => {
+            let ref_entry = hash_list.iter()
+                .find(|entry| file_name(entry) == *ref_name)
+                .ok_or_else(|| format!("reference image <{}> not found in project", ref_name))?;
+
+            Some(calc_similarity_list_from_hash(&ref_entry.hash, hash_list)
+                .iter()
+                .map(|dist| dist.similarity)
+                .collect())
+        },
+    };
+
+    let has_tags = |entry: &ImageHashEntry| facts.tags.get(&file_name(entry))
+        .is_some_and(|tags| filter.tags.iter().all(|tag| tags.contains(tag)));
+
+    let selected = hash_list.iter().enumerate()
+        .filter(|(_, entry)| filter.name_pattern.as_ref()
+            .is_none_or(|pattern| utils::wildcard_match(pattern, &file_name(entry))))
+        .filter(|(_, entry)| filter.tags.is_empty() || has_tags(entry))
+        .filter(|(_, entry)| filter.added_before
+            .is_none_or(|before| facts.modified_secs.get(&file_name(entry)).is_some_and(|secs| *secs < before)))
+        .filter(|(i, _)| match &ref_similarities {
+            Some(sims) => filter.max_distance_to_ref.is_none_or(|max| 1.0 - sims[*i] <= max as f64)
+                && filter.min_similarity_to_ref.is_none_or(|min| sims[*i] >= min as f64),
+            None => true,
+        })
+        .map(|(_, entry)| entry)
+        .collect();
+
+    Ok(selected)
+}
 
 
 #[cfg(test)]
@@ -120,6 +244,81 @@ mod tests {
 
         assert_eq!(im1_, base64_to_image(image_to_base64(&im1_).unwrap().as_str()).unwrap());
 
-        ()
+        // farbfeld is decodable by `image`, but not an accepted image type.
+        let farbfeld_1x1 = [b"farbfeld".as_slice(), &[0, 0, 0, 1, 0, 0, 0, 1], &[0; 8]].concat();
+        let farbfeld_b64 = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, farbfeld_1x1);
+        let err = base64_to_image(&farbfeld_b64).unwrap_err();
+        assert!(err.to_string().contains("not accepted"));
+
+        // default requests carry a decodable placeholder image.
+        assert_eq!(CompareImageReq::default().get_image().unwrap().width(), 1);
+        assert_eq!(UploadImageReq::default().get_image().unwrap().height(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_filter_hash_entries() {
+        use crate::image_hash::HashType;
+
+        let mk = |name: &str, bits: Vec<bool>| ImageHashEntry::new(
+            format!("proj/{}", name).into(), HashType::PHASH, Hash { bits });
+
+        let hash_list = vec![
+            mk("cat_1.png", vec![false, false, false, false]),
+            mk("cat_2.png", vec![true, false, false, false]),
+            mk("dog_1.png", vec![true, true, true, false]),
+        ];
+
+        let facts = ImageFacts {
+            tags: HashMap::from([
+                ("cat_1.png".to_owned(), vec!["draft".to_owned(), "indoor".to_owned()]),
+                ("dog_1.png".to_owned(), vec!["draft".to_owned()]),
+            ]),
+            modified_secs: HashMap::from([("cat_1.png".to_owned(), 100), ("cat_2.png".to_owned(), 200)]),
+        };
+
+        let names = |filter: &ImageFilter| filter_hash_entries(&hash_list, filter, &facts).unwrap()
+            .iter()
+            .map(|e| e.image_name.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        assert_eq!(names(&ImageFilter::default()).len(), 3);
+
+        let by_name = ImageFilter { name_pattern: Some("cat_?.png".to_owned()), ..Default::default() };
+        assert_eq!(names(&by_name), ["cat_1.png", "cat_2.png"]);
+
+        let by_ref = ImageFilter { 
+            max_distance_to_ref: Some(0.25), 
+            ref_image_name: Some("cat_1.png".to_owned()), 
+            ..Default::default() };
+        assert_eq!(names(&by_ref), ["cat_1.png", "cat_2.png"]);
+
+        let both = ImageFilter { name_pattern: Some("*_2*".to_owned()), ..by_ref.clone() };
+        assert_eq!(names(&both), ["cat_2.png"]);
+
+        let by_similarity = ImageFilter { 
+            min_similarity_to_ref: Some(0.5), 
+            ref_image_name: Some("dog_1.png".to_owned()), 
+            ..Default::default() };
+        assert_eq!(names(&by_similarity), ["cat_2.png", "dog_1.png"]);
+
+        // every tag must be set, unknown times never match.
+        let by_tags = |tags: &[&str]| ImageFilter { tags: tags.iter().map(|t| t.to_string()).collect(), ..Default::default() };
+        assert_eq!(names(&by_tags(&["draft"])), ["cat_1.png", "dog_1.png"]);
+        assert_eq!(names(&by_tags(&["draft", "indoor"])), ["cat_1.png"]);
+        assert!(names(&by_tags(&["outdoor"])).is_empty());
+        let by_date = ImageFilter { added_before: Some(200), ..Default::default() };
+        assert_eq!(names(&by_date), ["cat_1.png"]);
+
+        // missing reference and lonely threshold or reference are rejected.
+        let missing_ref = ImageFilter { ref_image_name: Some("nope.png".to_owned()), ..by_ref };
+        assert!(filter_hash_entries(&hash_list, &missing_ref, &facts).is_err());
+        let no_ref = ImageFilter { min_similarity_to_ref: Some(0.9), ..Default::default() };
+        assert!(filter_hash_entries(&hash_list, &no_ref, &facts).is_err());
+        let no_threshold = ImageFilter { ref_image_name: Some("cat_1.png".to_owned()), ..Default::default() };
+        assert!(filter_hash_entries(&hash_list, &no_threshold, &facts).is_err());
+
+        assert!(utils::wildcard_match("*", ""));
+        assert!(utils::wildcard_match("a*b*c", "a_b_b_c"));
+        assert!(!utils::wildcard_match("a*b", "a_b_c"));
+    }
+}
@@ -1,59 +1,255 @@
 
 use std::cmp::min;
 use std::error::Error;          // standard error trait
-use std::time::Instant;         // calculate time difference
-use std::collections::HashMap;  // hashmap support
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH}; // calculate time difference, timestamps
+use std::collections::{HashMap, HashSet, VecDeque}; // hashmap support, ring buffer
 use image::DynamicImage;        // image IO
 use itertools::Itertools;       // functional pattern support to make life easier
 
 // asynchronous execution and management
-use tokio::sync::RwLock;    // shared object management
-use std::sync::Arc;         // shared object reference
+use tokio::sync::{Mutex, RwLock, watch}; // shared object management, event broadcast
+use std::sync::Arc;                 // shared object reference
+use std::sync::atomic::{AtomicBool, Ordering}; // readiness flag
+use std::convert::Infallible;       // never-failing stream items
+use futures_util::stream::{self, Stream}; // SSE event stream
+use futures_util::future::join_all;       // wait for parallel tasks
+use tracing::Instrument;            // attach request spans to async blocks
 
 // HTTP related libs
-use axum::http::{Response, StatusCode}; // HTTP
+use axum::http::{HeaderMap, Response, StatusCode}; // HTTP
 use axum::response::IntoResponse;       // convert to response
-use axum::routing::{post};              // HTTP method
+use axum::response::sse::{Event, KeepAlive, Sse}; // server-sent events
+use axum::routing::{delete, get, post, put}; // HTTP method
 use axum::body::Body;                   // plain response body
-use axum::extract::{Json, State};       // response types
-use axum::{Router, http};               // router
+use axum::extract::{Extension, Json, Query, State}; // response types
+use axum::extract::Path as PathParam;   // path parameters
+use axum::extract::Multipart;           // multipart form uploads
+use axum::body::Bytes;                  // raw body parts
+use axum::{Router, http, middleware};   // router, middleware
+use tower_http::sensitive_headers::SetSensitiveRequestHeadersLayer; // header redaction
+use utoipa::OpenApi;                    // OpenAPI spec
+use utoipa_swagger_ui::SwaggerUi;       // API explorer
 use tokio::net::TcpListener;            // listener
+use axum_server::tls_rustls::RustlsConfig; // HTTPS listener
+use tonic::transport::server::TcpIncoming; // gRPC listener
 use std::net::SocketAddr;               // socker definition
 
 // filesystem and os-related libraries
 use std::path::{Path, PathBuf};      // filesystem path operations
-use std::fs::{read_dir, create_dir}; // filesystem utils
+use std::fs::{read_dir, create_dir, remove_dir_all}; // filesystem utils
 
 // internal libraries
 use vismatch_svc::{
     HasSingleImage,         // trait for getting image from request object
     base64_to_image, 
-    dist_entry_to_api_sim_entry, image_hash::*};     // our packaged hash algorithms
+    bytes_to_image, 
+    image_to_base64, 
+    api_json_to_hash_entry, 
+    hash_entry_to_api_json, 
+    dist_entry_to_api_sim_entry, 
+    filter_hash_entries, ImageFacts, image_hash::*};     // our packaged hash algorithms
 
 use vismatch_svc::project_mgmt::{
-    load_or_calc_project_hashes     
+    load_or_calc_project_hashes,
+    copy_project_images,
+    copy_image_files,
+    remove_image_files,
+    remove_project_files,
+    RemovedProjectFiles,
+    ProjectNamePolicy,
+    validate_image_name,
+    warm_project_images,
+    count_cache_files,
+    image_modified_secs,
+    write_missing_hash_caches,
+    merge_reindexed_hashes,
+    PendingProjects,
 };
 use vismatch_svc::api::*;           // API structure
+use vismatch_svc::service_metrics::ServiceMetrics; // prometheus metrics
+use vismatch_svc::idempotency::{request_fingerprint, Claim, IdempotencyCache, Reservation, ScopedKey, IDEMPOTENCY_KEY_HEADER}; // retry replays
+use vismatch_svc::blocking::{ComputePanic, run_blocking}; // panic-safe blocking tasks
+use clap::Parser;                        // command line flags
+use vismatch_svc::config::{Cli, LogFormat}; // server configuration file and flags
+use vismatch_svc::deletion_tokens::{DeletionTokens, SharedTokenStore, TokenStore, DELETION_TOKENS_FILE}; // upload deletion tokens
+use vismatch_svc::image_tags::{ImageTags, SharedImageTags, IMAGE_TAGS_FILE}; // upload tags
+use vismatch_svc::ann_index::{AnnIndexes, SharedIndex}; // approximate search of large projects
+use vismatch_svc::ensemble::{EnsembleIndexes, SharedEnsemble}; // several hash types per project
+use vismatch_svc::watcher::{ImageChange, ProjectWatcher}; // out-of-band image changes
+use vismatch_svc::hash_store::{HashStore, SharedHashStore, StoreError, StoredHash, fetch_stored_or_calc_hash, store_hashes}; // hashes outside sidecar files
+use vismatch_svc::image_store::{ImageStore, SharedImageStore, encode_image, open_image, split_image_path}; // images in object storage
+use vismatch_svc::jobs::{DEFAULT_FINISHED_JOBS, JobProgress, JobRegistry}; // background jobs
+use vismatch_svc::grpc::{self as pb, non_empty, vis_match_server::{VisMatch, VisMatchServer}}; // gRPC API
+use vismatch_svc::middleware::{     // request middlewares
+    RequestContext,
+    track_slow_requests,
+    BodyLimitConfig,
+    reject_oversized_body,
+    track_body_sizes,
+    ApiKeys,
+    ProjectAccess,
+    require_api_key,
+    RateLimiter,
+    RateLimitClient,
+    RouteClass,
+    limit_rate,
+    assign_request_id,
+};
 
 
-type ProjectHashDict = Arc<RwLock<HashMap<String, Vec<ImageHashEntry>>>>;
+// lists are shared with running comparisons, writers copy them with
+// `Arc::make_mut` only while a comparison still holds the old one.
+type ProjectHashDict = Arc<RwLock<HashMap<String, Arc<Vec<ImageHashEntry>>>>>;
+type CompareHistory = Arc<Mutex<VecDeque<CompareHistoryEntry>>>;
+type UploadReplays = Arc<Mutex<IdempotencyCache<UploadImageResp>>>;
+
+
+/// Most query images accepted by one `/diff/batch` request.
+const BATCH_COMPARE_MAX_IMAGES: usize = 64;
+
+/// Number of comparisons kept for `/diff/history`.
+const COMPARE_HISTORY_CAPACITY: usize = 1000;
+
+/// Default and upper bound of `/admin/dump` page size.
+const DUMP_DEFAULT_LIMIT: usize = 100;
+const DUMP_MAX_LIMIT: usize = 1000;
+
+/// Default and upper bound of image listing page size.
+const IMAGE_LIST_DEFAULT_LIMIT: usize = 100;
+const IMAGE_LIST_MAX_LIMIT: usize = 1000;
+
+/// Longest side of image listing thumbnails, in pixels.
+const IMAGE_LIST_THUMBNAIL_SIDE: u32 = 64;
+
+/// Quiet time before a file changed on disk is indexed, so files still
+/// being copied are not hashed half-written.
+const WATCH_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Default and upper bound of benchmark rounds.
+const BENCHMARK_DEFAULT_ITERATIONS: usize = 10;
+const BENCHMARK_MAX_ITERATIONS: usize = 1000;
+
+/// OpenAPI description of the HTTP API, served at `/openapi.json`.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "vismatch-svc", description = "Image similarity search by perceptual hashes."),
+    paths(
+        healthz_handler,
+        readyz_handler,
+        compare_handler,
+        batch_compare_handler,
+        compare_history_handler,
+        upload_handler,
+        upload_multipart_handler,
+        upload_raw_handler,
+        remove_handler,
+        events_handler,
+        dump_project_handler,
+        metrics_handler,
+        hash_metrics_handler,
+        list_projects_handler,
+        create_project_handler,
+        delete_project_handler,
+        rename_project_handler,
+        copy_project_handler,
+        clone_subset_handler,
+        list_images_handler,
+        delete_images_handler,
+        precompute_hash_handler,
+        benchmark_handler,
+        warm_cache_handler,
+        reindex_project_handler,
+        job_handler
+    ),
+    tags(
+        (name = "compare", description = "search similar images"),
+        (name = "images", description = "manage images of a project"),
+        (name = "projects", description = "manage projects"),
+        (name = "ops", description = "health, metrics and maintenance"),
+    ),
+)]
+struct ApiDoc;
 
 #[derive(Clone)]
 struct AppState {
     project_root: String,
     project_dict: ProjectHashDict,
+    hash_type: HashType,
+    /// Parameters new hashes are calculated with, see `[hash_params]`.
+    hash_params: HashParams,
+    project_events: watch::Sender<ProjectEvent>,
+    compare_history: CompareHistory,
+    metrics: ServiceMetrics,
+    upload_replays: UploadReplays,
+    project_name_policy: Arc<ProjectNamePolicy>,
+    deletion_tokens: SharedTokenStore,
+    image_tags: SharedImageTags,
+    hashes_loaded: Arc<AtomicBool>,
+    compare_top_k: usize,
+    api_keys: Arc<ApiKeys>,
+    /// Charged by the routes counting each query, and by gRPC.
+    rate_limiter: Arc<RateLimiter>,
+    ann_indexes: Arc<AnnIndexes>,
+    ensembles: Arc<EnsembleIndexes>,
+    /// Projects not loaded yet, empty unless `lazy_load` is set.
+    pending_projects: Arc<PendingProjects>,
+    /// Keeps hashes instead of sidecar cache files, when configured.
+    hash_store: Option<SharedHashStore>,
+    /// Keeps images instead of project folders, when configured.
+    image_store: Option<SharedImageStore>,
+    /// Background jobs, e.g. reindexing large projects.
+    jobs: Arc<JobRegistry>,
 }
 
 // common task definition
 
+/// Load a project whose loading was deferred, before its first use.
+/// Concurrent requests for the same project wait for a single load.
+async fn ensure_project_loaded(state: &AppState, project_name: &str) -> Result<(), AppError> {
+    state.pending_projects.load_once(project_name, || async {
+        let project_path = Path::new(&state.project_root).join(project_name);
+        let (hash_type, hash_params) = (state.hash_type, state.hash_params);
+        let (hash_store, image_store) = (state.hash_store.clone(), state.image_store.clone());
+
+        let hash_list = run_blocking(move || load_or_calc_project_hashes(&project_path, hash_type, hash_params, hash_store.as_deref(), image_store.as_deref(), false, None)
+                .map_err(|e| e.to_string()))
+            .await
+            .map_err(|e| e.to_string())??;
+
+        // an upload may have created the project meanwhile, keep its list.
+        state.project_dict.write().await
+            .entry(project_name.to_owned())
+            .or_insert(Arc::new(hash_list));
+        Ok(())
+    }).await
+        .map_err(|e| AppError::InternalError(format!("cannot load project <{}>: {}", project_name, e)))
+}
+
 
+/// Outcome of `save_image_to_project`.
+struct SavedImage {
+    /// Images in the project after saving.
+    image_count: usize,
+    /// Size of the image file as written.
+    image_size_bytes: u64,
+    /// Bits of the image's hash.
+    hash_size_bits: usize,
+    /// Time spent hashing the image.
+    hash_elapsed: Duration,
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn save_image_to_project(
     project_root: &str,
     project_name: &str, 
-    image: &DynamicImage, 
+    image: DynamicImage, 
     image_name: &str,
     hash_type: HashType,
-    project_hashes: ProjectHashDict) -> Result<(), Box<dyn Error + Send + Sync>> {
+    hash_params: HashParams,
+    project_hashes: ProjectHashDict,
+    ann_indexes: &AnnIndexes,
+    ensembles: &EnsembleIndexes,
+    hash_store: Option<SharedHashStore>) -> Result<SavedImage, Box<dyn Error + Send + Sync>> {
 
     let project_root = Path::new(project_root);
     let project_path = &project_root.join(project_name);
@@ -62,72 +258,196 @@ async fn save_image_to_project(
     let mut project_dict_wlock = _project_hashes.write().await;
 
     // check project dir
-    match project_path.is_dir() {
-        false => {
-            // create project folder
-            create_dir(project_path)
-                .map_err(|e| format!("cannot create project folder: {}", e.to_string()))?;
+    if !project_path.is_dir() {
+        // create project folder
+        create_dir(project_path)
+            .map_err(|e| format!("cannot create project folder: {}", e))?;
 
-            // create entry for our new project.
-            (*project_dict_wlock).insert(project_name.to_owned(), Vec::<ImageHashEntry>::new());
-        }
-        true => {} // continue execution
+        // create entry for our new project.
+        (*project_dict_wlock).insert(project_name.to_owned(), Arc::new(Vec::<ImageHashEntry>::new()));
     }
 
     // now add image name
     let image_target_path = project_path.join(image_name);
 
-    // [NOTE] verbose print
-    println!("[*] saving image to <{}>", image_target_path.to_string_lossy());
+    tracing::info!(path = %image_target_path.display(), "saving image");
 
     // save the image
     image.save(&image_target_path)
         .map_err(|e: image::ImageError| 
             Box::<dyn std::error::Error + Send + Sync>::from(   // I know it's tricky, but we need to cast the error
-                format!("error while saving image: {}", e.to_string())))?;
+                format!("error while saving image: {}", e)))?;
+
+    let image_size_bytes = std::fs::metadata(&image_target_path)
+        .map_err(|e| format!("cannot read saved image: {}", e))?
+        .len();
 
     // now we need to calculate, and update the global hash dict.
     // we clone this, since it will be moved to other thread
     let _image_target_path = image_target_path.clone();
+    let _project_name = project_name.to_owned();
+    let member_types = ensembles.member_types(project_name, hash_type);
+    let member_params = ensembles.params();
 
-    // we spawn a task to calculate hash.
+    // we spawn a task to calculate hash, from the image we already have
+    // in memory instead of reading the saved file back.
     let hash_calc_task = 
-        tokio::task::spawn_blocking(move || {    
+        run_blocking(move || {    
             let image_target_path = _image_target_path;
+            let hash_start = Instant::now();
 
             // we need type annotation, so we created a new varibale here to hold result.
             let res: Result<ImageHashEntry, Box<dyn Error + Send + Sync>> = 
-                fetch_cache_or_calc_hash(
+                calc_hash_from_image(
+                    &image,
                     &image_target_path, 
                     hash_type,
-                    true)
+                    hash_params)
                     .map_err(|f|f.to_string().into());  
-            res // return the result
+            let hash_elapsed = hash_start.elapsed();
+
+            // the other hash types of an ensemble, a failure only leaves
+            // the image out of that type.
+            let member_entries: Vec<ImageHashEntry> = member_types.into_iter()
+                .filter_map(|t| calc_hash_from_image(&image, &image_target_path, t, member_params).ok())
+                .collect();
+
+            // write-through, a store may replace the sidecar cache written above.
+            if let (Ok(entry), Some(store)) = (&res, &hash_store) {
+                store_hashes(store.as_ref(), &_project_name, std::slice::from_ref(entry));
+                store_hashes(store.as_ref(), &_project_name, &member_entries);
+            }
+            res.map(|entry| (entry, member_entries, hash_elapsed)) // return the result
         });
 
-    let hash_result: ImageHashEntry = hash_calc_task.await??; // now we have the calculated hash.
+    let (hash_result, member_entries, hash_elapsed) = hash_calc_task.await??; // now we have the calculated hash.
+    let hash_size_bits = hash_result.hash.bits.len();
 
     // now we can update the project hash dict.
-    let project_name = project_name;
+    let image_count = match (*project_dict_wlock).get_mut(project_name) {
+        Some(val) => {
+            ann_indexes.insert(project_name, &hash_result);
+            for member_entry in &member_entries {
+                ensembles.insert(project_name, member_entry);
+            }
+            // keep the list sorted by popcount, replace if already indexed.
+            insert_hash_entry(Arc::make_mut(val), hash_result);
+            val.len()
+        },
+        None => 0,
+    };
+
+    // All good, return the number of images in project
+    Ok(SavedImage { image_count, image_size_bytes, hash_size_bits, hash_elapsed })
+}
+
+/// `save_image_to_project` with images in object storage: the image is
+/// written as an object, and its hash goes to the hash store only.
+#[allow(clippy::too_many_arguments)]
+async fn save_image_to_object_store(
+    image_store: SharedImageStore,
+    project_root: &str,
+    project_name: &str,
+    image: DynamicImage,
+    image_name: &str,
+    hash_type: HashType,
+    hash_params: HashParams,
+    project_hashes: ProjectHashDict,
+    ann_indexes: &AnnIndexes,
+    ensembles: &EnsembleIndexes,
+    hash_store: Option<SharedHashStore>) -> Result<SavedImage, Box<dyn Error + Send + Sync>> {
+
+    let image_path = Path::new(project_root).join(project_name).join(image_name);
+
+    // held until the entry is indexed, like with project folders.
+    let mut project_dict_wlock = project_hashes.write().await;
+
+    tracing::info!(path = %image_path.display(), "saving image to image store");
+
+    let (_project_name, _image_name) = (project_name.to_owned(), image_name.to_owned());
+    let member_types = ensembles.member_types(project_name, hash_type);
+    let member_params = ensembles.params();
+    let save_task =
+        run_blocking(move || {
+            let stored_image = image_store.write(&_project_name, &_image_name, encode_image(&image, &_image_name)?)
+                .map_err(|e| format!("error while saving image: {}", e))?;
+
+            let hash_start = Instant::now();
+            let hash = calc_hash(&image, hash_type, hash_params);
+            let hash_elapsed = hash_start.elapsed();
+
+            // the other hash types of an ensemble.
+            let member_hashes: Vec<(HashType, Hash)> = member_types.into_iter()
+                .map(|t| (t, calc_hash(&image, t, member_params)))
+                .collect();
+
+            // there is no sidecar cache, without a hash store the image is
+            // downloaded and hashed again on the next load.
+            if let Some(store) = &hash_store {
+                let stored: Vec<StoredHash> = std::iter::once((hash_type, hash.clone(), hash_params))
+                    .chain(member_hashes.iter().map(|(t, member_hash)| (*t, member_hash.clone(), member_params)))
+                    .map(|(hash_type, hash, params)| StoredHash::new(_image_name.clone(), hash_type, hash, params, stored_image.modified_ns))
+                    .collect();
+                if let Err(e) = store.put(&_project_name, &stored) {
+                    tracing::warn!(project = %_project_name, error = %e, "cannot store hash");
+                }
+            }
 
-    if let Some(val) = 
-        (*project_dict_wlock).get_mut(project_name) { 
-            val.push(hash_result); 
+            let member_entries: Vec<ImageHashEntry> = member_hashes.into_iter()
+                .map(|(t, hash)| ImageHashEntry::with_params(image_path.clone(), t, hash, member_params))
+                .collect();
+            let h_entry = ImageHashEntry::with_params(image_path, hash_type, hash, hash_params);
+            Ok::<_, StoreError>((h_entry, member_entries, stored_image.size_bytes, hash_elapsed))
+        });
+
+    let (hash_result, member_entries, image_size_bytes, hash_elapsed) = save_task.await??;
+    let hash_size_bits = hash_result.hash.bits.len();
+
+    // uploading to an unknown project creates it, objects need no folder.
+    let hash_list = (*project_dict_wlock).entry(project_name.to_owned()).or_default();
+    ann_indexes.insert(project_name, &hash_result);
+    for member_entry in &member_entries {
+        ensembles.insert(project_name, member_entry);
     }
+    insert_hash_entry(Arc::make_mut(hash_list), hash_result);
 
-    Ok(()) // All good, return
+    Ok(SavedImage { image_count: hash_list.len(), image_size_bytes, hash_size_bits, hash_elapsed })
 }
 
 
-/// For a given image and specified project name, calculate
-/// the difference list across project images for provided image.
-async fn calc_sim_in_project(image: DynamicImage, project_name: &str, project_hashes: ProjectHashDict) 
-    -> Result<Vec<ImageDistEntry>, Box<dyn Error + Send + Sync>>{
-    // println!("[*] enter calculation blk");
+/// The query side of a comparison.
+enum CompareQuery {
+    /// An image to be hashed with the project's hash type, the requested
+    /// hash size if any, and variants of the image (e.g. rotations) an
+    /// entry may match instead.
+    Image(DynamicImage, Option<HashSize>, Vec<DynamicImage>),
+    /// An already calculated hash, must match the project's hash type and
+    /// size, and its parameters if given.
+    Hash(HashType, Hash, Option<HashParams>),
+}
+
+/// For a given query and specified project name, calculate
+/// the difference list across project images for provided query.
+/// 
+/// Returns the query hash along with the sorted distance list, only
+/// entries within `max_distance` if given. Projects with an ANN index
+/// only return the `top_k` closest entries it finds.
+#[allow(clippy::too_many_arguments)]
+async fn calc_sim_in_project(
+    query: CompareQuery, 
+    project_name: &str, 
+    hash_type: HashType, 
+    params: HashParams,
+    project_hashes: ProjectHashDict,
+    ann_indexes: &Arc<AnnIndexes>,
+    ensembles: &Arc<EnsembleIndexes>,
+    max_distance: Option<f64>,
+    top_k: usize,
+    metrics: &ServiceMetrics) 
+    -> Result<(Hash, Vec<ImageDistEntry>), Box<dyn Error + Send + Sync>>{
 
     let calc_start = Instant::now(); // Measure calc time
 
-    let image = image.clone();
     let project_dict_rlock = project_hashes.read().await;
 
     // first, we should check if the project exists.
@@ -135,110 +455,2747 @@ async fn calc_sim_in_project(image: DynamicImage, project_name: &str, project_ha
 
         // If exists, then calculate the distance.
         Some(hash_list) => {
-            let hash_list = hash_list.clone();
+            let approximate = ann_indexes.lookup(project_name, hash_list)
+                .map(|index| (index, top_k));
+            let is_approximate = approximate.is_some();
+            let project_hash_type = hash_list.first()
+                .map_or(hash_type, |h_ent| h_ent.hash_type);
+            let ensemble = ensembles.lookup(project_name, project_hash_type);
+            let hash_list = Arc::clone(hash_list);
+            let metrics = metrics.clone();
 
             // This involves image resizing, which is a cpu task.
             // So we put it in seprated thread. 
             let diff_calc_task = 
-                tokio::task::spawn_blocking(move || {            
-                    let res = calc_similarity_list(&image, &hash_list);  
-                    res
-                });
+                run_blocking(move || rank_query(query, &hash_list, hash_type, params, max_distance, approximate, ensemble, &metrics));
 
-            let mut diff_result = diff_calc_task.await?;
-            diff_result.sort();
+            let (query_hash, diff_result) = diff_calc_task.await??;
 
             let calc_done = calc_start.elapsed(); // Measure load time
 
-            println!("[*] calculation task done: {:.3?}", calc_done);
-            // println!("[*] leave calculation blk");
+            tracing::info!(elapsed = ?calc_done, candidates = diff_result.len(), approximate = is_approximate, "calculation task done");
             
-            Ok(diff_result)
+            Ok((query_hash, diff_result))
 
         },
         None => Err(format!("project <{}> not found in current database", project_name).into()),
     }
 }
 
+/// Hash the query if needed, then rank `hash_list` by distance to it.
+/// 
+/// This is a blocking task. `hash_type` and `params` are only used for an empty list,
+/// otherwise the project's own hash type is used so hashes are comparable.
+/// With an ANN index and a count, only that many entries found through
+/// the index are ranked, the exact scan is the fallback. With ensemble
+/// lists, image queries scan every entry and rank on the whole ensemble.
+/// Query variants are ranked the same way, each entry keeps its smallest
+/// distance to any of them.
+#[allow(clippy::too_many_arguments)]
+fn rank_query(
+    query: CompareQuery, 
+    hash_list: &[ImageHashEntry], 
+    hash_type: HashType, 
+    params: HashParams,
+    max_distance: Option<f64>,
+    approximate: Option<(SharedIndex, usize)>,
+    ensemble: Option<SharedEnsemble>,
+    metrics: &ServiceMetrics) -> Result<(Hash, Vec<ImageDistEntry>), String> {
+
+    let project_hash_type = hash_list.first()
+        .map_or(hash_type, |h_ent| h_ent.hash_type);
+
+    // the query first, then its variants.
+    let mut query_hashes: Vec<(Hash, Option<DynamicImage>)> = match query {
+        CompareQuery::Image(image, hash_size, variants) => {
+            let hash_params = resolve_hash_params(hash_size, params, project_hash_type, hash_list)?;
+            let _timer = metrics.hash_seconds
+                .with_label_values(&[&project_hash_type.to_string()])
+                .start_timer();
+            std::iter::once(image)
+                .chain(variants)
+                .map(|image| (calc_hash(&image, project_hash_type, hash_params), Some(image)))
+                .collect()
+        },
+        CompareQuery::Hash(query_hash_type, query_hash, query_params) => {
+            validate_query_hash(query_hash_type, &query_hash, query_params, hash_list)?;
+            vec![(query_hash, None)]
+        },
+    };
+
+    let _timer = metrics.compare_seconds.start_timer();
+
+    let mut diff_result = query_hashes.iter()
+        .map(|(query_hash, query_image)| 
+            rank_hash(query_hash, query_image.as_ref(), hash_list, max_distance, approximate.as_ref(), ensemble.as_ref()))
+        .reduce(merge_min_distance)
+        .unwrap_or_default();
+    diff_result.sort();
+
+    // never empty, there is always the query itself.
+    let (query_hash, _) = query_hashes.swap_remove(0);
+    Ok((query_hash, diff_result))
+}
+
+/// Distances of `hash_list` to one query hash for `rank_query`, unsorted.
+fn rank_hash(
+    query_hash: &Hash,
+    query_image: Option<&DynamicImage>,
+    hash_list: &[ImageHashEntry], 
+    max_distance: Option<f64>,
+    approximate: Option<&(SharedIndex, usize)>,
+    ensemble: Option<&SharedEnsemble>) -> Vec<ImageDistEntry> {
+
+    // a precomputed hash cannot be hashed under the other types.
+    if let Some((ensemble, image)) = ensemble.zip(query_image) {
+        let bit_length = hash_list.first().map_or(0, |h_ent| h_ent.hash.bits.len());
+        return ensemble.read().unwrap_or_else(|e| e.into_inner())
+            .combine(image, calc_similarity_list_from_hash(query_hash, hash_list), bit_length)
+            .into_iter()
+            .filter(|d| max_distance.is_none_or(|max_distance| d.distance <= max_distance))
+            .collect();
+    }
+    let approximate_result = approximate.and_then(|(index, top_k)| 
+        index.read().unwrap_or_else(|e| e.into_inner()).search(query_hash, *top_k));
+
+    // project lists are kept sorted by popcount.
+    match (approximate_result, max_distance) {
+        (Some(diff_result), max_distance) => diff_result.into_iter()
+            .filter(|d| max_distance.is_none_or(|max_distance| d.distance <= max_distance))
+            .collect(),
+        (None, Some(max_distance)) => 
+            calc_similarity_list_within_sorted(query_hash, hash_list, max_distance),
+        (None, None) => calc_similarity_list_from_hash(query_hash, hash_list),
+    }
+}
+
+/// Run one self-comparison per project, with its first image as query,
+/// so the blocking pool and the OS page cache are warm before serving.
+/// 
+/// Largest projects go first, failures are logged and skipped.
+async fn prewarm_projects(
+    project_hashes: ProjectHashDict, 
+    ann_indexes: &Arc<AnnIndexes>, 
+    ensembles: &Arc<EnsembleIndexes>,
+    hash_type: HashType, 
+    hash_params: HashParams,
+    metrics: &ServiceMetrics,
+    image_store: Option<SharedImageStore>) {
+    let mut first_images: Vec<(String, usize, PathBuf)> = project_hashes.read().await
+        .iter()
+        .filter_map(|(project_name, hash_list)| hash_list.first()
+            .map(|h_ent| (project_name.clone(), hash_list.len(), h_ent.image_name.clone())))
+        .collect();
+    first_images.sort_by_key(|(_, image_count, _)| std::cmp::Reverse(*image_count));
+
+    for (project_name, _, image_path) in first_images {
+        let prewarm_start = Instant::now();
+
+        let image_store = image_store.clone();
+        let image = match run_blocking(move || open_project_image(image_store.as_deref(), &image_path)).await {
+            Ok(Ok(image)) => image,
+            Ok(Err(e)) => {
+                tracing::warn!(project = %project_name, error = %e, "prewarm skipped, cannot open image");
+                continue;
+            },
+            Err(e) => {
+                tracing::warn!(project = %project_name, error = %e, "prewarm skipped");
+                continue;
+            },
+        };
+
+        let result = calc_sim_in_project(
+            CompareQuery::Image(image, None, Vec::new()),
+            &project_name,
+            hash_type,
+            hash_params,
+            Arc::clone(&project_hashes),
+            ann_indexes,
+            ensembles,
+            None,
+            1,
+            metrics).await;
+
+        match result {
+            Ok(_) => tracing::info!(project = %project_name, elapsed = ?prewarm_start.elapsed(), "project prewarmed"),
+            Err(e) => tracing::warn!(project = %project_name, error = %e, "prewarm failed"),
+        }
+    }
+}
+
+/// Write the current RFC 3339 timestamp to `<project_root>/heartbeat.txt`
+/// every `interval`, so external watchdogs can spot a hung process from
+/// the file's modification time.
+fn spawn_heartbeat(project_root: PathBuf, interval: Duration) {
+    let heartbeat_path = project_root.join("heartbeat.txt");
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            let timestamp = humantime::format_rfc3339_seconds(SystemTime::now());
+
+            // a failed write is logged, the service keeps running.
+            if let Err(e) = tokio::fs::write(&heartbeat_path, format!("{}\n", timestamp)).await {
+                tracing::error!(path = %heartbeat_path.display(), error = %e, "cannot write heartbeat");
+            }
+        }
+    });
+}
+
+/// Keep the index in sync with images changed in project folders by
+/// other programs, as `watcher` reports them.
+fn spawn_project_watcher(state: AppState, mut watcher: ProjectWatcher) {
+    tokio::spawn(async move {
+        loop {
+            for change in watcher.next_changes().await {
+                if let Err(e) = apply_image_change(&state, &change).await {
+                    tracing::warn!(project = %change.project_name, image = %change.image_name, error = %e, "cannot index changed image");
+                }
+            }
+        }
+    });
+}
+
+/// Index, re-index or drop an image changed on disk.
+async fn apply_image_change(state: &AppState, change: &ImageChange) -> Result<(), String> {
+    let image_path = Path::new(&state.project_root)
+        .join(&change.project_name)
+        .join(&change.image_name);
+
+    // a pending project reads its folder when it loads, only the tokens
+    // and tags of a removed image are dropped now.
+    if state.pending_projects.is_pending(&change.project_name) {
+        if !tokio::fs::try_exists(&image_path).await.unwrap_or(true) {
+            forget_removed_image(state, change).await?;
+        }
+        return Ok(());
+    }
+
+    // keep the project's hash type, so hashes stay comparable.
+    let hash_type = state.project_dict.read().await
+        .get(&change.project_name)
+        .and_then(|hash_list| hash_list.first())
+        .map_or(state.hash_type, |h_ent| h_ent.hash_type);
+
+    // our own uploads show up here too, their fresh hash is reused.
+    let _image_path = image_path.clone();
+    let _project_name = change.project_name.clone();
+    let _image_name = change.image_name.clone();
+    let hash_store = state.hash_store.clone();
+    let hash_params = state.hash_params;
+    let member_types = state.ensembles.member_types(&change.project_name, hash_type);
+    let h_entry = run_blocking(move || {
+        if !_image_path.is_file() {
+            forget_stored_images(hash_store.as_deref(), &_project_name, &[_image_name]);
+            return Ok(None);
+        }
+
+        let fetch_or_calc = |hash_type| match &hash_store {
+            Some(store) => fetch_stored_or_calc_hash(store.as_ref(), &_project_name, &_image_path, hash_type, hash_params),
+            None => {
+                let is_stale = is_cache_stale(&_image_path, hash_type);
+                fetch_cache_or_calc_hash(&_image_path, hash_type, hash_params, is_stale)
+            },
+        };
+        let h_entry = fetch_or_calc(hash_type).map_err(|e| e.to_string())?;
+
+        // the other hash types of an ensemble, a failure only leaves the
+        // image out of that type.
+        let member_entries: Vec<ImageHashEntry> = member_types.into_iter()
+            .filter_map(|t| fetch_or_calc(t).ok())
+            .collect();
+        Ok::<_, String>(Some((h_entry, member_entries)))
+    }).await.map_err(|e| e.to_string())??;
+
+    if h_entry.is_none() {
+        forget_removed_image(state, change).await?;
+    }
+
+    let mut project_dict_wlock = state.project_dict.write().await;
+
+    let hash_list = match h_entry.is_some() {
+        // images copied into a new folder make a new project.
+        true => (*project_dict_wlock).entry(change.project_name.clone()).or_default(),
+        false => match (*project_dict_wlock).get_mut(&change.project_name) {
+            Some(hash_list) => hash_list,
+            None => return Ok(()),
+        },
+    };
+
+    let is_changed = match h_entry {
+        Some((h_entry, member_entries)) => {
+            let is_indexed = hash_list.iter()
+                .any(|h_ent| h_ent.image_name == h_entry.image_name && h_ent.hash.bits == h_entry.hash.bits);
+            if !is_indexed {
+                state.ann_indexes.insert(&change.project_name, &h_entry);
+                for member_entry in &member_entries {
+                    state.ensembles.insert(&change.project_name, member_entry);
+                }
+                insert_hash_entry(Arc::make_mut(hash_list), h_entry);
+            }
+            !is_indexed
+        },
+        None => {
+            let image_count = hash_list.len();
+            state.ann_indexes.remove(&change.project_name, &image_path);
+            state.ensembles.remove(&change.project_name, &image_path);
+            Arc::make_mut(hash_list).retain(|h_ent| h_ent.image_name != image_path);
+            hash_list.len() != image_count
+        },
+    };
+
+    let image_count = hash_list.len();
+    drop(project_dict_wlock);
+
+    if is_changed {
+        tracing::info!(project = %change.project_name, image = %change.image_name, image_count, "indexed image changed on disk");
+        state.project_events.send_replace(ProjectEvent::ProjectUpdated {
+            project_name: change.project_name.clone(),
+            new_image_count: image_count,
+        });
+    }
+    Ok(())
+}
+
+/// Run a call of the deletion token store on the blocking pool.
+async fn with_tokens<T, F>(deletion_tokens: &SharedTokenStore, call: F) -> Result<T, String>
+    where F: FnOnce(&dyn TokenStore) -> Result<T, StoreError> + Send + 'static,
+          T: Send + 'static {
+
+    let deletion_tokens = Arc::clone(deletion_tokens);
+    run_blocking(move || call(deletion_tokens.as_ref()).map_err(|e| e.to_string()))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Run a call of the image tag store on the blocking pool.
+async fn with_tags<T, F>(image_tags: &SharedImageTags, call: F) -> Result<T, String>
+    where F: FnOnce(&mut ImageTags) -> Result<T, Box<dyn Error>> + Send + 'static,
+          T: Send + 'static {
+
+    let image_tags = Arc::clone(image_tags);
+    run_blocking(move || call(&mut image_tags.lock().unwrap_or_else(|e| e.into_inner())).map_err(|e| e.to_string()))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Revoke the deletion tokens and drop the tags of an image removed on disk.
+async fn forget_removed_image(state: &AppState, change: &ImageChange) -> Result<(), String> {
+    let _change = change.clone();
+    with_tokens(&state.deletion_tokens, move |tokens| tokens.revoke_image(&_change.project_name, &_change.image_name))
+        .await
+        .map_err(|e| format!("cannot revoke tokens of removed image: {}", e))?;
+
+    let _change = change.clone();
+    with_tags(&state.image_tags, move |tags| tags.remove_images(&_change.project_name, &[_change.image_name]))
+        .await
+        .map_err(|e| format!("cannot drop tags of removed image: {}", e))
+}
+
+/// Tags and modification times of the images of a project, as far as
+/// `filter` asks for them.
+async fn image_facts(state: &AppState, project_name: &str, filter: &ImageFilter) -> Result<ImageFacts, AppError> {
+    let mut facts = ImageFacts::default();
+
+    if !filter.tags.is_empty() {
+        let _project_name = project_name.to_owned();
+        facts.tags = with_tags(&state.image_tags, move |tags| Ok(tags.project(&_project_name)))
+            .await
+            .map_err(AppError::InternalError)?;
+    }
+
+    if filter.added_before.is_some() {
+        let project_path = Path::new(&state.project_root).join(project_name);
+        let image_store = state.image_store.clone();
+        facts.modified_secs = run_blocking(move || image_modified_secs(&project_path, image_store.as_deref())
+            .map_err(|e| e.to_string()))
+            .await?
+            .map_err(AppError::InternalError)?;
+    }
+
+    Ok(facts)
+}
+
+/// Drop the stored hashes of removed images. Blocks, a failure only
+/// leaves rows behind that the next load of the project drops.
+fn forget_stored_images(hash_store: Option<&dyn HashStore>, project_name: &str, image_names: &[String]) {
+    if let Some(store) = hash_store && let Err(e) = store.remove_images(project_name, image_names) {
+        tracing::warn!(project = %project_name, error = %e, "cannot drop stored hashes");
+    }
+}
+
+/// Delete the image of an entry, its file and hash caches or its object.
+/// Returns false when it was already gone.
+fn remove_project_image(image_store: Option<&dyn ImageStore>, image_path: &Path) -> Result<bool, String> {
+    match image_store {
+        Some(store) => split_image_path(image_path)
+            .and_then(|(project_name, image_name)| store.remove_image(project_name, image_name))
+            .map_err(|e| e.to_string()),
+        None => match image_path.exists() {
+            true => remove_image_files(image_path).map(|_| true).map_err(|e| e.to_string()),
+            false => Ok(false),
+        },
+    }
+}
+
+/// Decode the image of an entry, from its file or its object.
+fn open_project_image(image_store: Option<&dyn ImageStore>, image_path: &Path) -> Result<DynamicImage, String> {
+    match image_store {
+        Some(store) => open_image(store, image_path).map_err(|e| e.to_string()),
+        None => image::open(image_path).map_err(|e| e.to_string()),
+    }
+}
+
+/// Refuse operations on project folders when images are in object storage.
+fn require_project_folders(state: &AppState, operation: &str) -> Result<(), AppError> {
+    match state.image_store {
+        Some(_) => Err(AppError::BadRequest(
+            format!("{} is not supported with images in object storage", operation))),
+        None => Ok(()),
+    }
+}
+
+/// Record the size of a decoded image on the current request span.
+fn record_image_size(image: &DynamicImage) {
+    let span = tracing::Span::current();
+    span.record("width", image.width());
+    span.record("height", image.height());
+}
+
+/// Resolves on SIGINT (Ctrl-C) or SIGTERM.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %e, "cannot listen for SIGINT");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => { sigterm.recv().await; },
+            Err(e) => {
+                tracing::error!(error = %e, "cannot listen for SIGTERM");
+                std::future::pending::<()>().await;
+            },
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
+/// Write the hash caches missing on disk for every loaded project, so
+/// hashes only held in memory survive the restart.
+/// 
+/// With a hash store replacing sidecar caches there is nothing to
+/// write, every hash is stored when calculated.
+async fn persist_project_hashes(project_dict: &ProjectHashDict, hash_store: Option<&dyn HashStore>) {
+    if hash_store.is_some_and(|store| store.replaces_sidecar_caches()) {
+        return;
+    }
+
+    let project_dict = project_dict.read().await;
+
+    for (project_name, hash_list) in project_dict.iter() {
+        match write_missing_hash_caches(hash_list) {
+            Ok(0) => {},
+            Ok(written) => tracing::info!(project = %project_name, written, "wrote missing hash caches"),
+            Err(e) => tracing::error!(project = %project_name, error = %e, "cannot persist hash caches"),
+        }
+    }
+}
+
+/// Pick the `hash_type` hash parameters for a project, the parameters of
+/// existing hashes win, a requested size that differs from theirs is an
+/// error. A new project gets the configured `params`.
+fn resolve_hash_params(requested: Option<HashSize>, params: HashParams, hash_type: HashType, hash_list: &[ImageHashEntry]) 
+    -> Result<HashParams, String> {
+
+    match (requested, hash_list.first()) {
+        (Some(req), Some(h_ent)) if req != h_ent.params.hash_size => 
+            Err(format!("hash size {:?} does not match project hash size {:?}", req, h_ent.params.hash_size)),
+        (_, Some(h_ent)) => Ok(h_ent.params),
+        (req, None) => Ok(params.with_size(req.unwrap_or(params.hash_size)).for_type(hash_type)),
+    }
+}
+
+/// Map a task error to `AppError`, a panic in a blocking task is always
+/// a `ComputePanic`, anything else goes through `otherwise`.
+fn task_error(err: Box<dyn Error + Send + Sync>, otherwise: fn(String) -> AppError) -> AppError {
+    match err.downcast::<ComputePanic>() {
+        Ok(panic) => (*panic).into(),
+        Err(err) => otherwise(err.to_string()),
+    }
+}
+
+/// Parse the optional `hash_size` field of a request.
+fn parse_hash_size(hash_size: Option<&str>) -> Result<Option<HashSize>, AppError> {
+    hash_size
+        .map(|s| s.parse::<HashSize>())
+        .transpose()
+        .map_err(AppError::BadRequest)
+}
+
+/// Check that a precomputed query hash is comparable with project hashes.
+fn validate_query_hash(query_hash_type: HashType, query_hash: &Hash, query_params: Option<HashParams>, hash_list: &[ImageHashEntry]) 
+    -> Result<(), String> {
+
+    match hash_list.first() {
+        None => Ok(()), // nothing to compare against
+        Some(h_ent) if h_ent.hash_type != query_hash_type => 
+            Err(format!("hash type {:?} does not match project hash type {:?}", 
+                query_hash_type, h_ent.hash_type)),
+        Some(h_ent) if query_params.is_some_and(|p| p.for_type(query_hash_type) != h_ent.params) => 
+            Err(format!("hash parameters {:?} do not match project hash parameters {:?}", 
+                query_params, h_ent.params)),
+        Some(h_ent) if h_ent.hash.bits.len() != query_hash.bits.len() => 
+            Err(format!("hash has {} bits, project hashes have {} bits", 
+                query_hash.bits.len(), h_ent.hash.bits.len())),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Push a comparison record to history, dropping the oldest if full.
+async fn record_compare_history(
+    history: &CompareHistory,
+    project_name: &str,
+    query_hash: &Hash,
+    top_result: Option<&ImageDistEntry>) {
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let entry = CompareHistoryEntry {
+        timestamp,
+        project_name: project_name.to_owned(),
+        query_hash_hex: query_hash.to_hex(),
+        top_result_name: top_result.map(|d| dist_entry_to_api_sim_entry(d, false).image_name),
+        top_result_distance: top_result.map(|d| d.distance as f32),
+    };
+
+    let mut history_lock = history.lock().await;
+    if history_lock.len() >= COMPARE_HISTORY_CAPACITY {
+        history_lock.pop_front();
+    }
+    history_lock.push_back(entry);
+}
+
 // here's are the service handlers
 
+/// Check whether the client asked for a CSV response via `Accept`.
+fn accepts_csv(headers: &HeaderMap) -> bool {
+    headers.get(http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').any(|t| t.trim().starts_with("text/csv")))
+        .unwrap_or(false)
+}
+
+#[utoipa::path(
+    post,
+    path = "/diff",
+    tag = "compare",
+    request_body = CompareImageReq,
+    responses(
+        (status = 200, description = "closest images, or CSV with `Accept: text/csv`", body = CompareImageResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
 async fn compare_handler(
     State(state): State<AppState>, 
+    headers: HeaderMap,
     Json(payload): Json<CompareImageReq>)
-    -> Result<Json<CompareImageResp>, AppError> {
-    
-    // 1. we first get the image from data b64 string
-    let image_target 
-        = payload.get_image()
-            .map_err(|e| AppError::InternalError(e.to_string()))?;
-
-    // 2. 
-    let result = calc_sim_in_project(
-        image_target, 
-        &payload.project_name, 
-        state.project_dict
-    ).await.map_err(|e| AppError::BadRequest(e.to_string()));
-
-    match result {
-        Ok(dist_vec) => {
-
-            // [NOTE] we pick the top-3 entries from closest images, change if needed.
-            let ending_index = min(dist_vec.len(), 3);
-            let sim_vec: Vec<SimilarImageEntry> = (&dist_vec[0..ending_index])
-                .iter().map(
-                    |x| dist_entry_to_api_sim_entry(
-                        x, 
-                        payload.with_image))
-                .collect();
+    -> Result<Response<Body>, AppError> {
+    state.api_keys.authorize(&headers, ProjectAccess::Read, &payload.project_name)?;
+    ensure_project_loaded(&state, &payload.project_name).await?;
+
+    let top_k = payload.top_k.unwrap_or(state.compare_top_k);
+    let span = tracing::info_span!(
+        "compare_request",
+        project = %payload.project_name,
+        top_k,
+        width = tracing::field::Empty,
+        height = tracing::field::Empty);
+    let metrics = state.metrics.clone();
+
+    let result = async move {
+        // 1. we first get the query, either a precomputed hash or the image 
+        // from data b64 string
+        let query = match &payload.precomputed_entry {
+            Some(_) if payload.rotation_invariant || payload.flip_invariant => 
+                return Err(AppError::BadRequest("`rotation_invariant` and `flip_invariant` need a query image, a precomputed hash cannot be turned".to_owned())),
+            Some(entry) => {
+                let h_entry = api_json_to_hash_entry(entry)
+                    .map_err(|e| AppError::BadRequest(e.to_string()))?;
+                CompareQuery::Hash(h_entry.hash_type, h_entry.hash, entry.params)
+            },
+            None => {
+                let image = payload.get_image()
+                    .map_err(|e| AppError::InternalError(e.to_string()))?;
+                record_image_size(&image);
+
+                // stored images may be in another orientation.
+                let variants = query_orientations(&image, payload.rotation_invariant, payload.flip_invariant);
+                CompareQuery::Image(image, parse_hash_size(payload.hash_size.as_deref())?, variants)
+            },
+        };
+
+        // 2. 
+        let result = calc_sim_in_project(
+            query, 
+            &payload.project_name, 
+            state.hash_type,
+            state.hash_params,
+            state.project_dict,
+            &state.ann_indexes,
+            &state.ensembles,
+            payload.max_distance.map(f64::from),
+            top_k,
+            &state.metrics
+        ).await.map_err(|e| task_error(e, AppError::BadRequest));
+
+        match result {
+            Ok((query_hash, dist_vec)) => {
+
+                record_compare_history(
+                    &state.compare_history, 
+                    &payload.project_name, 
+                    &query_hash, 
+                    dist_vec.first()).await;
+
+                // projects may have fewer images than asked for.
+                let ending_index = min(dist_vec.len(), top_k);
+                let sim_vec: Vec<SimilarImageEntry> = dist_vec[0..ending_index]
+                    .iter().map(
+                        |x| dist_entry_to_api_sim_entry(
+                            x, 
+                            payload.with_image))
+                    .collect();
             
-            Ok(Json(CompareImageResp {
-            success: true,
-            message: "success".to_owned(),
-            project_name: payload.project_name,
-            compare_result: sim_vec,
-        }))},
-        Err(e) => Err(e),
+                let request_context = RequestContext {
+                    project_name: Some(payload.project_name.clone()),
+                    image_count: Some(dist_vec.len()),
+                };
+
+                // spreadsheet consumers may ask for CSV instead of JSON.
+                if accepts_csv(&headers) {
+                    return Ok((
+                        StatusCode::OK,
+                        [(http::header::CONTENT_TYPE, "text/csv")],
+                        Extension(request_context),
+                        to_csv(&sim_vec)
+                    ).into_response());
+                }
+
+                Ok((Extension(request_context), Json(CompareImageResp {
+                success: true,
+                message: "success".to_owned(),
+                project_name: payload.project_name,
+                compare_result: sim_vec,
+            })).into_response())},
+            Err(e) => Err(e),
+        }
+    }.instrument(span).await;
+
+    ServiceMetrics::count_outcome(&metrics.compares_total, &result);
+    result
+}
+
+/// Compare several query images against one project.
+/// 
+/// The project's hash list is read under one lock acquisition, and every
+/// query is decoded, hashed and ranked on its own blocking task, so the
+/// queries run in parallel.
+#[utoipa::path(
+    post,
+    path = "/diff/batch",
+    tag = "compare",
+    request_body = BatchCompareReq,
+    responses(
+        (status = 200, description = "success", body = BatchCompareResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+    ),
+)]
+async fn batch_compare_handler(
+    State(state): State<AppState>, 
+    headers: HeaderMap,
+    rate_limit_client: Option<Extension<RateLimitClient>>,
+    Json(payload): Json<BatchCompareReq>)
+    -> Result<(Extension<RequestContext>, Json<BatchCompareResp>), AppError> {
+
+    state.api_keys.authorize(&headers, ProjectAccess::Read, &payload.project_name)?;
+
+    if payload.images.is_empty() || payload.images.len() > BATCH_COMPARE_MAX_IMAGES {
+        return Err(AppError::BadRequest(
+            format!("a batch takes 1 to {} images", BATCH_COMPARE_MAX_IMAGES)));
     }
+
+    // each query costs as much as a single comparison.
+    if let Some(Extension(client)) = rate_limit_client {
+        state.rate_limiter.charge(RouteClass::Diff, &client, payload.images.len() as u32)?;
+    }
+    ensure_project_loaded(&state, &payload.project_name).await?;
+
+    let hash_size = parse_hash_size(payload.hash_size.as_deref())?;
+    let max_distance = payload.max_distance.map(f64::from);
+    let top_k = payload.top_k.unwrap_or(state.compare_top_k);
+
+    let (hash_list, ann_index, ensemble) = {
+        let project_dict_rlock = state.project_dict.read().await;
+        let hash_list = (*project_dict_rlock).get(&payload.project_name)
+            .ok_or_else(|| AppError::BadRequest(
+                format!("project <{}> not found in current database", payload.project_name)))?;
+        let project_hash_type = hash_list.first()
+            .map_or(state.hash_type, |h_ent| h_ent.hash_type);
+        (Arc::clone(hash_list), 
+            state.ann_indexes.lookup(&payload.project_name, hash_list), 
+            state.ensembles.lookup(&payload.project_name, project_hash_type))
+    };
+
+    let image_count = hash_list.len();
+    let query_count = payload.images.len();
+
+    let rank_tasks = payload.images.into_iter()
+        .map(|data| {
+            let hash_list = Arc::clone(&hash_list);
+            let approximate = ann_index.clone().map(|index| (index, top_k));
+            let ensemble = ensemble.clone();
+            let metrics = state.metrics.clone();
+            let (hash_type, hash_params) = (state.hash_type, state.hash_params);
+
+            run_blocking(move || {
+                let image = base64_to_image(&data)
+                    .map_err(|e| format!("cannot create image from b64: {}", e))?;
+                rank_query(CompareQuery::Image(image, hash_size, Vec::new()), &hash_list, hash_type, hash_params, max_distance, approximate, ensemble, &metrics)
+            })
+        });
+
+    let results: Vec<BatchCompareResult> = join_all(rank_tasks).await
+        .into_iter()
+        .map(|task_result| {
+            let rank_result = task_result.map_err(|e| e.to_string()).and_then(|r| r);
+            ServiceMetrics::count_outcome(&state.metrics.compares_total, &rank_result);
+
+            match rank_result {
+                Ok((_, dist_vec)) => BatchCompareResult {
+                    success: true,
+                    message: "success".to_owned(),
+                    compare_result: dist_vec.iter()
+                        .take(top_k)
+                        .map(|x| dist_entry_to_api_sim_entry(x, payload.with_image))
+                        .collect(),
+                },
+                Err(message) => BatchCompareResult {
+                    success: false,
+                    message,
+                    compare_result: vec![],
+                },
+            }
+        })
+        .collect();
+
+    tracing::info!(project = %payload.project_name, queries = query_count, "batch comparison done");
+
+    let request_context = RequestContext {
+        project_name: Some(payload.project_name.clone()),
+        image_count: Some(image_count),
+    };
+
+    Ok((Extension(request_context), Json(BatchCompareResp {
+        success: true,
+        message: "success".to_owned(),
+        project_name: payload.project_name,
+        results,
+    })))
 }
 
+#[utoipa::path(
+    post,
+    path = "/upload",
+    tag = "images",
+    params(("Idempotency-Key" = Option<String>, Header, description = "replay the stored response of a retried upload")),
+    request_body = UploadImageReq,
+    responses(
+        (status = 200, description = "success", body = UploadImageResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+        (status = 409, description = "an upload with the same idempotency key is still processed, or was a different request", body = AppErrorPayload),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
 async fn upload_handler(
     State(state): State<AppState>, 
+    headers: HeaderMap,
+    rate_limit_client: Option<Extension<RateLimitClient>>,
     Json(payload): Json<UploadImageReq>)
-    -> Result<Json<UploadImageResp>, AppError> {
-    
-    // 1. we first collect parameters we need
+    -> Result<(Extension<RequestContext>, Json<UploadImageResp>), AppError> {
+
+    let meta = UploadImageMeta {
+        project_name: payload.project_name,
+        image_name: payload.image_name,
+        hash_size: payload.hash_size,
+        tags: payload.tags,
+    };
+    let client = upload_client(&state, &headers, rate_limit_client);
+
+    upload_image(state, headers, client, meta, payload.data, |data| {
+        // [NOTE] conside resize to save spaces.
+        base64_to_image(&data)
+            .map_err(|e| AppError::BadRequest(format!("cannot create image from b64: {}", e)))
+    }).await
+}
+
+/// Upload handler for `multipart/form-data`, with a `metadata` part
+/// holding `UploadImageMeta` as JSON and an `image` part holding the raw
+/// image file, which saves the base64 overhead.
+#[utoipa::path(
+    post,
+    path = "/upload/multipart",
+    tag = "images",
+    request_body(content = UploadImageMeta, content_type = "multipart/form-data", description = "`metadata` part with this JSON, `image` part with the raw image file"),
+    responses(
+        (status = 200, description = "success", body = UploadImageResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+        (status = 409, description = "an upload with the same idempotency key is still processed, or was a different request", body = AppErrorPayload),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
+async fn upload_multipart_handler(
+    State(state): State<AppState>, 
+    headers: HeaderMap,
+    rate_limit_client: Option<Extension<RateLimitClient>>,
+    mut multipart: Multipart)
+    -> Result<(Extension<RequestContext>, Json<UploadImageResp>), AppError> {
+
+    let mut meta: Option<UploadImageMeta> = None;
+    let mut image_bytes: Option<Bytes> = None;
+
+    while let Some(field) = multipart.next_field().await
+        .map_err(|e| AppError::BadRequest(format!("invalid multipart body: {}", e)))? {
+
+        let field_name = field.name().unwrap_or_default().to_owned();
+        let data = field.bytes().await
+            .map_err(|e| AppError::BadRequest(format!("cannot read part <{}>: {}", field_name, e)))?;
+
+        match field_name.as_str() {
+            "metadata" => meta = Some(serde_json::from_slice(&data)
+                .map_err(|e| AppError::BadRequest(format!("invalid metadata part: {}", e)))?),
+            "image" => image_bytes = Some(data),
+            _ => return Err(AppError::BadRequest(format!("unexpected part <{}>", field_name))),
+        }
+    }
+
+    let meta = meta.ok_or_else(|| AppError::BadRequest("missing metadata part".to_owned()))?;
+    let image_bytes = image_bytes.ok_or_else(|| AppError::BadRequest("missing image part".to_owned()))?;
+    let client = upload_client(&state, &headers, rate_limit_client);
+
+    upload_image(state, headers, client, meta, image_bytes, |image_bytes| {
+        bytes_to_image(&image_bytes)
+            .map_err(|e| AppError::BadRequest(format!("cannot decode image: {}", e)))
+    }).await
+}
+
+/// Upload handler taking the image file as the request body, e.g.
+/// `PUT /projects/cats/images/tom.png` with `Content-Type: image/png`.
+/// 
+/// The format is detected from magic bytes, a declared image type must
+/// agree with it.
+#[utoipa::path(
+    put,
+    path = "/projects/{project_name}/images/{image_name}",
+    tag = "images",
+    params(("project_name" = String, Path, description = "project name"), ("image_name" = String, Path, description = "image file name"), RawUploadQuery),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream", description = "raw image file, or a matching `image/*` type"),
+    responses(
+        (status = 200, description = "success", body = UploadImageResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+        (status = 409, description = "an upload with the same idempotency key is still processed, or was a different request", body = AppErrorPayload),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
+async fn upload_raw_handler(
+    State(state): State<AppState>, 
+    PathParam((project_name, image_name)): PathParam<(String, String)>,
+    Query(query): Query<RawUploadQuery>,
+    headers: HeaderMap,
+    rate_limit_client: Option<Extension<RateLimitClient>>,
+    body: Bytes)
+    -> Result<(Extension<RequestContext>, Json<UploadImageResp>), AppError> {
+
+    let content_type = headers.get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or_default().trim().to_ascii_lowercase());
+
+    if let Some(content_type) = content_type.as_deref() {
+        check_raw_content_type(content_type, &body)?;
+    }
+
+    let meta = UploadImageMeta {
+        project_name,
+        image_name,
+        tags: query.tag_list(),
+        hash_size: query.hash_size,
+    };
+    let client = upload_client(&state, &headers, rate_limit_client);
+
+    upload_image(state, headers, client, meta, body, |body| {
+        bytes_to_image(&body)
+            .map_err(|e| AppError::BadRequest(format!("cannot decode image: {}", e)))
+    }).await
+}
+
+/// Check a raw upload's `Content-Type` against the detected format.
+/// 
+/// `application/octet-stream` leaves detection to the magic bytes only.
+fn check_raw_content_type(content_type: &str, body: &[u8]) -> Result<(), AppError> {
+    if content_type == "application/octet-stream" {
+        return Ok(());
+    }
+
+    let declared = image::ImageFormat::from_mime_type(content_type)
+        .ok_or_else(|| AppError::BadRequest(
+            format!("unsupported content type <{}>, expected an image type", content_type)))?;
+
+    let detected = image::guess_format(body)
+        .map_err(|e| AppError::BadRequest(format!("cannot detect image format: {}", e)))?;
+
+    match declared == detected {
+        true => Ok(()),
+        false => Err(AppError::BadRequest(
+            format!("content type <{}> does not match detected format {:?}", content_type, detected))),
+    }
+}
+
+/// Client an upload's idempotency key is scoped to, as counted by the
+/// rate limiter, or its API key when the limiter did not run.
+fn upload_client(state: &AppState, headers: &HeaderMap, rate_limit_client: Option<Extension<RateLimitClient>>)
+    -> RateLimitClient {
+
+    match rate_limit_client {
+        Some(Extension(client)) => client,
+        None => state.rate_limiter.client_of(headers, None),
+    }
+}
+
+/// Save and index an uploaded image, shared by every upload handler.
+/// 
+/// `decode_image` turns `body` into the image, it only runs when the
+/// request is not a replay of an earlier one with the same idempotency
+/// key, which must come from the same `client` with the same request.
+async fn upload_image<B, F>(
+    state: AppState, 
+    headers: HeaderMap,
+    client: RateLimitClient,
+    payload: UploadImageMeta,
+    body: B,
+    decode_image: F)
+    -> Result<(Extension<RequestContext>, Json<UploadImageResp>), AppError> 
+    where B: AsRef<[u8]> + Send, F: FnOnce(B) -> Result<DynamicImage, AppError> + Send {
+    // the name is joined to the project folder, it must stay inside.
+    validate_image_name(&payload.image_name)
+        .map_err(AppError::BadRequest)?;
+    state.api_keys.authorize(&headers, ProjectAccess::Write, &payload.project_name)?;
+    ensure_project_loaded(&state, &payload.project_name).await?;
+
+    let span = tracing::info_span!(
+        "upload_request",
+        project = %payload.project_name,
+        image = %payload.image_name,
+        width = tracing::field::Empty,
+        height = tracing::field::Empty,
+        hash_ms = tracing::field::Empty);
+    let metrics = state.metrics.clone();
+
+    let result = async move {
+        // 1. we first collect parameters we need
+
+        let project_root = state.project_root;
+        let project_name = payload.project_name;
+        let image_name = payload.image_name;
+
+        // a retry of an already processed upload gets the stored response,
+        // only if it is the same upload from the same client.
+        let idempotency_key: Option<ScopedKey> = headers.get(IDEMPOTENCY_KEY_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| ScopedKey {
+                client: client.as_str().to_owned(),
+                project_name: project_name.clone(),
+                key: v.to_owned(),
+            });
+        let fingerprint = request_fingerprint(&[
+            project_name.as_bytes(),
+            image_name.as_bytes(),
+            payload.hash_size.as_deref().unwrap_or_default().as_bytes(),
+            payload.tags.join(",").as_bytes(),
+            body.as_ref(),
+        ]);
+
+        // the key stays reserved until the response is stored, so a retry
+        // racing the first attempt never uploads twice.
+        let _reservation = match &idempotency_key {
+            Some(key) => {
+                let claim = state.upload_replays.lock().await.claim(key, fingerprint);
+
+                match claim {
+                    Claim::Replay(upload_resp) => {
+                        tracing::info!("replaying stored response of idempotency key");
+
+                        let request_context = RequestContext {
+                            project_name: Some(project_name),
+                            image_count: None,
+                        };
+                        return Ok((Extension(request_context), Json(upload_resp)));
+                    },
+                    Claim::InFlight => return Err(AppError::Conflict(
+                        "an upload with this idempotency key is still processed, retry later".to_owned())),
+                    Claim::Mismatch => return Err(AppError::Conflict(
+                        "this idempotency key was used for a different upload".to_owned())),
+                    Claim::Reserved => Some(Reservation::new(Arc::clone(&state.upload_replays), key.clone())),
+                }
+            },
+            None => None,
+        };
+
+        let image = decode_image(body)?;
+        record_image_size(&image);
+        let project_dict = Arc::clone(&state.project_dict);
+
+        // the project keeps the size of its existing hashes.
+        let requested_size = parse_hash_size(payload.hash_size.as_deref())?;
+        let hash_params = {
+            let project_dict_rlock = project_dict.read().await;
+            let hash_list = match project_dict_rlock.get(&project_name) {
+                Some(hash_list) => hash_list.as_slice(),
+                None => {
+                    // uploading to an unknown project creates it.
+                    state.project_name_policy.check(&project_name)
+                        .map_err(AppError::BadRequest)?;
+                    &[]
+                },
+            };
+            resolve_hash_params(requested_size, state.hash_params, state.hash_type, hash_list)
+                .map_err(AppError::BadRequest)?
+        };
+
+        tracing::info!(?hash_params, "received upload request");
+
+        // do saving image, return 500 if failed
+        let saved = match state.image_store.clone() {
+            Some(image_store) => save_image_to_object_store(
+                image_store,
+                &project_root,
+                &project_name,
+                image,
+                &image_name,
+                state.hash_type,
+                hash_params,
+                project_dict,
+                &state.ann_indexes,
+                &state.ensembles,
+                state.hash_store.clone()
+            ).await,
+            None => save_image_to_project(
+                &project_root,
+                &project_name,
+                image,
+                &image_name,
+                state.hash_type,
+                hash_params,
+                project_dict,
+                &state.ann_indexes,
+                &state.ensembles,
+                state.hash_store.clone()
+            ).await,
+        }.map_err(|e| task_error(e, AppError::InternalError))?;
+
+        state.metrics.hash_seconds
+            .with_label_values(&[&state.hash_type.to_string()])
+            .observe(saved.hash_elapsed.as_secs_f64());
+        tracing::Span::current().record("hash_ms", saved.hash_elapsed.as_secs_f64() * 1000.0);
+        tracing::info!(image_count = saved.image_count, "image saved and indexed");
+
+        // notify `/events` subscribers, fine if nobody is listening.
+        state.project_events.send_replace(ProjectEvent::ProjectUpdated {
+            project_name: project_name.clone(),
+            new_image_count: saved.image_count,
+        });
+
+        let (_project_name, _image_name) = (project_name.clone(), image_name.clone());
+        let token = with_tokens(&state.deletion_tokens, move |tokens| tokens.issue(&_project_name, &_image_name))
+            .await
+            .map_err(|e| AppError::InternalError(
+                format!("image saved, but cannot issue deletion token: {}", e)))?;
+
+        let (_project_name, _image_name) = (project_name.clone(), image_name.clone());
+        with_tags(&state.image_tags, move |tags| tags.set(&_project_name, &_image_name, payload.tags))
+            .await
+            .map_err(|e| AppError::InternalError(
+                format!("image saved, but cannot store its tags: {}", e)))?;
+
+        let request_context = RequestContext {
+            project_name: Some(project_name),
+            image_count: Some(saved.image_count),
+        };
+
+        let upload_resp = UploadImageResp {
+            success: true,
+            message: "image uploaded and indexed successfully".to_owned(),
+            token,
+            image_size_bytes: saved.image_size_bytes,
+            hash_size_bits: saved.hash_size_bits,
+        };
+
+        if let Some(key) = idempotency_key {
+            state.upload_replays.lock().await.insert(key, fingerprint, upload_resp.clone());
+        }
+
+        Ok((Extension(request_context), Json(upload_resp)))
+    }.instrument(span).await;
+
+    ServiceMetrics::count_outcome(&metrics.uploads_total, &result);
+    result
+}
+
+
+/// Delete the image an upload's deletion token refers to, along with
+/// its hash caches and index entry.
+#[utoipa::path(
+    post,
+    path = "/remove",
+    tag = "images",
+    request_body = RemoveImageReq,
+    responses(
+        (status = 200, description = "success", body = RemoveImageResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
+async fn remove_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<RemoveImageReq>)
+    -> Result<(Extension<RequestContext>, Json<RemoveImageResp>), AppError> {
+
+    let token = payload.token.clone();
+    let target = with_tokens(&state.deletion_tokens, move |tokens| tokens.get(&token))
+        .await
+        .map_err(|e| AppError::InternalError(format!("cannot look up token: {}", e)))?
+        .ok_or_else(|| AppError::BadRequest("token expired or invalid".to_owned()))?;
+
+    state.api_keys.authorize(&headers, ProjectAccess::Write, &target.project_name)?;
+
+    let image_path = Path::new(&state.project_root)
+        .join(&target.project_name)
+        .join(&target.image_name);
+
+    // hold the write lock while deleting, so no upload of the same
+    // name slips in between removing the file and the index entry.
+    let mut project_dict_wlock = state.project_dict.write().await;
+
+    let _image_path = image_path.clone();
+    let _target = target.clone();
+    let (hash_store, image_store) = (state.hash_store.clone(), state.image_store.clone());
+    let removed = run_blocking(move || {
+        forget_stored_images(hash_store.as_deref(), &_target.project_name, &[_target.image_name]);
+        remove_project_image(image_store.as_deref(), &_image_path)
+    }).await?
+        .map_err(AppError::InternalError)?;
+
+    let image_count = match (*project_dict_wlock).get_mut(&target.project_name) {
+        Some(hash_list) => {
+            state.ann_indexes.remove(&target.project_name, &image_path);
+            state.ensembles.remove(&target.project_name, &image_path);
+            Arc::make_mut(hash_list).retain(|entry| entry.image_name != image_path);
+            Some(hash_list.len())
+        },
+        None => None,
+    };
+    drop(project_dict_wlock);
+
+    let _target = target.clone();
+    with_tokens(&state.deletion_tokens, move |tokens| tokens.revoke_image(&_target.project_name, &_target.image_name))
+        .await
+        .map_err(|e| AppError::InternalError(
+            format!("image removed, but cannot revoke its tokens: {}", e)))?;
+
+    let _target = target.clone();
+    with_tags(&state.image_tags, move |tags| tags.remove_images(&_target.project_name, &[_target.image_name]))
+        .await
+        .map_err(|e| AppError::InternalError(
+            format!("image removed, but cannot drop its tags: {}", e)))?;
+
+    if !removed {
+        return Err(AppError::BadRequest("image already removed".to_owned()));
+    }
+
+    tracing::info!(project = %target.project_name, image = %target.image_name, "removed image");
+
+    if let Some(new_image_count) = image_count {
+        state.project_events.send_replace(ProjectEvent::ProjectUpdated {
+            project_name: target.project_name.clone(),
+            new_image_count,
+        });
+    }
+
+    let request_context = RequestContext {
+        project_name: Some(target.project_name),
+        image_count,
+    };
+
+    Ok((Extension(request_context), Json(RemoveImageResp {
+        success: true,
+        message: "image removed".to_owned(),
+    })))
+}
+
+
+/// Create project `destination_name` from images copied by `copy_images`,
+/// then index it and register it in the database.
+/// 
+/// `copy_images` runs on the blocking pool with the new project folder,
+/// the folder is removed again if copying or indexing fails.
+/// Returns the number of indexed images.
+async fn create_project_from<F>(state: &AppState, destination_name: &str, copy_images: F) 
+    -> Result<usize, AppError> 
+    where F: FnOnce(&Path) -> Result<usize, Box<dyn Error>> + Send + 'static {
+
+    state.project_name_policy.check(destination_name)
+        .map_err(AppError::BadRequest)?;
+
+    if state.project_dict.read().await.contains_key(destination_name) {
+        return Err(AppError::BadRequest(
+            format!("project <{}> already exists", destination_name)));
+    }
+
+    let dst_path = Path::new(&state.project_root).join(destination_name);
+    let (hash_type, hash_params) = (state.hash_type, state.hash_params);
+    let hash_store = state.hash_store.clone();
+
+    // `create_dir` fails if the folder exists, so concurrent copies
+    // to the same destination cannot both proceed.
+    create_dir(&dst_path)
+        .map_err(|e| AppError::BadRequest(
+            format!("cannot create project <{}>: {}", destination_name, e)))?;
+
+    // copying and hashing are blocking tasks.
+    let copy_task = 
+        run_blocking(move || {
+            let res: Result<Vec<ImageHashEntry>, String> = 
+                copy_images(&dst_path)
+                    .and_then(|_| load_or_calc_project_hashes(&dst_path, hash_type, hash_params, hash_store.as_deref(), None, false, None))
+                    .map_err(|e| e.to_string());
+
+            // don't leave a half-copied project behind.
+            if res.is_err() {
+                remove_dir_all(&dst_path).ok();
+            }
+            res
+        });
+
+    let hash_list = copy_task.await?
+        .map_err(AppError::InternalError)?;
+
+    let image_count = hash_list.len();
+
+    let mut project_dict_wlock = state.project_dict.write().await;
+    state.ann_indexes.invalidate(destination_name);
+    state.ensembles.invalidate(destination_name);
+    (*project_dict_wlock).insert(destination_name.to_owned(), Arc::new(hash_list));
+    drop(project_dict_wlock);
+
+    state.project_events.send_replace(ProjectEvent::ProjectUpdated {
+        project_name: destination_name.to_owned(),
+        new_image_count: image_count,
+    });
+
+    Ok(image_count)
+}
+
+/// `create_project_from` without images, for projects in object storage.
+async fn create_object_project(state: &AppState, image_store: SharedImageStore, project_name: &str) 
+    -> Result<usize, AppError> {
+
+    state.project_name_policy.check(project_name)
+        .map_err(AppError::BadRequest)?;
+
+    // the lock keeps concurrent creations of the same name apart.
+    let mut project_dict_wlock = state.project_dict.write().await;
+    if project_dict_wlock.contains_key(project_name) {
+        return Err(AppError::BadRequest(
+            format!("project <{}> already exists", project_name)));
+    }
+
+    let _project_name = project_name.to_owned();
+    run_blocking(move || image_store.create_project(&_project_name).map_err(|e| e.to_string()))
+        .await?
+        .map_err(|e| AppError::InternalError(
+            format!("cannot create project <{}>: {}", project_name, e)))?;
+
+    (*project_dict_wlock).insert(project_name.to_owned(), Arc::new(Vec::new()));
+    drop(project_dict_wlock);
+
+    state.project_events.send_replace(ProjectEvent::ProjectUpdated {
+        project_name: project_name.to_owned(),
+        new_image_count: 0,
+    });
+
+    Ok(0)
+}
+
+/// Create an empty project, so uploads to it do not depend on implicit
+/// project creation.
+#[utoipa::path(
+    post,
+    path = "/projects",
+    tag = "projects",
+    request_body = CreateProjectReq,
+    responses(
+        (status = 200, description = "success", body = CreateProjectResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+    ),
+)]
+async fn create_project_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateProjectReq>)
+    -> Result<(Extension<RequestContext>, Json<CreateProjectResp>), AppError> {
 
-    let project_root = state.project_root;
     let project_name = payload.project_name;
-    let image_name = payload.image_name;
-
-    // [NOTE] conside resize to save spaces.
-    let image = base64_to_image(&payload.data)
-                .map_err(|e| format!("cannot create image from b64: {}", e.to_string()))
-                .map_err(|e| AppError::BadRequest(e.to_string()))?;
-    let project_dict = Arc::clone(&state.project_dict);
-    
-
-    println!("[*] received upload request on <{}>", project_name); // [NOTE] verbose
-
-    // do saving image, return 500 if failed
-    save_image_to_project(
-        &project_root,
-        &project_name,
-        &image,
-        &image_name,
-        HashType::PHASH, // [NOTE] [WARN] change here later
-        project_dict
-    ).await.map_err(|e| AppError::InternalError(e.to_string()))?;
-
-    Ok(Json(UploadImageResp {
+    state.api_keys.authorize(&headers, ProjectAccess::Write, &project_name)?;
+
+    tracing::info!(project = %project_name, "creating project");
+
+    // nothing to copy, this only creates the folder and registers it.
+    let image_count = match state.image_store.clone() {
+        Some(image_store) => create_object_project(&state, image_store, &project_name).await?,
+        None => create_project_from(&state, &project_name, |_| Ok(0)).await?,
+    };
+
+    let request_context = RequestContext {
+        project_name: Some(project_name.clone()),
+        image_count: Some(image_count),
+    };
+
+    Ok((Extension(request_context), Json(CreateProjectResp {
+        success: true,
+        message: "project created".to_owned(),
+        project_name,
+    })))
+}
+
+/// Delete a project folder with its images and hash caches, and drop it
+/// from the database.
+/// 
+/// The write lock is held until the folder is gone, so no upload can
+/// recreate the project halfway.
+#[utoipa::path(
+    delete,
+    path = "/projects/{project_name}",
+    tag = "projects",
+    params(("project_name" = String, Path, description = "project name")),
+    responses(
+        (status = 200, description = "success", body = DeleteProjectResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
+async fn delete_project_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    headers: HeaderMap)
+    -> Result<(Extension<RequestContext>, Json<DeleteProjectResp>), AppError> {
+
+    state.api_keys.authorize(&headers, ProjectAccess::Write, &project_name)?;
+    ensure_project_loaded(&state, &project_name).await?;
+    let mut project_dict_wlock = state.project_dict.write().await;
+
+    if !(*project_dict_wlock).contains_key(&project_name) {
+        return Err(AppError::BadRequest(
+            format!("project <{}> not found in current database", project_name)));
+    }
+
+    tracing::info!(project = %project_name, "deleting project");
+
+    let project_path = Path::new(&state.project_root).join(&project_name);
+    let _project_name = project_name.clone();
+    let (hash_store, image_store) = (state.hash_store.clone(), state.image_store.clone());
+    let removed = run_blocking(move || {
+            if let Some(store) = &hash_store && let Err(e) = store.remove_project(&_project_name) {
+                tracing::warn!(project = %_project_name, error = %e, "cannot drop stored hashes");
+            }
+            match image_store {
+                Some(store) => store.remove_project(&_project_name)
+                    .map(|image_files| RemovedProjectFiles { image_files, ..Default::default() })
+                    .map_err(|e| e.to_string()),
+                None => remove_project_files(&project_path).map_err(|e| e.to_string()),
+            }
+        })
+        .await?
+        .map_err(AppError::InternalError)?;
+
+    (*project_dict_wlock).remove(&project_name);
+    state.ann_indexes.invalidate(&project_name);
+    state.ensembles.invalidate(&project_name);
+    drop(project_dict_wlock);
+
+    let _project_name = project_name.clone();
+    with_tokens(&state.deletion_tokens, move |tokens| tokens.revoke_project(&_project_name))
+        .await
+        .map_err(|e| AppError::InternalError(
+            format!("project deleted, but cannot revoke its tokens: {}", e)))?;
+
+    let _project_name = project_name.clone();
+    with_tags(&state.image_tags, move |tags| tags.remove_project(&_project_name))
+        .await
+        .map_err(|e| AppError::InternalError(
+            format!("project deleted, but cannot drop its tags: {}", e)))?;
+
+    state.project_events.send_replace(ProjectEvent::ProjectDeleted {
+        project_name: project_name.clone(),
+    });
+
+    let request_context = RequestContext {
+        project_name: Some(project_name.clone()),
+        image_count: None,
+    };
+
+    Ok((Extension(request_context), Json(DeleteProjectResp {
+        success: true,
+        message: format!("project deleted, {} images removed", removed.image_files),
+        project_name,
+        removed_images: removed.image_files,
+        removed_cache_files: removed.cache_files,
+        removed_other_files: removed.other_files,
+    })))
+}
+
+/// Rename a project folder and its database entry.
+/// 
+/// Both happen under the write lock, so comparisons see either the old
+/// or the new name, never a project whose images point to a missing folder.
+#[utoipa::path(
+    post,
+    path = "/projects/{project_name}/rename",
+    tag = "projects",
+    params(("project_name" = String, Path, description = "project name")),
+    request_body = RenameProjectReq,
+    responses(
+        (status = 200, description = "success", body = RenameProjectResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
+async fn rename_project_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    headers: HeaderMap,
+    Json(payload): Json<RenameProjectReq>)
+    -> Result<(Extension<RequestContext>, Json<RenameProjectResp>), AppError> {
+
+    let new_name = payload.new_name;
+    state.api_keys.authorize(&headers, ProjectAccess::Write, &project_name)?;
+    state.api_keys.authorize(&headers, ProjectAccess::Write, &new_name)?;
+
+    require_project_folders(&state, "renaming projects")?;
+    state.project_name_policy.check(&new_name)
+        .map_err(AppError::BadRequest)?;
+
+    ensure_project_loaded(&state, &project_name).await?;
+    let mut project_dict_wlock = state.project_dict.write().await;
+
+    if !(*project_dict_wlock).contains_key(&project_name) {
+        return Err(AppError::BadRequest(
+            format!("project <{}> not found in current database", project_name)));
+    }
+
+    if (*project_dict_wlock).contains_key(&new_name) {
+        return Err(AppError::BadRequest(
+            format!("project <{}> already exists", new_name)));
+    }
+
+    let src_path = Path::new(&state.project_root).join(&project_name);
+    let dst_path = Path::new(&state.project_root).join(&new_name);
+
+    // `rename` would replace an empty folder, refuse any existing one.
+    if dst_path.exists() {
+        return Err(AppError::BadRequest(
+            format!("cannot rename to <{}>: folder already exists", new_name)));
+    }
+
+    tracing::info!(project = %project_name, new_name = %new_name, "renaming project");
+
+    std::fs::rename(&src_path, &dst_path)
+        .map_err(|e| AppError::InternalError(
+            format!("cannot rename project folder: {}", e)))?;
+
+    if let Some(store) = state.hash_store.clone() {
+        let (_project_name, _new_name) = (project_name.clone(), new_name.clone());
+        // without the move, the project is hashed again on its next load.
+        let renamed = run_blocking(move || store.rename_project(&_project_name, &_new_name).map_err(|e| e.to_string()))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|r| r);
+        if let Err(e) = renamed {
+            tracing::warn!(project = %project_name, error = %e, "cannot rename stored hashes");
+        }
+    }
+
+    // entries hold full image paths, move them to the new folder.
+    let hash_list: Vec<ImageHashEntry> = (*project_dict_wlock).remove(&project_name)
+        .map(Arc::unwrap_or_clone)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|h_ent| h_ent.in_project(&dst_path))
+        .collect();
+
+    let image_count = hash_list.len();
+    (*project_dict_wlock).insert(new_name.clone(), Arc::new(hash_list));
+    // indexed paths are in the old folder, index the project anew.
+    state.ann_indexes.invalidate(&project_name);
+    state.ensembles.invalidate(&project_name);
+    state.ann_indexes.invalidate(&new_name);
+    state.ensembles.invalidate(&new_name);
+    drop(project_dict_wlock);
+
+    let (_project_name, _new_name) = (project_name.clone(), new_name.clone());
+    with_tokens(&state.deletion_tokens, move |tokens| tokens.rename_project(&_project_name, &_new_name))
+        .await
+        .map_err(|e| AppError::InternalError(
+            format!("project renamed, but cannot update its tokens: {}", e)))?;
+
+    let (_project_name, _new_name) = (project_name.clone(), new_name.clone());
+    with_tags(&state.image_tags, move |tags| tags.rename_project(&_project_name, &_new_name))
+        .await
+        .map_err(|e| AppError::InternalError(
+            format!("project renamed, but cannot move its tags: {}", e)))?;
+
+    state.project_events.send_replace(ProjectEvent::ProjectRenamed {
+        project_name,
+        new_project_name: new_name.clone(),
+    });
+
+    let request_context = RequestContext {
+        project_name: Some(new_name.clone()),
+        image_count: Some(image_count),
+    };
+
+    Ok((Extension(request_context), Json(RenameProjectResp {
+        success: true,
+        message: "project renamed".to_owned(),
+        project_name: new_name,
+        image_count,
+    })))
+}
+
+/// Clone a project's images under a new project name, then index it.
+/// 
+/// The source project is only read from disk, so it stays available
+/// for comparison while the copy is running.
+#[utoipa::path(
+    post,
+    path = "/projects/{project_name}/copy",
+    tag = "projects",
+    params(("project_name" = String, Path, description = "project name")),
+    request_body = CopyProjectReq,
+    responses(
+        (status = 200, description = "success", body = CopyProjectResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
+async fn copy_project_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    headers: HeaderMap,
+    Json(payload): Json<CopyProjectReq>)
+    -> Result<(Extension<RequestContext>, Json<CopyProjectResp>), AppError> {
+
+    let destination_name = payload.destination_name;
+    state.api_keys.authorize(&headers, ProjectAccess::Read, &project_name)?;
+    state.api_keys.authorize(&headers, ProjectAccess::Write, &destination_name)?;
+
+    require_project_folders(&state, "copying projects")?;
+    ensure_project_loaded(&state, &project_name).await?;
+    if !state.project_dict.read().await.contains_key(&project_name) {
+        return Err(AppError::BadRequest(
+            format!("project <{}> not found in current database", project_name)));
+    }
+
+    let src_path = Path::new(&state.project_root).join(&project_name);
+
+    tracing::info!(project = %project_name, destination = %destination_name, "copying project");
+
+    let image_count = create_project_from(
+        &state, 
+        &destination_name, 
+        move |dst_path| copy_project_images(&src_path, dst_path)).await?;
+
+    let request_context = RequestContext {
+        project_name: Some(destination_name.clone()),
+        image_count: Some(image_count),
+    };
+
+    Ok((Extension(request_context), Json(CopyProjectResp {
+        success: true,
+        message: "project copied and indexed successfully".to_owned(),
+        project_name: destination_name,
+        image_count,
+    })))
+}
+
+/// Delete every image of a project that matches a filter, or only list
+/// them on dry run.
+/// 
+/// The project stays write-locked for the whole operation, so comparisons
+/// never see a half-deleted selection.
+#[utoipa::path(
+    delete,
+    path = "/projects/{project_name}/images",
+    tag = "images",
+    params(("project_name" = String, Path, description = "project name")),
+    request_body = DeleteImagesReq,
+    responses(
+        (status = 200, description = "success", body = DeleteImagesResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
+async fn delete_images_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    headers: HeaderMap,
+    Json(payload): Json<DeleteImagesReq>)
+    -> Result<(Extension<RequestContext>, Json<DeleteImagesResp>), AppError> {
+
+    state.api_keys.authorize(&headers, ProjectAccess::Write, &project_name)?;
+
+    // an empty filter would wipe the project, ask for it explicitly.
+    if payload.filter.is_empty() {
+        return Err(AppError::BadRequest("filter must set at least one criterion".to_owned()));
+    }
+
+    ensure_project_loaded(&state, &project_name).await?;
+    let facts = image_facts(&state, &project_name, &payload.filter).await?;
+    let mut project_dict_wlock = state.project_dict.write().await;
+
+    let hash_list = (*project_dict_wlock).get_mut(&project_name)
+        .ok_or_else(|| AppError::BadRequest(
+            format!("project <{}> not found in current database", project_name)))?;
+
+    let image_paths: Vec<PathBuf> = filter_hash_entries(hash_list, &payload.filter, &facts)
+        .map_err(AppError::BadRequest)?
+        .into_iter()
+        .map(|entry| entry.image_name.clone())
+        .collect();
+
+    let removed_paths: Vec<PathBuf> = match payload.dry_run {
+        true => image_paths,
+        false => {
+            tracing::info!(project = %project_name, images = image_paths.len(), "deleting images");
+
+            // removing files is a blocking task, keep going on failure so
+            // the index matches what is left on disk.
+            let _project_name = project_name.clone();
+            let (hash_store, image_store) = (state.hash_store.clone(), state.image_store.clone());
+            let remove_task = run_blocking(move || {
+                let (removed, failed): (Vec<PathBuf>, Vec<PathBuf>) = image_paths.into_iter()
+                    .partition(|image_path| remove_project_image(image_store.as_deref(), image_path).is_ok_and(|removed| removed));
+
+                let removed_names: Vec<String> = removed.iter()
+                    .filter_map(|image_path| Some(image_path.file_name()?.to_string_lossy().into_owned()))
+                    .collect();
+                forget_stored_images(hash_store.as_deref(), &_project_name, &removed_names);
+                (removed, removed_names, failed)
+            });
+
+            let (removed, removed_names, failed) = remove_task.await?;
+
+            for image_path in &removed {
+                state.ann_indexes.remove(&project_name, image_path);
+                state.ensembles.remove(&project_name, image_path);
+            }
+            let removed_set: HashSet<&Path> = removed.iter().map(PathBuf::as_path).collect();
+            Arc::make_mut(hash_list).retain(|entry| !removed_set.contains(entry.image_name.as_path()));
+
+            state.project_events.send_replace(ProjectEvent::ProjectUpdated {
+                project_name: project_name.clone(),
+                new_image_count: hash_list.len(),
+            });
+
+            let (_project_name, _removed_names) = (project_name.clone(), removed_names.clone());
+            let revoked = with_tokens(&state.deletion_tokens, move |tokens| _removed_names.iter()
+                .try_for_each(|image_name| tokens.revoke_image(&_project_name, image_name)))
+                .await;
+            let _project_name = project_name.clone();
+            let untagged = with_tags(&state.image_tags, move |tags| tags.remove_images(&_project_name, &removed_names))
+                .await;
+
+            if !failed.is_empty() {
+                return Err(AppError::InternalError(
+                    format!("deleted {} images, cannot delete {} images", removed.len(), failed.len())));
+            }
+            revoked.map_err(|e| AppError::InternalError(
+                format!("images removed, but cannot revoke their tokens: {}", e)))?;
+            untagged.map_err(|e| AppError::InternalError(
+                format!("images removed, but cannot drop their tags: {}", e)))?;
+            removed
+        },
+    };
+
+    let image_count = hash_list.len();
+    drop(project_dict_wlock);
+
+    let deleted: Vec<String> = removed_paths.iter()
+        .map(|image_path| image_path.file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default())
+        .collect();
+
+    let request_context = RequestContext {
+        project_name: Some(project_name.clone()),
+        image_count: Some(image_count),
+    };
+
+    Ok((Extension(request_context), Json(DeleteImagesResp {
         success: true,
-        message: "image uploaded and indexed successfully".to_owned(),
-        token: "dummy-deletion-token".to_string(), // [WARN] [NOTE] change later to proper uuid
+        message: match payload.dry_run {
+            true => format!("{} images would be deleted", deleted.len()),
+            false => format!("{} images deleted", deleted.len()),
+        },
+        project_name,
+        dry_run: payload.dry_run,
+        deleted,
+    })))
+}
+
+/// Create a new project from the images of a project that match a filter.
+#[utoipa::path(
+    post,
+    path = "/projects/{project_name}/clone-subset",
+    tag = "projects",
+    params(("project_name" = String, Path, description = "project name")),
+    request_body = CloneSubsetReq,
+    responses(
+        (status = 200, description = "success", body = CopyProjectResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
+async fn clone_subset_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    headers: HeaderMap,
+    Json(payload): Json<CloneSubsetReq>)
+    -> Result<(Extension<RequestContext>, Json<CopyProjectResp>), AppError> {
+
+    let destination_name = payload.destination;
+    state.api_keys.authorize(&headers, ProjectAccess::Read, &project_name)?;
+    state.api_keys.authorize(&headers, ProjectAccess::Write, &destination_name)?;
+    require_project_folders(&state, "cloning projects")?;
+    ensure_project_loaded(&state, &project_name).await?;
+    let facts = image_facts(&state, &project_name, &payload.filter).await?;
+
+    // pick the matching images while holding the read lock only.
+    let image_paths: Vec<PathBuf> = {
+        let project_dict_rlock = state.project_dict.read().await;
+
+        let hash_list = (*project_dict_rlock).get(&project_name)
+            .ok_or_else(|| AppError::BadRequest(
+                format!("project <{}> not found in current database", project_name)))?;
+
+        filter_hash_entries(hash_list, &payload.filter, &facts)
+            .map_err(AppError::BadRequest)?
+            .into_iter()
+            .map(|entry| entry.image_name.clone())
+            .collect()
+    };
+
+    if image_paths.is_empty() {
+        return Err(AppError::BadRequest("no image matches the filter".to_owned()));
+    }
+
+    tracing::info!(project = %project_name, destination = %destination_name, images = image_paths.len(), "cloning images");
+
+    let image_count = create_project_from(
+        &state, 
+        &destination_name, 
+        move |dst_path| copy_image_files(&image_paths, dst_path)).await?;
+
+    let request_context = RequestContext {
+        project_name: Some(destination_name.clone()),
+        image_count: Some(image_count),
+    };
+
+    Ok((Extension(request_context), Json(CopyProjectResp {
+        success: true,
+        message: "project subset copied and indexed successfully".to_owned(),
+        project_name: destination_name,
+        image_count,
+    })))
+}
+
+/// List recent comparisons, newest first.
+#[utoipa::path(
+    get,
+    path = "/diff/history",
+    tag = "compare",
+    params(CompareHistoryQuery),
+    responses(
+        (status = 200, description = "success", body = CompareHistoryResp),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+    ),
+)]
+async fn compare_history_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<CompareHistoryQuery>)
+    -> Result<Json<CompareHistoryResp>, AppError> {
+
+    if let Some(project_name) = &query.project {
+        state.api_keys.authorize(&headers, ProjectAccess::Read, project_name)?;
+    }
+
+    let limit = query.limit.unwrap_or(50);
+    let history_lock = state.compare_history.lock().await;
+
+    // without a project filter, only the projects the key may read.
+    let history: Vec<CompareHistoryEntry> = history_lock.iter()
+        .rev()
+        .filter(|h| query.project.as_ref().is_none_or(|p| &h.project_name == p))
+        .filter(|h| state.api_keys.authorize(&headers, ProjectAccess::Read, &h.project_name).is_ok())
+        .take(limit)
+        .cloned()
+        .collect();
+
+    Ok(Json(CompareHistoryResp {
+        success: true,
+        message: "success".to_owned(),
+        history,
     }))
+}
 
+/// Measure comparison throughput of a project.
+/// 
+/// A random project image is used as query, and compared against the
+/// whole project `iterations` times on the blocking thread pool.
+#[utoipa::path(
+    post,
+    path = "/projects/{project_name}/benchmark",
+    tag = "ops",
+    params(("project_name" = String, Path, description = "project name")),
+    request_body = Option<BenchmarkReq>,
+    responses(
+        (status = 200, description = "success", body = BenchmarkResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+    ),
+)]
+async fn benchmark_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    headers: HeaderMap,
+    payload: Option<Json<BenchmarkReq>>)
+    -> Result<Json<BenchmarkResp>, AppError> {
+
+    state.api_keys.authorize(&headers, ProjectAccess::Read, &project_name)?;
+    let iterations = payload
+        .and_then(|Json(p)| p.iterations)
+        .unwrap_or(BENCHMARK_DEFAULT_ITERATIONS);
+
+    if iterations == 0 || iterations > BENCHMARK_MAX_ITERATIONS {
+        return Err(AppError::BadRequest(
+            format!("iterations must be within 1..={}", BENCHMARK_MAX_ITERATIONS)));
+    }
+
+    ensure_project_loaded(&state, &project_name).await?;
+    let hash_list = state.project_dict.read().await
+        .get(&project_name)
+        .cloned()
+        .ok_or_else(|| AppError::BadRequest(
+            format!("project <{}> not found in current database", project_name)))?;
+
+    if hash_list.is_empty() {
+        return Err(AppError::BadRequest(
+            format!("project <{}> has no image to benchmark with", project_name)));
+    }
+
+    // no need for a proper RNG, the clock is random enough here.
+    let query_index = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos() as usize) % hash_list.len();
+    let query_path = hash_list[query_index].image_name.clone();
+    let image_store = state.image_store.clone();
+
+    let benchmark_task = 
+        run_blocking(move || {
+            let query_image = open_project_image(image_store.as_deref(), &query_path)
+                .map_err(|e| format!("cannot open query image <{}>: {}", query_path.display(), e))?;
+
+            // black boxes keep the optimizer from hoisting or dropping the
+            // unused comparisons.
+            let bench_start = Instant::now();
+            for _ in 0..iterations {
+                std::hint::black_box(calc_similarity_list(std::hint::black_box(&query_image), std::hint::black_box(&hash_list)));
+            }
+            Ok::<_, String>((bench_start.elapsed(), hash_list.len()))
+        });
+
+    let (elapsed, project_size) = benchmark_task.await?
+        .map_err(AppError::InternalError)?;
+
+    tracing::info!(project = %project_name, iterations, ?elapsed, "benchmark done");
+
+    Ok(Json(BenchmarkResp {
+        success: true,
+        message: "success".to_owned(),
+        iterations,
+        total_ms: elapsed.as_secs_f64() * 1000.0,
+        qps: iterations as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        project_size,
+    }))
 }
 
+/// List loaded projects with their image count.
+#[utoipa::path(
+    get,
+    path = "/projects",
+    tag = "projects",
+    params(ListProjectsQuery),
+    responses(
+        (status = 200, description = "success", body = ListProjectsResp),
+    ),
+)]
+async fn list_projects_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ListProjectsQuery>)
+    -> Json<ListProjectsResp> {
+
+    let project_dict_rlock = state.project_dict.read().await;
+
+    let mut projects: Vec<ProjectInfo> = project_dict_rlock
+        .iter()
+        .map(|(project_name, hash_list)| ProjectInfo {
+            project_name: project_name.clone(),
+            image_count: hash_list.len(),
+            is_loaded: true,
+        })
+        .collect();
+
+    // a project being loaded may already be in the dict.
+    projects.extend(state.pending_projects.names().into_iter()
+        .filter(|project_name| !project_dict_rlock.contains_key(project_name))
+        .map(|project_name| ProjectInfo { project_name, image_count: 0, is_loaded: false }));
+    drop(project_dict_rlock);
+
+    // a key only sees the projects it may read.
+    projects.retain(|p| state.api_keys.authorize(&headers, ProjectAccess::Read, &p.project_name).is_ok());
+
+    // `HashMap` order is arbitrary, always sort before responding.
+    sort_projects(&mut projects, query.sort_by.unwrap_or_default());
+
+    Json(ListProjectsResp {
+        success: true,
+        message: "success".to_owned(),
+        projects,
+    })
+}
+
+/// Dump the in-memory hash entries of a project for debugging, hashes
+/// are always returned as hex. Needs an admin key.
+#[utoipa::path(
+    get,
+    path = "/admin/dump",
+    tag = "ops",
+    params(DumpProjectQuery),
+    responses(
+        (status = 200, description = "success", body = DumpProjectResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key is not an admin key", body = AppErrorPayload),
+    ),
+)]
+async fn dump_project_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<DumpProjectQuery>)
+    -> Result<Json<DumpProjectResp>, AppError> {
+
+    state.api_keys.authorize_admin(&headers)?;
+    let limit = query.limit.unwrap_or(DUMP_DEFAULT_LIMIT).min(DUMP_MAX_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+
+    ensure_project_loaded(&state, &query.project).await?;
+    let project_dict_rlock = state.project_dict.read().await;
+
+    let hash_list = (*project_dict_rlock).get(&query.project)
+        .ok_or_else(|| AppError::BadRequest(
+            format!("project <{}> not found in current database", query.project)))?;
+
+    let entries: Vec<ImageHashEntryJson> = hash_list.iter()
+        .skip(offset)
+        .take(limit)
+        .map(hash_entry_to_api_json)
+        .collect();
+
+    Ok(Json(DumpProjectResp {
+        success: true,
+        message: "success".to_owned(),
+        project_name: query.project.clone(),
+        total: hash_list.len(),
+        entries,
+    }))
+}
+
+/// Pre-load a project's image files into the OS page cache.
+#[utoipa::path(
+    post,
+    path = "/projects/{project_name}/warm-cache",
+    tag = "ops",
+    params(("project_name" = String, Path, description = "project name")),
+    responses(
+        (status = 200, description = "success", body = WarmCacheResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+    ),
+)]
+async fn warm_cache_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    headers: HeaderMap)
+    -> Result<Json<WarmCacheResp>, AppError> {
+
+    state.api_keys.authorize(&headers, ProjectAccess::Read, &project_name)?;
+    require_project_folders(&state, "warming the page cache")?;
+    ensure_project_loaded(&state, &project_name).await?;
+    if !state.project_dict.read().await.contains_key(&project_name) {
+        return Err(AppError::BadRequest(
+            format!("project <{}> not found in current database", project_name)));
+    }
+
+    let project_path = Path::new(&state.project_root).join(&project_name);
+
+    let warm_task = 
+        run_blocking(move || {
+            let warm_start = Instant::now();
+            warm_project_images(&project_path)
+                .map(|warmed| (warmed, warm_start.elapsed()))
+                .map_err(|e| e.to_string())
+        });
+
+    let (warmed_files, elapsed) = warm_task.await?
+        .map_err(AppError::InternalError)?;
+
+    tracing::info!(project = %project_name, warmed_files, ?elapsed, "warmed page cache");
+
+    Ok(Json(WarmCacheResp {
+        success: true,
+        message: "success".to_owned(),
+        project_name,
+        warmed_files,
+        elapsed_ms: elapsed.as_secs_f64() * 1000.0,
+    }))
+}
+
+/// Rescan a loaded project, hash its images again and swap the new hash
+/// list in, counting images in `progress`.
+async fn reindex_project(
+    state: &AppState, 
+    project_name: &str, 
+    force_recompute: bool, 
+    progress: Option<Arc<JobProgress>>) -> Result<ReindexProjectResp, String> {
+
+    // keep the project's hash type, and what was indexed before.
+    let (hash_type, snapshot) = {
+        let project_dict_rlock = state.project_dict.read().await;
+
+        let hash_list = (*project_dict_rlock).get(project_name)
+            .ok_or_else(|| format!("project <{}> not found in current database", project_name))?;
+
+        let hash_type = hash_list.first()
+            .map_or(state.hash_type, |h_ent| h_ent.hash_type);
+        let snapshot: HashSet<PathBuf> = hash_list.iter().map(|h_ent| h_ent.image_name.clone()).collect();
+        (hash_type, snapshot)
+    };
+
+    tracing::info!(project = %project_name, force_recompute, "reindexing project");
+
+    let project_path = Path::new(&state.project_root).join(project_name);
+    let (hash_store, image_store) = (state.hash_store.clone(), state.image_store.clone());
+    let hash_params = state.hash_params;
+    let reindex_task = 
+        run_blocking(move || {
+            let reindex_start = Instant::now();
+            load_or_calc_project_hashes(&project_path, hash_type, hash_params, hash_store.as_deref(), image_store.as_deref(), force_recompute, progress.as_deref())
+                .map(|hash_list| (hash_list, reindex_start.elapsed()))
+                .map_err(|e| e.to_string())
+        });
+
+    let (rescanned, elapsed) = reindex_task.await
+        .map_err(|e| e.to_string())??;
+
+    let (image_count, previous_image_count) = {
+        let mut project_dict_wlock = state.project_dict.write().await;
+
+        // the project may be gone while rescanning, don't bring it back.
+        let hash_list = (*project_dict_wlock).get_mut(project_name)
+            .ok_or_else(|| format!("project <{}> was removed while reindexing", project_name))?;
+
+        let previous_image_count = hash_list.len();
+        *hash_list = Arc::new(merge_reindexed_hashes(&snapshot, Arc::unwrap_or_clone(std::mem::take(hash_list)), rescanned));
+        state.ann_indexes.invalidate(project_name);
+        state.ensembles.invalidate(project_name);
+        (hash_list.len(), previous_image_count)
+    };
+
+    tracing::info!(project = %project_name, image_count, previous_image_count, ?elapsed, "reindexed project");
+
+    state.project_events.send_replace(ProjectEvent::ProjectUpdated {
+        project_name: project_name.to_owned(),
+        new_image_count: image_count,
+    });
+
+    Ok(ReindexProjectResp {
+        success: true,
+        message: "project reindexed".to_owned(),
+        project_name: project_name.to_owned(),
+        image_count,
+        previous_image_count,
+        elapsed_ms: elapsed.as_secs_f64() * 1000.0,
+    })
+}
+
+/// Rescan a project and hash its images again, then swap the new hash
+/// list in, without restarting the service.
+/// 
+/// The project stays available for comparison with its old hash list
+/// while rescanning. With `force_recompute`, cached and stored hashes are
+/// ignored and rewritten, e.g. to recover from corrupted caches. With
+/// `background`, the reindex runs as a job polled at `/jobs/{job_id}`,
+/// a reindex job already running on the project is returned instead of
+/// starting another.
+#[utoipa::path(
+    post,
+    path = "/projects/{project_name}/reindex",
+    tag = "ops",
+    params(("project_name" = String, Path, description = "project name")),
+    request_body = Option<ReindexProjectReq>,
+    responses(
+        (status = 200, description = "success", body = ReindexProjectResp),
+        (status = 202, description = "reindex job started", body = JobResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
+async fn reindex_project_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    headers: HeaderMap,
+    payload: Option<Json<ReindexProjectReq>>)
+    -> Result<Response<Body>, AppError> {
+
+    state.api_keys.authorize(&headers, ProjectAccess::Write, &project_name)?;
+    let request = payload.map(|Json(request)| request).unwrap_or_default();
+    ensure_project_loaded(&state, &project_name).await?;
+
+    if !state.project_dict.read().await.contains_key(&project_name) {
+        return Err(AppError::BadRequest(
+            format!("project <{}> not found in current database", project_name)));
+    }
+
+    if !request.background {
+        let reindexed = reindex_project(&state, &project_name, request.force_recompute, None).await
+            .map_err(AppError::InternalError)?;
+
+        let request_context = RequestContext {
+            project_name: Some(project_name),
+            image_count: Some(reindexed.image_count),
+        };
+        return Ok((Extension(request_context), Json(reindexed)).into_response());
+    }
+
+    let job_id = match state.jobs.start(JobKind::Reindex, &project_name) {
+        Ok((job_id, progress)) => {
+            let (_state, _project_name, _job_id) = (state.clone(), project_name.clone(), job_id.clone());
+            tokio::spawn(async move {
+                let outcome = reindex_project(&_state, &_project_name, request.force_recompute, Some(progress)).await
+                    .map(|reindexed| format!("project reindexed, {} images indexed", reindexed.image_count));
+                if let Err(e) = &outcome {
+                    tracing::warn!(project = %_project_name, job_id = %_job_id, error = %e, "reindex job failed");
+                }
+                _state.jobs.finish(&_job_id, outcome);
+            }.in_current_span());
+            job_id
+        },
+        Err(running_job_id) => running_job_id,
+    };
+
+    let job = state.jobs.get(&job_id)
+        .ok_or_else(|| AppError::InternalError(format!("job <{}> is gone", job_id)))?;
+
+    let request_context = RequestContext {
+        project_name: Some(project_name),
+        image_count: None,
+    };
+    Ok((StatusCode::ACCEPTED, Extension(request_context), Json(job)).into_response())
+}
+
+/// Progress of a background job, finished jobs are kept for a while.
+#[utoipa::path(
+    get,
+    path = "/jobs/{job_id}",
+    tag = "ops",
+    params(("job_id" = String, Path, description = "job id")),
+    responses(
+        (status = 200, description = "success", body = JobResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+    ),
+)]
+async fn job_handler(
+    State(state): State<AppState>,
+    PathParam(job_id): PathParam<String>,
+    headers: HeaderMap)
+    -> Result<Json<JobResp>, AppError> {
+
+    let job = state.jobs.get(&job_id)
+        .ok_or_else(|| AppError::BadRequest(format!("job <{}> not found", job_id)))?;
+
+    state.api_keys.authorize(&headers, ProjectAccess::Read, &job.project_name)?;
+    Ok(Json(job))
+}
+
+/// List the images of a project, one page at a time.
+/// 
+/// Pages are sorted by image name and chained with a cursor, see
+/// `paginate_after`.
+#[utoipa::path(
+    get,
+    path = "/projects/{project_name}/images",
+    tag = "images",
+    params(("project_name" = String, Path, description = "project name"), ListImagesQuery),
+    responses(
+        (status = 200, description = "success", body = ListImagesResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+    ),
+)]
+async fn list_images_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    headers: HeaderMap,
+    Query(query): Query<ListImagesQuery>)
+    -> Result<Json<ListImagesResp>, AppError> {
+
+    state.api_keys.authorize(&headers, ProjectAccess::Read, &project_name)?;
+
+    let limit = query.limit.unwrap_or(IMAGE_LIST_DEFAULT_LIMIT).min(IMAGE_LIST_MAX_LIMIT);
+    ensure_project_loaded(&state, &project_name).await?;
+
+    let mut indexed: HashMap<String, (HashType, PathBuf)> = {
+        let project_dict_rlock = state.project_dict.read().await;
+
+        let hash_list = (*project_dict_rlock).get(&project_name)
+            .ok_or_else(|| AppError::BadRequest(
+                format!("project <{}> not found in current database", project_name)))?;
+
+        hash_list.iter()
+            .map(|h_ent| (
+                hash_entry_to_api_json(h_ent).image_name,
+                (h_ent.hash_type, h_ent.image_name.clone())))
+            .collect()
+    };
+
+    let image_names: Vec<String> = indexed.keys().cloned().collect();
+    let (page, next_cursor) = paginate_after(image_names, query.after.as_deref(), limit);
+
+    let page: Vec<(String, HashType, PathBuf)> = page.into_iter()
+        .filter_map(|image_name| indexed.remove(&image_name)
+            .map(|(hash_type, image_path)| (image_name, hash_type, image_path)))
+        .collect();
+
+    // decoding images is a blocking task, only done when asked for.
+    let image_store = state.image_store.clone();
+    let images: Vec<ImageInfo> = match query.thumbnails {
+        false => page.into_iter()
+            .map(|(image_name, hash_type, _)| ImageInfo { image_name, hash_type, thumbnail: None })
+            .collect(),
+        true => run_blocking(move || {
+            page.into_iter()
+                .map(|(image_name, hash_type, image_path)| {
+                    // a broken image should not fail the whole listing.
+                    let thumbnail = open_project_image(image_store.as_deref(), &image_path)
+                        .and_then(|image| image_to_base64(
+                            &image.thumbnail(IMAGE_LIST_THUMBNAIL_SIDE, IMAGE_LIST_THUMBNAIL_SIDE))
+                            .map_err(|e| e.to_string()))
+                        .inspect_err(|e| tracing::warn!(
+                            path = %image_path.display(), error = %e, "cannot create thumbnail"))
+                        .ok();
+
+                    ImageInfo { image_name, hash_type, thumbnail }
+                })
+                .collect()
+        }).await?,
+    };
+
+    Ok(Json(ListImagesResp {
+        success: true,
+        message: "success".to_owned(),
+        project_name,
+        images,
+        next_cursor,
+    }))
+}
+
+/// Recompute the hash of one image from disk, rewrite its cache and
+/// update the project index, without reindexing the whole project.
+#[utoipa::path(
+    post,
+    path = "/projects/{project_name}/images/{image_name}/precompute-hash",
+    tag = "images",
+    params(("project_name" = String, Path, description = "project name"), ("image_name" = String, Path, description = "image file name")),
+    request_body = Option<PrecomputeHashReq>,
+    responses(
+        (status = 200, description = "success", body = PrecomputeHashResp),
+        (status = 400, description = "invalid request", body = AppErrorPayload),
+        (status = 403, description = "API key cannot access the project", body = AppErrorPayload),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
+async fn precompute_hash_handler(
+    State(state): State<AppState>,
+    PathParam((project_name, image_name)): PathParam<(String, String)>,
+    headers: HeaderMap,
+    payload: Option<Json<PrecomputeHashReq>>)
+    -> Result<(Extension<RequestContext>, Json<PrecomputeHashResp>), AppError> {
+
+    state.api_keys.authorize(&headers, ProjectAccess::Write, &project_name)?;
+    validate_image_name(&image_name)
+        .map_err(AppError::BadRequest)?;
+    require_project_folders(&state, "recomputing a hash from disk")?;
+
+    let requested_type = payload.unwrap_or_default().hash_type;
+    ensure_project_loaded(&state, &project_name).await?;
+
+    // keep the project's hash type and parameters, so hashes stay comparable.
+    let (hash_type, hash_params) = {
+        let project_dict_rlock = state.project_dict.read().await;
+
+        let hash_list = (*project_dict_rlock).get(&project_name)
+            .ok_or_else(|| AppError::BadRequest(
+                format!("project <{}> not found in current database", project_name)))?;
+
+        let project_hash_type = hash_list.first()
+            .map_or(state.hash_type, |h_ent| h_ent.hash_type);
+
+        match requested_type {
+            Some(t) if !hash_list.is_empty() && t != project_hash_type => 
+                return Err(AppError::BadRequest(
+                    format!("hash type {:?} does not match project hash type {:?}", t, project_hash_type))),
+            _ => (),
+        }
+
+        let hash_type = requested_type.unwrap_or(project_hash_type);
+        let hash_params = resolve_hash_params(None, state.hash_params, hash_type, hash_list)
+            .map_err(AppError::BadRequest)?;
+        (hash_type, hash_params)
+    };
+
+    let image_path = Path::new(&state.project_root).join(&project_name).join(&image_name);
+
+    if !image_path.is_file() {
+        return Err(AppError::BadRequest(
+            format!("image <{}> not found in project <{}>", image_name, project_name)));
+    }
+
+    let _project_name = project_name.clone();
+    let hash_store = state.hash_store.clone();
+    let member_types = state.ensembles.member_types(&project_name, hash_type);
+    let member_params = state.ensembles.params();
+    let hash_task = 
+        run_blocking(move || {
+            let image = image::open(&image_path)
+                .map_err(|e| format!("cannot open image: {}", e))?;
+            let hash = calc_hash(&image, hash_type, hash_params);
+
+            // the other hash types of an ensemble, like an upload.
+            let member_entries: Vec<ImageHashEntry> = member_types.into_iter()
+                .filter_map(|t| calc_hash_from_image(&image, &image_path, t, member_params).ok())
+                .collect();
+            let h_entry = ImageHashEntry::with_params(image_path, hash_type, hash, hash_params);
+
+            if let Some(store) = &hash_store {
+                StoredHash::of(&h_entry)
+                    .ok_or_else(|| "image is gone".to_owned())
+                    .and_then(|stored| store.put(&_project_name, &[stored]).map_err(|e| e.to_string()))
+                    .map_err(|e| format!("cannot store hash: {}", e))?;
+            }
+            if hash_store.as_ref().is_none_or(|store| !store.replaces_sidecar_caches()) {
+                write_hash_cache(&h_entry.image_name, &h_entry)
+                    .map_err(|e| format!("cannot write hash cache: {}", e))?;
+            }
+            if let Some(store) = &hash_store {
+                store_hashes(store.as_ref(), &_project_name, &member_entries);
+            }
+
+            Ok::<_, String>((h_entry, member_entries))
+        });
+
+    let (h_entry, member_entries) = hash_task.await?
+        .map_err(AppError::InternalError)?;
+    let hash_hex = h_entry.hash.to_hex();
+
+    let image_count = {
+        let mut project_dict_wlock = state.project_dict.write().await;
+
+        // the project may be gone while hashing, don't bring it back.
+        match (*project_dict_wlock).get_mut(&project_name) {
+            Some(hash_list) => {
+                state.ann_indexes.insert(&project_name, &h_entry);
+                for member_entry in &member_entries {
+                    state.ensembles.insert(&project_name, member_entry);
+                }
+                insert_hash_entry(Arc::make_mut(hash_list), h_entry);
+                hash_list.len()
+            },
+            None => return Err(AppError::BadRequest(
+                format!("project <{}> not found in current database", project_name))),
+        }
+    };
+
+    let request_context = RequestContext {
+        project_name: Some(project_name.clone()),
+        image_count: Some(image_count),
+    };
+
+    Ok((Extension(request_context), Json(PrecomputeHashResp {
+        success: true,
+        message: "hash recomputed and cached".to_owned(),
+        project_name,
+        image_name,
+        hash_type,
+        hash_hex,
+    })))
+}
+
+/// Prometheus scrape endpoint.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "ops",
+    responses(
+        (status = 200, description = "Prometheus text format", body = String, content_type = "text/plain"),
+    ),
+)]
+async fn metrics_handler(State(state): State<AppState>) 
+    -> Result<impl IntoResponse, AppError> {
+
+    state.metrics.set_project_images(
+        state.project_dict.read().await
+            .iter()
+            .map(|(project_name, hash_list)| (project_name.as_str(), hash_list.len())));
+
+    let body = state.metrics.render()
+        .map_err(|e| AppError::InternalError(e.to_string()))?;
+
+    Ok(([(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], body))
+}
+
+/// Aggregate statistics of the whole hash index.
+#[utoipa::path(
+    get,
+    path = "/metrics/hashes",
+    tag = "ops",
+    responses(
+        (status = 200, description = "success", body = HashMetricsResp),
+        (status = 500, description = "internal error", body = AppErrorPayload),
+    ),
+)]
+async fn hash_metrics_handler(State(state): State<AppState>)
+    -> Result<Json<HashMetricsResp>, AppError> {
+
+    // scanning the folders is a blocking task, it runs while the hashes
+    // are counted under the lock.
+    let project_root = PathBuf::from(&state.project_root);
+    let cache_scan_task = run_blocking(move || {
+        count_cache_files(&project_root)
+            .map_err(|e| e.to_string())
+    });
+
+    let count_task = async {
+        let mut total_hashes_stored: usize = 0;
+        let mut total_bits: usize = 0;
+        let mut projects_with_no_hashes: usize = 0;
+        let mut hashes_by_type: HashMap<String, usize> = HashMap::new();
+
+        let project_dict_rlock = state.project_dict.read().await;
+
+        for hash_list in (*project_dict_rlock).values() {
+            if hash_list.is_empty() {
+                projects_with_no_hashes += 1;
+            }
+
+            for h_entry in hash_list.iter() {
+                total_hashes_stored += 1;
+                total_bits += h_entry.hash.bits.len();
+                *hashes_by_type.entry(h_entry.hash_type.to_string()).or_default() += 1;
+            }
+        }
+
+        (total_hashes_stored, total_bits, projects_with_no_hashes, hashes_by_type)
+    };
+
+    let (cache_scan, (total_hashes_stored, total_bits, projects_with_no_hashes, hashes_by_type)) = 
+        tokio::join!(cache_scan_task, count_task);
+    let total_cache_files_on_disk = cache_scan?
+        .map_err(AppError::InternalError)?;
+
+    let average_hash_bits = match total_hashes_stored {
+        0 => 0.0,
+        n => total_bits as f64 / n as f64,
+    };
+
+    Ok(Json(HashMetricsResp {
+        success: true,
+        message: "success".to_owned(),
+        total_hashes_stored,
+        hashes_by_type,
+        average_hash_bits,
+        projects_with_no_hashes,
+        total_cache_files_on_disk,
+    }))
+}
+
+/// Stream project change events to the client as server-sent events.
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "ops",
+    responses(
+        (status = 200, description = "server-sent stream of project events", body = ProjectEvent, content_type = "text/event-stream"),
+    ),
+)]
+async fn events_handler(State(state): State<AppState>) 
+    -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+
+    let event_rx = state.project_events.subscribe();
+    let is_stopping = *event_rx.borrow() == ProjectEvent::ServiceStopping;
+
+    // wait for the next change, the stream ends when the sender is dropped
+    // or the service stops, so open streams don't hold up the shutdown.
+    let event_stream = stream::unfold((!is_stopping).then_some(event_rx), |rx| async move {
+        let mut rx = rx?;
+        rx.changed().await.ok()?;
+        let project_event = rx.borrow_and_update().clone();
+        let sse_event = Event::default()
+            .json_data(&project_event)
+            .unwrap_or_else(|_| Event::default().comment("unserializable event"));
+        let rx = (project_event != ProjectEvent::ServiceStopping).then_some(rx);
+        Some((Ok(sse_event), rx))
+    });
+
+    Sse::new(event_stream).keep_alive(KeepAlive::default())
+}
+
+/// Liveness probe, answers as long as the service can handle requests.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "ops",
+    responses(
+        (status = 200, description = "service is alive", body = String, content_type = "text/plain"),
+    ),
+)]
+async fn healthz_handler() -> &'static str {
+    "ok\n"
+}
+
+/// Readiness probe, 503 until the project root is readable and the
+/// initial hash loading has finished.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "ops",
+    responses(
+        (status = 200, description = "ready", body = ReadinessResp),
+        (status = 503, description = "not ready", body = ReadinessResp),
+    ),
+)]
+async fn readyz_handler(State(state): State<AppState>) -> (StatusCode, Json<ReadinessResp>) {
+    let project_root_accessible = tokio::fs::read_dir(&state.project_root).await.is_ok();
+    let hashes_loaded = state.hashes_loaded.load(Ordering::Acquire);
+    let ready = project_root_accessible && hashes_loaded;
+
+    let message = match (project_root_accessible, hashes_loaded) {
+        (true, true) => "ready",
+        (false, _) => "project root is not accessible",
+        (true, false) => "initial hash loading in progress",
+    };
+
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (status, Json(ReadinessResp {
+        ready,
+        project_root_accessible,
+        hashes_loaded,
+        message: message.to_owned(),
+    }))
+}
+
+/// gRPC front of the upload, compare and remove handlers, sharing
+/// `AppState` with the HTTP router.
+struct GrpcService {
+    state: AppState,
+    /// Limit of a whole streamed upload, same as the HTTP body limit.
+    max_upload_bytes: u64,
+}
+
+#[tonic::async_trait]
+impl VisMatch for GrpcService {
+    async fn upload(&self, request: tonic::Request<tonic::Streaming<pb::UploadChunk>>) 
+        -> Result<tonic::Response<pb::UploadReply>, tonic::Status> {
+
+        // metadata carries the idempotency key, like HTTP headers.
+        let headers = request.metadata().clone().into_headers();
+        let client = self.state.rate_limiter.client_of(&headers, request.remote_addr());
+        self.state.rate_limiter.charge(RouteClass::Upload, &client, 1)?;
+        let mut chunks = request.into_inner();
+
+        let mut meta: Option<UploadImageMeta> = None;
+        let mut data: Vec<u8> = Vec::new();
+
+        while let Some(chunk) = chunks.message().await? {
+            if meta.is_none() {
+                // refused before buffering, the name is joined to the project folder.
+                validate_image_name(&chunk.image_name)
+                    .map_err(tonic::Status::invalid_argument)?;
+                meta = Some(UploadImageMeta {
+                    project_name: chunk.project_name,
+                    image_name: chunk.image_name,
+                    hash_size: non_empty(chunk.hash_size),
+                    tags: chunk.tags,
+                });
+            }
+
+            if (data.len() + chunk.data.len()) as u64 > self.max_upload_bytes {
+                return Err(AppError::PayloadTooLarge(format!(
+                    "upload exceeds the limit of {} bytes", self.max_upload_bytes)).into());
+            }
+            data.extend_from_slice(&chunk.data);
+        }
+
+        let meta = meta.ok_or_else(|| AppError::BadRequest("empty upload stream".to_owned()))?;
+
+        let (_, Json(upload_resp)) = upload_image(self.state.clone(), headers, client, meta, data, |data| {
+            bytes_to_image(&data)
+                .map_err(|e| AppError::BadRequest(format!("cannot decode image: {}", e)))
+        }).await?;
+
+        Ok(tonic::Response::new(pb::UploadReply {
+            success: upload_resp.success,
+            message: upload_resp.message,
+            token: upload_resp.token,
+            image_size_bytes: upload_resp.image_size_bytes,
+            hash_size_bits: upload_resp.hash_size_bits as u32,
+        }))
+    }
+
+    async fn compare(&self, request: tonic::Request<pb::CompareRequest>) 
+        -> Result<tonic::Response<pb::CompareReply>, tonic::Status> {
+
+        let state = &self.state;
+        let headers = request.metadata().clone().into_headers();
+        let client = state.rate_limiter.client_of(&headers, request.remote_addr());
+        state.rate_limiter.charge(RouteClass::Diff, &client, 1)?;
+        let payload = request.into_inner();
+
+        state.api_keys.authorize(&headers, ProjectAccess::Read, &payload.project_name)?;
+        ensure_project_loaded(state, &payload.project_name).await?;
+
+        let hash_size = parse_hash_size(non_empty(payload.hash_size).as_deref())?;
+        let top_k = payload.top_k.map_or(state.compare_top_k, |k| k as usize);
+        let image = bytes_to_image(&payload.data)
+            .map_err(|e| AppError::BadRequest(format!("cannot decode image: {}", e)))?;
+
+        let result = calc_sim_in_project(
+            CompareQuery::Image(image, hash_size, Vec::new()),
+            &payload.project_name,
+            state.hash_type,
+            state.hash_params,
+            Arc::clone(&state.project_dict),
+            &state.ann_indexes,
+            &state.ensembles,
+            payload.max_distance.map(f64::from),
+            top_k,
+            &state.metrics
+        ).await.map_err(|e| task_error(e, AppError::BadRequest));
+
+        ServiceMetrics::count_outcome(&state.metrics.compares_total, &result);
+        let (query_hash, dist_vec) = result?;
+
+        record_compare_history(
+            &state.compare_history, 
+            &payload.project_name, 
+            &query_hash, 
+            dist_vec.first()).await;
+
+        Ok(tonic::Response::new(pb::CompareReply {
+            success: true,
+            message: "success".to_owned(),
+            project_name: payload.project_name,
+            compare_result: dist_vec.iter()
+                .take(top_k)
+                .map(|x| dist_entry_to_api_sim_entry(x, false))
+                .map(|entry| pb::SimilarImage {
+                    image_name: entry.image_name,
+                    distance: entry.distance,
+                    similarity_score: entry.similarity_score,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn remove(&self, request: tonic::Request<pb::RemoveRequest>) 
+        -> Result<tonic::Response<pb::RemoveReply>, tonic::Status> {
+
+        let headers = request.metadata().clone().into_headers();
+        let payload = RemoveImageReq { token: request.into_inner().token };
+        let (_, Json(remove_resp)) = remove_handler(State(self.state.clone()), headers, Json(payload)).await?;
+
+        Ok(tonic::Response::new(pb::RemoveReply {
+            success: remove_resp.success,
+            message: remove_resp.message,
+        }))
+    }
+}
 
 /// Handler for "404 not found" error, returning plain text body.
 async fn not_found_handler() -> Response<Body> { 
@@ -252,13 +3209,47 @@ async fn not_found_handler() -> Response<Body> {
 #[tokio::main]
 async fn main() {
 
+    let cli = Cli::parse();
+
+    // e.g. `vismatch-svc --config-check && deploy.sh` in CI.
+    if cli.config_check {
+        match cli.check_config() {
+            Ok(summary) => {
+                println!("{}", summary);
+                std::process::exit(0);
+            },
+            Err(e) => {
+                eprintln!("[x] {}", e);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    let config = cli.load_config()
+        .unwrap_or_else(|e| panic!("[x] {}, shutting down.", e));
+    set_cache_writes(config.cache_rewrite);
+
+    let log_level = config.log_level().expect("validated when loading");
+    match config.log_format {
+        LogFormat::Pretty => tracing_subscriber::fmt()
+            .with_max_level(log_level)
+            .init(),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .with_max_level(log_level)
+            .init(),
+    }
+
     // Stage 1: check prerequisites
 
-    let standard_hash_type: HashType = HashType::PHASH;
+    let standard_hash_type: HashType = config.hash_type;
+    let hash_params: HashParams = config.hash_params;
 
     let load_all = Instant::now(); // Measure load time
 
-    let project_root: &Path = Path::new("./image_root");
+    let project_root: &Path = config.project_root.as_path();
 
     let is_project_root_exists = 
         project_root.try_exists()
@@ -267,37 +3258,93 @@ async fn main() {
     match is_project_root_exists {
         false => {
             match create_dir(project_root) {
-                Ok(_) => println!("[*] created project root folder."),
+                Ok(_) => tracing::info!(path = %project_root.display(), "created project root folder"),
                 Err(_) => panic!("[x] cannot create project folder, shutting down."),
             }
         },
         true => {
-            match project_root.is_dir() {
-                false => panic!("[x] project folder is not valid, shutting down."),
-                true => {}, // Do nothing, continue the service process
+            if !project_root.is_dir() {
+                panic!("[x] project folder is not valid, shutting down.");
             }
         }
     }
 
+    // bounds bulk hashing of project images, 0 lets rayon use one thread per CPU.
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(config.hash_threads)
+        .thread_name(|i| format!("hash-{}", i))
+        .build_global()
+        .expect("[x] cannot start hashing threads, shutting down.");
+
+    // database clients block, keep them off the async worker threads.
+    let (hash_store, token_store) = tokio::task::block_in_place(|| config.hash_store.open(project_root))
+        .unwrap_or_else(|e| panic!("[x] {}, shutting down.", e));
+
+    if let Some(store) = &hash_store {
+        // a store may take the place of sidecar caches, existing ones are
+        // still read once to fill it.
+        if store.replaces_sidecar_caches() {
+            set_cache_writes(false);
+        }
+        tracing::info!("hashes are kept in the hash store");
+    }
+
+    let image_store = tokio::task::block_in_place(|| config.image_store.open())
+        .unwrap_or_else(|e| panic!("[x] {}, shutting down.", e));
+
+    if image_store.is_some() {
+        match hash_store {
+            Some(_) => tracing::info!("images are kept in the image store"),
+            None => tracing::warn!("images are kept in the image store, without a hash store they are all hashed again on every start"),
+        }
+    }
+
     // Stage 2: load or calculate hash for children projects
 
-    let child_project_reader = 
-        read_dir(project_root)
-            .map_err(|e: std::io::Error| format!("error reading root project contents: <{}>", e))
-            .unwrap(); // [Panics] Terminates process if cannot access project root.
+    // reported by `/readyz`.
+    let hashes_loaded = Arc::new(AtomicBool::new(false));
+
+    let children_projects: Vec<PathBuf> = match &image_store {
+        // entries are named as if projects were folders under project root.
+        Some(store) => tokio::task::block_in_place(|| store.list_projects())
+            .unwrap_or_else(|e| panic!("[x] cannot list projects of image store: {}, shutting down.", e))
+            .into_iter()
+            .map(|project_name| project_root.join(project_name))
+            .collect(),
+        None => {
+            let child_project_reader = 
+                read_dir(project_root)
+                    .map_err(|e: std::io::Error| format!("error reading root project contents: <{}>", e))
+                    .unwrap(); // [Panics] Terminates process if cannot access project root.
+
+            let (children_projects, _): (Vec<_>, Vec<_>) = 
+                child_project_reader.filter_ok(|f| f.path().is_dir())
+                        .map_ok(|f| f.path())
+                        .partition_result();
+            children_projects
+        },
+    };
 
-    let (children_projects, _): (Vec<_>, Vec<_>) = 
-        child_project_reader.filter_ok(|f| f.path().is_dir())
-                .map_ok(|f| f.path())
-                .partition_result();
 
+    // in lazy mode, projects are only listed now and loaded on first use.
+    let (children_projects, pending_projects) = match config.lazy_load {
+        true => {
+            let project_names = children_projects.iter()
+                .filter_map(|f| f.file_name())
+                .map(|f| f.to_string_lossy().into_owned())
+                .collect::<Vec<String>>();
+            tracing::info!(projects = project_names.len(), "projects will load on first use");
+            (Vec::new(), PendingProjects::new(project_names))
+        },
+        false => (children_projects, PendingProjects::default()),
+    };
 
     // Load and create a list of tuple (project name, [hash entries])
     let (children_project_hashes, _): 
-        (Vec<(String, Vec<ImageHashEntry>)>, Vec<_>) = 
+        (Vec<(String, Vec<ImageHashEntry>)>, Vec<_>) = tokio::task::block_in_place(|| 
             children_projects.into_iter()
                 .map(|f: PathBuf| {
-                    match load_or_calc_project_hashes(&f, standard_hash_type) {
+                    match load_or_calc_project_hashes(&f, standard_hash_type, hash_params, hash_store.as_deref(), image_store.as_deref(), false, None) {
                         Ok(h) => {
                             let project_name = 
                                 f.file_name().ok_or("invalid project name")?;
@@ -305,39 +3352,580 @@ async fn main() {
                         },
                         Err(err) => Err(err),
                     }})
-                .partition_result();
+                .partition_result());
 
     // Create a Arc to wrap shared project hashes.
     let project_name_hash_map: ProjectHashDict
-            = Arc::new(RwLock::new(children_project_hashes.into_iter().collect()));
+            = Arc::new(RwLock::new(children_project_hashes.into_iter()
+                .map(|(project_name, hash_list)| (project_name, Arc::new(hash_list)))
+                .collect()));
 
     let load_all_done = load_all.elapsed(); // Measure load time
+    hashes_loaded.store(true, Ordering::Release);
 
     // [NOTE] any other init stage thingy goes here.
 
-    println!("[*] initialization stage costs: {:.3?}", load_all_done);
-    println!("[v] initialization stage done, strating service...");
+    let service_metrics = ServiceMetrics::new()
+        .expect("[x] cannot register metrics, shutting down.");
+
+    let ann_indexes = Arc::new(AnnIndexes::new(config.ann_index.clone()));
+
+    // ensemble hash types load like project hash lists do.
+    let (_project_root, _hash_store, _image_store) = (project_root.to_owned(), hash_store.clone(), image_store.clone());
+    let ensembles = Arc::new(EnsembleIndexes::new(config.ensemble.clone(), hash_params, Box::new(move |project_name, hash_type|
+        load_or_calc_project_hashes(&_project_root.join(project_name), hash_type, hash_params, _hash_store.as_deref(), _image_store.as_deref(), false, None)
+            .map_err(|e| e.to_string()))));
+
+    // optional prewarm, trades startup time for first-request latency.
+    if config.prewarm {
+        prewarm_projects(Arc::clone(&project_name_hash_map), &ann_indexes, &ensembles, standard_hash_type, hash_params, &service_metrics, image_store.clone()).await;
+    }
+
+    let compare_top_k: usize = config.compare_top_k;
 
-    let addr: SocketAddr = SocketAddr::from(([0, 0, 0, 0], 3000));
+    // heartbeat file for external watchdogs, 0 turns it off.
+    if config.heartbeat_secs > 0 {
+        spawn_heartbeat(project_root.to_owned(), Duration::from_secs(config.heartbeat_secs));
+    }
+
+    tracing::info!(elapsed = ?load_all_done, "initialization stage done, starting service");
+
+    let addr: SocketAddr = config.listen_addr;
+
+    // HTTPS when a certificate is configured, plain HTTP otherwise.
+    let tls_config: Option<RustlsConfig> = match config.tls_files() {
+        None => None,
+        Some((tls_cert, tls_key)) => {
+            rustls::crypto::ring::default_provider().install_default()
+                .expect("[x] cannot install TLS crypto provider, shutting down.");
+
+            let tls_config = RustlsConfig::from_pem_file(tls_cert, tls_key).await
+                .unwrap_or_else(|e| panic!("[x] cannot load TLS certificate <{}>: {}, shutting down.", tls_cert.display(), e));
+            Some(tls_config)
+        },
+    };
 
     let listener: TcpListener = 
         TcpListener::bind(addr).await.unwrap();
 
-    println!("[*] image comparison service listening on {}", addr);
+    match tls_config {
+        None => tracing::info!("image comparison service listening on http://{}", addr),
+        Some(_) => tracing::info!("image comparison service listening on https://{}", addr),
+    }
 
 
     // Stage 3: starting service
+    let (project_events, _) = watch::channel(ProjectEvent::ServiceStarted);
+
+    let slow_request_config = config.slow_request.clone();
+
+    let body_limit_config = BodyLimitConfig { max_body_bytes: config.max_body_bytes };
+
+    let api_keys = Arc::new(ApiKeys::new(&config.api_keys));
+    if api_keys.is_enabled() {
+        tracing::info!(keys = config.api_keys.len(), "API keys required");
+    }
+
+    let rate_limiter = Arc::new(RateLimiter::new(config.rate_limit, Arc::clone(&api_keys)));
+
+    let upload_replays = IdempotencyCache::with_ttl(Duration::from_secs(config.idempotency_ttl_secs));
+
+    // the JSON file under project root, unless the hash store keeps tokens.
+    let deletion_tokens: SharedTokenStore = match token_store {
+        Some(token_store) => token_store,
+        None => Arc::new(std::sync::Mutex::new(
+            DeletionTokens::load(&project_root.join(DELETION_TOKENS_FILE))
+                .expect("[x] cannot load deletion tokens, shutting down."))),
+    };
+    let image_tags: SharedImageTags = Arc::new(std::sync::Mutex::new(
+        ImageTags::load(&project_root.join(IMAGE_TAGS_FILE))
+            .expect("[x] cannot load image tags, shutting down.")));
+
     let axum_state: AppState = AppState { 
         project_root: project_root.to_string_lossy().to_string(),
-        project_dict: project_name_hash_map };
+        project_dict: project_name_hash_map,
+        hash_type: standard_hash_type,
+        hash_params,
+        project_events,
+        compare_history: Arc::new(Mutex::new(VecDeque::with_capacity(COMPARE_HISTORY_CAPACITY))),
+        metrics: service_metrics.clone(),
+        upload_replays: Arc::new(Mutex::new(upload_replays)),
+        project_name_policy: Arc::new(config.project_name_policy()),
+        deletion_tokens,
+        image_tags,
+        hashes_loaded,
+        compare_top_k,
+        api_keys: Arc::clone(&api_keys),
+        rate_limiter: Arc::clone(&rate_limiter),
+        ann_indexes,
+        ensembles,
+        pending_projects: Arc::new(pending_projects),
+        hash_store: hash_store.clone(),
+        image_store: image_store.clone(),
+        jobs: Arc::new(JobRegistry::new(DEFAULT_FINISHED_JOBS)) };
+
+    let grpc_state = axum_state.clone();
+    let shutdown_project_dict = Arc::clone(&axum_state.project_dict);
+    let shutdown_events = axum_state.project_events.clone();
+
+    if config.watch_project_root {
+        let watcher = ProjectWatcher::start(project_root, WATCH_SETTLE_TIME)
+            .unwrap_or_else(|e| panic!("[x] cannot watch project root: {}, shutting down.", e));
+        spawn_project_watcher(axum_state.clone(), watcher);
+        tracing::info!(path = %project_root.display(), "watching project root for changes");
+    }
+
+    let cors_layer = config.cors.layer().expect("validated when loading");
 
     let axum_app: Router = Router::new()
+                    .route("/healthz", get(healthz_handler))
+                    .route("/readyz", get(readyz_handler))
                     .route("/diff", post(compare_handler))
+                    .route("/diff/batch", post(batch_compare_handler))
+                    .route("/diff/history", get(compare_history_handler))
                     .route("/upload", post(upload_handler))
+                    .route("/upload/multipart", post(upload_multipart_handler))
+                    .route("/remove", post(remove_handler))
+                    .route("/events", get(events_handler))
+                    .route("/admin/dump", get(dump_project_handler))
+                    .route("/metrics", get(metrics_handler))
+                    .route("/metrics/hashes", get(hash_metrics_handler))
+                    .route("/projects", get(list_projects_handler).post(create_project_handler))
+                    .route("/projects/{project_name}", delete(delete_project_handler))
+                    .route("/projects/{project_name}/rename", post(rename_project_handler))
+                    .route("/projects/{project_name}/copy", post(copy_project_handler))
+                    .route("/projects/{project_name}/clone-subset", post(clone_subset_handler))
+                    .route("/projects/{project_name}/images", get(list_images_handler).delete(delete_images_handler))
+                    .route("/projects/{project_name}/images/{image_name}", put(upload_raw_handler))
+                    .route("/projects/{project_name}/images/{image_name}/precompute-hash", post(precompute_hash_handler))
+                    .route("/projects/{project_name}/benchmark", post(benchmark_handler))
+                    .route("/projects/{project_name}/warm-cache", post(warm_cache_handler))
+                    .route("/projects/{project_name}/reindex", post(reindex_project_handler))
+                    .route("/jobs/{job_id}", get(job_handler))
+                    .route_layer(middleware::from_fn_with_state(
+                        slow_request_config, 
+                        track_slow_requests))
+                    .route_layer(middleware::from_fn_with_state(
+                        service_metrics, 
+                        track_body_sizes))
+                    .route_layer(middleware::from_fn_with_state(
+                        rate_limiter, 
+                        limit_rate))
+                    .route_layer(middleware::from_fn_with_state(
+                        api_keys, 
+                        require_api_key))
+                    .merge(SwaggerUi::new("/swagger-ui").url("/openapi.json", ApiDoc::openapi()))
                     .with_state(axum_state)
-                    .fallback(not_found_handler);
+                    .fallback(not_found_handler)
+                    .layer(body_limit_config.layer())
+                    // refuse oversized bodies before they are buffered.
+                    .layer(middleware::from_fn_with_state(
+                        body_limit_config, 
+                        reject_oversized_body));
+
+    // answer preflights before any key or limit check.
+    let axum_app = match cors_layer {
+        Some(cors_layer) => axum_app.layer(cors_layer),
+        None => axum_app,
+    };
+
+    let axum_app = axum_app
+                    // logs and errors of the request carry its ID.
+                    .layer(middleware::from_fn(assign_request_id))
+                    // mark credentials as sensitive so trace output redacts
+                    // them, keep it outermost so it runs before any tracing.
+                    .layer(SetSensitiveRequestHeadersLayer::new([
+                        http::header::AUTHORIZATION,
+                    ]));
+
+    // gRPC runs on its own port next to HTTP, with the same certificate.
+    let grpc_tls_config: Option<tonic::transport::ServerTlsConfig> = config.tls_files()
+        .map(|(tls_cert, tls_key)| {
+            let read_pem = |path: &Path| std::fs::read(path)
+                .unwrap_or_else(|e| panic!("[x] cannot load TLS certificate <{}>: {}, shutting down.", path.display(), e));
+            let identity = tonic::transport::Identity::from_pem(read_pem(tls_cert), read_pem(tls_key));
+            tonic::transport::ServerTlsConfig::new().identity(identity)
+        });
+
+    // set once a shutdown signal arrives, both servers stop accepting
+    // and drain the requests in flight.
+    let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+
+    // bound before serving, a taken port stops startup instead of only
+    // the gRPC task.
+    let grpc_listener = match config.grpc_addr() {
+        Some(grpc_addr) => Some(TcpListener::bind(grpc_addr).await
+            .unwrap_or_else(|e| panic!("[x] cannot listen on gRPC address {}: {}, shutting down.", grpc_addr, e))),
+        None => None,
+    };
+
+    let grpc_server = grpc_listener.map(|grpc_listener| {
+        let grpc_addr = grpc_listener.local_addr().expect("bound listener has an address");
+        let grpc_service = VisMatchServer::new(GrpcService {
+                state: grpc_state,
+                max_upload_bytes: body_limit_config.max_body_bytes,
+            })
+            .max_decoding_message_size(body_limit_config.max_body_bytes as usize);
+
+        let mut grpc_builder = tonic::transport::Server::builder();
+        if let Some(grpc_tls_config) = grpc_tls_config {
+            grpc_builder = grpc_builder.tls_config(grpc_tls_config)
+                .unwrap_or_else(|e| panic!("[x] invalid gRPC TLS configuration: {}, shutting down.", e));
+        }
+
+        match config.tls_files() {
+            None => tracing::info!("gRPC service listening on http://{}", grpc_addr),
+            Some(_) => tracing::info!("gRPC service listening on https://{}", grpc_addr),
+        }
+
+        tokio::spawn(async move {
+            let serve_result = grpc_builder
+                .add_service(grpc_service)
+                .serve_with_incoming_shutdown(TcpIncoming::from(grpc_listener).with_nodelay(Some(true)), async move { 
+                    shutdown_rx.wait_for(|is_shutdown| *is_shutdown).await.ok(); 
+                }).await;
+
+            if let Err(e) = serve_result {
+                tracing::error!(error = %e, "gRPC service stopped");
+            }
+        })
+    });
+
+    let shutdown = async move {
+        shutdown_signal().await;
+        tracing::info!("shutdown requested, draining in-flight requests");
+        shutdown_tx.send_replace(true);
+        shutdown_events.send_replace(ProjectEvent::ServiceStopping);
+    };
+
+    match tls_config {
+        None => {
+            axum::serve(listener, axum_app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown)
+                .await
+                .unwrap();
+        },
+        Some(tls_config) => {
+            let server_handle = axum_server::Handle::new();
+            let shutdown_handle = server_handle.clone();
+
+            tokio::spawn(async move {
+                shutdown.await;
+                shutdown_handle.graceful_shutdown(None);
+            });
+
+            axum_server::from_tcp_rustls(listener.into_std().unwrap(), tls_config)
+                .unwrap()
+                .handle(server_handle)
+                .serve(axum_app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        },
+    }
+
+    if let Some(grpc_server) = grpc_server {
+        grpc_server.await.ok();
+    }
+
+    // uploads and removals are done by now, hash list is final. Objects
+    // have no sidecar caches, hashes only go to the hash store.
+    if image_store.is_none() {
+        persist_project_hashes(&shutdown_project_dict, hash_store.as_deref()).await;
+    }
 
-    axum::serve(listener, axum_app).await.unwrap();
+    tracing::info!("service stopped");
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroUsize;
+    use vismatch_svc::middleware::ApiKeyConfig;
+
+    /// State of a service over `project_root`, with empty projects and
+    /// the given API keys.
+    fn mk_state(project_root: &Path, api_keys: &[ApiKeyConfig]) -> AppState {
+        let (project_events, _) = watch::channel(ProjectEvent::ServiceStarted);
+        let deletion_tokens: SharedTokenStore = Arc::new(std::sync::Mutex::new(
+            DeletionTokens::load(&project_root.join(DELETION_TOKENS_FILE)).unwrap()));
+        let image_tags: SharedImageTags = Arc::new(std::sync::Mutex::new(
+            ImageTags::load(&project_root.join(IMAGE_TAGS_FILE)).unwrap()));
+        let api_keys = Arc::new(ApiKeys::new(api_keys));
+
+        AppState {
+            project_root: project_root.to_string_lossy().into_owned(),
+            project_dict: Arc::new(RwLock::new(HashMap::new())),
+            hash_type: HashType::PHASH,
+            hash_params: HashParams::default(),
+            project_events,
+            compare_history: Arc::new(Mutex::new(VecDeque::new())),
+            metrics: ServiceMetrics::new().unwrap(),
+            upload_replays: Arc::new(Mutex::new(IdempotencyCache::new(NonZeroUsize::new(16).unwrap(), Duration::from_secs(60)))),
+            project_name_policy: Arc::new(ProjectNamePolicy::default()),
+            deletion_tokens,
+            image_tags,
+            hashes_loaded: Arc::new(AtomicBool::new(true)),
+            compare_top_k: 3,
+            rate_limiter: Arc::new(RateLimiter::new(Default::default(), Arc::clone(&api_keys))),
+            api_keys,
+            ann_indexes: Arc::new(AnnIndexes::default()),
+            ensembles: Arc::new(EnsembleIndexes::new(Default::default(), HashParams::default(), Box::new(|_, _| Ok(Vec::new())))),
+            pending_projects: Arc::new(PendingProjects::default()),
+            hash_store: None,
+            image_store: None,
+            jobs: Arc::new(JobRegistry::new(DEFAULT_FINISHED_JOBS)),
+        }
+    }
+
+    /// A fresh empty project root, removed by the caller.
+    fn mk_project_root(test_name: &str) -> PathBuf {
+        let project_root = std::env::temp_dir()
+            .join(format!("vismatch-main-{}-{}", test_name, std::process::id()));
+        std::fs::create_dir_all(&project_root).unwrap();
+        project_root
+    }
+
+    /// A small valid PNG file.
+    fn png_bytes() -> Bytes {
+        let image = DynamicImage::ImageLuma8(image::ImageBuffer::from_fn(32, 32, |x, y| image::Luma([(x * y % 256) as u8])));
+        let mut buf = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut buf, image::ImageOutputFormat::Png).unwrap();
+        Bytes::from(buf.into_inner())
+    }
+
+    fn is_bad_request<T>(result: &Result<T, AppError>) -> bool {
+        matches!(result, Err(AppError::BadRequest(_)))
+    }
+
+    #[tokio::test]
+    async fn test_project_scopes() {
+        let project_root = mk_project_root("project-scopes");
+        let key = |key: &str, read: &[&str], write: &[&str]| ApiKeyConfig {
+            key: key.to_owned(),
+            read: read.iter().map(|p| p.to_string()).collect(),
+            write: write.iter().map(|p| p.to_string()).collect(),
+            admin: false,
+        };
+        let state = mk_state(&project_root, &[
+            key("reader", &["cats"], &[]),
+            key("writer", &[], &["cats"]),
+            key("stranger", &["dogs"], &[]),
+            ApiKeyConfig { admin: true, ..key("admin", &[], &[]) },
+        ]);
+        for project_name in ["cats", "dogs"] {
+            std::fs::create_dir_all(project_root.join(project_name)).unwrap();
+            state.project_dict.write().await.insert(project_name.to_owned(), Arc::default());
+        }
+
+        let with_key = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(http::header::AUTHORIZATION, format!("Bearer {}", key).parse().unwrap());
+            headers
+        };
+        let list_images = |headers: HeaderMap| list_images_handler(
+            State(state.clone()),
+            PathParam("cats".to_owned()),
+            headers,
+            Query(ListImagesQuery { after: None, limit: None, thumbnails: false }));
+        let reindex = |headers: HeaderMap| reindex_project_handler(
+            State(state.clone()),
+            PathParam("cats".to_owned()),
+            headers,
+            None);
+        let listed = |headers: HeaderMap| async {
+            let Json(resp) = list_projects_handler(State(state.clone()), headers, Query(ListProjectsQuery { sort_by: None })).await;
+            resp.projects.into_iter().map(|p| p.project_name).collect::<Vec<_>>()
+        };
+        let dump = |headers: HeaderMap| dump_project_handler(
+            State(state.clone()),
+            headers,
+            Query(DumpProjectQuery { project: "cats".to_owned(), offset: None, limit: None }));
+
+        // no key at all.
+        assert!(matches!(list_images(HeaderMap::new()).await, Err(AppError::Unauthorized(_))));
+        assert!(matches!(reindex(HeaderMap::new()).await, Err(AppError::Unauthorized(_))));
+
+        // read access only.
+        assert!(list_images(with_key("reader")).await.is_ok());
+        assert!(matches!(reindex(with_key("reader")).await, Err(AppError::Forbidden(_))));
+        assert_eq!(listed(with_key("reader")).await, vec!["cats"]);
+
+        // write access implies read access.
+        assert!(list_images(with_key("writer")).await.is_ok());
+        assert!(reindex(with_key("writer")).await.is_ok());
+        assert_eq!(listed(with_key("writer")).await, vec!["cats"]);
+
+        // another project only.
+        assert!(matches!(list_images(with_key("stranger")).await, Err(AppError::Forbidden(_))));
+        assert!(matches!(reindex(with_key("stranger")).await, Err(AppError::Forbidden(_))));
+        assert_eq!(listed(with_key("stranger")).await, vec!["dogs"]);
+
+        // dumping hashes needs an admin key, project access is not enough.
+        assert!(matches!(dump(with_key("reader")).await, Err(AppError::Forbidden(_))));
+        assert!(matches!(dump(with_key("writer")).await, Err(AppError::Forbidden(_))));
+        assert!(dump(with_key("admin")).await.is_ok());
+
+        std::fs::remove_dir_all(&project_root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upload_rejects_traversal() {
+        let project_root = mk_project_root("upload-traversal");
+        let state = mk_state(&project_root, &[]);
+
+        for image_name in ["../../etc/x.png", "/tmp/x.png", "..", "a/b.png"] {
+            let result = upload_raw_handler(
+                State(state.clone()),
+                PathParam(("cats".to_owned(), image_name.to_owned())),
+                Query(RawUploadQuery { hash_size: None, tags: None }),
+                HeaderMap::new(),
+                None,
+                png_bytes()).await;
+            assert!(is_bad_request(&result), "{}", image_name);
+        }
+        assert!(!project_root.join("cats").exists());
+
+        std::fs::remove_dir_all(&project_root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_multipart_upload_rejects_traversal() {
+        use axum::extract::FromRequest;
+
+        let project_root = mk_project_root("multipart-traversal");
+        let state = mk_state(&project_root, &[]);
+
+        let boundary = "vismatch-boundary";
+        let mut body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"metadata\"\r\n\r\n{{\"project_name\":\"cats\",\"image_name\":\"../../x.png\"}}\r\n\
+            --{b}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"x.png\"\r\nContent-Type: image/png\r\n\r\n",
+            b = boundary).into_bytes();
+        body.extend_from_slice(&png_bytes());
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        let request = http::Request::builder()
+            .method("POST")
+            .header(http::header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+            .body(Body::from(body))
+            .unwrap();
+        let multipart = Multipart::from_request(request, &()).await.unwrap();
+
+        let result = upload_multipart_handler(State(state), HeaderMap::new(), None, multipart).await;
+        assert!(matches!(&result, Err(AppError::BadRequest(message)) if message.contains("invalid image name")));
+        assert!(!project_root.join("cats").exists());
+
+        std::fs::remove_dir_all(&project_root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_removed_image_revokes_tokens() {
+        let project_root = mk_project_root("removed-image-tokens");
+        let state = mk_state(&project_root, &[]);
+        std::fs::create_dir_all(project_root.join("cats")).unwrap();
+        std::fs::write(project_root.join("cats/a.png"), png_bytes()).unwrap();
+
+        let token = state.deletion_tokens.issue("cats", "a.png").unwrap();
+        std::fs::remove_file(project_root.join("cats/a.png")).unwrap();
+
+        let change = ImageChange { project_name: "cats".to_owned(), image_name: "a.png".to_owned() };
+        apply_image_change(&state, &change).await.unwrap();
+        assert!(state.deletion_tokens.get(&token).unwrap().is_none());
+
+        std::fs::remove_dir_all(&project_root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_images() {
+        let project_root = mk_project_root("delete-images");
+        let state = mk_state(&project_root, &[]);
+
+        let mut tokens = HashMap::new();
+        for (image_name, tags) in [("a.png", "draft,outdoor"), ("b.png", "draft"), ("c.png", "")] {
+            let (_, Json(upload_resp)) = upload_raw_handler(
+                State(state.clone()),
+                PathParam(("cats".to_owned(), image_name.to_owned())),
+                Query(RawUploadQuery { hash_size: None, tags: Some(tags.to_owned()) }),
+                HeaderMap::new(),
+                None,
+                png_bytes()).await.unwrap();
+            tokens.insert(image_name, upload_resp.token);
+        }
+
+        let delete_images = |filter: ImageFilter, dry_run: bool| delete_images_handler(
+            State(state.clone()),
+            PathParam("cats".to_owned()),
+            HeaderMap::new(),
+            Json(DeleteImagesReq { filter, dry_run }));
+        let deleted = |result: Result<(Extension<RequestContext>, Json<DeleteImagesResp>), AppError>| 
+            result.unwrap().1.0.deleted.into_iter().sorted().collect::<Vec<_>>();
+        let by_tags = |tags: &[&str]| ImageFilter { tags: tags.iter().map(|t| t.to_string()).collect(), ..Default::default() };
+
+        assert!(is_bad_request(&delete_images(ImageFilter::default(), false).await));
+        let by_ref = ImageFilter { ref_image_name: Some("a.png".to_owned()), ..Default::default() };
+        assert!(is_bad_request(&delete_images(by_ref, false).await));
+
+        // a dry run only lists, every image is older than a future date
+        // and the same as the reference.
+        assert_eq!(deleted(delete_images(by_tags(&["draft"]), true).await), ["a.png", "b.png"]);
+        let by_date = ImageFilter { added_before: Some(u64::MAX), ..Default::default() };
+        assert_eq!(deleted(delete_images(by_date, true).await), ["a.png", "b.png", "c.png"]);
+        let by_similarity = ImageFilter { 
+            min_similarity_to_ref: Some(0.95), 
+            ref_image_name: Some("c.png".to_owned()), 
+            ..Default::default() };
+        assert_eq!(deleted(delete_images(by_similarity, true).await).len(), 3);
+        assert!(project_root.join("cats/a.png").exists());
+
+        assert_eq!(deleted(delete_images(by_tags(&["draft", "outdoor"]), false).await), ["a.png"]);
+        assert!(!project_root.join("cats/a.png").exists());
+        assert_eq!(state.project_dict.read().await["cats"].len(), 2);
+        assert!(state.deletion_tokens.get(&tokens["a.png"]).unwrap().is_none());
+        assert!(state.deletion_tokens.get(&tokens["b.png"]).unwrap().is_some());
+        assert!(!state.image_tags.lock().unwrap().project("cats").contains_key("a.png"));
+
+        let by_old_date = ImageFilter { added_before: Some(1), ..Default::default() };
+        assert!(deleted(delete_images(by_old_date, false).await).is_empty());
+
+        std::fs::remove_dir_all(&project_root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_upload_scopes() {
+        let project_root = mk_project_root("idempotent-upload");
+        let writer = |key: &str| ApiKeyConfig { key: key.to_owned(), read: vec![], write: vec!["*".to_owned()], admin: false };
+        let state = mk_state(&project_root, &[writer("alice"), writer("bob")]);
+
+        let upload = |key: &str, project_name: &str, image_name: &str, body: Bytes| {
+            let mut headers = HeaderMap::new();
+            headers.insert(http::header::AUTHORIZATION, format!("Bearer {}", key).parse().unwrap());
+            headers.insert(IDEMPOTENCY_KEY_HEADER, "retry-1".parse().unwrap());
+            upload_raw_handler(
+                State(state.clone()),
+                PathParam((project_name.to_owned(), image_name.to_owned())),
+                Query(RawUploadQuery { hash_size: None, tags: None }),
+                headers,
+                None,
+                body)
+        };
+        let token = |result: Result<(Extension<RequestContext>, Json<UploadImageResp>), AppError>| result.unwrap().1.0.token;
+
+        // a retry gets the stored response, another client or project
+        // with the same key uploads on its own.
+        let first = token(upload("alice", "cats", "a.png", png_bytes()).await);
+        assert_eq!(token(upload("alice", "cats", "a.png", png_bytes()).await), first);
+        assert_ne!(token(upload("bob", "cats", "c.png", png_bytes()).await), first);
+        assert!(project_root.join("cats/c.png").exists());
+        assert!(upload("alice", "dogs", "a.png", png_bytes()).await.is_ok());
+        assert!(project_root.join("dogs/a.png").exists());
+
+        // the same key for another upload is refused.
+        let result = upload("alice", "cats", "b.png", png_bytes()).await;
+        assert!(matches!(result, Err(AppError::Conflict(_))));
+        assert!(!project_root.join("cats/b.png").exists());
+        let result = upload("alice", "cats", "a.png", Bytes::from_static(b"other body")).await;
+        assert!(matches!(result, Err(AppError::Conflict(_))));
+
+        std::fs::remove_dir_all(&project_root).unwrap();
+    }
+}
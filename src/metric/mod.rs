@@ -12,7 +12,7 @@ impl traits::Metrizable for imagehash::Hash {
       let hm_diff = zip(lhs.iter(), rhs.iter())
         .map(|(x, y)| x != y)
         .map(|x| if x {1} else {0})
-        .fold(0, |acc, x| acc + x);
+        .sum::<i32>();
       
       hm_diff as f64
     }
@@ -0,0 +1,179 @@
+//! API key authentication, with per-project scopes.
+//!
+//! Keys are sent as `Authorization: Bearer <key>`. Without configured
+//! keys every request is let through, as before keys existed. The
+//! middleware only checks that a key is known, every handler taking a
+//! project checks its scope with `ApiKeys::authorize`, and every
+//! `/admin/*` handler checks for an admin key with
+//! `ApiKeys::authorize_admin`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{self, HeaderMap};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+
+use crate::api::AppError;
+
+/// Routes reachable without a key, so probes need no credentials.
+const PUBLIC_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+/// Project list entry matching every project.
+const ANY_PROJECT: &str = "*";
+
+/// One API key and the projects it may access, as written in the config
+/// file. Write access implies read access.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    pub key: String,
+    /// Projects the key may compare against, `*` for all.
+    #[serde(default)]
+    pub read: Vec<String>,
+    /// Projects the key may upload to and remove from, `*` for all.
+    #[serde(default)]
+    pub write: Vec<String>,
+    /// Whether the key may use the `/admin/*` routes, which reach every
+    /// project.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// Kind of access a request needs on a project.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProjectAccess {
+    Read,
+    Write,
+}
+
+/// Known API keys.
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    keys: HashMap<String, ApiKeyConfig>,
+}
+
+impl ApiKeys {
+    pub fn new(keys: &[ApiKeyConfig]) -> Self {
+        ApiKeys {
+            keys: keys.iter().map(|k| (k.key.clone(), k.clone())).collect(),
+        }
+    }
+
+    /// Whether keys are required at all.
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Key of a request, `None` when keys are not required.
+    fn lookup(&self, headers: &HeaderMap) -> Result<Option<&ApiKeyConfig>, AppError> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+
+        let key = headers.get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| AppError::Unauthorized("missing API key".to_owned()))?;
+
+        self.keys.get(key.trim())
+            .map(Some)
+            .ok_or_else(|| AppError::Unauthorized("unknown API key".to_owned()))
+    }
+
+    /// Key of a request when it is a configured one, `None` otherwise.
+    pub fn known_key<'a>(&'a self, headers: &HeaderMap) -> Option<&'a str> {
+        self.lookup(headers).ok().flatten().map(|k| k.key.as_str())
+    }
+
+    /// Check that the request key may access `project_name`.
+    pub fn authorize(&self, headers: &HeaderMap, access: ProjectAccess, project_name: &str)
+        -> Result<(), AppError> {
+
+        let Some(key) = self.lookup(headers)? else {
+            return Ok(());
+        };
+
+        let listed = |projects: &[String]| projects.iter()
+            .any(|p| p == ANY_PROJECT || p == project_name);
+
+        let is_allowed = match access {
+            ProjectAccess::Read => listed(&key.read) || listed(&key.write),
+            ProjectAccess::Write => listed(&key.write),
+        };
+
+        match is_allowed {
+            true => Ok(()),
+            false => Err(AppError::Forbidden(format!(
+                "API key has no {} access to project <{}>",
+                match access { ProjectAccess::Read => "read", ProjectAccess::Write => "write" },
+                project_name))),
+        }
+    }
+
+    /// Check that the request key is an admin key.
+    pub fn authorize_admin(&self, headers: &HeaderMap) -> Result<(), AppError> {
+        match self.lookup(headers)? {
+            None => Ok(()),
+            Some(key) if key.admin => Ok(()),
+            Some(_) => Err(AppError::Forbidden("API key has no admin access".to_owned())),
+        }
+    }
+}
+
+/// Middleware returning 401 when keys are configured and the request
+/// has no known key.
+pub async fn require_api_key(
+    State(api_keys): State<Arc<ApiKeys>>,
+    request: Request,
+    next: Next) -> Response {
+
+    if PUBLIC_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    match api_keys.lookup(request.headers()) {
+        Ok(_) => next.run(request).await,
+        Err(err) => err.into_response(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorize() {
+        let with_key = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(http::header::AUTHORIZATION, format!("Bearer {}", key).parse().unwrap());
+            headers
+        };
+
+        let open = ApiKeys::default();
+        assert!(open.authorize(&HeaderMap::new(), ProjectAccess::Write, "cats").is_ok());
+
+        let keys = ApiKeys::new(&[
+            ApiKeyConfig { key: "reader".to_owned(), read: vec!["cats".to_owned()], write: vec![], admin: false },
+            ApiKeyConfig { key: "admin".to_owned(), read: vec![], write: vec!["*".to_owned()], admin: true },
+        ]);
+
+        assert!(keys.authorize(&with_key("reader"), ProjectAccess::Read, "cats").is_ok());
+        assert!(matches!(keys.authorize(&with_key("reader"), ProjectAccess::Write, "cats"), Err(AppError::Forbidden(_))));
+        assert!(matches!(keys.authorize(&with_key("reader"), ProjectAccess::Read, "dogs"), Err(AppError::Forbidden(_))));
+        assert!(keys.authorize(&with_key("admin"), ProjectAccess::Read, "dogs").is_ok());
+        assert!(keys.authorize(&with_key("admin"), ProjectAccess::Write, "dogs").is_ok());
+
+        assert!(matches!(keys.authorize(&with_key("nope"), ProjectAccess::Read, "cats"), Err(AppError::Unauthorized(_))));
+        assert!(matches!(keys.authorize(&HeaderMap::new(), ProjectAccess::Read, "cats"), Err(AppError::Unauthorized(_))));
+
+        // write access to every project is still no admin access.
+        assert!(open.authorize_admin(&HeaderMap::new()).is_ok());
+        assert!(keys.authorize_admin(&with_key("admin")).is_ok());
+        assert!(matches!(keys.authorize_admin(&with_key("reader")), Err(AppError::Forbidden(_))));
+        assert!(matches!(keys.authorize_admin(&HeaderMap::new()), Err(AppError::Unauthorized(_))));
+    }
+}
@@ -0,0 +1,72 @@
+//! Early request body size check.
+//! 
+//! Rejects requests whose `Content-Length` header exceeds the limit,
+//! before any body extractor starts buffering. Streamed bodies are cut
+//! off by the extractors, under the `DefaultBodyLimit` of `layer`.
+
+use axum::extract::{DefaultBodyLimit, Request, State};
+use axum::http;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::api::AppError;
+
+/// Default limit when the config does not set `max_body_bytes`, same
+/// as axum's default body limit.
+pub const DEFAULT_MAX_BODY_BYTES: u64 = 2 * 1024 * 1024;
+
+/// Maximum accepted request body size.
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimitConfig {
+    pub max_body_bytes: u64,
+}
+
+impl Default for BodyLimitConfig {
+    fn default() -> Self {
+        BodyLimitConfig { max_body_bytes: DEFAULT_MAX_BODY_BYTES }
+    }
+}
+
+impl BodyLimitConfig {
+    /// Body limit of the extractors, for bodies without `Content-Length`.
+    pub fn layer(&self) -> DefaultBodyLimit {
+        DefaultBodyLimit::max(usize::try_from(self.max_body_bytes).unwrap_or(usize::MAX))
+    }
+
+    fn too_large(&self, detail: &str) -> Response {
+        AppError::PayloadTooLarge(format!(
+            "{} exceeds the limit of {} bytes, send a smaller image", 
+            detail, self.max_body_bytes)).into_response()
+    }
+}
+
+/// Middleware returning 413 when the declared body size is over the limit.
+/// 
+/// Requests without `Content-Length` (e.g. chunked) pass through, and
+/// are left to the body limit of the extractors, whose plain text 413
+/// is replaced by the same JSON error.
+pub async fn reject_oversized_body(
+    State(config): State<BodyLimitConfig>,
+    request: Request,
+    next: Next) -> Response {
+
+    let content_length = request.headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    if let Some(len) = content_length.filter(|len| *len > config.max_body_bytes) {
+        return config.too_large(&format!("request body of {} bytes", len));
+    }
+
+    let response = next.run(request).await;
+
+    let is_json = response.headers()
+        .get(http::header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+
+    match response.status() == http::StatusCode::PAYLOAD_TOO_LARGE && !is_json {
+        true => config.too_large("request body"),
+        false => response,
+    }
+}
@@ -0,0 +1,54 @@
+//! Request and response body size metrics.
+//! 
+//! Records body sizes per route as Prometheus histograms, to tune the
+//! body size limit and spot unexpectedly large payloads.
+
+use axum::body::HttpBody;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{HeaderMap, header::CONTENT_LENGTH};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::service_metrics::ServiceMetrics;
+
+/// Size of a body from its `Content-Length` header, or from the body
+/// itself when its size is known upfront.
+/// 
+/// Streamed bodies of unknown size (e.g. `/events`) are not measured.
+fn body_size<B: HttpBody>(headers: &HeaderMap, body: &B) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .or_else(|| body.size_hint().exact())
+}
+
+/// Record request and response body sizes per route in `ServiceMetrics`.
+/// 
+/// Must be added with `route_layer`, the route pattern is used as the
+/// `endpoint` label so every project shares the same series.
+pub async fn track_body_sizes(
+    State(metrics): State<ServiceMetrics>,
+    request: Request,
+    next: Next) -> Response {
+
+    let endpoint = request.extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+
+    if let Some(size) = body_size(request.headers(), request.body()) {
+        metrics.request_body_bytes
+            .with_label_values(&[endpoint.as_str()])
+            .observe(size as f64);
+    }
+
+    let response = next.run(request).await;
+
+    if let Some(size) = body_size(response.headers(), response.body()) {
+        metrics.response_body_bytes
+            .with_label_values(&[endpoint.as_str()])
+            .observe(size as f64);
+    }
+
+    response
+}
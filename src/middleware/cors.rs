@@ -0,0 +1,104 @@
+//! Cross-origin requests from browser frontends.
+//!
+//! Without allowed origins no CORS headers are sent, and browsers keep
+//! refusing cross-origin calls, as before.
+
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use serde::Deserialize;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+use crate::idempotency::IDEMPOTENCY_KEY_HEADER;
+use crate::middleware::REQUEST_ID_HEADER;
+
+/// CORS settings as written in the config file.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API, e.g. `https://app.example.com`,
+    /// or `*` for any origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers browsers may send.
+    pub allowed_headers: Vec<String>,
+    /// How long browsers may cache a preflight answer.
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT", "DELETE"].map(str::to_owned).to_vec(),
+            allowed_headers: ["content-type", "authorization", IDEMPOTENCY_KEY_HEADER, REQUEST_ID_HEADER]
+                .map(str::to_owned).to_vec(),
+            max_age_secs: 600,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// The CORS layer, `None` when no origin is allowed.
+    pub fn layer(&self) -> Result<Option<CorsLayer>, String> {
+        if self.allowed_origins.is_empty() {
+            return Ok(None);
+        }
+
+        let allow_origin = match self.allowed_origins.iter().any(|o| o == "*") {
+            true => AllowOrigin::from(Any),
+            false => AllowOrigin::list(self.allowed_origins.iter()
+                .map(|o| HeaderValue::from_str(o.trim_end_matches('/'))
+                    .map_err(|_| format!("invalid CORS origin <{}>", o)))
+                .collect::<Result<Vec<_>, _>>()?),
+        };
+
+        let methods = self.allowed_methods.iter()
+            .map(|m| m.to_ascii_uppercase().parse::<Method>()
+                .map_err(|_| format!("invalid CORS method <{}>", m)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let headers = self.allowed_headers.iter()
+            .map(|h| h.parse::<HeaderName>()
+                .map_err(|_| format!("invalid CORS header <{}>", h)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let layer = CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            // let frontends read the request ID and rate limit hints.
+            .expose_headers([
+                HeaderName::from_static(REQUEST_ID_HEADER),
+                axum::http::header::RETRY_AFTER,
+            ])
+            .max_age(Duration::from_secs(self.max_age_secs));
+
+        Ok(Some(layer))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cors_layer() {
+        assert!(CorsConfig::default().layer().unwrap().is_none());
+
+        let config = CorsConfig {
+            allowed_origins: vec!["https://app.example.com/".to_owned()],
+            ..Default::default()
+        };
+        assert!(config.layer().unwrap().is_some());
+
+        let any = CorsConfig { allowed_origins: vec!["*".to_owned()], ..Default::default() };
+        assert!(any.layer().unwrap().is_some());
+
+        let bad_method = CorsConfig { allowed_methods: vec!["GE T".to_owned()], ..config.clone() };
+        assert!(bad_method.layer().is_err());
+        let bad_header = CorsConfig { allowed_headers: vec!["x y".to_owned()], ..config };
+        assert!(bad_header.layer().is_err());
+    }
+}
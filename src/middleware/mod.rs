@@ -0,0 +1,20 @@
+//! Request middlewares shared by the service router.
+//! 
+//! Each middleware lives in its own module, with its configuration
+//! next to it.
+
+mod slow_request;
+mod body_limit;
+mod body_size;
+mod api_key;
+mod rate_limit;
+mod request_id;
+mod cors;
+
+pub use slow_request::*;
+pub use body_limit::*;
+pub use body_size::*;
+pub use api_key::*;
+pub use rate_limit::*;
+pub use request_id::*;
+pub use cors::*;
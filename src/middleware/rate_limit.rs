@@ -0,0 +1,296 @@
+//! Per-client rate limiting of the hashing routes.
+//!
+//! Uploads and comparisons hash images, one client sending many of them
+//! can starve the others. Each client gets a token bucket per route
+//! class, keyed by its API key, or by its IP address without a known
+//! key. A request finding the bucket empty gets 429 with `Retry-After`.
+//!
+//! The gRPC service and `/diff/batch`, which costs one token per query
+//! image, charge the limiter themselves with `RateLimiter::charge`.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+
+use crate::api::AppError;
+use super::ApiKeys;
+
+/// Buckets kept before full ones, then the oldest ones, are dropped,
+/// bounds memory when many clients come and go.
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+/// Routes the middleware lets through, their handler charges one token
+/// per query once the body is parsed.
+const PER_QUERY_ROUTES: &[&str] = &["/diff/batch"];
+
+/// Rate limits as written in the config file, 0 turns a limit off.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Uploads per minute and client.
+    pub upload_per_minute: u32,
+    /// Uploads a client may send at once, at least 1.
+    pub upload_burst: u32,
+    /// Comparisons per minute and client, a batch counts each query.
+    pub diff_per_minute: u32,
+    /// Comparisons a client may send at once, at least 1.
+    pub diff_burst: u32,
+}
+
+/// Routes sharing a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    Upload,
+    Diff,
+}
+
+impl RouteClass {
+    fn of(route: &str) -> Option<Self> {
+        match route {
+            "/upload" | "/upload/multipart" | "/projects/{project_name}/images/{image_name}" => Some(RouteClass::Upload),
+            "/diff" | "/diff/batch" => Some(RouteClass::Diff),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Client a request is counted for, added to the request extensions of
+/// every limited route, for handlers charging per query or scoping state
+/// to the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitClient(String);
+
+impl RateLimitClient {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Token buckets of every client.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    /// Only a known key gets its own bucket, any other bearer would
+    /// let a client dodge its limit.
+    api_keys: Arc<ApiKeys>,
+    buckets: Mutex<HashMap<(RouteClass, String), TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, api_keys: Arc<ApiKeys>) -> Self {
+        RateLimiter { config, api_keys, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Client of a request, its API key when known, else its IP address.
+    pub fn client_of(&self, headers: &HeaderMap, remote_addr: Option<SocketAddr>) -> RateLimitClient {
+        let client = match self.api_keys.known_key(headers) {
+            Some(key) => format!("key:{}", key),
+            None => remote_addr.map(|addr| format!("ip:{}", addr.ip())).unwrap_or_default(),
+        };
+        RateLimitClient(client)
+    }
+
+    /// Take `cost` tokens for `client`, 429 when its bucket is short.
+    pub fn charge(&self, class: RouteClass, client: &RateLimitClient, cost: u32) -> Result<(), AppError> {
+        self.acquire(class, &client.0, cost, Instant::now())
+            .map_err(|retry_after| AppError::TooManyRequests(
+                "rate limit exceeded, retry later".to_owned(),
+                retry_after.as_secs_f64().ceil() as u64))
+    }
+
+    /// Refill rate in tokens per second and bucket size of a class,
+    /// `None` when the class is not limited.
+    fn limit(&self, class: RouteClass) -> Option<(f64, f64)> {
+        let (per_minute, burst) = match class {
+            RouteClass::Upload => (self.config.upload_per_minute, self.config.upload_burst),
+            RouteClass::Diff => (self.config.diff_per_minute, self.config.diff_burst),
+        };
+
+        (per_minute > 0).then(|| (per_minute as f64 / 60.0, burst.max(1) as f64))
+    }
+
+    /// Take `cost` tokens for `client`, or tell how long until they are
+    /// available.
+    /// 
+    /// A cost above the bucket size is let through on a full bucket and
+    /// owed, so a large batch is delayed rather than refused forever.
+    fn acquire(&self, class: RouteClass, client: &str, cost: u32, now: Instant) -> Result<(), Duration> {
+        let Some((rate, capacity)) = self.limit(class) else {
+            return Ok(());
+        };
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            // a full bucket is the same as a missing one.
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < capacity);
+        }
+
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            // still too many busy clients, drop the least recently seen
+            // tenth so this does not run on every request.
+            let mut updated: Vec<Instant> = buckets.values().map(|b| b.updated).collect();
+            let cutoff = *updated.select_nth_unstable(MAX_TRACKED_BUCKETS / 10).1;
+            buckets.retain(|_, b| b.updated > cutoff);
+        }
+
+        let bucket = buckets.entry((class, client.to_owned()))
+            .or_insert(TokenBucket { tokens: capacity, updated: now });
+
+        let refill = now.saturating_duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.updated = now;
+
+        let needed = (cost as f64).min(capacity);
+        match bucket.tokens >= needed {
+            true => {
+                bucket.tokens -= cost as f64;
+                Ok(())
+            },
+            false => Err(Duration::from_secs_f64((needed - bucket.tokens) / rate)),
+        }
+    }
+}
+
+/// Middleware returning 429 when the client used up its limit.
+///
+/// Must be added with `route_layer` for the route pattern, and the app
+/// served with `ConnectInfo<SocketAddr>` to key clients without API key.
+pub async fn limit_rate(
+    State(limiter): State<Arc<RateLimiter>>,
+    mut request: Request,
+    next: Next) -> Response {
+
+    let Some(route) = request.extensions().get::<MatchedPath>().map(|p| p.as_str().to_owned()) else {
+        return next.run(request).await;
+    };
+    let Some(class) = RouteClass::of(&route) else {
+        return next.run(request).await;
+    };
+
+    let remote_addr = request.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0);
+    let client = limiter.client_of(request.headers(), remote_addr);
+    request.extensions_mut().insert(client.clone());
+
+    if PER_QUERY_ROUTES.contains(&route.as_str()) {
+        return next.run(request).await;
+    }
+
+    match limiter.charge(class, &client, 1) {
+        Ok(()) => next.run(request).await,
+        Err(err) => err.into_response(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            upload_per_minute: 60,
+            upload_burst: 2,
+            ..Default::default()
+        }, Arc::default());
+        let start = Instant::now();
+
+        assert!(limiter.acquire(RouteClass::Upload, "a", 1, start).is_ok());
+        assert!(limiter.acquire(RouteClass::Upload, "a", 1, start).is_ok());
+        let retry_after = limiter.acquire(RouteClass::Upload, "a", 1, start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+
+        // clients and classes have their own buckets.
+        assert!(limiter.acquire(RouteClass::Upload, "b", 1, start).is_ok());
+        for _ in 0..10 {
+            assert!(limiter.acquire(RouteClass::Diff, "a", 1, start).is_ok());
+        }
+
+        // one token per second comes back.
+        assert!(limiter.acquire(RouteClass::Upload, "a", 1, start + Duration::from_secs(1)).is_ok());
+        assert!(limiter.acquire(RouteClass::Upload, "a", 1, start + Duration::from_secs(1)).is_err());
+
+        assert_eq!(RouteClass::of("/diff/batch"), Some(RouteClass::Diff));
+        assert_eq!(RouteClass::of("/projects/{project_name}/images/{image_name}"), Some(RouteClass::Upload));
+        assert_eq!(RouteClass::of("/metrics"), None);
+    }
+
+    #[test]
+    fn test_batch_cost() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            diff_per_minute: 60,
+            diff_burst: 4,
+            ..Default::default()
+        }, Arc::default());
+        let start = Instant::now();
+
+        assert!(limiter.acquire(RouteClass::Diff, "a", 3, start).is_ok());
+        assert_eq!(limiter.acquire(RouteClass::Diff, "a", 2, start), Err(Duration::from_secs(1)));
+
+        // a batch above the bucket size waits for a full bucket, and is owed.
+        assert_eq!(limiter.acquire(RouteClass::Diff, "b", 10, start), Ok(()));
+        assert_eq!(limiter.acquire(RouteClass::Diff, "b", 1, start), Err(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn test_rate_limit_client() {
+        use crate::middleware::ApiKeyConfig;
+
+        let api_keys = Arc::new(ApiKeys::new(&[
+            ApiKeyConfig { key: "known".to_owned(), read: vec!["*".to_owned()], write: vec![], admin: false },
+        ]));
+        let limiter = RateLimiter::new(RateLimitConfig::default(), api_keys);
+        let remote_addr = Some(SocketAddr::from(([10, 0, 0, 1], 4000)));
+
+        let with_key = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(axum::http::header::AUTHORIZATION, format!("Bearer {}", key).parse().unwrap());
+            headers
+        };
+
+        assert_eq!(limiter.client_of(&with_key("known"), remote_addr), RateLimitClient("key:known".to_owned()));
+        // a made up key must not get a fresh bucket.
+        assert_eq!(limiter.client_of(&with_key("made-up"), remote_addr), RateLimitClient("ip:10.0.0.1".to_owned()));
+        assert_eq!(limiter.client_of(&HeaderMap::new(), remote_addr), RateLimitClient("ip:10.0.0.1".to_owned()));
+    }
+
+    #[test]
+    fn test_prune_oldest_buckets() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            upload_per_minute: 1,
+            upload_burst: 2,
+            ..Default::default()
+        }, Arc::default());
+        let start = Instant::now();
+
+        // every bucket is in use, none of them is full again.
+        for i in 0..MAX_TRACKED_BUCKETS {
+            let now = start + Duration::from_millis(i as u64);
+            assert!(limiter.acquire(RouteClass::Upload, &i.to_string(), 1, now).is_ok());
+        }
+        let now = start + Duration::from_millis(MAX_TRACKED_BUCKETS as u64);
+        assert!(limiter.acquire(RouteClass::Upload, "new", 1, now).is_ok());
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.len() < MAX_TRACKED_BUCKETS);
+        assert!(!buckets.contains_key(&(RouteClass::Upload, "0".to_owned())));
+        assert!(buckets.contains_key(&(RouteClass::Upload, (MAX_TRACKED_BUCKETS - 1).to_string())));
+        assert!(buckets.contains_key(&(RouteClass::Upload, "new".to_owned())));
+    }
+}
@@ -0,0 +1,135 @@
+//! Slow request tracking.
+//! 
+//! Measures the time from request start to response, and emits a
+//! `tracing::warn!` when it exceeds the threshold of the route.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+
+/// Default threshold of routes without an override.
+const DEFAULT_SLOW_REQUEST_MS: u64 = 500;
+
+/// Context attached by handlers as a response extension, so slow
+/// request logs can tell which project was involved.
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    pub project_name: Option<String>,
+    pub image_count: Option<usize>,
+}
+
+/// Thresholds for slow request warnings, in milliseconds.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlowRequestConfig {
+    /// Applies to routes without an override.
+    pub threshold_ms: u64,
+    /// Per-route thresholds, keyed by the route pattern (e.g. `/upload`).
+    pub route_thresholds_ms: HashMap<String, u64>,
+}
+
+impl Default for SlowRequestConfig {
+    fn default() -> Self {
+        SlowRequestConfig {
+            threshold_ms: DEFAULT_SLOW_REQUEST_MS,
+            route_thresholds_ms: HashMap::new(),
+        }
+    }
+}
+
+impl SlowRequestConfig {
+    /// Threshold for the given route pattern.
+    pub fn threshold_for(&self, route: &str) -> Duration {
+        let ms = self.route_thresholds_ms.get(route)
+            .copied()
+            .unwrap_or(self.threshold_ms);
+        Duration::from_millis(ms)
+    }
+}
+
+/// Parse `route=ms` pairs separated by commas, e.g.
+/// `/upload=2000,/admin/gc=60000`.
+pub fn parse_route_thresholds(routes: &str) -> Result<HashMap<String, u64>, String> {
+    routes.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (route, ms) = pair.split_once('=')
+                .ok_or_else(|| format!("invalid route threshold <{}>, expected route=ms", pair))?;
+            let ms: u64 = ms.trim().parse()
+                .map_err(|e| format!("invalid threshold for route <{}>: {}", route, e))?;
+            Ok((route.trim().to_owned(), ms))
+        })
+        .collect()
+}
+
+/// Middleware that logs requests slower than their configured threshold.
+pub async fn track_slow_requests(
+    State(config): State<SlowRequestConfig>,
+    request: Request,
+    next: Next) -> Response {
+
+    let request_start = Instant::now();
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+
+    // prefer the route pattern, so thresholds apply to all project names.
+    let route = request.extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_else(|| path.clone());
+
+    let response = next.run(request).await;
+
+    let elapsed = request_start.elapsed();
+    let threshold = config.threshold_for(&route);
+
+    if elapsed > threshold {
+        let context = response.extensions()
+            .get::<RequestContext>()
+            .cloned()
+            .unwrap_or_default();
+
+        tracing::warn!(
+            %method,
+            %path,
+            route = %route,
+            project_name = context.project_name.as_deref().unwrap_or("-"),
+            image_count = context.image_count,
+            duration_ms = elapsed.as_millis() as u64,
+            threshold_ms = threshold.as_millis() as u64,
+            "slow request"
+        );
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_route_thresholds() {
+        let routes = parse_route_thresholds(" /upload=2000, /admin/gc=60000 ,").unwrap();
+
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes["/upload"], 2000);
+        assert_eq!(routes["/admin/gc"], 60000);
+
+        assert!(parse_route_thresholds("/upload").is_err());
+        assert!(parse_route_thresholds("/upload=fast").is_err());
+
+        let config = SlowRequestConfig {
+            threshold_ms: 500,
+            route_thresholds_ms: routes,
+        };
+        assert_eq!(config.threshold_for("/diff"), Duration::from_millis(500));
+        assert_eq!(config.threshold_for("/upload"), Duration::from_millis(2000));
+    }
+}
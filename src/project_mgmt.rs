@@ -4,54 +4,393 @@
 //! concept: take a project path, and returns a vec of image hashing result.
 //! 
 //! The ``
-use std::time::Instant;                // calculate time difference
+use std::time::{Instant, UNIX_EPOCH};   // calculate time difference, modification times
 use std::error::Error;                 // standard error trait
+use std::collections::{HashMap, HashSet}; // pending project lookup, reindex merge
+use std::future::Future;               // project loading task
+use std::sync::{Arc, Mutex};           // shared pending list
+use tokio::sync::OnceCell;             // load a project at most once
 
-use crate::utils::is_image_file;
+use crate::utils::{is_image_file, is_image_extension};
+use crate::hash_store::{HashStore, load_project_hashes};
+use crate::image_store::{self, ImageStore};
+use crate::jobs::JobProgress;
 
 // functional pattern support for clean code
 use itertools::Itertools;
+// parallel hashing of project images
+use rayon::prelude::*;
 
-use std::path::Path;      // filesystem path operations
-use std::fs::read_dir; // filesystem utils
+use std::path::{Path, PathBuf}; // filesystem path operations
+use std::fs::{read_dir, copy, remove_file, remove_dir_all, File}; // filesystem utils
+use std::io::Read;
 
 use crate::image_hash::{
     ImageHashEntry,
     //ImageDistEntry,
+    HashParams,
     HashType,
     fetch_cache_or_calc_hash,
+    sort_hash_list,
+    cache_path,
+    is_cache_file,
+    write_hash_cache,
 };
 
-/// Calculate project-wide hash from given path.
-pub fn calc_hash_project(project_path: &Path, hash_type: HashType) -> Result<Vec<ImageHashEntry>, Box<dyn Error>> {
+/// Check that a project name is a plain folder name.
+/// 
+/// Project names end up as folder names under project root, so anything
+/// that could escape the root (separators, `..`) is rejected, as well as
+/// characters some filesystems reserve and control characters.
+pub fn validate_project_name(project_name: &str) -> Result<(), String> {
+    let is_valid = !project_name.is_empty()
+        && project_name != "."
+        && project_name != ".."
+        && !project_name.contains(['/', '\\', '<', '>', ':', '"', '|', '?', '*'])
+        && !project_name.contains(char::is_control);
+
+    match is_valid {
+        true => Ok(()),
+        false => Err(format!("invalid project name <{}>", project_name)),
+    }
+}
+
+/// Check that an image name is a plain file name inside its project
+/// folder, with an accepted image extension.
+pub fn validate_image_name(image_name: &str) -> Result<(), String> {
+    let is_plain = !image_name.is_empty()
+        && image_name != "."
+        && image_name != ".."
+        && !image_name.contains(['/', '\\', '\0']);
+
+    let is_image = Path::new(image_name).extension()
+        .is_some_and(|ext| is_image_extension(&ext.to_string_lossy()));
+
+    match is_plain && is_image {
+        true => Ok(()),
+        false => Err(format!("invalid image name <{}>", image_name)),
+    }
+}
+
+/// Operator rules on which project names may be created.
+/// 
+/// Built from `Config::project_name_policy`. Without an allowlist every
+/// name not denied is allowed.
+#[derive(Debug, Clone, Default)]
+pub struct ProjectNamePolicy {
+    pub allowlist: Option<Vec<String>>,
+    pub denylist: Vec<String>,
+}
+
+impl ProjectNamePolicy {
+    /// Check that a new project may use `project_name`, a plain folder
+    /// name (see `validate_project_name`) that passes both lists.
+    pub fn check(&self, project_name: &str) -> Result<(), String> {
+        validate_project_name(project_name)?;
+
+        if self.denylist.iter().any(|name| name == project_name) {
+            return Err(format!("project name <{}> is reserved", project_name));
+        }
+
+        match &self.allowlist {
+            Some(allowlist) if !allowlist.iter().any(|name| name == project_name) => 
+                Err(format!("project name <{}> is not in the allowed project names", project_name)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Projects found on disk but not loaded yet, when projects are loaded
+/// on first use instead of at startup.
+#[derive(Debug, Default)]
+pub struct PendingProjects {
+    projects: Mutex<HashMap<String, Arc<OnceCell<()>>>>,
+}
+
+impl PendingProjects {
+    pub fn new(project_names: impl IntoIterator<Item = String>) -> Self {
+        PendingProjects {
+            projects: Mutex::new(project_names.into_iter()
+                .map(|project_name| (project_name, Arc::new(OnceCell::new())))
+                .collect()),
+        }
+    }
+
+    /// Names of the projects still to load.
+    pub fn names(&self) -> Vec<String> {
+        self.projects.lock().unwrap_or_else(|e| e.into_inner())
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_pending(&self, project_name: &str) -> bool {
+        self.projects.lock().unwrap_or_else(|e| e.into_inner())
+            .contains_key(project_name)
+    }
+
+    /// Run `load` for a pending project, unless another request is
+    /// already loading it, then wait for that one instead. Does nothing
+    /// for other projects.
+    /// 
+    /// A failed load leaves the project pending, the next request retries.
+    pub async fn load_once<F, Fut>(&self, project_name: &str, load: F) -> Result<(), String>
+        where F: FnOnce() -> Fut,
+              Fut: Future<Output = Result<(), String>> {
+
+        let Some(cell) = self.projects.lock().unwrap_or_else(|e| e.into_inner())
+            .get(project_name)
+            .cloned() else {
+            return Ok(());
+        };
+
+        cell.get_or_try_init(load).await?;
+
+        self.projects.lock().unwrap_or_else(|e| e.into_inner())
+            .remove(project_name);
+        Ok(())
+    }
+}
+
+/// Copy all image files from one project folder into another.
+/// 
+/// Hash caches are not copied, they are regenerated for the new project.
+/// Returns the number of copied images.
+pub fn copy_project_images(src_project: &Path, dst_project: &Path) -> Result<usize, Box<dyn Error>> {
+    let project_dir_reader = 
+        read_dir(src_project)
+            .map_err(|e: std::io::Error| format!("error reading project folder: <{}>", e))?;
+
+    let mut copied: usize = 0;
+
+    for image_entry in project_dir_reader.filter_ok(is_image_file) {
+        let image_path = image_entry?.path();
+        let image_name = image_path.file_name().ok_or("invalid image name")?;
+
+        copy(&image_path, dst_project.join(image_name))
+            .map_err(|e| format!("cannot copy <{}>: {}", image_path.display(), e))?;
+        copied += 1;
+    }
+
+    Ok(copied)
+}
+
+/// Copy the given image files into a project folder, keeping their names.
+/// 
+/// Returns the number of copied images.
+pub fn copy_image_files(image_paths: &[PathBuf], dst_project: &Path) -> Result<usize, Box<dyn Error>> {
+    for image_path in image_paths {
+        let image_name = image_path.file_name().ok_or("invalid image name")?;
+
+        copy(image_path, dst_project.join(image_name))
+            .map_err(|e| format!("cannot copy <{}>: {}", image_path.display(), e))?;
+    }
+
+    Ok(image_paths.len())
+}
+
+/// Remove an image file along with its hash caches of every hash type.
+/// 
+/// Missing cache files are fine, a missing image is an error.
+pub fn remove_image_files(image_path: &Path) -> Result<(), Box<dyn Error>> {
+    remove_file(image_path)
+        .map_err(|e| format!("cannot remove <{}>: {}", image_path.display(), e))?;
+
+    for hash_type in HashType::ALL {
+        remove_file(cache_path(image_path, *hash_type)).ok(); // IGNORE: cache may not exist
+    }
+
+    Ok(())
+}
+
+/// Files found in a project folder before it was removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RemovedProjectFiles {
+    pub image_files: usize,
+    pub cache_files: usize,
+    /// Anything else, e.g. files dropped in by hand.
+    pub other_files: usize,
+}
+
+/// Remove a project folder with everything in it, and count what was
+/// removed.
+pub fn remove_project_files(project_path: &Path) -> Result<RemovedProjectFiles, Box<dyn Error>> {
+    let mut removed = RemovedProjectFiles::default();
+
+    let files = read_dir(project_path)
+        .map_err(|e| format!("cannot read project folder <{}>: {}", project_path.display(), e))?
+        .filter_map(Result::ok)
+        .map(|f| f.path());
+
+    for file in files {
+        if is_cache_file(&file) {
+            removed.cache_files += 1;
+        } else if file.extension().is_some_and(|ext| is_image_extension(&ext.to_string_lossy())) {
+            removed.image_files += 1;
+        } else {
+            removed.other_files += 1;
+        }
+    }
+
+    remove_dir_all(project_path)
+        .map_err(|e| format!("cannot remove project folder <{}>: {}", project_path.display(), e))?;
+
+    Ok(removed)
+}
+
+/// Count hash cache files in every project folder under project root.
+pub fn count_cache_files(project_root: &Path) -> Result<usize, Box<dyn Error>> {
+    let root_dir_reader = 
+        read_dir(project_root)
+            .map_err(|e: std::io::Error| format!("error reading project root: <{}>", e))?;
+
+    let count = root_dir_reader
+        .filter_map(Result::ok)
+        .filter(|d| d.path().is_dir())
+        .filter_map(|d| read_dir(d.path()).ok())
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|f| f.path().is_file() && is_cache_file(&f.path()))
+        .count();
+
+    Ok(count)
+}
+
+/// Write the hash cache of entries that have none on disk, e.g. after a
+/// failed cache write, so the next start does not rehash them. Entries
+/// whose image is gone are skipped.
+/// 
+/// Returns the number of written caches, or the first write error.
+pub fn write_missing_hash_caches(hash_list: &[ImageHashEntry]) -> Result<usize, Box<dyn Error>> {
+    let mut written = 0;
+
+    for entry in hash_list {
+        if !entry.image_name.is_file() || cache_path(&entry.image_name, entry.hash_type).exists() {
+            continue;
+        }
+        write_hash_cache(&entry.image_name, entry)?;
+        written += 1;
+    }
+
+    Ok(written)
+}
+
+/// Last modification of every image of a project, in seconds since the
+/// Unix epoch, by file name. With an image store, images are listed from
+/// the store instead of the project folder.
+pub fn image_modified_secs(project_path: &Path, image_store: Option<&dyn ImageStore>) -> Result<HashMap<String, u64>, Box<dyn Error>> {
+    let Some(store) = image_store else {
+        let project_dir_reader = 
+            read_dir(project_path)
+                .map_err(|e: std::io::Error| format!("error reading project folder: <{}>", e))?;
+
+        let modified_secs = project_dir_reader.filter_ok(is_image_file)
+            .filter_map(Result::ok)
+            .filter_map(|f| {
+                let modified = f.metadata().ok()?.modified().ok()?;
+                Some((f.file_name().to_string_lossy().into_owned(), modified.duration_since(UNIX_EPOCH).ok()?.as_secs()))
+            })
+            .collect();
+        return Ok(modified_secs);
+    };
+
+    let project_name = project_path.file_name().ok_or("invalid project name")?;
+    let modified_secs = store.list_images(&project_name.to_string_lossy())
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|image| (image.image_name, image.modified_ns.max(0) as u64 / 1_000_000_000))
+        .collect();
+    Ok(modified_secs)
+}
+
+/// Read the head of every image file in project folder, so the OS pulls
+/// them into page cache. Images are not decoded.
+/// 
+/// Returns the number of warmed files, unreadable files are skipped.
+pub fn warm_project_images(project_path: &Path) -> Result<usize, Box<dyn Error>> {
+    let project_dir_reader = 
+        read_dir(project_path)
+            .map_err(|e: std::io::Error| format!("error reading project folder: <{}>", e))?;
+
+    let mut buf = [0u8; 4096];
+
+    let warmed = project_dir_reader.filter_ok(is_image_file)
+        .filter_map_ok(|f| File::open(f.path()).ok())
+        .filter_map_ok(|mut f| f.read(&mut buf).ok())
+        .filter(Result::is_ok)
+        .count();
+
+    Ok(warmed)
+}
+
+/// Calculate project-wide hash from given path, with `params`.
+/// 
+/// Images are hashed in parallel on the rayon pool, its size bounds
+/// how many are hashed at once. With `force_recompute`, cached hashes
+/// are recalculated and their cache files rewritten.
+#[tracing::instrument(skip_all, fields(project_path = %project_path.display(), hash_type = ?hash_type))]
+pub fn calc_hash_project(project_path: &Path, hash_type: HashType, params: HashParams, force_recompute: bool, progress: Option<&JobProgress>)
+    -> Result<Vec<ImageHashEntry>, Box<dyn Error>> {
     let project_dir_reader = 
         read_dir(project_path)
             .map_err(|e: std::io::Error| format!("error reading project folder: <{}>", e))?;
 
     let (images_in_project, _): (Vec<_>, Vec<_>) = 
-        project_dir_reader.filter_ok(|f| is_image_file(f))
+        project_dir_reader.filter_ok(is_image_file)
                 .map_ok(|f| f.path())
                 .partition_result();
 
-    let (h, _): (Vec<_>, Vec<_>) = images_in_project.into_iter()
-                                    .map(|f| fetch_cache_or_calc_hash(
-                                            &f, 
-                                            hash_type, 
-                                            false))
-                                    .partition_result();
+    progress.inspect(|p| p.set_total(images_in_project.len()));
+
+    // errors are not `Send`, keep their message only.
+    let hash_results: Vec<Result<ImageHashEntry, String>> = images_in_project.into_par_iter()
+                                    .map(|f| {
+                                        let result = fetch_cache_or_calc_hash(
+                                                &f, 
+                                                hash_type, 
+                                                params,
+                                                force_recompute)
+                                            .map_err(|e| e.to_string());
+                                        progress.inspect(|p| p.record(f.display(), &result));
+                                        result
+                                    })
+                                    .collect();
+
+    let (mut h, _): (Vec<_>, Vec<_>) = hash_results.into_iter().partition_result();
+
+    // `read_dir` order is platform dependent, sort by popcount and image
+    // name so that the same project always yields the same hash list,
+    // and threshold queries can binary search it.
+    sort_hash_list(&mut h);
+
     Ok(h)
 }
 
 /// For all images in project folder, try to load hash cache file,
-/// and calculate if not found hash cache.
-pub fn load_or_calc_project_hashes(project_path: &Path, hash_type: HashType) 
-    -> Result<Vec<ImageHashEntry>, Box<dyn Error>> {
+/// and calculate with `params` if not found hash cache.
+/// 
+/// With a hash store, hashes are loaded from and kept in the store instead.
+/// With an image store, images are listed and read from the store, the
+/// project folder is not used.
+/// 
+/// With `force_recompute`, no cached or stored hash is used: every image
+/// is hashed again and its cache or stored hash replaced, e.g. to recover
+/// from corrupted caches. Images hashed are counted in `progress`.
+#[tracing::instrument(skip_all, fields(project_path = %project_path.display(), hash_type = ?hash_type))]
+pub fn load_or_calc_project_hashes(
+    project_path: &Path, 
+    hash_type: HashType, 
+    params: HashParams,
+    hash_store: Option<&dyn HashStore>, 
+    image_store: Option<&dyn ImageStore>,
+    force_recompute: bool,
+    progress: Option<&JobProgress>) -> Result<Vec<ImageHashEntry>, Box<dyn Error>> {
 
     let load_now = Instant::now(); // Measure load time
     
     // Initial check
-    project_path.is_dir()
-        .then(|| ())
+    (image_store.is_some() || project_path.is_dir())
+        .then_some(())
         .ok_or_else( || 
             format!("failed to access project path {:?}", project_path))?;
 
@@ -59,15 +398,174 @@ pub fn load_or_calc_project_hashes(project_path: &Path, hash_type: HashType)
         project_path.file_name().ok_or("invalid project name")?;
 
     // NOTE: Change standard hash type if needed.
-    let hash_list: Vec<ImageHashEntry> = 
-        calc_hash_project(project_path, hash_type)?;
+    let hash_list: Vec<ImageHashEntry> = match (image_store, hash_store) {
+        (Some(image_store), _) => image_store::load_project_hashes(image_store, hash_store, project_path, hash_type, params, force_recompute, progress)?,
+        (None, Some(store)) => load_project_hashes(store, project_path, hash_type, params, force_recompute, progress)?,
+        (None, None) => calc_hash_project(project_path, hash_type, params, force_recompute, progress)?,
+    };
 
     let load_done = load_now.elapsed(); // Measure load time
 
-    // Verbose
-
-    println!("[*] loading project <{:?}> costs: {:.3?}", project_name, load_done);
-    println!("[v] loaded {} entries from project <{:?}>", hash_list.len(), project_name);
+    tracing::info!(
+        project = %project_name.to_string_lossy(), 
+        entries = hash_list.len(), 
+        elapsed = ?load_done, 
+        "loaded project");
     
     Ok(hash_list)
-}
\ No newline at end of file
+}
+
+/// Merge a reindexed hash list of a project with the changes made to
+/// its `live` list while reindexing, `snapshot` holds the images indexed
+/// when the reindex started.
+/// 
+/// Images uploaded meanwhile are kept and images removed meanwhile stay
+/// removed, other images are indexed as the rescan found them.
+pub fn merge_reindexed_hashes(
+    snapshot: &HashSet<PathBuf>, 
+    live: Vec<ImageHashEntry>, 
+    rescanned: Vec<ImageHashEntry>) -> Vec<ImageHashEntry> {
+
+    let live_images: HashSet<PathBuf> = live.iter().map(|h_ent| h_ent.image_name.clone()).collect();
+    let rescanned_images: HashSet<PathBuf> = rescanned.iter().map(|h_ent| h_ent.image_name.clone()).collect();
+
+    let mut hash_list: Vec<ImageHashEntry> = rescanned.into_iter()
+        .filter(|h_ent| live_images.contains(&h_ent.image_name) || !snapshot.contains(&h_ent.image_name))
+        .chain(live.into_iter()
+            .filter(|h_ent| !snapshot.contains(&h_ent.image_name) && !rescanned_images.contains(&h_ent.image_name)))
+        .collect();
+
+    sort_hash_list(&mut hash_list);
+    hash_list
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_image_name() {
+        assert!(validate_image_name("cat.png").is_ok());
+        assert!(validate_image_name("cat.JPG").is_ok());
+        assert!(validate_image_name("cat.png.phash").is_err());
+        assert!(validate_image_name("../cat.png").is_err());
+        assert!(validate_image_name("..").is_err());
+        assert!(validate_image_name("").is_err());
+    }
+
+    #[test]
+    fn test_project_name_policy() {
+        let open = ProjectNamePolicy::default();
+        assert!(open.check("cats").is_ok());
+        assert!(open.check("..").is_err());
+
+        let deny = ProjectNamePolicy { denylist: vec!["admin".to_owned()], ..Default::default() };
+        assert!(deny.check("cats").is_ok());
+        assert!(deny.check("admin").unwrap_err().contains("reserved"));
+
+        let allow = ProjectNamePolicy { 
+            allowlist: Some(vec!["cats".to_owned(), "admin".to_owned()]), 
+            denylist: vec!["admin".to_owned()] };
+        assert!(allow.check("cats").is_ok());
+        assert!(allow.check("dogs").is_err());
+        // the denylist wins over the allowlist.
+        assert!(allow.check("admin").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pending_projects() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let pending = PendingProjects::new(["cats".to_owned()]);
+        let loads = AtomicUsize::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            Ok(())
+        };
+
+        // concurrent requests load the project once.
+        let (a, b) = tokio::join!(pending.load_once("cats", load), pending.load_once("cats", load));
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(pending.names().is_empty());
+
+        pending.load_once("dogs", load).await.unwrap();
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        let failing = PendingProjects::new(["cats".to_owned()]);
+        assert!(failing.load_once("cats", || async { Err("disk gone".to_owned()) }).await.is_err());
+        assert_eq!(failing.names(), ["cats"]);
+    }
+
+    #[test]
+    fn test_merge_reindexed_hashes() {
+        let mk = |name: &str| ImageHashEntry::new(PathBuf::from(name), HashType::PHASH, 
+            crate::image_hash::Hash { bits: vec![true; 8] });
+        let names = |hash_list: &[ImageHashEntry]| hash_list.iter()
+            .map(|h_ent| h_ent.image_name.to_string_lossy().into_owned())
+            .sorted()
+            .collect::<Vec<_>>();
+
+        let snapshot: HashSet<PathBuf> = ["kept.png", "removed.png", "gone.png"].iter().map(PathBuf::from).collect();
+        // "removed.png" removed and "uploaded.png" added while reindexing.
+        let live = vec![mk("kept.png"), mk("gone.png"), mk("uploaded.png")];
+        // "gone.png" no longer on disk, "found.png" added out of band.
+        let rescanned = vec![mk("kept.png"), mk("removed.png"), mk("found.png")];
+
+        let merged = merge_reindexed_hashes(&snapshot, live, rescanned);
+        assert_eq!(names(&merged), vec!["found.png", "kept.png", "uploaded.png"]);
+    }
+
+    #[test]
+    fn test_remove_project_files() {
+        let dir = std::env::temp_dir().join(format!("vismatch-remove-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for f in ["a.png", "a.png.phash", "b.jpg", "notes.txt"] {
+            std::fs::write(dir.join(f), b"").unwrap();
+        }
+
+        let removed = remove_project_files(&dir).unwrap();
+        assert_eq!(removed, RemovedProjectFiles { image_files: 2, cache_files: 1, other_files: 1 });
+        assert!(!dir.exists());
+        assert!(remove_project_files(&dir).is_err());
+    }
+
+    #[test]
+    fn test_write_missing_hash_caches() {
+        use crate::image_hash::Hash;
+
+        let dir = std::env::temp_dir().join(format!("vismatch-persist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.png"), b"").unwrap();
+        std::fs::write(dir.join("b.png"), b"").unwrap();
+        std::fs::write(dir.join("b.png.phash"), b"").unwrap();
+
+        let mk = |name: &str| ImageHashEntry::new(dir.join(name), HashType::PHASH, Hash { bits: vec![true, false] });
+        let hash_list = [mk("a.png"), mk("b.png"), mk("gone.png")];
+
+        assert_eq!(write_missing_hash_caches(&hash_list).unwrap(), 1);
+        assert!(dir.join("a.png.phash").is_file());
+        assert!(!dir.join("gone.png.phash").exists());
+        assert_eq!(write_missing_hash_caches(&hash_list).unwrap(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_project_name() {
+        assert!(validate_project_name("my_project").is_ok());
+        assert!(validate_project_name("backup-2024.01").is_ok());
+
+        assert!(validate_project_name("").is_err());
+        assert!(validate_project_name("..").is_err());
+        assert!(validate_project_name("../etc").is_err());
+        assert!(validate_project_name("a/b").is_err());
+        assert!(validate_project_name("a\\b").is_err());
+        assert!(validate_project_name("a:b").is_err());
+        assert!(validate_project_name("what?").is_err());
+        assert!(validate_project_name("tab\tname").is_err());
+        assert!(validate_project_name("nul\0").is_err());
+    }
+}
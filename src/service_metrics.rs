@@ -0,0 +1,154 @@
+//! Prometheus metrics of the service.
+//! 
+//! All metrics live in one registry owned by `ServiceMetrics`, which is
+//! shared with middlewares and rendered by `GET /metrics`.
+
+use prometheus::{
+    Encoder,
+    Histogram,
+    HistogramOpts,
+    HistogramVec,
+    IntCounterVec,
+    IntGaugeVec,
+    Opts,
+    Registry,
+    TextEncoder,
+    exponential_buckets,
+};
+
+/// Registry and handles of every exported metric.
+#[derive(Clone)]
+pub struct ServiceMetrics {
+    registry: Registry,
+    /// Request body sizes in bytes, labelled by route.
+    pub request_body_bytes: HistogramVec,
+    /// Response body sizes in bytes, labelled by route.
+    pub response_body_bytes: HistogramVec,
+    /// Handled uploads, labelled by outcome (`success` or `error`).
+    pub uploads_total: IntCounterVec,
+    /// Handled comparisons, labelled by outcome (`success` or `error`).
+    pub compares_total: IntCounterVec,
+    /// Time to hash one image, labelled by hash type.
+    pub hash_seconds: HistogramVec,
+    /// Time to rank a query hash against a whole project.
+    pub compare_seconds: Histogram,
+    /// Indexed images, labelled by project. Refreshed on render, see
+    /// `set_project_images`.
+    pub project_images: IntGaugeVec,
+}
+
+impl ServiceMetrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+
+        // 64 B up to 16 MiB, wide enough for small JSON and large uploads.
+        let body_buckets = exponential_buckets(64.0, 4.0, 10)?;
+
+        let request_body_bytes = HistogramVec::new(
+            HistogramOpts::new("vismatch_request_body_bytes", "Request body size in bytes.")
+                .buckets(body_buckets.clone()),
+            &["endpoint"])?;
+        let response_body_bytes = HistogramVec::new(
+            HistogramOpts::new("vismatch_response_body_bytes", "Response body size in bytes.")
+                .buckets(body_buckets),
+            &["endpoint"])?;
+
+        let uploads_total = IntCounterVec::new(
+            Opts::new("vismatch_uploads_total", "Uploads handled."),
+            &["outcome"])?;
+        let compares_total = IntCounterVec::new(
+            Opts::new("vismatch_compares_total", "Comparisons handled."),
+            &["outcome"])?;
+
+        // 100 us up to about 26 s.
+        let latency_buckets = exponential_buckets(0.0001, 4.0, 10)?;
+
+        let hash_seconds = HistogramVec::new(
+            HistogramOpts::new("vismatch_hash_computation_seconds", "Time to hash one image.")
+                .buckets(latency_buckets.clone()),
+            &["hash_type"])?;
+        let compare_seconds = Histogram::with_opts(
+            HistogramOpts::new("vismatch_comparison_seconds", "Time to rank a query against a project.")
+                .buckets(latency_buckets))?;
+
+        let project_images = IntGaugeVec::new(
+            Opts::new("vismatch_project_images", "Indexed images per project."),
+            &["project"])?;
+
+        registry.register(Box::new(request_body_bytes.clone()))?;
+        registry.register(Box::new(response_body_bytes.clone()))?;
+        registry.register(Box::new(uploads_total.clone()))?;
+        registry.register(Box::new(compares_total.clone()))?;
+        registry.register(Box::new(hash_seconds.clone()))?;
+        registry.register(Box::new(compare_seconds.clone()))?;
+        registry.register(Box::new(project_images.clone()))?;
+
+        Ok(ServiceMetrics {
+            registry,
+            request_body_bytes,
+            response_body_bytes,
+            uploads_total,
+            compares_total,
+            hash_seconds,
+            compare_seconds,
+            project_images,
+        })
+    }
+
+    /// Replace the per-project image gauges, so removed or renamed
+    /// projects do not linger.
+    pub fn set_project_images<'a>(&self, image_counts: impl IntoIterator<Item = (&'a str, usize)>) {
+        self.project_images.reset();
+        for (project_name, image_count) in image_counts {
+            self.project_images.with_label_values(&[project_name]).set(image_count as i64);
+        }
+    }
+
+    /// Count a handled request in `counter` by its outcome.
+    pub fn count_outcome<T, E>(counter: &IntCounterVec, result: &Result<T, E>) {
+        let outcome = match result {
+            Ok(_) => "success",
+            Err(_) => "error",
+        };
+        counter.with_label_values(&[outcome]).inc();
+    }
+
+    /// Render all metrics in the Prometheus text format.
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buf: Vec<u8> = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+
+        String::from_utf8(buf).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = ServiceMetrics::new().unwrap();
+        metrics.request_body_bytes.with_label_values(&["/upload"]).observe(1000.0);
+
+        let text = metrics.render().unwrap();
+        assert!(text.contains(r#"vismatch_request_body_bytes_bucket{endpoint="/upload",le="1024"} 1"#));
+        assert!(text.contains(r#"vismatch_request_body_bytes_count{endpoint="/upload"} 1"#));
+
+        ServiceMetrics::count_outcome(&metrics.uploads_total, &Ok::<_, ()>(()));
+        ServiceMetrics::count_outcome(&metrics.uploads_total, &Err::<(), _>(()));
+        ServiceMetrics::count_outcome(&metrics.uploads_total, &Err::<(), _>(()));
+        metrics.compare_seconds.observe(0.002);
+
+        metrics.set_project_images([("a", 3), ("b", 5)]);
+        metrics.set_project_images([("b", 6)]);
+
+        let text = metrics.render().unwrap();
+        assert!(text.contains(r#"vismatch_uploads_total{outcome="success"} 1"#));
+        assert!(text.contains(r#"vismatch_uploads_total{outcome="error"} 2"#));
+        assert!(text.contains("vismatch_comparison_seconds_count 1"));
+        assert!(!text.contains(r#"vismatch_project_images{project="a"}"#));
+        assert!(text.contains(r#"vismatch_project_images{project="b"} 6"#));
+    }
+}
@@ -12,13 +12,55 @@ pub fn is_image_file(file: &DirEntry) -> bool {
         true => {
             match file.path().extension() {
                 None => false,
-                Some(ext) => {
-                    IMAGE_EXTENSIONS.contains(
-                        &ext.to_string_lossy()
-                            .to_lowercase()
-                            .as_str())
-                },
+                Some(ext) => is_image_extension(&ext.to_string_lossy()),
             }
         },
     }
-}
\ No newline at end of file
+}
+
+/// Check if a file extension is one of the accepted image types,
+/// case-insensitive.
+pub fn is_image_extension(ext: &str) -> bool {
+    IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str())
+}
+
+/// Check if a decoded image format is one of the accepted image types.
+pub fn is_allowed_image_format(format: image::ImageFormat) -> bool {
+    format.extensions_str()
+        .iter()
+        .any(|ext| IMAGE_EXTENSIONS.contains(ext))
+}
+
+/// Match a name against a pattern with `*` (any run of characters) and
+/// `?` (any single character) wildcards.
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // classic greedy matching, backtrack to the last `*` on mismatch.
+    let (mut p, mut n) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                last_star = Some((p, n));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match last_star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    last_star = Some((star_p, star_n + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
@@ -0,0 +1,156 @@
+//! Watch the project root for images changed out-of-band.
+//!
+//! Images copied into a project folder with rsync, SFTP and the like
+//! should reach the in-memory index without a restart. The watcher
+//! reports every image file touched under a project folder, once the
+//! file stayed quiet for a while, so half-written files are not hashed.
+//! Whether it was written or removed is up to the caller to check.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use notify::event::{AccessKind, AccessMode, EventKind, ModifyKind};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::image_hash::is_cache_file;
+use crate::utils::is_image_extension;
+
+/// An image file of a project that changed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ImageChange {
+    pub project_name: String,
+    pub image_name: String,
+}
+
+impl ImageChange {
+    /// Change of `path` if it is an image directly inside a project
+    /// folder of `project_root`.
+    fn of(project_root: &Path, path: &Path) -> Option<Self> {
+        let project_path = path.parent()?;
+        if project_path.parent()? != project_root || is_cache_file(path) {
+            return None;
+        }
+
+        let is_image = path.extension()
+            .is_some_and(|ext| is_image_extension(&ext.to_string_lossy()));
+        if !is_image {
+            return None;
+        }
+
+        Some(ImageChange {
+            project_name: project_path.file_name()?.to_str()?.to_owned(),
+            image_name: path.file_name()?.to_str()?.to_owned(),
+        })
+    }
+}
+
+/// Whether a filesystem event may change the content or existence of a file.
+fn is_content_change(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Create(_) | EventKind::Remove(_) => true,
+        EventKind::Modify(ModifyKind::Metadata(_)) => false,
+        EventKind::Modify(_) => true,
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        _ => false,
+    }
+}
+
+/// Watcher of the project root, yields settled image changes.
+pub struct ProjectWatcher {
+    // events stop when the watcher is dropped.
+    _watcher: RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<ImageChange>,
+    /// Changed images and when they last changed.
+    unsettled: HashMap<ImageChange, Instant>,
+    settle: Duration,
+}
+
+impl ProjectWatcher {
+    /// Watch `project_root` and its project folders, a change is
+    /// reported after `settle` without further changes to the file.
+    pub fn start(project_root: &Path, settle: Duration) -> Result<Self, notify::Error> {
+        // events carry the watched path as prefix, make it comparable.
+        let project_root: PathBuf = project_root.canonicalize()?;
+        let (sender, events) = mpsc::unbounded_channel();
+
+        let watched_root = project_root.clone();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    tracing::warn!(error = %e, "project root watcher error");
+                    return;
+                },
+            };
+
+            if !is_content_change(&event.kind) {
+                return;
+            }
+
+            for change in event.paths.iter().filter_map(|path| ImageChange::of(&watched_root, path)) {
+                // the receiver is gone on shutdown, nothing left to do.
+                let _ = sender.send(change);
+            }
+        })?;
+
+        watcher.watch(&project_root, RecursiveMode::Recursive)?;
+
+        Ok(ProjectWatcher { _watcher: watcher, events, unsettled: HashMap::new(), settle })
+    }
+
+    /// Wait for the next images whose changes settled.
+    pub async fn next_changes(&mut self) -> Vec<ImageChange> {
+        loop {
+            let next_settled = self.unsettled.values().min().map(|last| *last + self.settle);
+
+            tokio::select! {
+                change = self.events.recv() => match change {
+                    Some(change) => {
+                        self.unsettled.insert(change, Instant::now());
+                    },
+                    // the watcher callback lives as long as `self`.
+                    None => std::future::pending::<()>().await,
+                },
+                _ = tokio::time::sleep_until(next_settled.unwrap_or_else(Instant::now)), if next_settled.is_some() => {
+                    let now = Instant::now();
+                    let settled: Vec<ImageChange> = self.unsettled.iter()
+                        .filter(|(_, last)| **last + self.settle <= now)
+                        .map(|(change, _)| change.clone())
+                        .collect();
+
+                    for change in &settled {
+                        self.unsettled.remove(change);
+                    }
+                    return settled;
+                },
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_change_of() {
+        let root = Path::new("/data/image_root");
+        let change = |path: &str| ImageChange::of(root, Path::new(path));
+
+        assert_eq!(change("/data/image_root/cats/a.PNG"), Some(ImageChange {
+            project_name: "cats".to_owned(),
+            image_name: "a.PNG".to_owned(),
+        }));
+        assert_eq!(change("/data/image_root/cats/a.png.phash"), None);
+        assert_eq!(change("/data/image_root/cats/notes.txt"), None);
+        assert_eq!(change("/data/image_root/a.png"), None);
+        assert_eq!(change("/data/image_root/cats/nested/a.png"), None);
+
+        assert!(is_content_change(&EventKind::Access(AccessKind::Close(AccessMode::Write))));
+        assert!(!is_content_change(&EventKind::Access(AccessKind::Close(AccessMode::Read))));
+    }
+}